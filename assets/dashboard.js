@@ -0,0 +1,310 @@
+// Dashboard for the flood monitor. Everything comes from the monitor's own
+// API: /api/nodes (with stage forecasts), /api/alerts, /api/nodes.geojson and
+// per-gauge history, refreshed by the /api/events feed. No external resources
+// are loaded.
+"use strict";
+
+// Nodes not heard from for this long are greyed out
+const STALE_SECS = 3 * 3600;
+// Water level history shown in the sparklines
+const SPARKLINE_SECS = 24 * 3600;
+const SVG = "http://www.w3.org/2000/svg";
+
+const stages = new Map(); // node id -> stage from the GeoJSON feed
+const sparklines = new Map(); // node id -> samples
+
+async function getJson(path) {
+  const response = await fetch(path);
+  if (!response.ok) {
+    throw new Error(`${path}: ${response.status}`);
+  }
+  return response.json();
+}
+
+function el(tag, attrs = {}, text) {
+  const node = tag === "svg" || ["polyline", "circle", "text", "line", "title"].includes(tag)
+    ? document.createElementNS(SVG, tag)
+    : document.createElement(tag);
+  for (const [key, value] of Object.entries(attrs)) {
+    node.setAttribute(key, value);
+  }
+  if (text !== undefined) {
+    node.textContent = text;
+  }
+  return node;
+}
+
+function ago(timestamp) {
+  if (timestamp == null) {
+    return "never";
+  }
+  const secs = Math.max(0, Date.now() / 1000 - timestamp);
+  if (secs < 90) return `${Math.round(secs)} s ago`;
+  if (secs < 5400) return `${Math.round(secs / 60)} min ago`;
+  if (secs < 129600) return `${Math.round(secs / 3600)} h ago`;
+  return `${Math.round(secs / 86400)} d ago`;
+}
+
+function fixed(sample, digits, unit) {
+  return sample ? `${sample.value.toFixed(digits)}${unit}` : "–";
+}
+
+// A table cell for a reading, marked when it failed a data-quality check
+function readingCell(sample, digits, unit) {
+  if (!sample || !sample.quality || sample.quality === "good") {
+    return el("td", { class: "num" }, fixed(sample, digits, unit));
+  }
+  return el("td", { class: "num flagged", title: `Suspect reading: ${sample.quality}` },
+    `${fixed(sample, digits, unit)} ⚠`);
+}
+
+// Battery level, with the hours left while the latest fit says discharging
+function battery(readings) {
+  const text = fixed(readings.battery_level, 0, " %");
+  const left = readings.hours_to_cutoff;
+  const slope = readings.voltage_slope;
+  if (!left || !slope || left.timestamp !== slope.timestamp) {
+    return text;
+  }
+  return `${text} (~${Math.round(left.value)} h left)`;
+}
+
+// Time until `timestamp`, e.g. "~2h40m"
+function leadTime(timestamp) {
+  const mins = Math.max(0, Math.round((timestamp - Date.now() / 1000) / 60));
+  if (mins < 60) return `~${mins}m`;
+  const hours = Math.floor(mins / 60);
+  return mins % 60 ? `~${hours}h${mins % 60}m` : `~${hours}h`;
+}
+
+// Stage trend and the next flood rule the forecast breaches, if any
+function forecastCell(forecast) {
+  if (!forecast) {
+    return el("td", {}, "–");
+  }
+  const rate = `${forecast.rate_per_hour >= 0 ? "+" : ""}${forecast.rate_per_hour.toFixed(2)} m/h`;
+  const crossing = forecast.crossings[0];
+  if (!crossing) {
+    return el("td", { class: "num" }, rate);
+  }
+  const time = (t) => new Date(t * 1000).toLocaleTimeString();
+  const range = crossing.latest
+    ? `${time(crossing.earliest)}–${time(crossing.latest)}`
+    : `from ${time(crossing.earliest)}`;
+  const percent = Math.round(forecast.confidence * 100);
+  return el("td", {
+    class: `num forecast ${crossing.severity}`,
+    title: `${crossing.rule} (${crossing.threshold} m) expected ${time(crossing.at)}; ` +
+      `${percent} % interval ${range} (${forecast.model} model)`,
+  }, `${rate}, ${crossing.rule} in ${leadTime(crossing.at)}`);
+}
+
+/* ---------------- Alerts ---------------- */
+
+async function refreshAlerts() {
+  const alerts = await getJson("/api/alerts");
+  const banner = document.getElementById("alerts");
+  banner.replaceChildren();
+  banner.hidden = alerts.length === 0;
+  if (alerts.length === 0) {
+    return;
+  }
+  // Most severe first, so the first sets the colour
+  banner.className = `alerts ${alerts[0].severity}`;
+  for (const alert of alerts) {
+    const since = new Date(alert.raised_at * 1000).toLocaleTimeString();
+    const ack = alert.acknowledged ? `, acknowledged by ${alert.acknowledged.by}` : "";
+    const escalated = alert.status === "escalated" ? `, escalated to tier ${alert.tier}` : "";
+    const expected = alert.expected_at
+      ? `, expected ${new Date(alert.expected_at * 1000).toLocaleTimeString()}` : "";
+    const line = el("div", {},
+      `#${alert.id} ${alert.severity.toUpperCase()}: ${alert.node_name} ${alert.metric} ` +
+      `${alert.value.toFixed(2)} (threshold ${alert.threshold}) — ${alert.rule}${expected}, since ${since}` +
+      `${escalated}${ack}`);
+    if (!alert.acknowledged) {
+      const button = el("button", { type: "button" }, "Acknowledge");
+      button.addEventListener("click", () => acknowledge(alert.id));
+      line.append(" ", button);
+    }
+    banner.append(line);
+  }
+}
+
+async function acknowledge(id) {
+  const by = window.prompt(`Acknowledge alert #${id} as:`, localStorage.getItem("ackName") || "");
+  if (!by || !by.trim()) {
+    return;
+  }
+  localStorage.setItem("ackName", by.trim());
+  const response = await fetch(`/api/alerts/${id}/ack`, {
+    method: "POST",
+    headers: { "Content-Type": "application/json" },
+    body: JSON.stringify({ by: by.trim() }),
+  });
+  if (!response.ok) {
+    const body = await response.json().catch(() => ({}));
+    window.alert(body.error || `Acknowledging failed: ${response.status}`);
+  }
+  scheduleRefresh(0);
+}
+
+/* ---------------- Node table ---------------- */
+
+function sparkline(samples) {
+  const svg = el("svg", { class: "sparkline", viewBox: "0 0 120 28", preserveAspectRatio: "none" });
+  if (!samples || samples.length < 2) {
+    return svg;
+  }
+  const t0 = samples[0].timestamp;
+  const t1 = samples[samples.length - 1].timestamp;
+  const values = samples.map((s) => s.value);
+  const min = Math.min(...values);
+  const max = Math.max(...values);
+  const points = samples.map((s) => {
+    const x = t1 === t0 ? 0 : ((s.timestamp - t0) / (t1 - t0)) * 120;
+    const y = max === min ? 14 : 26 - ((s.value - min) / (max - min)) * 24;
+    return `${x.toFixed(1)},${y.toFixed(1)}`;
+  });
+  svg.append(el("polyline", { points: points.join(" ") }));
+  svg.append(el("title", {}, `${min.toFixed(2)} – ${max.toFixed(2)} over 24 h`));
+  return svg;
+}
+
+async function refreshSparkline(node) {
+  const from = Math.floor(Date.now() / 1000) - SPARKLINE_SECS;
+  const history = await getJson(
+    `/api/nodes/${encodeURIComponent(node.id)}/history?metric=water_level&from=${from}`);
+  sparklines.set(node.id, history.samples);
+}
+
+async function refreshNodes() {
+  const nodes = await getJson("/api/nodes");
+  await Promise.all(nodes.filter((n) => n.gauge).map(refreshSparkline));
+
+  const now = Date.now() / 1000;
+  const rows = nodes.map((node) => {
+    const stage = stages.get(node.id) || "normal";
+    const stale = node.last_heard == null || now - node.last_heard > STALE_SECS;
+    const signal = node.rx_snr == null ? "–" : `${node.rx_snr.toFixed(1)} dB / ${node.rx_rssi} dBm`;
+
+    const name = el("td");
+    name.append(el("span", { class: `stage ${stage}`, title: stage }), node.name);
+    const trend = el("td");
+    if (node.gauge) {
+      trend.append(sparkline(sparklines.get(node.id)));
+    }
+
+    const row = el("tr", stale ? { class: "stale" } : {});
+    row.append(
+      name,
+      el("td", {}, node.id),
+      readingCell(node.readings.water_level, 2, " m"),
+      trend,
+      node.gauge ? forecastCell(node.forecast) : el("td"),
+      el("td", { class: "num" }, battery(node.readings)),
+      el("td", { class: "num" }, signal),
+      el("td", { title: node.last_heard ? new Date(node.last_heard * 1000).toString() : "" },
+        ago(node.last_heard)),
+    );
+    return row;
+  });
+  document.querySelector("#nodes tbody").replaceChildren(...rows);
+}
+
+/* ---------------- Map ---------------- */
+
+async function refreshMap() {
+  const collection = await getJson("/api/nodes.geojson");
+  const svg = document.getElementById("map");
+  const [width, height, margin] = [600, 400, 40];
+  svg.replaceChildren();
+
+  for (const feature of collection.features) {
+    stages.set(feature.properties.node, feature.properties.stage);
+  }
+  if (collection.features.length === 0) {
+    svg.append(el("text", { x: width / 2, y: height / 2, "text-anchor": "middle", class: "empty" },
+      "No node has reported a position yet"));
+    return;
+  }
+
+  // Equirectangular around the nodes, with longitude scaled by latitude so
+  // distances look right at local scale
+  const coords = collection.features.map((f) => f.geometry.coordinates);
+  const lats = coords.map((c) => c[1]);
+  const lons = coords.map((c) => c[0]);
+  const midLat = (Math.min(...lats) + Math.max(...lats)) / 2;
+  const xScale = Math.cos((midLat * Math.PI) / 180);
+  const spanX = Math.max((Math.max(...lons) - Math.min(...lons)) * xScale, 1e-4);
+  const spanY = Math.max(Math.max(...lats) - Math.min(...lats), 1e-4);
+  const scale = Math.min((width - 2 * margin) / spanX, (height - 2 * margin) / spanY);
+  const project = ([lon, lat]) => [
+    width / 2 + (lon - (Math.min(...lons) + Math.max(...lons)) / 2) * xScale * scale,
+    height / 2 - (lat - midLat) * scale,
+  ];
+
+  const points = coords.map(project);
+  collection.features.forEach((feature, i) => {
+    const p = feature.properties;
+    const [x, y] = points[i];
+    const circle = el("circle", { cx: x, cy: y, r: p.gauge ? 8 : 5, class: p.stage });
+    const suspect = p.water_level_quality && p.water_level_quality !== "good"
+      ? ` (suspect: ${p.water_level_quality})` : "";
+    const level = p.water_level == null ? "" : `, ${p.water_level.toFixed(2)} m${suspect}`;
+    circle.append(el("title", {}, `${p.name} (${p.node})${level}, ${p.stage}`));
+    svg.append(circle, el("text", { x: x + 10, y: y + 4 }, p.name));
+  });
+
+  // Scale bar: degrees of latitude to metres
+  const metres = spanY * 111_320;
+  const barMetres = 10 ** Math.floor(Math.log10(Math.max(metres, 10)));
+  const barPx = (barMetres / 111_320) * scale;
+  svg.append(
+    el("line", { x1: 10, y1: height - 12, x2: 10 + barPx, y2: height - 12, stroke: "#1d2430", "stroke-width": 2 }),
+    el("text", { x: 14 + barPx, y: height - 8 },
+      barMetres >= 1000 ? `${barMetres / 1000} km` : `${barMetres} m`),
+  );
+}
+
+/* ---------------- Refresh ---------------- */
+
+let pending = null;
+
+// Coalesces bursts of events into one refresh
+function scheduleRefresh(delay = 1000) {
+  if (pending) {
+    return;
+  }
+  pending = setTimeout(async () => {
+    pending = null;
+    try {
+      await refreshMap();
+      await Promise.all([refreshNodes(), refreshAlerts()]);
+    } catch (e) {
+      console.error(e);
+    }
+  }, delay);
+}
+
+function connectFeed() {
+  const state = document.getElementById("feed-state");
+  const events = new EventSource("/api/events");
+  events.onopen = () => {
+    state.textContent = "live";
+    state.classList.add("live");
+  };
+  events.onerror = () => {
+    // EventSource reconnects on its own
+    state.textContent = "reconnecting…";
+    state.classList.remove("live");
+  };
+  events.addEventListener("message", () => scheduleRefresh());
+  events.addEventListener("link", () => scheduleRefresh());
+  events.addEventListener("alert", () => scheduleRefresh(0));
+  events.addEventListener("lagged", () => scheduleRefresh(0));
+}
+
+scheduleRefresh(0);
+connectFeed();
+// Keeps "last heard" current while the mesh is quiet
+setInterval(() => scheduleRefresh(0), 60_000);
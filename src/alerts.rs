@@ -0,0 +1,337 @@
+use std::collections::HashSet;
+use std::fmt;
+
+use crate::config::{AlertRule, Config, Severity};
+use crate::quality::Quality;
+use crate::table::Timezone;
+use crate::{battery, forecast, heartbeat, reach};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AlertState {
+    Raised,
+    Cleared,
+}
+
+/// A threshold rule changing state for one node.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AlertEvent {
+    pub rule: String,
+    /// Time of the reading that caused the transition
+    pub timestamp: u64,
+    pub node_id: u32,
+    pub node_name: String,
+    pub metric: String,
+    pub value: f64,
+    pub threshold: f64,
+    /// When a forecast expects `value` to be reached; `None` for alerts on
+    /// a reading
+    pub expected_at: Option<u64>,
+    pub severity: Severity,
+    pub state: AlertState,
+}
+
+impl fmt::Display for AlertEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let state = match self.state {
+            AlertState::Raised => "RAISED",
+            AlertState::Cleared => "CLEARED",
+        };
+        write!(
+            f,
+            "[{}] {} {} on {}: {} = {:.3} (threshold {:.3})",
+            self.severity,
+            state,
+            self.rule,
+            self.node_name,
+            self.metric,
+            self.value,
+            self.threshold
+        )?;
+        if let Some(at) = self.expected_at {
+            write!(f, ", expected {}", Timezone::Utc.format(at))?;
+        }
+        Ok(())
+    }
+}
+
+/// How long until a forecast comes true, e.g. `~2h40m` or `~25m`.
+pub fn lead_time(secs: u64) -> String {
+    let mins = (secs + 30) / 60;
+    match (mins / 60, mins % 60) {
+        (0, mins) => format!("~{}m", mins),
+        (hours, 0) => format!("~{}h", hours),
+        (hours, mins) => format!("~{}h{:02}m", hours, mins),
+    }
+}
+
+struct CompiledRule {
+    rule: AlertRule,
+    /// `None` applies the rule to every node.
+    node: Option<u32>,
+}
+
+impl CompiledRule {
+    fn threshold(&self) -> f64 {
+        self.rule.above.or(self.rule.below).unwrap_or_default()
+    }
+
+    fn breached(&self, value: f64) -> bool {
+        match (self.rule.above, self.rule.below) {
+            (Some(above), _) => value > above,
+            (_, Some(below)) => value < below,
+            _ => false,
+        }
+    }
+
+    fn recovered(&self, value: f64) -> bool {
+        match (self.rule.above, self.rule.below) {
+            (Some(above), _) => value <= above - self.rule.hysteresis,
+            (_, Some(below)) => value >= below + self.rule.hysteresis,
+            _ => true,
+        }
+    }
+}
+
+fn compile_rules(config: &Config) -> Vec<CompiledRule> {
+    config
+        .alerts
+        .iter()
+        .map(|rule| CompiledRule {
+            rule: rule.clone(),
+            node: rule
+                .node
+                .as_deref()
+                .and_then(|n| config.resolve_node(n))
+                .map(|id| id.0),
+        })
+        .collect()
+}
+
+/// A `water_level` rule with `above`, the stages forecasts are measured
+/// against.
+#[derive(Debug, Clone, PartialEq)]
+pub struct StageRule {
+    pub name: String,
+    /// `None` applies the rule to every node.
+    pub node: Option<u32>,
+    pub above: f64,
+    pub severity: Severity,
+}
+
+impl StageRule {
+    pub fn applies_to(&self, node_id: u32) -> bool {
+        self.node.is_none_or(|n| n == node_id)
+    }
+}
+
+pub fn stage_rules(config: &Config) -> Vec<StageRule> {
+    compile_rules(config)
+        .into_iter()
+        .filter(|c| c.rule.metric == "water_level")
+        .filter_map(|c| {
+            Some(StageRule {
+                above: c.rule.above?,
+                name: c.rule.name,
+                node: c.node,
+                severity: c.rule.severity,
+            })
+        })
+        .collect()
+}
+
+/// Whether alerts named `rule` can still be raised and cleared under
+/// `config`: a configured rule, the forecast of a `water_level` one, or an
+/// alert of one of the built-in monitors.
+pub fn rule_exists(config: &Config, rule: &str) -> bool {
+    let configured = |name: &str| config.alerts.iter().any(|r| r.name == name);
+    configured(rule)
+        || rule
+            .strip_suffix(forecast::RULE_SUFFIX)
+            .is_some_and(|name| stage_rules(config).iter().any(|r| r.name == name))
+        || [
+            heartbeat::STALE_RULE,
+            heartbeat::LOST_RULE,
+            battery::DEPLETING_RULE,
+            battery::SOLAR_RULE,
+            reach::FORECAST_RULE,
+        ]
+        .contains(&rule)
+        || Quality::FAULTS.iter().any(|q| q.rule() == rule)
+}
+
+/// Evaluates configured threshold rules against incoming readings and
+/// reports raise/clear transitions.
+pub struct AlertEngine {
+    rules: Vec<CompiledRule>,
+    /// (rule name, node id) pairs currently in the raised state.
+    active: HashSet<(String, u32)>,
+}
+
+impl AlertEngine {
+    pub fn from_config(config: &Config) -> Self {
+        Self {
+            rules: compile_rules(config),
+            active: HashSet::new(),
+        }
+    }
+
+    /// Swaps in the rules from a reloaded config. Alerts raised by rules
+    /// that still exist stay raised, so an edit does not re-fire them.
+    pub fn replace_rules(&mut self, config: &Config) {
+        self.rules = compile_rules(config);
+        let rules = &self.rules;
+        self.active
+            .retain(|(name, _)| rules.iter().any(|r| &r.rule.name == name));
+    }
+
+    /// Marks alerts raised before a restart as active, so they are not
+    /// raised again and clear as usual. Rules since removed are ignored.
+    pub fn restore(&mut self, active: impl IntoIterator<Item = (String, u32)>) {
+        for key in active {
+            if self.rules.iter().any(|r| r.rule.name == key.0) {
+                self.active.insert(key);
+            }
+        }
+    }
+
+    pub fn evaluate(
+        &mut self,
+        timestamp: u64,
+        node_id: u32,
+        node_name: &str,
+        metric: &str,
+        value: f64,
+    ) -> Vec<AlertEvent> {
+        let mut events = Vec::new();
+
+        for compiled in &self.rules {
+            if compiled.rule.metric != metric {
+                continue;
+            }
+            if compiled.node.is_some_and(|n| n != node_id) {
+                continue;
+            }
+
+            let key = (compiled.rule.name.clone(), node_id);
+            let state = if self.active.contains(&key) {
+                if !compiled.recovered(value) {
+                    continue;
+                }
+                self.active.remove(&key);
+                AlertState::Cleared
+            } else {
+                if !compiled.breached(value) {
+                    continue;
+                }
+                self.active.insert(key);
+                AlertState::Raised
+            };
+
+            events.push(AlertEvent {
+                rule: compiled.rule.name.clone(),
+                timestamp,
+                node_id,
+                node_name: node_name.to_string(),
+                metric: metric.to_string(),
+                value,
+                threshold: compiled.threshold(),
+                expected_at: None,
+                severity: compiled.rule.severity,
+                state,
+            });
+        }
+
+        events
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn engine(toml: &str) -> AlertEngine {
+        AlertEngine::from_config(&Config::parse(toml).unwrap())
+    }
+
+    #[test]
+    fn raises_once_and_clears_with_hysteresis() {
+        let mut engine = engine(
+            r#"
+            [[alerts]]
+            name = "high"
+            metric = "water_level"
+            above = 3.0
+            hysteresis = 0.2
+            "#,
+        );
+
+        assert!(engine.evaluate(0, 1, "a", "water_level", 2.9).is_empty());
+
+        let raised = engine.evaluate(0, 1, "a", "water_level", 3.1);
+        assert_eq!(raised.len(), 1);
+        assert_eq!(raised[0].state, AlertState::Raised);
+
+        // Still above, and inside the hysteresis band: no new events
+        assert!(engine.evaluate(0, 1, "a", "water_level", 3.4).is_empty());
+        assert!(engine.evaluate(0, 1, "a", "water_level", 2.9).is_empty());
+
+        let cleared = engine.evaluate(0, 1, "a", "water_level", 2.7);
+        assert_eq!(cleared.len(), 1);
+        assert_eq!(cleared[0].state, AlertState::Cleared);
+    }
+
+    #[test]
+    fn node_scoped_rules_ignore_other_nodes() {
+        let mut engine = engine(
+            r#"
+            [[nodes]]
+            id = 7
+            alias = "bridge"
+
+            [[alerts]]
+            name = "low-battery"
+            node = "bridge"
+            metric = "battery_level"
+            below = 20
+            "#,
+        );
+
+        assert!(
+            engine
+                .evaluate(0, 8, "other", "battery_level", 5.0)
+                .is_empty()
+        );
+        assert_eq!(
+            engine.evaluate(0, 7, "bridge", "battery_level", 5.0).len(),
+            1
+        );
+    }
+
+    #[test]
+    fn reload_keeps_active_alerts_of_surviving_rules() {
+        let mut engine = engine(
+            r#"
+            [[alerts]]
+            name = "high"
+            metric = "water_level"
+            above = 3.0
+            "#,
+        );
+        assert_eq!(engine.evaluate(0, 1, "a", "water_level", 3.5).len(), 1);
+
+        let lowered = Config::parse(
+            r#"
+            [[alerts]]
+            name = "high"
+            metric = "water_level"
+            above = 2.5
+            "#,
+        )
+        .unwrap();
+        engine.replace_rules(&lowered);
+        assert!(engine.evaluate(0, 1, "a", "water_level", 3.5).is_empty());
+
+        engine.replace_rules(&Config::default());
+        assert!(engine.active.is_empty());
+    }
+}
@@ -0,0 +1,336 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+
+use crate::alerts::{AlertEvent, AlertState};
+use crate::config::{BatteryConfig, Config};
+
+/// Alert raised when a node's battery is expected to reach its cutoff
+/// voltage within `alert_within_hours`.
+pub const DEPLETING_RULE: &str = "battery-depleting";
+/// Alert raised when a node that used to recharge has not for
+/// `charge_window_hours`.
+pub const SOLAR_RULE: &str = "solar-charge-failed";
+
+/// Metrics derived from a node's battery voltage, added to its readings.
+pub const SLOPE_METRIC: &str = "voltage_slope";
+pub const HOURS_LEFT_METRIC: &str = "hours_to_cutoff";
+
+/// Fewest samples a discharge slope is fitted to.
+const MIN_SAMPLES: usize = 4;
+
+/// How far past `alert_within_hours` the estimate must recover before a
+/// depleting alert clears, so a noisy estimate does not flap.
+const CLEAR_MARGIN: f64 = 1.25;
+
+#[derive(Debug, Default)]
+struct Battery {
+    /// `voltage` from device metrics, or `power_voltage` for nodes that
+    /// only send power metrics
+    source: Option<&'static str>,
+    /// (timestamp, volts) within the slope window, oldest first
+    samples: VecDeque<(u64, f64)>,
+    /// Lowest voltage since the last recharge
+    trough: Option<f64>,
+    last_charge: Option<u64>,
+    depleting: bool,
+    solar_failed: bool,
+}
+
+impl Battery {
+    /// Least-squares slope in volts per hour and the fitted voltage at the
+    /// latest sample, once the samples span `min_span_secs`.
+    fn fit(&self, min_span_secs: u64) -> Option<(f64, f64)> {
+        let (&(first, _), &(last, _)) = (self.samples.front()?, self.samples.back()?);
+        if self.samples.len() < MIN_SAMPLES || last - first < min_span_secs {
+            return None;
+        }
+        let n = self.samples.len() as f64;
+        let hours = |t: u64| (t - first) as f64 / 3600.0;
+        let mean_t = self.samples.iter().map(|&(t, _)| hours(t)).sum::<f64>() / n;
+        let mean_v = self.samples.iter().map(|&(_, v)| v).sum::<f64>() / n;
+        let (mut covariance, mut variance) = (0.0, 0.0);
+        for &(t, v) in &self.samples {
+            covariance += (hours(t) - mean_t) * (v - mean_v);
+            variance += (hours(t) - mean_t).powi(2);
+        }
+        let slope = covariance / variance;
+        Some((slope, mean_v + slope * (hours(last) - mean_t)))
+    }
+}
+
+/// Tracks each node's battery voltage: fits a discharge slope over
+/// `window_hours` since the last recharge, estimates the hours left until the cutoff voltage, and
+/// notices solar nodes that stop recharging. The estimates are added to
+/// the node's readings; alerts are raised only when `[battery]` is enabled.
+pub struct BatteryMonitor {
+    config: BatteryConfig,
+    /// Per-node `cutoff_voltage` overrides
+    cutoffs: HashMap<u32, f64>,
+    nodes: HashMap<u32, Battery>,
+}
+
+impl BatteryMonitor {
+    pub fn from_config(config: &Config) -> Self {
+        let mut monitor = Self {
+            config: config.battery.clone(),
+            cutoffs: HashMap::new(),
+            nodes: HashMap::new(),
+        };
+        monitor.reconfigure(config);
+        monitor
+    }
+
+    /// Takes settings from a reloaded config, keeping the voltage history
+    /// and which alerts are raised.
+    pub fn reconfigure(&mut self, config: &Config) {
+        self.config = config.battery.clone();
+        self.cutoffs = config
+            .nodes
+            .iter()
+            .filter_map(|n| Some((n.id.0, n.cutoff_voltage?)))
+            .collect();
+    }
+
+    /// Marks battery alerts raised before a restart as active, so they
+    /// clear as usual.
+    pub fn restore(&mut self, active: &HashSet<(String, u32)>) {
+        for (rule, node_id) in active {
+            let node = self.nodes.entry(*node_id).or_default();
+            match rule.as_str() {
+                DEPLETING_RULE => node.depleting = true,
+                SOLAR_RULE => node.solar_failed = true,
+                _ => {}
+            }
+        }
+    }
+
+    /// Adds `voltage_slope` and, while discharging, `hours_to_cutoff` to a
+    /// telemetry packet's readings, returning any alert transitions.
+    pub fn observe(
+        &mut self,
+        timestamp: u64,
+        node_id: u32,
+        node_name: &str,
+        readings: &mut Vec<(&'static str, f64)>,
+    ) -> Vec<AlertEvent> {
+        let node = self.nodes.entry(node_id).or_default();
+        let voltage = readings
+            .iter()
+            .find(|(metric, _)| *metric == "voltage")
+            .or_else(|| {
+                readings
+                    .iter()
+                    .find(|(metric, _)| *metric == "power_voltage")
+            })
+            .copied();
+        // Device metrics of 0 V mean no battery sensor
+        let Some((source, volts)) = voltage.filter(|&(_, v)| v > 0.0) else {
+            return Vec::new();
+        };
+        match node.source {
+            Some(current) if current == source => {}
+            // Device voltage wins over a power channel once it shows up
+            Some("voltage") => return Vec::new(),
+            _ => {
+                node.source = Some(source);
+                node.samples.clear();
+            }
+        }
+        if node
+            .samples
+            .back()
+            .is_some_and(|&(last, _)| timestamp <= last)
+        {
+            return Vec::new();
+        }
+
+        // A rise off the lowest voltage since the last one is a recharge,
+        // after which the discharge slope starts over
+        let trough = node.trough.get_or_insert(volts);
+        if volts >= *trough + self.config.charge_rise_volts {
+            node.trough = Some(volts);
+            node.last_charge = Some(timestamp);
+            node.samples.clear();
+        } else if volts < *trough {
+            *trough = volts;
+        }
+
+        let window = (self.config.window_hours * 3600.0) as u64;
+        node.samples.push_back((timestamp, volts));
+        while node
+            .samples
+            .front()
+            .is_some_and(|&(t, _)| timestamp - t > window)
+        {
+            node.samples.pop_front();
+        }
+
+        let mut events = Vec::new();
+        let cutoff = self
+            .cutoffs
+            .get(&node_id)
+            .copied()
+            .unwrap_or(self.config.cutoff_voltage);
+        let within = self.config.alert_within_hours;
+        let min_span = (self.config.min_span_hours * 3600.0) as u64;
+        let hours_left = node.fit(min_span).and_then(|(slope, fitted)| {
+            readings.push((SLOPE_METRIC, slope));
+            (slope < 0.0).then(|| ((fitted - cutoff) / -slope).max(0.0))
+        });
+        if let Some(hours) = hours_left {
+            readings.push((HOURS_LEFT_METRIC, hours));
+        }
+        let depleting = match hours_left {
+            Some(hours) if node.depleting => hours <= within * CLEAR_MARGIN,
+            Some(hours) => hours <= within,
+            None => false,
+        };
+        if self.config.enabled && depleting != node.depleting {
+            node.depleting = depleting;
+            if depleting {
+                log::warn!(
+                    "Battery of {} expected to reach {:.2} V in {:.0} h",
+                    node_name,
+                    cutoff,
+                    hours_left.unwrap_or_default()
+                );
+            }
+            events.push(AlertEvent {
+                rule: DEPLETING_RULE.to_string(),
+                timestamp,
+                node_id,
+                node_name: node_name.to_string(),
+                // No estimate once charging, so the voltage shows instead
+                metric: if hours_left.is_some() {
+                    HOURS_LEFT_METRIC
+                } else {
+                    "voltage"
+                }
+                .to_string(),
+                value: hours_left.unwrap_or(volts),
+                threshold: if hours_left.is_some() { within } else { cutoff },
+                expected_at: None,
+                severity: self.config.depleting_severity,
+                state: if depleting {
+                    AlertState::Raised
+                } else {
+                    AlertState::Cleared
+                },
+            });
+        }
+
+        let charge_window = (self.config.charge_window_hours * 3600.0) as u64;
+        let since_charge = node.last_charge.map(|t| timestamp - t);
+        let solar_failed = since_charge.is_some_and(|secs| secs > charge_window);
+        if self.config.enabled && solar_failed != node.solar_failed {
+            node.solar_failed = solar_failed;
+            if solar_failed {
+                log::warn!(
+                    "{} has not recharged for {:.0} h",
+                    node_name,
+                    since_charge.unwrap_or_default() as f64 / 3600.0
+                );
+            }
+            events.push(AlertEvent {
+                rule: SOLAR_RULE.to_string(),
+                timestamp,
+                node_id,
+                node_name: node_name.to_string(),
+                metric: "hours_since_charge".to_string(),
+                value: since_charge.unwrap_or_default() as f64 / 3600.0,
+                threshold: self.config.charge_window_hours,
+                expected_at: None,
+                severity: self.config.solar_severity,
+                state: if solar_failed {
+                    AlertState::Raised
+                } else {
+                    AlertState::Cleared
+                },
+            });
+        }
+        events
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HOUR: u64 = 3600;
+
+    fn monitor() -> BatteryMonitor {
+        BatteryMonitor::from_config(
+            &Config::parse(
+                r#"
+                [battery]
+                enabled = true
+                alert_within_hours = 24
+
+                [[nodes]]
+                id = 6
+                cutoff_voltage = 3.0
+                "#,
+            )
+            .unwrap(),
+        )
+    }
+
+    fn metric(readings: &[(&'static str, f64)], name: &str) -> Option<f64> {
+        readings.iter().find(|(m, _)| *m == name).map(|&(_, v)| v)
+    }
+
+    #[test]
+    fn estimates_hours_left_and_alerts_before_cutoff() {
+        let mut monitor = monitor();
+        let mut raised_at = None;
+        for hour in 0..=30 {
+            let mut readings = vec![
+                ("battery_level", 80.0),
+                ("voltage", 4.0 - hour as f64 / 64.0),
+            ];
+            let events = monitor.observe(hour * HOUR, 5, "bridge", &mut readings);
+            if hour < 3 {
+                assert_eq!(metric(&readings, SLOPE_METRIC), None, "hour {}", hour);
+                continue;
+            }
+            assert!((metric(&readings, SLOPE_METRIC).unwrap() + 1.0 / 64.0).abs() < 1e-9);
+            let hours_left = metric(&readings, HOURS_LEFT_METRIC).unwrap();
+            assert!((hours_left - (44.8 - hour as f64)).abs() < 1e-6);
+            if let Some(event) = events.first() {
+                assert_eq!(
+                    (event.rule.as_str(), event.state),
+                    (DEPLETING_RULE, AlertState::Raised)
+                );
+                raised_at.get_or_insert(hour);
+            }
+        }
+        assert_eq!(raised_at, Some(21));
+
+        // Back on charge: the slope turns and the alert clears
+        let mut readings = vec![("voltage", 4.1)];
+        let events = monitor.observe(31 * HOUR, 5, "bridge", &mut readings);
+        assert_eq!(metric(&readings, HOURS_LEFT_METRIC), None);
+        assert_eq!(events[0].state, AlertState::Cleared);
+    }
+
+    #[test]
+    fn notices_a_solar_node_that_stops_recharging() {
+        let mut monitor = monitor();
+        let volts = [
+            3.8, 4.0, 3.9, 3.8, 3.7, 4.0, 3.9, 3.85, 3.8, 3.75, 3.7, 3.65, 3.6, 3.8,
+        ];
+        let mut transitions = Vec::new();
+        for (i, v) in volts.into_iter().enumerate() {
+            let hour = 6 * i as u64;
+            for event in monitor.observe(hour * HOUR, 6, "ford", &mut vec![("voltage", v)]) {
+                transitions.push((hour, event.rule, event.state));
+            }
+        }
+        assert_eq!(
+            transitions,
+            [
+                (72, SOLAR_RULE.to_string(), AlertState::Raised),
+                (78, SOLAR_RULE.to_string(), AlertState::Cleared)
+            ]
+        );
+    }
+}
@@ -0,0 +1,554 @@
+use std::collections::{HashMap, HashSet};
+use std::str::FromStr;
+
+use chrono::DateTime;
+use meshtastic::protobufs::{Channel, channel, from_radio};
+
+use crate::config::{MeshBotConfig, NodeId};
+use crate::frame::Frame;
+use crate::handler::DecodedMessage;
+use crate::mesh::{Outbox, OutgoingText};
+use crate::radio_message::AppMessage;
+use crate::sinks::Sink;
+use crate::store::{SharedStore, Store};
+use crate::table;
+
+const HELP: &str = "Commands: STATUS, LEVEL <node>, ACK <alert>, MUTE <30m|2h|1d|OFF>";
+
+/// Longest mute accepted, so a typo can't silence a flood season.
+const MAX_MUTE_SECS: u64 = 7 * 86_400;
+
+/// A command as typed on a handset; case doesn't matter.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Command {
+    Help,
+    Status,
+    Level(String),
+    Ack(u32),
+    /// Seconds to mute for, or `None` to unmute
+    Mute(Option<u64>),
+}
+
+impl FromStr for Command {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut words = s.split_whitespace();
+        let verb = words.next().unwrap_or_default().to_ascii_uppercase();
+        let arg = words.next();
+        if words.next().is_some() {
+            return Err(format!("Too many words. {}", HELP));
+        }
+
+        match (verb.as_str(), arg) {
+            ("HELP" | "?", None) => Ok(Command::Help),
+            ("STATUS", None) => Ok(Command::Status),
+            ("LEVEL", Some(node)) => Ok(Command::Level(node.to_string())),
+            ("ACK", Some(id)) => id
+                .trim_start_matches('#')
+                .parse()
+                .map(Command::Ack)
+                .map_err(|_| format!("`{}` is not an alert number", id)),
+            ("MUTE", Some(off)) if off.eq_ignore_ascii_case("off") => Ok(Command::Mute(None)),
+            ("MUTE", Some(duration)) => match table::parse_interval(&duration.to_lowercase()) {
+                Ok(secs) if secs <= MAX_MUTE_SECS => Ok(Command::Mute(Some(secs))),
+                Ok(_) => Err("Mute for at most 7d".to_string()),
+                Err(_) => Err(format!("`{}` is not a duration like 30m or 2h", duration)),
+            },
+            ("UNMUTE", None) => Ok(Command::Mute(None)),
+            ("LEVEL" | "ACK" | "MUTE", None) => {
+                Err(format!("{} needs an argument. {}", verb, HELP))
+            }
+            _ => Err(format!("Unknown command. {}", HELP)),
+        }
+    }
+}
+
+impl Command {
+    /// Changes alert state rather than just reading it.
+    pub fn privileged(&self) -> bool {
+        matches!(self, Command::Ack(_) | Command::Mute(_))
+    }
+}
+
+fn clock(timestamp: u64) -> String {
+    DateTime::from_timestamp(timestamp as i64, 0)
+        .map(|t| t.format("%H:%M UTC").to_string())
+        .unwrap_or_default()
+}
+
+/// Runs a command against the store. `by` names the sender for the
+/// acknowledgement; `now` is when the command arrived.
+pub fn execute(command: &Command, store: &mut Store, by: &str, now: u64) -> String {
+    match command {
+        Command::Help => HELP.to_string(),
+        Command::Status => {
+            let alerts = store.active_alerts();
+            let mut reply = if alerts.is_empty() {
+                let gauges = store.nodes().filter(|n| n.gauge).count();
+                format!("No active alerts, {} gauges", gauges)
+            } else {
+                let list: Vec<String> = alerts
+                    .iter()
+                    .map(|a| {
+                        format!(
+                            "#{} {} {} {} {:.2}{}",
+                            a.id,
+                            a.severity.to_string().to_uppercase(),
+                            a.node_name,
+                            a.metric,
+                            a.value,
+                            if a.acknowledged.is_some() {
+                                " (ack)"
+                            } else {
+                                ""
+                            }
+                        )
+                    })
+                    .collect();
+                format!("Alerts: {}", list.join("; "))
+            };
+            if store.muted(now)
+                && let Some(until) = store.muted_until()
+            {
+                reply.push_str(&format!(". Muted until {}", clock(until)));
+            }
+            reply
+        }
+        Command::Level(node) => {
+            let Some(status) = store.find_node(node) else {
+                return format!("Unknown node `{}`", node);
+            };
+            let Some(level) = status.readings.get("water_level") else {
+                return format!("{} has not reported a water level", status.name);
+            };
+            let mut reply = format!(
+                "{}: water_level {:.2} at {}",
+                status.name,
+                level.value,
+                clock(level.timestamp)
+            );
+            for alert in store.active_alerts() {
+                if alert.node == status.id {
+                    reply.push_str(&format!(", #{} {}", alert.id, alert.severity));
+                }
+            }
+            reply
+        }
+        Command::Ack(id) => {
+            let Some(alert) = store.alert(*id) else {
+                return format!("No active alert #{}", id);
+            };
+            if let Some(ack) = &alert.acknowledged {
+                return format!(
+                    "Alert #{} was already acknowledged by {} at {}",
+                    id,
+                    ack.by,
+                    clock(ack.at)
+                );
+            }
+            let reply = format!(
+                "Alert #{} acknowledged: {} {}",
+                id, alert.node_name, alert.rule
+            );
+            store.acknowledge(*id, by, now);
+            reply
+        }
+        Command::Mute(None) => {
+            store.mute(None, by, now);
+            "Alerts unmuted".to_string()
+        }
+        Command::Mute(Some(secs)) => {
+            let until = now + secs;
+            store.mute(Some(until), by, now);
+            format!("Alerts muted until {}", clock(until))
+        }
+    }
+}
+
+/// Answers direct messages to the monitor's own radios. Their node numbers
+/// come from the `my_info` each radio sends during the handshake, and
+/// their channels from the config dump that follows.
+pub struct MeshBot {
+    admins: HashSet<NodeId>,
+    store: SharedStore,
+    outbox: Outbox,
+    /// Connection replies go out on; `None` when there is only one source
+    transmitter: Option<String>,
+    own_nodes: HashSet<u32>,
+    /// (connection, channel index) → channel name and key
+    channels: HashMap<(String, u32), (String, Vec<u8>)>,
+}
+
+impl MeshBot {
+    pub fn new(
+        config: &MeshBotConfig,
+        transmitter: Option<String>,
+        store: SharedStore,
+        outbox: Outbox,
+    ) -> Self {
+        Self {
+            admins: config.admins.iter().copied().collect(),
+            store,
+            outbox,
+            transmitter,
+            own_nodes: HashSet::new(),
+            channels: HashMap::new(),
+        }
+    }
+
+    fn learn_channel(&mut self, connection: &str, channel: &Channel) {
+        let Ok(index) = u32::try_from(channel.index) else {
+            return;
+        };
+        let key = (connection.to_string(), index);
+        match &channel.settings {
+            Some(settings) if channel.role != channel::Role::Disabled as i32 => {
+                self.channels
+                    .insert(key, (settings.name.clone(), settings.psk.clone()));
+            }
+            _ => {
+                self.channels.remove(&key);
+            }
+        }
+    }
+
+    /// The transmitting radio's index of the channel `msg` arrived on,
+    /// found by name and key when another radio heard it; `None` when the
+    /// transmitting radio has no such channel.
+    fn reply_channel(&self, msg: &DecodedMessage) -> Option<u32> {
+        let Some(transmitter) = &self.transmitter else {
+            return Some(msg.channel);
+        };
+        if msg.connection == *transmitter {
+            return Some(msg.channel);
+        }
+        let heard_on = self.channels.get(&(msg.connection.clone(), msg.channel))?;
+        self.channels
+            .iter()
+            .find(|((connection, _), settings)| connection == transmitter && *settings == heard_on)
+            .map(|((_, index), _)| *index)
+    }
+}
+
+impl Sink for MeshBot {
+    fn name(&self) -> &'static str {
+        "mesh-bot"
+    }
+
+    fn on_frame(&mut self, frame: &Frame) {
+        let Some(from_radio) = frame.as_from_radio() else {
+            return;
+        };
+        match &from_radio.payload_variant {
+            Some(from_radio::PayloadVariant::MyInfo(info)) => {
+                self.own_nodes.insert(info.my_node_num);
+            }
+            Some(from_radio::PayloadVariant::Channel(channel)) => {
+                self.learn_channel(&frame.connection, channel);
+            }
+            _ => {}
+        }
+    }
+
+    fn on_message(&mut self, msg: &DecodedMessage) {
+        let AppMessage::Text(text) = &msg.message.app else {
+            return;
+        };
+        let to = text.to.as_deref().and_then(|to| to.parse::<NodeId>().ok());
+        if !to.is_some_and(|to| self.own_nodes.contains(&to.0)) {
+            return;
+        }
+
+        let sender = NodeId(msg.message.node_id);
+        log::info!("Command from {}: {}", msg.node_name, text.msg);
+        // Left undone rather than done without a reply
+        let Some(channel) = self.reply_channel(msg) else {
+            log::warn!(
+                "Ignoring command from {} via {}: channel {} is not on the transmitting radio",
+                msg.node_name,
+                msg.connection,
+                msg.channel
+            );
+            return;
+        };
+        let reply = match text.msg.parse::<Command>() {
+            Err(e) => e,
+            Ok(command) if command.privileged() && !self.admins.contains(&sender) => {
+                log::warn!(
+                    "Refused `{}` from {}, not an admin",
+                    text.msg,
+                    msg.node_name
+                );
+                "Not allowed from this node".to_string()
+            }
+            Ok(command) => execute(
+                &command,
+                &mut self.store.write().unwrap(),
+                &msg.node_name,
+                msg.timestamp,
+            ),
+        };
+
+        let _ = self.outbox.send(OutgoingText {
+            to: Some(sender.0),
+            channel,
+            text: reply,
+            reply_id: Some(msg.packet_id),
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use meshtastic::Message;
+    use meshtastic::protobufs::{
+        Data, EnvironmentMetrics, FromRadio, MeshPacket, MyNodeInfo, PortNum, mesh_packet,
+        telemetry,
+    };
+    use tokio::sync::mpsc;
+
+    use super::*;
+    use crate::config::Config;
+    use crate::frame::{REPLAY_CONNECTION, RadioFrame};
+    use crate::pipeline::Pipeline;
+    use crate::store::StoreSink;
+
+    const GATEWAY: u32 = 0x1000;
+    const ADMIN: u32 = 0x2000;
+    const STRANGER: u32 = 0x3000;
+
+    fn frame(timestamp: u64, payload: from_radio::PayloadVariant) -> Frame {
+        Frame {
+            connection: REPLAY_CONNECTION.to_string(),
+            timestamp,
+            preamble: false,
+            frame: RadioFrame::FromRadio(FromRadio {
+                id: 0,
+                payload_variant: Some(payload),
+            }),
+        }
+    }
+
+    fn packet(id: u32, from: u32, to: u32, portnum: PortNum, payload: Vec<u8>) -> FromRadio {
+        FromRadio {
+            id: 0,
+            payload_variant: Some(from_radio::PayloadVariant::Packet(MeshPacket {
+                id,
+                from,
+                to,
+                channel: 1,
+                payload_variant: Some(mesh_packet::PayloadVariant::Decoded(Data {
+                    portnum: portnum as i32,
+                    payload,
+                    ..Default::default()
+                })),
+                ..Default::default()
+            })),
+        }
+    }
+
+    fn text(timestamp: u64, id: u32, from: u32, to: u32, msg: &str) -> Frame {
+        let FromRadio {
+            payload_variant: Some(payload),
+            ..
+        } = packet(
+            id,
+            from,
+            to,
+            PortNum::TextMessageApp,
+            msg.as_bytes().to_vec(),
+        )
+        else {
+            unreachable!()
+        };
+        frame(timestamp, payload)
+    }
+
+    #[test]
+    fn parses_commands() {
+        assert_eq!("status".parse(), Ok(Command::Status));
+        assert_eq!(
+            " LEVEL  bridge ".parse(),
+            Ok(Command::Level("bridge".to_string()))
+        );
+        assert_eq!("ack #12".parse(), Ok(Command::Ack(12)));
+        assert_eq!("MUTE 2h".parse(), Ok(Command::Mute(Some(7_200))));
+        assert_eq!("mute off".parse(), Ok(Command::Mute(None)));
+        assert!("MUTE 30d".parse::<Command>().is_err());
+        assert!(
+            "ACK"
+                .parse::<Command>()
+                .unwrap_err()
+                .contains("needs an argument")
+        );
+        assert!(
+            "flood?"
+                .parse::<Command>()
+                .unwrap_err()
+                .starts_with("Unknown")
+        );
+    }
+
+    #[test]
+    fn answers_direct_messages_and_guards_privileged_commands() {
+        let config = Config::parse(&format!(
+            r#"
+            [[nodes]]
+            id = 5
+            alias = "bridge"
+            calibration = {{ offset = 6.0, scale = -0.001 }}
+
+            [[alerts]]
+            name = "flood-stage"
+            node = "bridge"
+            metric = "water_level"
+            above = 4.0
+            severity = "critical"
+
+            [mesh.bot]
+            enabled = true
+            admins = [{}]
+            "#,
+            ADMIN
+        ))
+        .unwrap();
+        let store = Store::shared(&config);
+        let (outbox, mut replies) = mpsc::unbounded_channel();
+        let mut pipeline = Pipeline::with_sinks(
+            &config,
+            vec![
+                Box::new(StoreSink(store.clone())),
+                Box::new(MeshBot::new(&config.mesh.bot, None, store.clone(), outbox)),
+            ],
+        );
+
+        pipeline.process(&frame(
+            100,
+            from_radio::PayloadVariant::MyInfo(MyNodeInfo {
+                my_node_num: GATEWAY,
+                ..Default::default()
+            }),
+        ));
+        // Distance 1750 mm is a 4.25 m stage, over the alert threshold
+        let reading = packet(
+            1,
+            5,
+            u32::MAX,
+            PortNum::TelemetryApp,
+            meshtastic::protobufs::Telemetry {
+                time: 0,
+                variant: Some(telemetry::Variant::EnvironmentMetrics(EnvironmentMetrics {
+                    distance: Some(1750.0),
+                    ..Default::default()
+                })),
+            }
+            .encode_to_vec(),
+        );
+        pipeline.process(&frame(200, reading.payload_variant.unwrap()));
+
+        let mut ask = |id: u32, from: u32, to: u32, msg: &str| {
+            pipeline.process(&text(300 + u64::from(id), id, from, to, msg));
+            replies.try_recv().ok()
+        };
+
+        // Broadcasts on the channel are chat, not commands
+        assert_eq!(ask(10, STRANGER, u32::MAX, "STATUS"), None);
+
+        let reply = ask(11, STRANGER, GATEWAY, "STATUS").unwrap();
+        assert_eq!(reply.text, "Alerts: #1 CRITICAL bridge water_level 4.25");
+        assert_eq!(
+            (reply.to, reply.channel, reply.reply_id),
+            (Some(STRANGER), 1, Some(11))
+        );
+
+        let reply = ask(12, STRANGER, GATEWAY, "level bridge").unwrap();
+        assert_eq!(
+            reply.text,
+            "bridge: water_level 4.25 at 00:03 UTC, #1 critical"
+        );
+
+        assert_eq!(
+            ask(13, STRANGER, GATEWAY, "MUTE 2h").unwrap().text,
+            "Not allowed from this node"
+        );
+        assert!(!store.read().unwrap().muted(400));
+
+        assert_eq!(
+            ask(14, ADMIN, GATEWAY, "ACK 1").unwrap().text,
+            "Alert #1 acknowledged: bridge flood-stage"
+        );
+        assert_eq!(
+            ask(15, ADMIN, GATEWAY, "MUTE 2h").unwrap().text,
+            "Alerts muted until 02:05 UTC"
+        );
+        assert!(store.read().unwrap().muted(400));
+        assert_eq!(
+            ask(16, STRANGER, GATEWAY, "STATUS").unwrap().text,
+            "Alerts: #1 CRITICAL bridge water_level 4.25 (ack). Muted until 02:05 UTC"
+        );
+    }
+
+    #[test]
+    fn replies_on_the_transmitting_radios_channel() {
+        let config = Config::parse("[mesh.bot]\nenabled = true").unwrap();
+        let store = Store::shared(&config);
+        let (outbox, mut replies) = mpsc::unbounded_channel();
+        let mut pipeline = Pipeline::with_sinks(
+            &config,
+            vec![Box::new(MeshBot::new(
+                &config.mesh.bot,
+                Some("north".to_string()),
+                store,
+                outbox,
+            ))],
+        );
+
+        let via = |connection: &str, mut frame: Frame| {
+            frame.connection = connection.to_string();
+            frame
+        };
+        let channel = |index: i32, name: &str, psk: &[u8]| {
+            from_radio::PayloadVariant::Channel(Channel {
+                index,
+                settings: Some(meshtastic::protobufs::ChannelSettings {
+                    name: name.to_string(),
+                    psk: psk.to_vec(),
+                    ..Default::default()
+                }),
+                role: channel::Role::Secondary as i32,
+            })
+        };
+        for (connection, payload) in [
+            ("north", channel(2, "floodnet", &[9, 9])),
+            ("south", channel(1, "floodnet", &[9, 9])),
+            ("south", channel(3, "chat", &[7])),
+            (
+                "south",
+                from_radio::PayloadVariant::MyInfo(MyNodeInfo {
+                    my_node_num: GATEWAY,
+                    ..Default::default()
+                }),
+            ),
+        ] {
+            pipeline.process(&via(connection, frame(100, payload)));
+        }
+
+        // Heard by the other radio on its channel 1, the north radio's 2
+        pipeline.process(&via("south", text(200, 21, STRANGER, GATEWAY, "HELP")));
+        let reply = replies.try_recv().unwrap();
+        assert_eq!((reply.to, reply.channel), (Some(STRANGER), 2));
+
+        // The north radio has no such channel
+        let mut on_chat = via("south", text(300, 22, STRANGER, GATEWAY, "HELP"));
+        if let RadioFrame::FromRadio(FromRadio {
+            payload_variant: Some(from_radio::PayloadVariant::Packet(packet)),
+            ..
+        }) = &mut on_chat.frame
+        {
+            packet.channel = 3;
+        }
+        pipeline.process(&on_chat);
+        assert!(replies.try_recv().is_err());
+    }
+}
@@ -0,0 +1,292 @@
+use std::path::{Path, PathBuf};
+
+use crate::config::{Config, ConnectionConfig};
+use crate::export::ExportFormat;
+use crate::table::{self, TableOptions};
+
+pub const USAGE: &str = "\
+Usage: flood_monitor [OPTIONS] [COMMAND]
+
+Commands:
+    live                    Stream from the radio (default)
+    record [DIR]            Same as `live --record DIR`
+    replay FILE             Replay a recording through the same pipeline; with
+                            the JSON API enabled, keeps serving until Ctrl-C
+    export FILE...          Write the decoded packets of one or more recordings,
+                            in the order given, to --output (default stdout)
+    import FILE [DIR]       Turn a JSON Lines export back into a recording in DIR
+                            (default: recording.dir)
+    learn-reaches FILE...   Learn the travel times of the configured reaches
+                            from recordings, printed as config
+    backtest FILE...        Score the [forecast] model against the water
+                            levels in recordings
+    check-config [FILE]     Validate a config file and exit
+
+Options:
+    --config FILE           Config file (default: flood_monitor.toml if present)
+    --serial PATH           Read from this serial port instead of the configured connections
+    --tcp HOST:PORT         Read from this TCP radio instead of the configured connections
+    --record DIR            Also write every frame to rotating .bin files in DIR;
+                            the only way to record a replay
+    --format FORMAT         Export format: jsonl (default), csv, parquet or geojson
+    --output FILE           Export to FILE instead of stdout
+    --columns LIST          CSV/Parquet metric columns, e.g. water_level,battery_level
+                            (default: every metric)
+    --interval DURATION     CSV/Parquet: average readings per node over 30s, 15m, 1h, 1d…
+                            (default: one row per telemetry packet)
+    --timezone TZ           CSV timestamps and interval alignment: utc (default),
+                            local or an offset like +02:00
+    --http ADDR             Serve the JSON API on ADDR, e.g. 127.0.0.1:8080
+    --log-level LEVEL       Override logging.level
+    --max-file-size BYTES   Override recording.max_file_size
+";
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Command {
+    Live,
+    Record(Option<PathBuf>),
+    Replay(PathBuf),
+    Export {
+        inputs: Vec<PathBuf>,
+        output: Option<PathBuf>,
+    },
+    Import {
+        input: PathBuf,
+        dir: Option<PathBuf>,
+    },
+    LearnReaches(Vec<PathBuf>),
+    Backtest(Vec<PathBuf>),
+    CheckConfig,
+}
+
+/// Parsed command line. Flags override values from the config file.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Cli {
+    pub command: Command,
+    pub config_path: Option<PathBuf>,
+    pub serial: Option<String>,
+    pub tcp: Option<String>,
+    pub record_dir: Option<PathBuf>,
+    pub http: Option<String>,
+    pub format: ExportFormat,
+    pub table: TableOptions,
+    pub log_level: Option<String>,
+    pub max_file_size: Option<u64>,
+}
+
+impl Cli {
+    pub fn parse<I: IntoIterator<Item = String>>(args: I) -> Result<Self, String> {
+        let mut cli = Cli {
+            command: Command::Live,
+            config_path: None,
+            serial: None,
+            tcp: None,
+            record_dir: None,
+            http: None,
+            format: ExportFormat::Jsonl,
+            table: TableOptions::default(),
+            log_level: None,
+            max_file_size: None,
+        };
+        let mut positional = Vec::new();
+        let mut output = None;
+
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            let mut value = |flag: &str| args.next().ok_or(format!("{} requires a value", flag));
+            match arg.as_str() {
+                "--config" => cli.config_path = Some(PathBuf::from(value(&arg)?)),
+                "--serial" => cli.serial = Some(value(&arg)?),
+                "--tcp" => cli.tcp = Some(value(&arg)?),
+                "--record" => cli.record_dir = Some(PathBuf::from(value(&arg)?)),
+                "--format" => cli.format = value(&arg)?.parse()?,
+                "--output" => output = Some(PathBuf::from(value(&arg)?)),
+                "--columns" => cli.table.columns = table::parse_columns(&value(&arg)?)?,
+                "--interval" => cli.table.interval = Some(table::parse_interval(&value(&arg)?)?),
+                "--timezone" => cli.table.timezone = value(&arg)?.parse()?,
+                "--http" => cli.http = Some(value(&arg)?),
+                "--log-level" => cli.log_level = Some(value(&arg)?),
+                "--max-file-size" => {
+                    let raw = value(&arg)?;
+                    cli.max_file_size = Some(
+                        raw.parse()
+                            .map_err(|_| format!("invalid --max-file-size `{}`", raw))?,
+                    );
+                }
+                flag if flag.starts_with("--") => return Err(format!("unknown option `{}`", flag)),
+                _ => positional.push(arg),
+            }
+        }
+
+        let mut positional = positional.into_iter();
+        cli.command = match positional.next().as_deref() {
+            None | Some("live") => Command::Live,
+            Some("record") => Command::Record(positional.next().map(PathBuf::from)),
+            Some("replay") => Command::Replay(
+                positional
+                    .next()
+                    .map(PathBuf::from)
+                    .ok_or("missing replay file path")?,
+            ),
+            Some("export") => {
+                let inputs: Vec<PathBuf> = positional.by_ref().map(PathBuf::from).collect();
+                if inputs.is_empty() {
+                    return Err("missing recording to export".to_string());
+                }
+                if cli.table.columns.is_empty() {
+                    return Err("--columns needs at least one metric".to_string());
+                }
+                Command::Export {
+                    inputs,
+                    output: output.take(),
+                }
+            }
+            Some("import") => Command::Import {
+                input: positional
+                    .next()
+                    .map(PathBuf::from)
+                    .ok_or("missing JSON Lines file to import")?,
+                dir: positional.next().map(PathBuf::from),
+            },
+            Some("learn-reaches") => {
+                let inputs: Vec<PathBuf> = positional.by_ref().map(PathBuf::from).collect();
+                if inputs.is_empty() {
+                    return Err("missing recording to learn from".to_string());
+                }
+                Command::LearnReaches(inputs)
+            }
+            Some("backtest") => {
+                let inputs: Vec<PathBuf> = positional.by_ref().map(PathBuf::from).collect();
+                if inputs.is_empty() {
+                    return Err("missing recording to back-test against".to_string());
+                }
+                Command::Backtest(inputs)
+            }
+            Some("check-config") => {
+                if let Some(path) = positional.next() {
+                    cli.config_path = Some(PathBuf::from(path));
+                }
+                Command::CheckConfig
+            }
+            Some(other) => return Err(format!("unknown command `{}`", other)),
+        };
+
+        if let Some(extra) = positional.next() {
+            return Err(format!("unexpected argument `{}`", extra));
+        }
+        if output.is_some() {
+            return Err("--output only applies to export".to_string());
+        }
+        // The replay would read the files it is appending to
+        if let (Command::Replay(input), Some(dir)) = (&cli.command, &cli.record_dir)
+            && same_dir(input.parent().unwrap_or(Path::new("")), dir)
+        {
+            return Err("--record must not be the directory being replayed".to_string());
+        }
+
+        Ok(cli)
+    }
+
+    pub fn apply_overrides(&self, config: &mut Config) {
+        if let Some(level) = &self.log_level {
+            config.logging.level = level.clone();
+        }
+        if let Some(size) = self.max_file_size {
+            config.recording.max_file_size = size;
+        }
+        if let Command::Record(dir) = &self.command {
+            config.recording.enabled = true;
+            if let Some(dir) = dir {
+                config.recording.dir = dir.clone();
+            }
+        }
+        // A replay is only recorded when asked to, never into the live capture
+        if let Command::Replay(_) = &self.command {
+            config.recording.enabled = false;
+        }
+        if let Some(dir) = &self.record_dir {
+            config.recording.enabled = true;
+            config.recording.dir = dir.clone();
+        }
+        if let Some(listen) = &self.http {
+            config.http.enabled = true;
+            config.http.listen = listen.clone();
+        }
+        if self.serial.is_some() || self.tcp.is_some() {
+            config.connections = vec![ConnectionConfig {
+                id: "cli".to_string(),
+                serial: self.serial.clone(),
+                tcp: self.tcp.clone(),
+            }];
+            // The radio named there is gone; the only one left transmits
+            config.mesh.connection = None;
+        }
+    }
+}
+
+/// Whether `a` and `b` name the same directory; an empty path is the
+/// current one.
+fn same_dir(a: &Path, b: &Path) -> bool {
+    let canonical = |p: &Path| {
+        let p = if p.as_os_str().is_empty() {
+            Path::new(".")
+        } else {
+            p
+        };
+        p.canonicalize().unwrap_or_else(|_| p.to_path_buf())
+    };
+    canonical(a) == canonical(b)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(line: &str) -> impl Iterator<Item = String> {
+        line.split_whitespace().map(str::to_string)
+    }
+
+    #[test]
+    fn serial_replaces_the_transmitting_connection() {
+        let mut config = Config::parse(
+            r#"
+            [[connections]]
+            id = "north"
+            tcp = "192.168.1.20:4403"
+
+            [mesh]
+            connection = "north"
+            "#,
+        )
+        .unwrap();
+        let cli = Cli::parse(args("--serial /dev/ttyUSB0 live")).unwrap();
+        cli.apply_overrides(&mut config);
+
+        assert!(config.validate().is_empty(), "{:?}", config.validate());
+        assert_eq!(config.connections.len(), 1);
+        assert_eq!(config.connections[0].id, "cli");
+        assert_eq!(config.mesh.connection, None);
+    }
+
+    #[test]
+    fn replay_records_only_with_an_explicit_dir() {
+        let mut config = Config::parse("[recording]\nenabled = true").unwrap();
+        Cli::parse(args("replay old.bin"))
+            .unwrap()
+            .apply_overrides(&mut config);
+        assert!(!config.recording.enabled);
+
+        Cli::parse(args("--record elsewhere replay old.bin"))
+            .unwrap()
+            .apply_overrides(&mut config);
+        assert!(config.recording.enabled);
+        assert_eq!(config.recording.dir, PathBuf::from("elsewhere"));
+    }
+
+    #[test]
+    fn replay_refuses_to_record_into_its_own_dir() {
+        assert!(Cli::parse(args("--record captures replay captures/old.bin")).is_err());
+        assert!(Cli::parse(args("--record . replay old.bin")).is_err());
+        assert!(Cli::parse(args("--record captures replay old.bin")).is_ok());
+    }
+}
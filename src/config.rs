@@ -0,0 +1,2043 @@
+use std::collections::{BTreeMap, HashSet};
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+
+use crate::quality::Quality;
+use crate::radio_message::METRIC_NAMES;
+use crate::{battery, forecast, heartbeat, reach};
+
+pub const DEFAULT_CONFIG_PATH: &str = "flood_monitor.toml";
+
+/* ---------------- Schema ---------------- */
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    #[serde(default)]
+    pub logging: LoggingConfig,
+    #[serde(default = "default_connections")]
+    pub connections: Vec<ConnectionConfig>,
+    #[serde(default)]
+    pub recording: RecordingConfig,
+    #[serde(default)]
+    pub dedup: DedupConfig,
+    #[serde(default)]
+    pub nodes: Vec<NodeConfig>,
+    #[serde(default)]
+    pub alerts: Vec<AlertRule>,
+    #[serde(default)]
+    pub heartbeat: HeartbeatConfig,
+    #[serde(default)]
+    pub battery: BatteryConfig,
+    #[serde(default)]
+    pub quality: QualityConfig,
+    #[serde(default)]
+    pub reaches: Vec<ReachConfig>,
+    #[serde(default)]
+    pub forecast: ForecastConfig,
+    #[serde(default)]
+    pub rain: RainConfig,
+    #[serde(default)]
+    pub sinks: SinksConfig,
+    #[serde(default)]
+    pub http: HttpConfig,
+    #[serde(default)]
+    pub history: HistoryConfig,
+    #[serde(default)]
+    pub notifiers: Vec<NotifierConfig>,
+    #[serde(default)]
+    pub alert_lifecycle: AlertLifecycleConfig,
+    #[serde(default)]
+    pub mesh: MeshConfig,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LoggingConfig {
+    #[serde(default = "default_log_level")]
+    pub level: String,
+    /// Per-module overrides, e.g. `"meshtastic::connections" = "warn"`.
+    #[serde(default = "default_log_modules")]
+    pub modules: BTreeMap<String, String>,
+}
+
+/// A radio to read from. Exactly one of `serial` or `tcp` must be set.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ConnectionConfig {
+    pub id: String,
+    pub serial: Option<String>,
+    /// `host:port` of a network-enabled radio, usually port 4403.
+    pub tcp: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RecordingConfig {
+    /// Write every frame to `dir`; also enabled by `--record DIR`.
+    #[serde(default)]
+    pub enabled: bool,
+    #[serde(default = "default_recording_dir")]
+    pub dir: PathBuf,
+    /// Rotate to a new `.bin` once the current one would exceed this many bytes.
+    #[serde(default = "default_max_file_size")]
+    pub max_file_size: u64,
+}
+
+/// Copies of the same mesh packet, via relays or other radios, are only
+/// decoded once.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DedupConfig {
+    /// How long a packet is remembered after it was first heard.
+    #[serde(default = "default_dedup_window")]
+    pub window_secs: u64,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct NodeConfig {
+    pub id: NodeId,
+    pub alias: Option<String>,
+    pub calibration: Option<Calibration>,
+    /// How often the node reports telemetry; learned from its reports when
+    /// unset.
+    pub report_interval_secs: Option<u64>,
+    /// Overrides `battery.cutoff_voltage`, e.g. for a LiFePO4 pack.
+    pub cutoff_voltage: Option<f64>,
+    /// A tipping-bucket rain gauge reporting over detection-sensor or
+    /// serial packets. Nodes sending `rainfall_1h` need no entry.
+    pub rain_gauge: Option<RainGauge>,
+}
+
+/// How a node reports rain when it does not send rainfall telemetry.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RainGauge {
+    pub source: RainSource,
+    /// Rain per tip of the bucket.
+    #[serde(default = "default_mm_per_tip")]
+    pub mm_per_tip: f64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RainSource {
+    /// Every detection-sensor packet is one tip
+    Detection,
+    /// Serial lines carry the bucket's running tip count, the first number
+    /// in the line
+    Serial,
+}
+
+/// Linear conversion of a node's raw `distance` reading (mm from the sensor
+/// face) into `water_level`: `water_level = offset + scale * distance`.
+///
+/// A downward-looking sensor mounted 6.1 m above the gauge datum that
+/// should report metres uses `offset = 6.1`, `scale = -0.001`.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Calibration {
+    #[serde(default)]
+    pub offset: f64,
+    #[serde(default = "default_scale")]
+    pub scale: f64,
+}
+
+impl Calibration {
+    pub fn apply(&self, distance: f64) -> f64 {
+        self.offset + self.scale * distance
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AlertRule {
+    pub name: String,
+    /// Node alias or id; the rule applies to every node when omitted.
+    pub node: Option<String>,
+    pub metric: String,
+    pub above: Option<f64>,
+    pub below: Option<f64>,
+    /// How far back across the threshold a value must go before the alert clears.
+    #[serde(default)]
+    pub hysteresis: f64,
+    #[serde(default)]
+    pub severity: Severity,
+}
+
+#[derive(
+    Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    Info,
+    #[default]
+    Warning,
+    Critical,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = match self {
+            Severity::Info => "info",
+            Severity::Warning => "warning",
+            Severity::Critical => "critical",
+        };
+        f.write_str(s)
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SinksConfig {
+    #[serde(default)]
+    pub log: LogSinkConfig,
+    #[serde(default)]
+    pub mqtt: MqttSinkConfig,
+    #[serde(default)]
+    pub influx: InfluxSinkConfig,
+}
+
+/// Decoded messages and alerts written through the `log` facade.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LogSinkConfig {
+    #[serde(default = "default_true")]
+    pub enabled: bool,
+}
+
+impl Default for LogSinkConfig {
+    fn default() -> Self {
+        Self { enabled: true }
+    }
+}
+
+/// Readings published as JSON to `<topic_prefix>/<node>/<metric>`, alerts
+/// to `<alert_topic>/<node>/<rule>`.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MqttSinkConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    #[serde(default = "default_mqtt_host")]
+    pub host: String,
+    #[serde(default = "default_mqtt_port")]
+    pub port: u16,
+    #[serde(default = "default_mqtt_client_id")]
+    pub client_id: String,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    #[serde(default = "default_mqtt_topic_prefix")]
+    pub topic_prefix: String,
+    #[serde(default = "default_mqtt_alert_topic")]
+    pub alert_topic: String,
+    /// 0, 1 or 2
+    #[serde(default = "default_mqtt_qos")]
+    pub qos: u8,
+    /// Retain the last reading of every topic on the broker.
+    #[serde(default = "default_true")]
+    pub retain: bool,
+    /// Messages held in memory while the broker is unreachable; newer ones
+    /// are dropped once it is full, and all are lost if the monitor stops.
+    #[serde(default = "default_mqtt_buffer")]
+    pub buffer: usize,
+}
+
+/// Telemetry written as InfluxDB line protocol, one line per packet with
+/// the node id, name, channel and port as tags. Goes to `url` when set,
+/// otherwise to `file`, otherwise to stdout.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct InfluxSinkConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    #[serde(default = "default_influx_measurement")]
+    pub measurement: String,
+    pub file: Option<PathBuf>,
+    /// Write endpoint, e.g. `http://localhost:8086/api/v2/write?org=o&bucket=b`.
+    pub url: Option<String>,
+    /// Sent as `Authorization: Token <token>`.
+    pub token: Option<String>,
+    #[serde(default = "default_influx_batch_size")]
+    pub batch_size: usize,
+    /// Write a partial batch after this long without reaching `batch_size`.
+    #[serde(default = "default_influx_flush_interval")]
+    pub flush_interval_secs: u64,
+    /// Attempts per batch beyond the first before it is dropped.
+    #[serde(default = "default_influx_max_retries")]
+    pub max_retries: u32,
+}
+
+impl Default for InfluxSinkConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            measurement: default_influx_measurement(),
+            file: None,
+            url: None,
+            token: None,
+            batch_size: default_influx_batch_size(),
+            flush_interval_secs: default_influx_flush_interval(),
+            max_retries: default_influx_max_retries(),
+        }
+    }
+}
+
+impl Default for MqttSinkConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            host: default_mqtt_host(),
+            port: default_mqtt_port(),
+            client_id: default_mqtt_client_id(),
+            username: None,
+            password: None,
+            topic_prefix: default_mqtt_topic_prefix(),
+            alert_topic: default_mqtt_alert_topic(),
+            qos: default_mqtt_qos(),
+            retain: true,
+            buffer: default_mqtt_buffer(),
+        }
+    }
+}
+
+/// The embedded JSON API.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct HttpConfig {
+    /// Also enabled by `--http ADDR`.
+    #[serde(default)]
+    pub enabled: bool,
+    #[serde(default = "default_http_listen")]
+    pub listen: String,
+}
+
+/// Alerts for nodes that stop reporting, measured in reporting intervals.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct HeartbeatConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    /// Missed intervals before `node-stale` is raised.
+    #[serde(default = "default_stale_after_intervals")]
+    pub stale_after_intervals: f64,
+    /// Missed intervals before `node-lost` is raised.
+    #[serde(default = "default_lost_after_intervals")]
+    pub lost_after_intervals: f64,
+    /// Reports needed to learn an interval that isn't configured.
+    #[serde(default = "default_heartbeat_min_samples")]
+    pub min_samples: usize,
+    #[serde(default)]
+    pub stale_severity: Severity,
+    #[serde(default = "default_lost_severity")]
+    pub lost_severity: Severity,
+    /// Also watch nodes not listed under `[[nodes]]`, such as handsets.
+    #[serde(default)]
+    pub all_nodes: bool,
+}
+
+/// Battery trend analysis of each node's `voltage` (or `power_voltage`).
+/// The `voltage_slope` and `hours_to_cutoff` metrics are always derived;
+/// `enabled` turns on the `battery-depleting` and `solar-charge-failed`
+/// alerts.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BatteryConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    /// Voltage at which the node browns out.
+    #[serde(default = "default_cutoff_voltage")]
+    pub cutoff_voltage: f64,
+    /// Voltage history the discharge slope is fitted to.
+    #[serde(default = "default_battery_window_hours")]
+    pub window_hours: f64,
+    /// Shortest history a slope is fitted to.
+    #[serde(default = "default_battery_min_span_hours")]
+    pub min_span_hours: f64,
+    /// Raise `battery-depleting` when the cutoff is expected sooner.
+    #[serde(default = "default_alert_within_hours")]
+    pub alert_within_hours: f64,
+    #[serde(default)]
+    pub depleting_severity: Severity,
+    /// A rise this far off the lowest voltage counts as a recharge.
+    #[serde(default = "default_charge_rise_volts")]
+    pub charge_rise_volts: f64,
+    /// Raise `solar-charge-failed` when a node that has recharged before
+    /// goes this long without.
+    #[serde(default = "default_charge_window_hours")]
+    pub charge_window_hours: f64,
+    #[serde(default)]
+    pub solar_severity: Severity,
+}
+
+/// Data-quality checks between decoding and alerting.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct QualityConfig {
+    #[serde(default = "default_true")]
+    pub enabled: bool,
+    /// Severity of the `sensor-*` maintenance notices.
+    #[serde(default = "default_notice_severity")]
+    pub notice_severity: Severity,
+    /// Good readings in a row before a flagged metric counts as fixed.
+    #[serde(default = "default_quality_clear_after")]
+    pub clear_after: usize,
+    /// Jumps past `max_step` that agree with each other before they are
+    /// taken as a real change of level.
+    #[serde(default = "default_quality_confirm_after")]
+    pub confirm_after: usize,
+    /// Replaces the built-in limits when set.
+    #[serde(default = "default_metric_limits")]
+    pub metrics: Vec<MetricLimits>,
+}
+
+/// Plausibility limits for one metric. Every entry matching a reading
+/// applies.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MetricLimits {
+    pub metric: String,
+    /// Node alias or id; every node when omitted.
+    pub node: Option<String>,
+    pub min: Option<f64>,
+    pub max: Option<f64>,
+    /// Largest believable change from the last good reading.
+    pub max_step: Option<f64>,
+    /// The same value for this long means the sensor is stuck.
+    pub stuck_hours: Option<f64>,
+    /// The sensor reports exactly zero when it has no reading.
+    #[serde(default)]
+    pub zero_invalid: bool,
+}
+
+/// Gauges along one river, upstream first. A crest at one gauge is
+/// forecast to reach the gauges below it after their travel times.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ReachConfig {
+    pub name: String,
+    pub gauges: Vec<ReachGauge>,
+    /// Rise in `water_level` above the lowest level of the last
+    /// `window_hours` that starts a flood wave.
+    #[serde(default = "default_crest_rise")]
+    pub rise: f64,
+    /// Fall below the highest level of a wave that confirms its crest.
+    #[serde(default = "default_crest_fall")]
+    pub fall: f64,
+    #[serde(default = "default_reach_window_hours")]
+    pub window_hours: f64,
+    /// Severity of forecasts that breach no `water_level` alert rule.
+    #[serde(default = "default_forecast_severity")]
+    pub severity: Severity,
+    /// How long after the expected arrival a forecast clears if the gauge
+    /// shows no crest of its own.
+    #[serde(default = "default_forecast_clear_mins")]
+    pub clear_after_mins: f64,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ReachGauge {
+    /// Node alias or id.
+    pub node: String,
+    /// Typical time for a crest to travel from the gauge above; required
+    /// for every gauge but the first.
+    pub travel_mins: Option<f64>,
+    /// Rise here per unit of rise at the gauge above.
+    #[serde(default = "default_scale")]
+    pub stage_ratio: f64,
+}
+
+/// Short-term stage forecasts for every gauge, fitted to its recent
+/// `water_level`. They are always published; `enabled` adds alerts.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ForecastConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    #[serde(default)]
+    pub model: ForecastModel,
+    /// Readings the model is fitted to.
+    #[serde(default = "default_forecast_window_hours")]
+    pub window_hours: f64,
+    #[serde(default = "default_forecast_horizon_hours")]
+    pub horizon_hours: f64,
+    /// Spacing of the published forecast levels.
+    #[serde(default = "default_forecast_step_mins")]
+    pub step_mins: u64,
+    /// Fewest readings in the window to forecast from.
+    #[serde(default = "default_forecast_min_samples")]
+    pub min_samples: usize,
+    /// Of the intervals around forecast levels and crossing times.
+    #[serde(default = "default_forecast_confidence")]
+    pub confidence: f64,
+    /// Level and trend smoothing factors of the `smoothing` model.
+    #[serde(default = "default_smoothing_alpha")]
+    pub alpha: f64,
+    #[serde(default = "default_smoothing_beta")]
+    pub beta: f64,
+    /// Raise `<rule>-forecast` when a `water_level` rule is expected to
+    /// be breached within this.
+    #[serde(default = "default_forecast_alert_within_hours")]
+    pub alert_within_hours: f64,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ForecastModel {
+    /// Least-squares line
+    #[default]
+    Linear,
+    /// Least-squares parabola, which follows a wave turning
+    Quadratic,
+    /// Holt's exponential smoothing of level and trend
+    Smoothing,
+}
+
+impl fmt::Display for ForecastModel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = match self {
+            ForecastModel::Linear => "linear",
+            ForecastModel::Quadratic => "quadratic",
+            ForecastModel::Smoothing => "smoothing",
+        };
+        f.write_str(s)
+    }
+}
+
+/// Rolling rain totals and rain events for every rain gauge, added to its
+/// readings as `rain_1h`, `rain_6h`, `rain_24h`, `rain_72h`, `rain_event`
+/// and `rain_intensity` for alert rules to use.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RainConfig {
+    /// A dry spell this long ends a rain event.
+    #[serde(default = "default_rain_event_gap_hours")]
+    pub event_gap_hours: f64,
+    /// Period `rain_intensity` is averaged over, in mm per hour.
+    #[serde(default = "default_rain_intensity_mins")]
+    pub intensity_mins: u64,
+    /// Reports further apart than this leave a gap: a tip count rise across
+    /// it is spread over the gap, and rainfall telemetry cannot cover it.
+    #[serde(default = "default_rain_max_gap_mins")]
+    pub max_gap_mins: u64,
+}
+
+/// Readings kept in memory for the API's history queries.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct HistoryConfig {
+    /// Samples older than this, relative to the newest sample of the same
+    /// series, are dropped.
+    #[serde(default = "default_retention_hours")]
+    pub retention_hours: u64,
+}
+
+/* ---------------- Notifiers ---------------- */
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum NotifierKind {
+    /// JSON POSTed to `url`, once per alert or once per recipient
+    Webhook,
+    /// JSON POSTed to an SMS gateway at `url`, once per recipient
+    Sms,
+    /// One email per alert to every matching recipient
+    Smtp,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SmtpTls {
+    /// Plain SMTP, for a relay on the same host or a local test sink
+    None,
+    #[default]
+    Starttls,
+    /// Implicit TLS, usually port 465
+    Tls,
+}
+
+/// Where alert events go beyond the log. Which alerts reach a recipient is
+/// narrowed by `min_severity` and `nodes`, on the notifier and then per
+/// recipient.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct NotifierConfig {
+    pub name: String,
+    pub kind: NotifierKind,
+    /// Endpoint for `webhook` and `sms`.
+    pub url: Option<String>,
+    /// Extra request headers for `webhook` and `sms`, e.g. `Authorization`.
+    #[serde(default)]
+    pub headers: BTreeMap<String, String>,
+    /// Request body for `webhook` and `sms`, or email body for `smtp`, with
+    /// `{{placeholders}}`; see the example config for the list.
+    pub template: Option<String>,
+    /// Email subject template.
+    pub subject: Option<String>,
+    pub host: Option<String>,
+    #[serde(default = "default_smtp_port")]
+    pub port: u16,
+    #[serde(default)]
+    pub tls: SmtpTls,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    /// Sender address for `smtp`.
+    pub from: Option<String>,
+    pub min_severity: Option<Severity>,
+    /// Node aliases or ids; every node when empty.
+    #[serde(default)]
+    pub nodes: Vec<String>,
+    #[serde(default)]
+    pub recipients: Vec<RecipientConfig>,
+    /// Also notify when an alert clears.
+    #[serde(default = "default_true")]
+    pub notify_cleared: bool,
+    /// Attempts per notification beyond the first before it is dropped.
+    #[serde(default = "default_notify_max_retries")]
+    pub max_retries: u32,
+    /// Notifications per recipient in any hour; further ones are dropped
+    /// and counted.
+    #[serde(default = "default_notify_max_per_hour")]
+    pub max_per_hour: u32,
+    /// Contact tier of the recipients that don't set their own. Tier 0 is
+    /// told when an alert is raised, higher tiers only once it has gone
+    /// unacknowledged for long enough.
+    #[serde(default)]
+    pub tier: u32,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RecipientConfig {
+    /// Email address, phone number, or for webhooks whatever the template
+    /// uses as `{{to}}`, e.g. a chat channel.
+    pub to: String,
+    pub min_severity: Option<Severity>,
+    /// Node aliases or ids; every node when empty.
+    #[serde(default)]
+    pub nodes: Vec<String>,
+    /// Overrides the notifier's `tier`.
+    pub tier: Option<u32>,
+}
+
+/// What happens to an alert after it is raised: it stays open until
+/// someone acknowledges it, moves up one contact tier each time
+/// `escalate_after_mins` passes unacknowledged, and is resolved when its
+/// rule clears.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AlertLifecycleConfig {
+    /// How long an alert may go unacknowledged before the next tier is told.
+    #[serde(default = "default_escalate_after_mins")]
+    pub escalate_after_mins: u64,
+    /// JSON file keeping open alerts, acknowledgements and mutes across
+    /// restarts; nothing is kept when unset.
+    pub state_file: Option<PathBuf>,
+    /// JSON Lines file every raise, acknowledgement, escalation, mute and
+    /// resolution is appended to.
+    pub audit_log: Option<PathBuf>,
+}
+
+/* ---------------- Mesh ---------------- */
+
+/// Meshtastic modem presets, used to estimate how long a transmission
+/// keeps the channel busy. Must match the radio's `lora.modem_preset`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ModemPreset {
+    #[default]
+    LongFast,
+    LongSlow,
+    LongModerate,
+    MediumSlow,
+    MediumFast,
+    ShortSlow,
+    ShortFast,
+    ShortTurbo,
+}
+
+/// Text the monitor itself sends onto the mesh through one of its radios.
+/// Everything sent shares the airtime budget and spacing below.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MeshConfig {
+    /// `id` of the connection that transmits; the first one when unset.
+    pub connection: Option<String>,
+    #[serde(default)]
+    pub modem_preset: ModemPreset,
+    /// Estimated seconds on air allowed in any hour; 36 is a 1 % duty cycle.
+    #[serde(default = "default_mesh_airtime")]
+    pub airtime_per_hour_secs: f64,
+    /// Minimum gap between two transmissions.
+    #[serde(default = "default_mesh_min_interval")]
+    pub min_interval_secs: u64,
+    /// Longer texts are cut short; the Meshtastic apps stop at 228 bytes.
+    #[serde(default = "default_mesh_max_text_bytes")]
+    pub max_text_bytes: usize,
+    #[serde(default)]
+    pub alerts: MeshAlertsConfig,
+    #[serde(default)]
+    pub bot: MeshBotConfig,
+}
+
+/// Alerts broadcast as text messages, for people on the mesh without
+/// internet access.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MeshAlertsConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    /// Channel index on the transmitting radio, 0 being the primary.
+    #[serde(default)]
+    pub channel: u32,
+    pub min_severity: Option<Severity>,
+    /// Node aliases or ids; every node when empty.
+    #[serde(default)]
+    pub nodes: Vec<String>,
+    #[serde(default = "default_true")]
+    pub notify_cleared: bool,
+    /// Broadcasts in any hour; further alerts are dropped and counted.
+    #[serde(default = "default_mesh_alerts_max_per_hour")]
+    pub max_per_hour: u32,
+    #[serde(default)]
+    pub templates: MeshTemplates,
+}
+
+/// Answers commands such as `STATUS` or `MUTE 2h` sent to any of the
+/// monitor's radios as direct messages.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MeshBotConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    /// Nodes allowed to acknowledge and mute alerts; anyone may ask for
+    /// status and levels.
+    #[serde(default)]
+    pub admins: Vec<NodeId>,
+}
+
+/// Text per severity, and for any cleared alert, with the same
+/// `{{placeholders}}` as notifier templates.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MeshTemplates {
+    #[serde(default = "default_mesh_template_info")]
+    pub info: String,
+    #[serde(default = "default_mesh_template_warning")]
+    pub warning: String,
+    #[serde(default = "default_mesh_template_critical")]
+    pub critical: String,
+    #[serde(default = "default_mesh_template_cleared")]
+    pub cleared: String,
+}
+
+impl MeshTemplates {
+    pub fn for_severity(&self, severity: Severity) -> &str {
+        match severity {
+            Severity::Info => &self.info,
+            Severity::Warning => &self.warning,
+            Severity::Critical => &self.critical,
+        }
+    }
+}
+
+/* ---------------- Node ids ---------------- */
+
+/// A Meshtastic node number, written in config either as an integer or in
+/// the `!a1b2c3d4` form the apps display.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct NodeId(pub u32);
+
+impl fmt::Display for NodeId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "!{:08x}", self.0)
+    }
+}
+
+impl FromStr for NodeId {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parsed = match s.strip_prefix('!') {
+            Some(hex) => u32::from_str_radix(hex, 16),
+            None => s.parse::<u32>(),
+        };
+        parsed
+            .map(NodeId)
+            .map_err(|_| format!("invalid node id `{}`, expected a number or `!hex`", s))
+    }
+}
+
+impl Serialize for NodeId {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for NodeId {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Raw {
+            Num(u32),
+            Str(String),
+        }
+
+        match Raw::deserialize(deserializer)? {
+            Raw::Num(n) => Ok(NodeId(n)),
+            Raw::Str(s) => s.parse().map_err(serde::de::Error::custom),
+        }
+    }
+}
+
+/* ---------------- Defaults ---------------- */
+
+fn default_connections() -> Vec<ConnectionConfig> {
+    vec![ConnectionConfig {
+        id: "default".to_string(),
+        serial: Some("/dev/ttyACM0".to_string()),
+        tcp: None,
+    }]
+}
+
+fn default_log_level() -> String {
+    "debug".to_string()
+}
+
+fn default_log_modules() -> BTreeMap<String, String> {
+    BTreeMap::from([(
+        "meshtastic::connections::stream_buffer".to_string(),
+        "error".to_string(),
+    )])
+}
+
+fn default_recording_dir() -> PathBuf {
+    PathBuf::from("recordings")
+}
+
+fn default_max_file_size() -> u64 {
+    10 * 1024 * 1024 // 10 MB
+}
+
+fn default_dedup_window() -> u64 {
+    crate::dedup::DEFAULT_WINDOW_SECS
+}
+
+fn default_influx_measurement() -> String {
+    "flood".to_string()
+}
+
+fn default_influx_batch_size() -> usize {
+    500
+}
+
+fn default_influx_flush_interval() -> u64 {
+    5
+}
+
+fn default_influx_max_retries() -> u32 {
+    5
+}
+
+fn default_mqtt_host() -> String {
+    "localhost".to_string()
+}
+
+fn default_mqtt_port() -> u16 {
+    1883
+}
+
+fn default_mqtt_client_id() -> String {
+    "flood_monitor".to_string()
+}
+
+fn default_mqtt_topic_prefix() -> String {
+    "flood".to_string()
+}
+
+fn default_mqtt_alert_topic() -> String {
+    "flood/alerts".to_string()
+}
+
+fn default_mqtt_qos() -> u8 {
+    1
+}
+
+fn default_mqtt_buffer() -> usize {
+    1000
+}
+
+fn default_smtp_port() -> u16 {
+    587
+}
+
+fn default_notify_max_retries() -> u32 {
+    3
+}
+
+fn default_stale_after_intervals() -> f64 {
+    3.0
+}
+
+fn default_lost_after_intervals() -> f64 {
+    12.0
+}
+
+fn default_heartbeat_min_samples() -> usize {
+    5
+}
+
+fn default_lost_severity() -> Severity {
+    Severity::Critical
+}
+
+fn default_cutoff_voltage() -> f64 {
+    3.3
+}
+
+fn default_battery_window_hours() -> f64 {
+    24.0
+}
+
+fn default_battery_min_span_hours() -> f64 {
+    3.0
+}
+
+fn default_alert_within_hours() -> f64 {
+    48.0
+}
+
+fn default_charge_rise_volts() -> f64 {
+    0.1
+}
+
+fn default_charge_window_hours() -> f64 {
+    36.0
+}
+
+fn default_notice_severity() -> Severity {
+    Severity::Info
+}
+
+fn default_quality_clear_after() -> usize {
+    3
+}
+
+fn default_quality_confirm_after() -> usize {
+    3
+}
+
+/// Limits no sensor should break: an ultrasonic `distance` of 0 is a
+/// missed echo and one unchanged for a day is stuck.
+fn default_metric_limits() -> Vec<MetricLimits> {
+    let limits = |metric: &str, min, max| MetricLimits {
+        metric: metric.to_string(),
+        node: None,
+        min,
+        max,
+        max_step: None,
+        stuck_hours: None,
+        zero_invalid: false,
+    };
+    vec![
+        MetricLimits {
+            stuck_hours: Some(24.0),
+            zero_invalid: true,
+            ..limits("distance", Some(0.0), None)
+        },
+        limits("humidity", Some(0.0), Some(100.0)),
+        limits("temperature", Some(-60.0), Some(85.0)),
+        limits("battery_level", Some(0.0), Some(101.0)),
+    ]
+}
+
+fn default_crest_rise() -> f64 {
+    0.3
+}
+
+fn default_crest_fall() -> f64 {
+    0.05
+}
+
+fn default_reach_window_hours() -> f64 {
+    24.0
+}
+
+fn default_forecast_severity() -> Severity {
+    Severity::Info
+}
+
+fn default_forecast_clear_mins() -> f64 {
+    120.0
+}
+
+fn default_forecast_window_hours() -> f64 {
+    3.0
+}
+
+fn default_forecast_horizon_hours() -> f64 {
+    6.0
+}
+
+fn default_forecast_step_mins() -> u64 {
+    15
+}
+
+fn default_forecast_min_samples() -> usize {
+    6
+}
+
+fn default_forecast_confidence() -> f64 {
+    0.9
+}
+
+fn default_smoothing_alpha() -> f64 {
+    0.5
+}
+
+fn default_smoothing_beta() -> f64 {
+    0.2
+}
+
+fn default_forecast_alert_within_hours() -> f64 {
+    3.0
+}
+
+fn default_mm_per_tip() -> f64 {
+    0.2
+}
+
+fn default_rain_event_gap_hours() -> f64 {
+    6.0
+}
+
+fn default_rain_intensity_mins() -> u64 {
+    15
+}
+
+fn default_rain_max_gap_mins() -> u64 {
+    60
+}
+
+fn default_notify_max_per_hour() -> u32 {
+    12
+}
+
+fn default_escalate_after_mins() -> u64 {
+    15
+}
+
+fn default_mesh_airtime() -> f64 {
+    36.0
+}
+
+fn default_mesh_min_interval() -> u64 {
+    30
+}
+
+fn default_mesh_max_text_bytes() -> usize {
+    200
+}
+
+fn default_mesh_alerts_max_per_hour() -> u32 {
+    6
+}
+
+fn default_mesh_template_info() -> String {
+    "{{node_name}}: {{metric}} {{value}} ({{rule}})".to_string()
+}
+
+fn default_mesh_template_warning() -> String {
+    "FLOOD WARNING {{node_name}}: {{metric}} {{value}}, threshold {{threshold}}".to_string()
+}
+
+fn default_mesh_template_critical() -> String {
+    "FLOOD ALERT {{node_name}}: {{metric}} {{value}}, threshold {{threshold}}".to_string()
+}
+
+fn default_mesh_template_cleared() -> String {
+    "Cleared {{node_name}}: {{rule}}, {{metric}} {{value}}".to_string()
+}
+
+fn default_http_listen() -> String {
+    "127.0.0.1:8080".to_string()
+}
+
+fn default_retention_hours() -> u64 {
+    72
+}
+
+fn default_scale() -> f64 {
+    1.0
+}
+
+fn default_true() -> bool {
+    true
+}
+
+impl Default for LoggingConfig {
+    fn default() -> Self {
+        Self {
+            level: default_log_level(),
+            modules: default_log_modules(),
+        }
+    }
+}
+
+impl Default for RecordingConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            dir: default_recording_dir(),
+            max_file_size: default_max_file_size(),
+        }
+    }
+}
+
+impl Default for DedupConfig {
+    fn default() -> Self {
+        Self {
+            window_secs: default_dedup_window(),
+        }
+    }
+}
+
+impl Default for HttpConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            listen: default_http_listen(),
+        }
+    }
+}
+
+impl Default for HeartbeatConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            stale_after_intervals: default_stale_after_intervals(),
+            lost_after_intervals: default_lost_after_intervals(),
+            min_samples: default_heartbeat_min_samples(),
+            stale_severity: Severity::default(),
+            lost_severity: default_lost_severity(),
+            all_nodes: false,
+        }
+    }
+}
+
+impl Default for BatteryConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            cutoff_voltage: default_cutoff_voltage(),
+            window_hours: default_battery_window_hours(),
+            min_span_hours: default_battery_min_span_hours(),
+            alert_within_hours: default_alert_within_hours(),
+            depleting_severity: Severity::default(),
+            charge_rise_volts: default_charge_rise_volts(),
+            charge_window_hours: default_charge_window_hours(),
+            solar_severity: Severity::default(),
+        }
+    }
+}
+
+impl Default for QualityConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            notice_severity: default_notice_severity(),
+            clear_after: default_quality_clear_after(),
+            confirm_after: default_quality_confirm_after(),
+            metrics: default_metric_limits(),
+        }
+    }
+}
+
+impl Default for ForecastConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            model: ForecastModel::default(),
+            window_hours: default_forecast_window_hours(),
+            horizon_hours: default_forecast_horizon_hours(),
+            step_mins: default_forecast_step_mins(),
+            min_samples: default_forecast_min_samples(),
+            confidence: default_forecast_confidence(),
+            alpha: default_smoothing_alpha(),
+            beta: default_smoothing_beta(),
+            alert_within_hours: default_forecast_alert_within_hours(),
+        }
+    }
+}
+
+impl Default for RainConfig {
+    fn default() -> Self {
+        Self {
+            event_gap_hours: default_rain_event_gap_hours(),
+            intensity_mins: default_rain_intensity_mins(),
+            max_gap_mins: default_rain_max_gap_mins(),
+        }
+    }
+}
+
+impl Default for HistoryConfig {
+    fn default() -> Self {
+        Self {
+            retention_hours: default_retention_hours(),
+        }
+    }
+}
+
+impl Default for AlertLifecycleConfig {
+    fn default() -> Self {
+        Self {
+            escalate_after_mins: default_escalate_after_mins(),
+            state_file: None,
+            audit_log: None,
+        }
+    }
+}
+
+impl Default for MeshConfig {
+    fn default() -> Self {
+        Self {
+            connection: None,
+            modem_preset: ModemPreset::default(),
+            airtime_per_hour_secs: default_mesh_airtime(),
+            min_interval_secs: default_mesh_min_interval(),
+            max_text_bytes: default_mesh_max_text_bytes(),
+            alerts: MeshAlertsConfig::default(),
+            bot: MeshBotConfig::default(),
+        }
+    }
+}
+
+impl Default for MeshAlertsConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            channel: 0,
+            min_severity: None,
+            nodes: Vec::new(),
+            notify_cleared: true,
+            max_per_hour: default_mesh_alerts_max_per_hour(),
+            templates: MeshTemplates::default(),
+        }
+    }
+}
+
+impl Default for MeshTemplates {
+    fn default() -> Self {
+        Self {
+            info: default_mesh_template_info(),
+            warning: default_mesh_template_warning(),
+            critical: default_mesh_template_critical(),
+            cleared: default_mesh_template_cleared(),
+        }
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            logging: LoggingConfig::default(),
+            connections: default_connections(),
+            recording: RecordingConfig::default(),
+            dedup: DedupConfig::default(),
+            nodes: Vec::new(),
+            alerts: Vec::new(),
+            heartbeat: HeartbeatConfig::default(),
+            battery: BatteryConfig::default(),
+            quality: QualityConfig::default(),
+            reaches: Vec::new(),
+            forecast: ForecastConfig::default(),
+            rain: RainConfig::default(),
+            sinks: SinksConfig::default(),
+            http: HttpConfig::default(),
+            history: HistoryConfig::default(),
+            notifiers: Vec::new(),
+            alert_lifecycle: AlertLifecycleConfig::default(),
+            mesh: MeshConfig::default(),
+        }
+    }
+}
+
+/* ---------------- Loading ---------------- */
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(PathBuf, std::io::Error),
+    /// TOML syntax or schema error; `key` is the dotted path of the offending entry.
+    Parse {
+        key: String,
+        message: String,
+    },
+    Invalid(Vec<Issue>),
+}
+
+/// A semantic problem found after the file parsed successfully.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Issue {
+    pub key: String,
+    pub message: String,
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Io(path, e) => write!(f, "cannot read {}: {}", path.display(), e),
+            ConfigError::Parse { key, message } if key.is_empty() => write!(f, "{}", message),
+            ConfigError::Parse { key, message } => write!(f, "at `{}`: {}", key, message),
+            ConfigError::Invalid(issues) => {
+                for (i, issue) in issues.iter().enumerate() {
+                    if i > 0 {
+                        writeln!(f)?;
+                    }
+                    write!(f, "at `{}`: {}", issue.key, issue.message)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+impl Config {
+    pub fn load(path: &Path) -> Result<Self, ConfigError> {
+        let text = fs::read_to_string(path).map_err(|e| ConfigError::Io(path.to_path_buf(), e))?;
+        Self::parse(&text)
+    }
+
+    /// Loads `path` if given, otherwise `flood_monitor.toml` when present,
+    /// falling back to built-in defaults.
+    pub fn load_or_default(path: Option<&Path>) -> Result<Self, ConfigError> {
+        match path {
+            Some(path) => Self::load(path),
+            None if Path::new(DEFAULT_CONFIG_PATH).exists() => {
+                Self::load(Path::new(DEFAULT_CONFIG_PATH))
+            }
+            None => Ok(Self::default()),
+        }
+    }
+
+    pub fn parse(text: &str) -> Result<Self, ConfigError> {
+        let deserializer = toml::Deserializer::parse(text).map_err(|e| ConfigError::Parse {
+            key: String::new(),
+            message: e.to_string(),
+        })?;
+
+        let config: Config =
+            serde_path_to_error::deserialize(deserializer).map_err(|e| ConfigError::Parse {
+                key: e.path().to_string().trim_start_matches('.').to_string(),
+                message: e.inner().to_string().trim_end().to_string(),
+            })?;
+
+        let issues = config.validate();
+        if issues.is_empty() {
+            Ok(config)
+        } else {
+            Err(ConfigError::Invalid(issues))
+        }
+    }
+
+    /// Checks cross-field rules the schema alone cannot express.
+    pub fn validate(&self) -> Vec<Issue> {
+        let mut issues = Vec::new();
+        let mut issue = |key: String, message: String| issues.push(Issue { key, message });
+
+        if log::LevelFilter::from_str(&self.logging.level).is_err() {
+            issue(
+                "logging.level".into(),
+                format!("unknown log level `{}`", self.logging.level),
+            );
+        }
+        for (module, level) in &self.logging.modules {
+            if log::LevelFilter::from_str(level).is_err() {
+                issue(
+                    format!("logging.modules.{}", module),
+                    format!("unknown log level `{}`", level),
+                );
+            }
+        }
+
+        if self.connections.is_empty() {
+            issue(
+                "connections".into(),
+                "at least one connection is required".into(),
+            );
+        }
+        let mut connection_ids = HashSet::new();
+        for (i, conn) in self.connections.iter().enumerate() {
+            if !connection_ids.insert(conn.id.as_str()) {
+                issue(
+                    format!("connections[{}].id", i),
+                    format!("duplicate connection id `{}`", conn.id),
+                );
+            }
+            match (&conn.serial, &conn.tcp) {
+                (Some(_), None) | (None, Some(_)) => {}
+                _ => issue(
+                    format!("connections[{}]", i),
+                    "exactly one of `serial` or `tcp` must be set".into(),
+                ),
+            }
+        }
+
+        if self.recording.max_file_size == 0 {
+            issue(
+                "recording.max_file_size".into(),
+                "must be greater than zero".into(),
+            );
+        }
+
+        if self.dedup.window_secs == 0 {
+            issue(
+                "dedup.window_secs".into(),
+                "must be greater than zero".into(),
+            );
+        }
+
+        let mqtt = &self.sinks.mqtt;
+        if mqtt.qos > 2 {
+            issue("sinks.mqtt.qos".into(), "must be 0, 1 or 2".into());
+        }
+        if mqtt.buffer == 0 {
+            issue(
+                "sinks.mqtt.buffer".into(),
+                "must be greater than zero".into(),
+            );
+        }
+        for (key, topic) in [
+            ("sinks.mqtt.topic_prefix", &mqtt.topic_prefix),
+            ("sinks.mqtt.alert_topic", &mqtt.alert_topic),
+        ] {
+            if topic.is_empty() || topic.contains(['+', '#']) {
+                issue(
+                    key.into(),
+                    "must be a non-empty topic without wildcards".into(),
+                );
+            }
+        }
+
+        let influx = &self.sinks.influx;
+        if influx.file.is_some() && influx.url.is_some() {
+            issue(
+                "sinks.influx".into(),
+                "set at most one of `file` or `url`".into(),
+            );
+        }
+        if let Some(url) = &influx.url
+            && !url.starts_with("http://")
+            && !url.starts_with("https://")
+        {
+            issue(
+                "sinks.influx.url".into(),
+                format!("`{}` is not an http:// or https:// URL", url),
+            );
+        }
+        if influx.measurement.is_empty() {
+            issue(
+                "sinks.influx.measurement".into(),
+                "must not be empty".into(),
+            );
+        }
+        if influx.batch_size == 0 {
+            issue(
+                "sinks.influx.batch_size".into(),
+                "must be greater than zero".into(),
+            );
+        }
+
+        if self.http.listen.parse::<std::net::SocketAddr>().is_err() {
+            issue(
+                "http.listen".into(),
+                format!(
+                    "`{}` is not an address like 127.0.0.1:8080",
+                    self.http.listen
+                ),
+            );
+        }
+        if self.history.retention_hours == 0 {
+            issue(
+                "history.retention_hours".into(),
+                "must be greater than zero".into(),
+            );
+        }
+
+        let mut node_ids = HashSet::new();
+        let mut aliases = HashSet::new();
+        for (i, node) in self.nodes.iter().enumerate() {
+            if !node_ids.insert(node.id) {
+                issue(
+                    format!("nodes[{}].id", i),
+                    format!("node {} is listed twice", node.id),
+                );
+            }
+            if let Some(alias) = &node.alias
+                && !aliases.insert(alias.as_str())
+            {
+                issue(
+                    format!("nodes[{}].alias", i),
+                    format!("duplicate alias `{}`", alias),
+                );
+            }
+            if let Some(cal) = &node.calibration
+                && cal.scale == 0.0
+            {
+                issue(
+                    format!("nodes[{}].calibration.scale", i),
+                    "must not be zero".into(),
+                );
+            }
+            if node.report_interval_secs == Some(0) {
+                issue(
+                    format!("nodes[{}].report_interval_secs", i),
+                    "must be greater than zero".into(),
+                );
+            }
+            if node.cutoff_voltage.is_some_and(|v| v <= 0.0) {
+                issue(
+                    format!("nodes[{}].cutoff_voltage", i),
+                    "must be greater than zero".into(),
+                );
+            }
+            if node.rain_gauge.is_some_and(|g| g.mm_per_tip <= 0.0) {
+                issue(
+                    format!("nodes[{}].rain_gauge.mm_per_tip", i),
+                    "must be greater than zero".into(),
+                );
+            }
+        }
+
+        let heartbeat = &self.heartbeat;
+        if heartbeat.stale_after_intervals < 1.0 {
+            issue(
+                "heartbeat.stale_after_intervals".into(),
+                "must be at least 1".into(),
+            );
+        }
+        if heartbeat.lost_after_intervals <= heartbeat.stale_after_intervals {
+            issue(
+                "heartbeat.lost_after_intervals".into(),
+                "must be greater than stale_after_intervals".into(),
+            );
+        }
+        if heartbeat.min_samples < 2 {
+            issue("heartbeat.min_samples".into(), "must be at least 2".into());
+        }
+
+        let battery = &self.battery;
+        for (field, value) in [
+            ("cutoff_voltage", battery.cutoff_voltage),
+            ("min_span_hours", battery.min_span_hours),
+            ("alert_within_hours", battery.alert_within_hours),
+            ("charge_rise_volts", battery.charge_rise_volts),
+            ("charge_window_hours", battery.charge_window_hours),
+        ] {
+            if value <= 0.0 {
+                issue(
+                    format!("battery.{}", field),
+                    "must be greater than zero".into(),
+                );
+            }
+        }
+        if battery.window_hours < battery.min_span_hours {
+            issue(
+                "battery.window_hours".into(),
+                "must be at least min_span_hours".into(),
+            );
+        }
+
+        let quality = &self.quality;
+        if quality.clear_after == 0 {
+            issue(
+                "quality.clear_after".into(),
+                "must be greater than zero".into(),
+            );
+        }
+        if quality.confirm_after < 2 {
+            issue("quality.confirm_after".into(), "must be at least 2".into());
+        }
+        for (i, limits) in quality.metrics.iter().enumerate() {
+            let field = |name: &str| format!("quality.metrics[{}].{}", i, name);
+            if !METRIC_NAMES.contains(&limits.metric.as_str()) {
+                issue(
+                    field("metric"),
+                    format!("unknown metric `{}`", limits.metric),
+                );
+            }
+            if let Some(node) = &limits.node
+                && !aliases.contains(node.as_str())
+                && node.parse::<NodeId>().is_err()
+            {
+                issue(
+                    field("node"),
+                    format!("`{}` is neither a configured alias nor a node id", node),
+                );
+            }
+            if let (Some(min), Some(max)) = (limits.min, limits.max)
+                && min > max
+            {
+                issue(field("min"), "must not be above max".into());
+            }
+            if limits.max_step.is_some_and(|step| step <= 0.0) {
+                issue(field("max_step"), "must be greater than zero".into());
+            }
+            if limits.stuck_hours.is_some_and(|hours| hours <= 0.0) {
+                issue(field("stuck_hours"), "must be greater than zero".into());
+            }
+        }
+
+        let mut reach_names = HashSet::new();
+        for (i, reach) in self.reaches.iter().enumerate() {
+            let key = |field: &str| format!("reaches[{}].{}", i, field);
+            if !reach_names.insert(reach.name.as_str()) {
+                issue(
+                    key("name"),
+                    format!("duplicate reach name `{}`", reach.name),
+                );
+            }
+            if reach.gauges.len() < 2 {
+                issue(key("gauges"), "needs at least two gauges".into());
+            }
+            for (field, value) in [
+                ("rise", reach.rise),
+                ("fall", reach.fall),
+                ("window_hours", reach.window_hours),
+            ] {
+                if value <= 0.0 {
+                    issue(key(field), "must be greater than zero".into());
+                }
+            }
+            if reach.clear_after_mins < 0.0 {
+                issue(key("clear_after_mins"), "must not be negative".into());
+            }
+            let mut gauge_nodes = HashSet::new();
+            for (j, gauge) in reach.gauges.iter().enumerate() {
+                let key = |field: &str| format!("reaches[{}].gauges[{}].{}", i, j, field);
+                match self.resolve_node(&gauge.node) {
+                    None => issue(
+                        key("node"),
+                        format!(
+                            "`{}` is neither a configured alias nor a node id",
+                            gauge.node
+                        ),
+                    ),
+                    Some(id) if !gauge_nodes.insert(id) => issue(
+                        key("node"),
+                        format!("`{}` is already in this reach", gauge.node),
+                    ),
+                    Some(_) => {}
+                }
+                match (j, gauge.travel_mins) {
+                    (0, Some(_)) => issue(
+                        key("travel_mins"),
+                        "the first gauge has no gauge above it".into(),
+                    ),
+                    (1.., None) => issue(
+                        key("travel_mins"),
+                        "must be set for every gauge but the first".into(),
+                    ),
+                    (_, Some(mins)) if mins <= 0.0 => {
+                        issue(key("travel_mins"), "must be greater than zero".into())
+                    }
+                    _ => {}
+                }
+                if gauge.stage_ratio <= 0.0 {
+                    issue(key("stage_ratio"), "must be greater than zero".into());
+                }
+            }
+        }
+
+        let forecast = &self.forecast;
+        for (field, value) in [
+            ("window_hours", forecast.window_hours),
+            ("horizon_hours", forecast.horizon_hours),
+            ("alert_within_hours", forecast.alert_within_hours),
+        ] {
+            if value <= 0.0 {
+                issue(
+                    format!("forecast.{}", field),
+                    "must be greater than zero".into(),
+                );
+            }
+        }
+        if forecast.step_mins == 0 {
+            issue(
+                "forecast.step_mins".into(),
+                "must be greater than zero".into(),
+            );
+        }
+        if forecast.min_samples < 4 {
+            issue("forecast.min_samples".into(), "must be at least 4".into());
+        }
+        if !(forecast.confidence > 0.0 && forecast.confidence < 1.0) {
+            issue(
+                "forecast.confidence".into(),
+                "must be between 0 and 1".into(),
+            );
+        }
+        for (field, value) in [("alpha", forecast.alpha), ("beta", forecast.beta)] {
+            if !(value > 0.0 && value <= 1.0) {
+                issue(
+                    format!("forecast.{}", field),
+                    "must be above 0 and at most 1".into(),
+                );
+            }
+        }
+
+        let rain = &self.rain;
+        if rain.event_gap_hours <= 0.0 {
+            issue(
+                "rain.event_gap_hours".into(),
+                "must be greater than zero".into(),
+            );
+        }
+        for (field, value) in [
+            ("intensity_mins", rain.intensity_mins),
+            ("max_gap_mins", rain.max_gap_mins),
+        ] {
+            if value == 0 {
+                issue(
+                    format!("rain.{}", field),
+                    "must be greater than zero".into(),
+                );
+            }
+        }
+
+        let mut rule_names = HashSet::new();
+        for (i, rule) in self.alerts.iter().enumerate() {
+            if !rule_names.insert(rule.name.as_str()) {
+                issue(
+                    format!("alerts[{}].name", i),
+                    format!("duplicate rule name `{}`", rule.name),
+                );
+            }
+            if [
+                heartbeat::STALE_RULE,
+                heartbeat::LOST_RULE,
+                battery::DEPLETING_RULE,
+                battery::SOLAR_RULE,
+                reach::FORECAST_RULE,
+            ]
+            .contains(&rule.name.as_str())
+                || Quality::FAULTS.iter().any(|q| q.rule() == rule.name)
+            {
+                issue(
+                    format!("alerts[{}].name", i),
+                    format!("`{}` is reserved for built-in alerts", rule.name),
+                );
+            } else if rule.name.ends_with(forecast::RULE_SUFFIX) {
+                issue(
+                    format!("alerts[{}].name", i),
+                    format!(
+                        "names ending in `{}` are reserved for forecast alerts",
+                        forecast::RULE_SUFFIX
+                    ),
+                );
+            }
+            if !METRIC_NAMES.contains(&rule.metric.as_str()) {
+                issue(
+                    format!("alerts[{}].metric", i),
+                    format!(
+                        "unknown metric `{}`, expected one of {}",
+                        rule.metric,
+                        METRIC_NAMES.join(", ")
+                    ),
+                );
+            }
+            if rule.above.is_some() == rule.below.is_some() {
+                issue(
+                    format!("alerts[{}]", i),
+                    "exactly one of `above` or `below` must be set".into(),
+                );
+            }
+            if rule.hysteresis < 0.0 {
+                issue(
+                    format!("alerts[{}].hysteresis", i),
+                    "must not be negative".into(),
+                );
+            }
+            if let Some(node) = &rule.node
+                && !aliases.contains(node.as_str())
+                && node.parse::<NodeId>().is_err()
+            {
+                issue(
+                    format!("alerts[{}].node", i),
+                    format!("`{}` is neither a configured alias nor a node id", node),
+                );
+            }
+        }
+
+        let mut notifier_names = HashSet::new();
+        for (i, notifier) in self.notifiers.iter().enumerate() {
+            let key = |field: &str| format!("notifiers[{}].{}", i, field);
+            if !notifier_names.insert(notifier.name.as_str()) {
+                issue(
+                    key("name"),
+                    format!("duplicate notifier name `{}`", notifier.name),
+                );
+            }
+            match notifier.kind {
+                NotifierKind::Webhook | NotifierKind::Sms => match &notifier.url {
+                    None => issue(key("url"), "must be set for webhook and sms".into()),
+                    Some(url) if !url.starts_with("http://") && !url.starts_with("https://") => {
+                        issue(
+                            key("url"),
+                            format!("`{}` is not an http:// or https:// URL", url),
+                        )
+                    }
+                    Some(_) => {}
+                },
+                NotifierKind::Smtp => {
+                    if notifier.host.is_none() {
+                        issue(key("host"), "must be set for smtp".into());
+                    }
+                    if notifier.from.is_none() {
+                        issue(key("from"), "must be set for smtp".into());
+                    }
+                }
+            }
+            if notifier.kind == NotifierKind::Smtp {
+                let addresses =
+                    notifier.from.iter().map(|from| (key("from"), from)).chain(
+                        notifier.recipients.iter().enumerate().map(|(j, r)| {
+                            (format!("notifiers[{}].recipients[{}].to", i, j), &r.to)
+                        }),
+                    );
+                for (key, address) in addresses {
+                    if address.parse::<lettre::message::Mailbox>().is_err() {
+                        issue(key, format!("`{}` is not an email address", address));
+                    }
+                }
+            }
+            if notifier.kind != NotifierKind::Webhook && notifier.recipients.is_empty() {
+                issue(
+                    key("recipients"),
+                    "sms and smtp need at least one recipient".into(),
+                );
+            }
+            for message in crate::notify::check_templates(notifier) {
+                issue(key("template"), message);
+            }
+            if notifier.max_per_hour == 0 {
+                issue(key("max_per_hour"), "must be greater than zero".into());
+            }
+            let nodes = notifier.nodes.iter().map(|n| (key("nodes"), n)).chain(
+                notifier.recipients.iter().enumerate().flat_map(|(j, r)| {
+                    r.nodes
+                        .iter()
+                        .map(move |n| (format!("notifiers[{}].recipients[{}].nodes", i, j), n))
+                }),
+            );
+            for (key, node) in nodes {
+                if !aliases.contains(node.as_str()) && node.parse::<NodeId>().is_err() {
+                    issue(
+                        key,
+                        format!("`{}` is neither a configured alias nor a node id", node),
+                    );
+                }
+            }
+        }
+
+        let escalates = self
+            .notifiers
+            .iter()
+            .any(|n| n.tier > 0 || n.recipients.iter().any(|r| r.tier.is_some_and(|t| t > 0)));
+        if escalates && self.alert_lifecycle.escalate_after_mins == 0 {
+            issue(
+                "alert_lifecycle.escalate_after_mins".into(),
+                "must be greater than zero when notifiers have tiers above 0".into(),
+            );
+        }
+
+        let mesh = &self.mesh;
+        if let Some(connection) = &mesh.connection
+            && !connection_ids.contains(connection.as_str())
+        {
+            issue(
+                "mesh.connection".into(),
+                format!("no connection with id `{}`", connection),
+            );
+        }
+        if !(mesh.airtime_per_hour_secs > 0.0 && mesh.airtime_per_hour_secs <= 3600.0) {
+            issue(
+                "mesh.airtime_per_hour_secs".into(),
+                "must be greater than zero and at most 3600".into(),
+            );
+        }
+        if !(1..=crate::mesh::MAX_TEXT_BYTES).contains(&mesh.max_text_bytes) {
+            issue(
+                "mesh.max_text_bytes".into(),
+                format!("must be between 1 and {}", crate::mesh::MAX_TEXT_BYTES),
+            );
+        }
+        if mesh.alerts.channel > 7 {
+            issue("mesh.alerts.channel".into(), "must be 0 to 7".into());
+        }
+        if mesh.alerts.max_per_hour == 0 {
+            issue(
+                "mesh.alerts.max_per_hour".into(),
+                "must be greater than zero".into(),
+            );
+        }
+        for (severity, message) in crate::mesh::check_templates(&mesh.alerts.templates) {
+            issue(format!("mesh.alerts.templates.{}", severity), message);
+        }
+        for node in &mesh.alerts.nodes {
+            if !aliases.contains(node.as_str()) && node.parse::<NodeId>().is_err() {
+                issue(
+                    "mesh.alerts.nodes".into(),
+                    format!("`{}` is neither a configured alias nor a node id", node),
+                );
+            }
+        }
+
+        issues
+    }
+
+    /// The connection that transmits onto the mesh.
+    pub fn mesh_connection(&self) -> Option<&ConnectionConfig> {
+        match &self.mesh.connection {
+            Some(id) => self.connections.iter().find(|c| &c.id == id),
+            None => self.connections.first(),
+        }
+    }
+
+    /// Resolves a node alias or id as written in config.
+    pub fn resolve_node(&self, name: &str) -> Option<NodeId> {
+        self.nodes
+            .iter()
+            .find(|n| n.alias.as_deref() == Some(name))
+            .map(|n| n.id)
+            .or_else(|| name.parse().ok())
+    }
+
+    pub fn init_logging(&self) {
+        let mut builder = env_logger::Builder::new();
+        builder.filter_level(
+            log::LevelFilter::from_str(&self.logging.level).unwrap_or(log::LevelFilter::Debug),
+        );
+        for (module, level) in &self.logging.modules {
+            if let Ok(level) = log::LevelFilter::from_str(level) {
+                builder.filter_module(module, level);
+            }
+        }
+        builder.init();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn empty_file_uses_defaults() {
+        let config = Config::parse("").unwrap();
+        assert_eq!(config.connections.len(), 1);
+        assert_eq!(
+            config.connections[0].serial.as_deref(),
+            Some("/dev/ttyACM0")
+        );
+        assert_eq!(config.recording.max_file_size, 10 * 1024 * 1024);
+        assert!(config.sinks.log.enabled);
+    }
+
+    #[test]
+    fn parses_full_file() {
+        let config = Config::parse(
+            r#"
+            [logging]
+            level = "info"
+
+            [[connections]]
+            id = "north"
+            tcp = "192.168.1.20:4403"
+
+            [recording]
+            dir = "/var/lib/flood_monitor"
+            max_file_size = 1048576
+
+            [[nodes]]
+            id = "!a1b2c3d4"
+            alias = "bridge"
+            calibration = { offset = 6.1, scale = -0.001 }
+
+            [[alerts]]
+            name = "bridge-moderate"
+            node = "bridge"
+            metric = "water_level"
+            above = 3.5
+            severity = "critical"
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(config.nodes[0].id, NodeId(0xa1b2c3d4));
+        assert_eq!(config.resolve_node("bridge"), Some(NodeId(0xa1b2c3d4)));
+        assert_eq!(config.alerts[0].severity, Severity::Critical);
+        let cal = config.nodes[0].calibration.unwrap();
+        assert!((cal.apply(2600.0) - 3.5).abs() < 1e-9);
+    }
+
+    #[test]
+    fn reports_path_of_unknown_key() {
+        let err = Config::parse(
+            r#"
+            [[alerts]]
+            name = "a"
+            metric = "water_level"
+            above = 1.0
+
+            [[alerts]]
+            name = "b"
+            metric = "water_level"
+            abvoe = 2.0
+            "#,
+        )
+        .unwrap_err();
+
+        match err {
+            ConfigError::Parse { key, message } => {
+                assert_eq!(key, "alerts[1].abvoe");
+                assert!(message.contains("abvoe"), "{}", message);
+            }
+            other => panic!("expected parse error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn reports_semantic_issues() {
+        let err = Config::parse(
+            r#"
+            [[connections]]
+            id = "a"
+            serial = "/dev/ttyACM0"
+            tcp = "localhost:4403"
+
+            [[alerts]]
+            name = "x"
+            node = "nowhere"
+            metric = "depth"
+            above = 1.0
+            "#,
+        )
+        .unwrap_err();
+
+        let ConfigError::Invalid(issues) = err else {
+            panic!("expected validation issues");
+        };
+        let keys: Vec<&str> = issues.iter().map(|i| i.key.as_str()).collect();
+        assert_eq!(
+            keys,
+            vec!["connections[0]", "alerts[0].metric", "alerts[0].node"]
+        );
+    }
+}
@@ -0,0 +1,24 @@
+use axum::http::header;
+use axum::response::{Html, IntoResponse, Response};
+
+/// The dashboard page, script and stylesheet, compiled in so the monitor
+/// serves them with no files to deploy and no internet access.
+const INDEX: &str = include_str!("../assets/dashboard.html");
+const SCRIPT: &str = include_str!("../assets/dashboard.js");
+const STYLE: &str = include_str!("../assets/dashboard.css");
+
+pub async fn index() -> Html<&'static str> {
+    Html(INDEX)
+}
+
+pub async fn script() -> Response {
+    (
+        [(header::CONTENT_TYPE, "text/javascript; charset=utf-8")],
+        SCRIPT,
+    )
+        .into_response()
+}
+
+pub async fn style() -> Response {
+    ([(header::CONTENT_TYPE, "text/css; charset=utf-8")], STYLE).into_response()
+}
@@ -0,0 +1,238 @@
+use std::collections::{BTreeSet, HashMap, VecDeque};
+
+use meshtastic::protobufs::{FromRadio, MeshPacket, from_radio};
+
+/// How long a packet id is remembered. Meshtastic packet ids are random
+/// 32-bit values, so collisions inside this window are not a concern.
+pub const DEFAULT_WINDOW_SECS: u64 = 600;
+
+/// Reception details of a single copy of a packet.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CopyInfo {
+    pub connection: String,
+    pub rx_snr: f32,
+    pub rx_rssi: i32,
+    /// Hops taken, when the sender's firmware reports `hop_start`
+    pub hops: Option<u32>,
+    /// Low byte of the node that relayed this copy, 0 when unknown
+    pub relay_node: u32,
+}
+
+impl CopyInfo {
+    fn from_packet(connection: &str, packet: &MeshPacket) -> Self {
+        Self {
+            connection: connection.to_string(),
+            rx_snr: packet.rx_snr,
+            rx_rssi: packet.rx_rssi,
+            hops: (packet.hop_start > 0).then(|| packet.hop_start.saturating_sub(packet.hop_limit)),
+            relay_node: packet.relay_node,
+        }
+    }
+}
+
+/// Everything heard of one mesh packet while it was in the window.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PacketCopies {
+    pub from: u32,
+    pub id: u32,
+    pub first_seen: u64,
+    /// Copies received across all radios, including the first
+    pub copies: u32,
+    /// Distinct relay nodes the copies came through
+    pub relays: BTreeSet<u32>,
+    /// The copy with the best SNR
+    pub best: CopyInfo,
+}
+
+impl PacketCopies {
+    /// Returns whether `copy` became the best one.
+    fn add(&mut self, copy: CopyInfo) -> bool {
+        self.copies += 1;
+        if copy.relay_node != 0 {
+            self.relays.insert(copy.relay_node);
+        }
+        let better = copy.rx_snr > self.best.rx_snr;
+        if better {
+            self.best = copy;
+        }
+        better
+    }
+}
+
+/// What the deduplicator made of a frame.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Observation {
+    /// Not a mesh packet with an id (config dump, local packets)
+    Untracked,
+    First,
+    Duplicate,
+    /// A duplicate heard with a better SNR than any copy before it
+    Better {
+        from: u32,
+        id: u32,
+    },
+}
+
+impl Observation {
+    /// A copy of a packet that was already passed on.
+    pub fn is_duplicate(self) -> bool {
+        matches!(self, Observation::Duplicate | Observation::Better { .. })
+    }
+}
+
+/// Remembers which mesh packets have already been handled so copies heard
+/// through other relays or by a second radio are not decoded and counted
+/// twice, and tallies those copies for link diagnostics.
+pub struct Deduplicator {
+    window_secs: u64,
+    /// (from, packet id) → copies seen so far
+    seen: HashMap<(u32, u32), PacketCopies>,
+    /// Insertion order, for expiring old entries
+    order: VecDeque<(u64, (u32, u32))>,
+}
+
+impl Deduplicator {
+    pub fn new(window_secs: u64) -> Self {
+        Self {
+            window_secs,
+            seen: HashMap::new(),
+            order: VecDeque::new(),
+        }
+    }
+
+    /// Records a frame heard on `connection`. Only the first copy of a
+    /// packet within the window should be passed on for decoding.
+    pub fn observe(&mut self, now: u64, connection: &str, msg: &FromRadio) -> Observation {
+        let Some(from_radio::PayloadVariant::Packet(packet)) = &msg.payload_variant else {
+            return Observation::Untracked;
+        };
+        if packet.id == 0 {
+            return Observation::Untracked;
+        }
+
+        let key = (packet.from, packet.id);
+        let copy = CopyInfo::from_packet(connection, packet);
+        if let Some(entry) = self.seen.get_mut(&key) {
+            return if entry.add(copy) {
+                Observation::Better {
+                    from: packet.from,
+                    id: packet.id,
+                }
+            } else {
+                Observation::Duplicate
+            };
+        }
+
+        let mut entry = PacketCopies {
+            from: packet.from,
+            id: packet.id,
+            first_seen: now,
+            copies: 0,
+            relays: BTreeSet::new(),
+            best: copy.clone(),
+        };
+        entry.add(copy);
+        self.seen.insert(key, entry);
+        self.order.push_back((now, key));
+        Observation::First
+    }
+
+    /// What has been heard so far of a packet still in the window.
+    pub fn copies(&self, from: u32, id: u32) -> Option<&PacketCopies> {
+        self.seen.get(&(from, id))
+    }
+
+    /// Forgets packets older than the window and returns their final
+    /// tallies. Pass `u64::MAX` to drain everything.
+    pub fn expire(&mut self, now: u64) -> Vec<PacketCopies> {
+        let mut expired = Vec::new();
+        while let Some(&(seen_at, key)) = self.order.front() {
+            if now.saturating_sub(seen_at) < self.window_secs {
+                break;
+            }
+            self.order.pop_front();
+            expired.extend(self.seen.remove(&key));
+        }
+        expired
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn packet(from: u32, id: u32) -> FromRadio {
+        relayed(from, id, 0.0, 0)
+    }
+
+    fn relayed(from: u32, id: u32, rx_snr: f32, relay_node: u32) -> FromRadio {
+        FromRadio {
+            id: 1,
+            payload_variant: Some(from_radio::PayloadVariant::Packet(MeshPacket {
+                from,
+                id,
+                rx_snr,
+                relay_node,
+                hop_start: 3,
+                hop_limit: 2,
+                ..Default::default()
+            })),
+        }
+    }
+
+    #[test]
+    fn drops_repeats_until_the_window_expires() {
+        let mut dedup = Deduplicator::new(60);
+
+        assert_eq!(dedup.observe(100, "a", &packet(1, 42)), Observation::First);
+        assert_eq!(
+            dedup.observe(101, "b", &packet(1, 42)),
+            Observation::Duplicate
+        );
+        // Same id from another node is a different packet
+        assert_eq!(dedup.observe(101, "a", &packet(2, 42)), Observation::First);
+
+        let expired = dedup.expire(160);
+        assert_eq!(expired.len(), 1);
+        assert_eq!(dedup.observe(161, "a", &packet(1, 42)), Observation::First);
+    }
+
+    #[test]
+    fn ignores_frames_without_packet_id() {
+        let mut dedup = Deduplicator::new(60);
+        assert_eq!(
+            dedup.observe(100, "a", &packet(1, 0)),
+            Observation::Untracked
+        );
+        assert_eq!(
+            dedup.observe(100, "a", &packet(1, 0)),
+            Observation::Untracked
+        );
+        assert_eq!(
+            dedup.observe(100, "a", &FromRadio::default()),
+            Observation::Untracked
+        );
+    }
+
+    #[test]
+    fn keeps_best_snr_copy_and_counts_relays() {
+        let mut dedup = Deduplicator::new(60);
+        dedup.observe(100, "north", &relayed(1, 7, -4.0, 0x11));
+        assert_eq!(
+            dedup.observe(100, "south", &relayed(1, 7, 6.5, 0x22)),
+            Observation::Better { from: 1, id: 7 }
+        );
+        assert_eq!(dedup.copies(1, 7).unwrap().best.connection, "south");
+        assert_eq!(
+            dedup.observe(101, "north", &relayed(1, 7, 2.0, 0x11)),
+            Observation::Duplicate
+        );
+
+        let tally = dedup.expire(u64::MAX).remove(0);
+        assert_eq!(tally.copies, 3);
+        assert_eq!(tally.relays, BTreeSet::from([0x11, 0x22]));
+        assert_eq!(tally.best.connection, "south");
+        assert_eq!(tally.best.rx_snr, 6.5);
+        assert_eq!(tally.best.hops, Some(1));
+    }
+}
@@ -0,0 +1,590 @@
+use std::collections::{HashMap, VecDeque};
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use meshtastic::Message;
+use meshtastic::protobufs::{Data, FromRadio, MeshPacket, from_radio, mesh_packet};
+use serde::{Deserialize, Serialize};
+
+use crate::config::Config;
+use crate::dedup::{Deduplicator, Observation};
+use crate::frame::{Frame, REPLAY_CONNECTION, RadioFrame};
+use crate::geojson;
+use crate::handler::{Handled, Handler};
+use crate::playback::PlaybackStream;
+use crate::radio_message::{AppMessage, RadioMessage};
+use crate::recording_stream::RecordingStream;
+use crate::store::Store;
+use crate::table::{CsvWriter, ParquetWriter, TableBuilder, TableOptions, TableWriter};
+use crate::{forecast, reach};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExportFormat {
+    Jsonl,
+    Table(TableFormat),
+    Geojson,
+}
+
+/// The formats a telemetry table can be written in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TableFormat {
+    Csv,
+    Parquet,
+}
+
+impl FromStr for ExportFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "jsonl" => Ok(ExportFormat::Jsonl),
+            "csv" => Ok(ExportFormat::Table(TableFormat::Csv)),
+            "parquet" => Ok(ExportFormat::Table(TableFormat::Parquet)),
+            "geojson" => Ok(ExportFormat::Geojson),
+            other => Err(format!(
+                "unknown export format `{}`, expected jsonl, csv, parquet or geojson",
+                other
+            )),
+        }
+    }
+}
+
+/* ---------------- JSON Lines ---------------- */
+
+/// One decoded packet, in the shape of `test.jsonl`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct JsonlRecord {
+    /// The `FromRadio` frame as recorded
+    pub raw_bytes: Vec<u8>,
+    /// Node that sent the packet
+    pub source_id: u32,
+    /// Seconds since the Unix epoch when the frame was received
+    pub timestamp: u64,
+    pub decoded: AppMessage,
+}
+
+impl JsonlRecord {
+    fn from_frame(frame: &Frame) -> Option<Self> {
+        let rm = RadioMessage::try_from(frame.as_from_radio()?).ok()?;
+        Some(Self {
+            raw_bytes: frame.encode_payload(),
+            source_id: rm.node_id,
+            timestamp: frame.timestamp,
+            decoded: rm.app,
+        })
+    }
+
+    /// The recorded frame when `raw_bytes` holds one, otherwise a packet
+    /// rebuilt from `source_id` and `decoded`.
+    fn to_frame(&self) -> Frame {
+        let from_radio = FromRadio::decode(&self.raw_bytes[..])
+            .ok()
+            .filter(|msg| RadioMessage::try_from(msg).is_ok())
+            .unwrap_or_else(|| self.rebuild());
+
+        Frame {
+            connection: REPLAY_CONNECTION.to_string(),
+            timestamp: self.timestamp,
+            preamble: false,
+            frame: RadioFrame::FromRadio(from_radio),
+        }
+    }
+
+    fn rebuild(&self) -> FromRadio {
+        let packet = MeshPacket {
+            from: self.source_id,
+            rx_time: self.timestamp as u32,
+            payload_variant: Some(mesh_packet::PayloadVariant::Decoded(Data {
+                portnum: self.decoded.portnum() as i32,
+                payload: self.decoded.encode_payload(self.timestamp as u32),
+                ..Default::default()
+            })),
+            ..Default::default()
+        };
+        FromRadio {
+            id: 0,
+            payload_variant: Some(from_radio::PayloadVariant::Packet(packet)),
+        }
+    }
+}
+
+/// Writes one record per decodable inbound packet, de-duplicated across
+/// radios like the other formats; config dump frames and packets on
+/// unsupported ports are skipped. Each record holds the copy heard with
+/// the best SNR, so records are written once their dedup window closes.
+/// Returns the records written.
+pub fn write_jsonl<W: Write>(
+    frames: impl Iterator<Item = io::Result<Frame>>,
+    config: &Config,
+    out: &mut W,
+) -> io::Result<usize> {
+    let mut dedup = Deduplicator::new(config.dedup.window_secs);
+    // In input order, with (from, packet id) of the packets dedup tracks
+    let mut pending: VecDeque<(Option<(u32, u32)>, JsonlRecord)> = VecDeque::new();
+    let mut written = 0;
+    for frame in frames {
+        let frame = frame?;
+        let mut key = None;
+        if let Some(from_radio) = frame.as_from_radio() {
+            dedup.expire(frame.timestamp);
+            written += write_closed(&mut pending, &dedup, out)?;
+            match dedup.observe(frame.timestamp, &frame.connection, from_radio) {
+                Observation::Duplicate => continue,
+                Observation::Better { from, id } => {
+                    if let Some((_, record)) =
+                        pending.iter_mut().find(|(k, _)| *k == Some((from, id)))
+                    {
+                        record.raw_bytes = frame.encode_payload();
+                    }
+                    continue;
+                }
+                Observation::First => key = packet_key(from_radio),
+                Observation::Untracked => {}
+            }
+        }
+        if let Some(record) = JsonlRecord::from_frame(&frame) {
+            pending.push_back((key, record));
+        }
+    }
+    written += write_closed(&mut pending, &Deduplicator::new(0), out)?;
+    out.flush()?;
+    Ok(written)
+}
+
+/// Writes the records at the front of `pending` whose packets `dedup` no
+/// longer tracks.
+fn write_closed<W: Write>(
+    pending: &mut VecDeque<(Option<(u32, u32)>, JsonlRecord)>,
+    dedup: &Deduplicator,
+    out: &mut W,
+) -> io::Result<usize> {
+    let mut written = 0;
+    while let Some((key, _)) = pending.front()
+        && key.is_none_or(|(from, id)| dedup.copies(from, id).is_none())
+    {
+        let (_, record) = pending.pop_front().unwrap();
+        serde_json::to_writer(&mut *out, &record)?;
+        out.write_all(b"\n")?;
+        written += 1;
+    }
+    Ok(written)
+}
+
+fn packet_key(msg: &FromRadio) -> Option<(u32, u32)> {
+    match &msg.payload_variant {
+        Some(from_radio::PayloadVariant::Packet(packet)) => Some((packet.from, packet.id)),
+        _ => None,
+    }
+}
+
+/// Appends every record of a JSON Lines file to a recording. Records may
+/// also be pretty-printed across several lines, as in `test.jsonl`.
+pub fn read_jsonl<R: Read>(input: R, recorder: &mut RecordingStream) -> io::Result<usize> {
+    let mut imported = 0;
+    for record in serde_json::Deserializer::from_reader(input).into_iter::<JsonlRecord>() {
+        let record = record.map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("record {}: {}", imported + 1, e),
+            )
+        })?;
+        recorder.record_frame(&record.to_frame())?;
+        imported += 1;
+    }
+    recorder.flush()?;
+    Ok(imported)
+}
+
+/* ---------------- Telemetry tables ---------------- */
+
+/// Decodes frames the way the live pipeline does, de-duplicated across
+/// radios and with calibrated `water_level` and alerts.
+fn decode_frames(
+    frames: impl Iterator<Item = io::Result<Frame>>,
+    config: &Config,
+    mut on_handled: impl FnMut(Handled) -> io::Result<()>,
+) -> io::Result<()> {
+    let mut dedup = Deduplicator::new(config.dedup.window_secs);
+    let mut handler = Handler::new(config);
+    for frame in frames {
+        let frame = frame?;
+        let Some(from_radio) = frame.as_from_radio() else {
+            continue;
+        };
+        dedup.expire(frame.timestamp);
+        if dedup
+            .observe(frame.timestamp, &frame.connection, from_radio)
+            .is_duplicate()
+        {
+            continue;
+        }
+        on_handled(handler.handle_from_radio(&frame.connection, frame.timestamp, from_radio))?;
+    }
+    Ok(())
+}
+
+/// Adds each telemetry packet to `table` at the time the radio received it.
+pub fn write_table(
+    frames: impl Iterator<Item = io::Result<Frame>>,
+    config: &Config,
+    table: &mut TableBuilder,
+) -> io::Result<()> {
+    decode_frames(frames, config, |handled| match handled.message {
+        Some(msg) => table.push(
+            msg.packet_time,
+            msg.message.node_id,
+            &msg.node_name,
+            &msg.readings,
+            &msg.quality,
+        ),
+        None => Ok(()),
+    })
+}
+
+/// A CSV or Parquet writer for `format`.
+pub fn table_writer<W: Write + Send + 'static>(
+    format: TableFormat,
+    out: W,
+    options: &TableOptions,
+) -> io::Result<Box<dyn TableWriter>> {
+    Ok(match format {
+        TableFormat::Csv => Box::new(CsvWriter::new(out, options)?),
+        TableFormat::Parquet => Box::new(ParquetWriter::new(out, options)?),
+    })
+}
+
+/* ---------------- GeoJSON ---------------- */
+
+/// Replays the frames into a store and writes where every node was last
+/// seen, with its state at the end of the recordings. Returns the
+/// features written.
+pub fn write_geojson<W: Write>(
+    frames: impl Iterator<Item = io::Result<Frame>>,
+    config: &Config,
+    out: &mut W,
+) -> io::Result<usize> {
+    let mut store = Store::new(config);
+    decode_frames(frames, config, |handled| {
+        if let Some(msg) = &handled.message {
+            store.record_message(msg);
+        }
+        if let Some(info) = &handled.node_info {
+            store.record_node_info(info);
+        }
+        for event in &handled.alerts {
+            store.record_alert(event);
+        }
+        Ok(())
+    })?;
+
+    let collection = geojson::feature_collection(&store);
+    serde_json::to_writer_pretty(&mut *out, &collection)?;
+    out.write_all(b"\n")?;
+    out.flush()?;
+    Ok(collection["features"].as_array().map_or(0, Vec::len))
+}
+
+/* ---------------- Reaches ---------------- */
+
+/// Replays the frames and collects every node's good `water_level`
+/// readings by the time the radio received them, oldest first.
+pub fn water_levels(
+    frames: impl Iterator<Item = io::Result<Frame>>,
+    config: &Config,
+) -> io::Result<HashMap<u32, Vec<(u64, f64)>>> {
+    let mut series: HashMap<u32, Vec<(u64, f64)>> = HashMap::new();
+    decode_frames(frames, config, |handled| {
+        if let Some(msg) = &handled.message
+            && let Some(&(_, level)) = msg.readings.iter().find(|(m, _)| *m == "water_level")
+            && msg.quality("water_level").is_good()
+        {
+            series
+                .entry(msg.message.node_id)
+                .or_default()
+                .push((msg.packet_time, level));
+        }
+        Ok(())
+    })?;
+    for levels in series.values_mut() {
+        levels.sort_by_key(|&(t, _)| t);
+        levels.dedup_by_key(|&mut (t, _)| t);
+    }
+    Ok(series)
+}
+
+/* ---------------- Commands ---------------- */
+
+/// `export`: recordings, in the order given, to `output` or stdout.
+/// Returns the records or rows written.
+pub fn export(
+    inputs: &[PathBuf],
+    output: Option<&Path>,
+    format: ExportFormat,
+    config: &Config,
+    options: &TableOptions,
+) -> io::Result<usize> {
+    let mut frames = Vec::new();
+    for input in inputs {
+        frames.push(PlaybackStream::open(input)?.skip_outbound());
+    }
+    let frames = frames.into_iter().flatten();
+    let mut out: Box<dyn Write + Send> = match output {
+        Some(path) => Box::new(BufWriter::new(File::create(path)?)),
+        None => Box::new(BufWriter::new(io::stdout())),
+    };
+
+    match format {
+        ExportFormat::Jsonl => write_jsonl(frames, config, &mut out),
+        ExportFormat::Geojson => write_geojson(frames, config, &mut out),
+        ExportFormat::Table(format) => {
+            let mut table = TableBuilder::new(options.clone(), table_writer(format, out, options)?);
+            write_table(frames, config, &mut table)?;
+            table.finish()
+        }
+    }
+}
+
+/// `learn-reaches`: travel times of the configured reaches learned from
+/// recordings, as config to paste.
+pub fn learn_reaches(inputs: &[PathBuf], config: &Config) -> io::Result<String> {
+    let mut frames = Vec::new();
+    for input in inputs {
+        frames.push(PlaybackStream::open(input)?.skip_outbound());
+    }
+    let series = water_levels(frames.into_iter().flatten(), config)?;
+    Ok(reach::learn(config, &series))
+}
+
+/// `backtest`: scores the stage forecast against recorded water levels.
+pub fn backtest(inputs: &[PathBuf], config: &Config) -> io::Result<String> {
+    let mut frames = Vec::new();
+    for input in inputs {
+        frames.push(PlaybackStream::open(input)?.skip_outbound());
+    }
+    let series = water_levels(frames.into_iter().flatten(), config)?;
+    Ok(forecast::backtest_report(config, &series))
+}
+
+/// `import`: a JSON Lines file into a recording in `dir`.
+pub fn import(input: &Path, dir: &Path, max_file_size: u64) -> io::Result<usize> {
+    let mut recorder = RecordingStream::new(dir, max_file_size)?;
+    read_jsonl(BufReader::new(File::open(input)?), &mut recorder)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::ApiState;
+    use crate::pipeline::Pipeline;
+    use crate::radio_message::Telemetry;
+
+    fn temp_dir(name: &str) -> std::path::PathBuf {
+        let dir = std::env::temp_dir().join(format!("{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        dir
+    }
+
+    #[test]
+    fn imports_test_jsonl_and_exports_it_again() {
+        let dir = temp_dir("jsonl-import");
+        let imported = import(Path::new("test.jsonl"), &dir, 1024 * 1024).unwrap();
+        assert_eq!(imported, 2);
+
+        let recording = dir.join("meshtastic-recording-00000.bin");
+        let mut out = Vec::new();
+        let frames = PlaybackStream::open(&recording).unwrap();
+        assert_eq!(
+            write_jsonl(frames, &Config::default(), &mut out).unwrap(),
+            2
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let records: Vec<JsonlRecord> = out
+            .split(|b| *b == b'\n')
+            .filter(|line| !line.is_empty())
+            .map(|line| serde_json::from_slice(line).unwrap())
+            .collect();
+        assert_eq!(records[0].source_id, 12345);
+        assert_eq!(records[0].timestamp, 1766889471);
+        assert_eq!(
+            records[0].decoded,
+            AppMessage::Telemetry(Telemetry::Device {
+                battery_level: Some(100),
+                voltage: Some(4.2),
+                uptime_seconds: Some(3600),
+            })
+        );
+
+        // A second import of the export keeps the recorded frames as they are
+        let dir = temp_dir("jsonl-reimport");
+        let mut recorder = RecordingStream::new(&dir, 1024 * 1024).unwrap();
+        read_jsonl(&out[..], &mut recorder).unwrap();
+        let frames: Vec<Frame> = PlaybackStream::open(dir.join("meshtastic-recording-00000.bin"))
+            .unwrap()
+            .collect::<io::Result<_>>()
+            .unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(frames[1].encode_payload(), records[1].raw_bytes);
+    }
+
+    #[test]
+    fn writes_one_record_per_packet() {
+        let record = JsonlRecord {
+            raw_bytes: Vec::new(),
+            source_id: 5,
+            timestamp: 1_000,
+            decoded: AppMessage::Telemetry(Telemetry::Device {
+                battery_level: Some(80),
+                voltage: None,
+                uptime_seconds: None,
+            }),
+        };
+        let heard = |connection: &str, rx_snr: f32| {
+            let mut from_radio = record.rebuild();
+            if let Some(from_radio::PayloadVariant::Packet(packet)) =
+                &mut from_radio.payload_variant
+            {
+                packet.id = 7;
+                packet.rx_snr = rx_snr;
+            }
+            Ok(Frame::inbound(connection, from_radio, false))
+        };
+        // The same packet heard by two radios, better by the second
+        let frames = [heard("north", 1.0), heard("south", 6.5)];
+
+        let mut out = Vec::new();
+        let written = write_jsonl(frames.into_iter(), &Config::default(), &mut out).unwrap();
+        assert_eq!(written, 1);
+
+        let record: JsonlRecord = serde_json::from_slice(&out).unwrap();
+        let frame = FromRadio::decode(&record.raw_bytes[..]).unwrap();
+        let Some(from_radio::PayloadVariant::Packet(packet)) = frame.payload_variant else {
+            panic!("not a packet: {:?}", frame);
+        };
+        assert_eq!(packet.rx_snr, 6.5);
+    }
+
+    #[test]
+    fn csv_has_rain_columns() {
+        let record = JsonlRecord {
+            raw_bytes: Vec::new(),
+            source_id: 7,
+            timestamp: 3_600,
+            decoded: AppMessage::Telemetry(Telemetry::Environment {
+                temperature: None,
+                humidity: None,
+                pressure: None,
+                distance: None,
+                rainfall_1h: Some(2.5),
+                rainfall_24h: None,
+            }),
+        };
+        let frames = [Ok(Frame::inbound("radio", record.rebuild(), false))];
+        let options = TableOptions {
+            columns: crate::table::parse_columns("rainfall_1h,rain_24h").unwrap(),
+            ..TableOptions::default()
+        };
+
+        let path = temp_dir("rain.csv");
+        let writer = table_writer(TableFormat::Csv, File::create(&path).unwrap(), &options);
+        let mut table = TableBuilder::new(options, writer.unwrap());
+        write_table(frames.into_iter(), &Config::default(), &mut table).unwrap();
+        assert_eq!(table.finish().unwrap(), 1);
+
+        let csv = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(
+            csv,
+            "timestamp,node,name,rainfall_1h,rain_24h,quality\n\
+             1970-01-01T01:00:00Z,!00000007,!00000007,2.5,2.5,\n"
+        );
+    }
+
+    #[test]
+    fn csv_has_battery_columns() {
+        let frames =
+            [(0, 4.0), (3_600, 3.75), (7_200, 3.5), (10_800, 3.25)].map(|(timestamp, voltage)| {
+                let record = JsonlRecord {
+                    raw_bytes: Vec::new(),
+                    source_id: 7,
+                    timestamp,
+                    decoded: AppMessage::Telemetry(Telemetry::Device {
+                        battery_level: None,
+                        voltage: Some(voltage),
+                        uptime_seconds: None,
+                    }),
+                };
+                Ok(record.to_frame())
+            });
+        let config = Config::parse("[battery]\ncutoff_voltage = 3.0\nmin_span_hours = 1").unwrap();
+        let options = TableOptions {
+            columns: crate::table::parse_columns("voltage_slope,hours_to_cutoff").unwrap(),
+            ..TableOptions::default()
+        };
+
+        let path = temp_dir("battery.csv");
+        let writer = table_writer(TableFormat::Csv, File::create(&path).unwrap(), &options);
+        let mut table = TableBuilder::new(options, writer.unwrap());
+        write_table(frames.into_iter(), &config, &mut table).unwrap();
+        table.finish().unwrap();
+
+        let csv = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let last = csv.lines().last().unwrap();
+        assert!(csv.starts_with("timestamp,node,name,voltage_slope,hours_to_cutoff,"));
+        assert_eq!(last, "1970-01-01T03:00:00Z,!00000007,!00000007,-0.25,1,");
+    }
+
+    #[test]
+    fn geojson_file_matches_the_api() {
+        let node_info = meshtastic::protobufs::NodeInfo {
+            num: 6,
+            user: Some(meshtastic::protobufs::User {
+                long_name: "Mill Creek".to_string(),
+                ..Default::default()
+            }),
+            position: Some(meshtastic::protobufs::Position {
+                latitude_i: Some(515_000_000),
+                longitude_i: Some(-1_200_000),
+                time: 900,
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        let dump = FromRadio {
+            id: 1,
+            payload_variant: Some(from_radio::PayloadVariant::NodeInfo(node_info)),
+        };
+        let frames = vec![Frame::inbound("radio", dump, true)];
+        // The API's store is only fed when it is served
+        let config = Config::parse("[http]\nenabled = true").unwrap();
+
+        let mut out = Vec::new();
+        let written = write_geojson(frames.iter().cloned().map(Ok), &config, &mut out).unwrap();
+        assert_eq!(written, 1);
+
+        let api = ApiState::new(&config);
+        let mut pipeline = Pipeline::new(&config, &api, None, false).unwrap();
+        for frame in &frames {
+            pipeline.process(frame);
+        }
+        let from_file: serde_json::Value = serde_json::from_slice(&out).unwrap();
+        assert_eq!(
+            from_file,
+            geojson::feature_collection(&api.store.read().unwrap())
+        );
+        assert_eq!(from_file["features"][0]["properties"]["name"], "Mill Creek");
+    }
+
+    #[test]
+    fn reports_the_failing_record() {
+        let dir = temp_dir("jsonl-bad");
+        let mut recorder = RecordingStream::new(&dir, 1024).unwrap();
+        let err =
+            read_jsonl(&br#"{"raw_bytes": [], "source_id": 1}"#[..], &mut recorder).unwrap_err();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert!(err.to_string().starts_with("record 1:"), "{}", err);
+    }
+}
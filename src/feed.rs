@@ -0,0 +1,389 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use serde_json::{Value, json};
+use tokio::sync::broadcast;
+
+use crate::alerts::{AlertEvent, AlertState};
+use crate::config::NodeId;
+use crate::dedup::PacketCopies;
+use crate::handler::DecodedMessage;
+use crate::radio_message::AppMessage;
+use crate::sinks::Sink;
+
+/// Events buffered per subscriber. A client that falls further behind
+/// skips the oldest events and is told how many it missed.
+pub const FEED_CAPACITY: usize = 256;
+
+pub type FeedSender = broadcast::Sender<Arc<FeedEvent>>;
+
+pub fn channel() -> FeedSender {
+    broadcast::channel(FEED_CAPACITY).0
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EventKind {
+    Message,
+    Alert,
+    /// A later copy of a message heard with a better SNR
+    Link,
+}
+
+/// One entry of the live feed, serialized once and shared by every
+/// subscriber. The other fields are only there for filtering.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FeedEvent {
+    pub kind: EventKind,
+    pub node_id: u32,
+    pub node_name: String,
+    /// Port name as in the protobufs, e.g. `TELEMETRY_APP`; alerts have none
+    pub port: Option<&'static str>,
+    pub json: String,
+}
+
+impl FeedEvent {
+    pub fn message(msg: &DecodedMessage) -> Self {
+        let rm = &msg.message;
+        let mut body = json!({
+            "type": "message",
+            "timestamp": msg.timestamp,
+            "connection": msg.connection,
+            "packet_id": msg.packet_id,
+            "node": NodeId(rm.node_id),
+            "node_name": msg.node_name,
+            "port": rm.portnum.as_str_name(),
+        });
+        let extra = match &rm.app {
+            AppMessage::Telemetry(_) => {
+                let readings: serde_json::Map<String, Value> = msg
+                    .readings
+                    .iter()
+                    .map(|(metric, value)| (metric.to_string(), json!(value)))
+                    .collect();
+                if !msg.quality.is_empty() {
+                    body["quality"] = msg
+                        .quality
+                        .iter()
+                        .map(|(metric, quality)| (metric.to_string(), json!(quality)))
+                        .collect::<serde_json::Map<_, _>>()
+                        .into();
+                }
+                ("readings", Value::Object(readings))
+            }
+            AppMessage::Position(pos) => (
+                "position",
+                json!({
+                    "latitude": pos.latitude,
+                    "longitude": pos.longitude,
+                    "altitude": pos.altitude,
+                    "accuracy": pos.accuracy,
+                }),
+            ),
+            AppMessage::Text(text) => ("text", json!(text.msg)),
+            AppMessage::Detection(text) | AppMessage::Serial(text) => {
+                if !msg.readings.is_empty() {
+                    body["readings"] = msg
+                        .readings
+                        .iter()
+                        .map(|(metric, value)| (metric.to_string(), json!(value)))
+                        .collect::<serde_json::Map<_, _>>()
+                        .into();
+                }
+                ("text", json!(text))
+            }
+        };
+        body[extra.0] = extra.1;
+
+        Self {
+            kind: EventKind::Message,
+            node_id: rm.node_id,
+            node_name: msg.node_name.clone(),
+            port: Some(rm.portnum.as_str_name()),
+            json: body.to_string(),
+        }
+    }
+
+    /// The reception of `copies.best`, for a message published as heard
+    /// on another connection.
+    pub fn link(copies: &PacketCopies, node_name: &str, port: &'static str) -> Self {
+        let best = &copies.best;
+        let body = json!({
+            "type": "link",
+            "timestamp": copies.first_seen,
+            "connection": best.connection,
+            "packet_id": copies.id,
+            "node": NodeId(copies.from),
+            "node_name": node_name,
+            "port": port,
+            "rx_snr": best.rx_snr,
+            "rx_rssi": best.rx_rssi,
+        });
+
+        Self {
+            kind: EventKind::Link,
+            node_id: copies.from,
+            node_name: node_name.to_string(),
+            port: Some(port),
+            json: body.to_string(),
+        }
+    }
+
+    pub fn alert(event: &AlertEvent) -> Self {
+        let state = match event.state {
+            AlertState::Raised => "raised",
+            AlertState::Cleared => "cleared",
+        };
+        let body = json!({
+            "type": "alert",
+            "timestamp": event.timestamp,
+            "rule": event.rule,
+            "node": NodeId(event.node_id),
+            "node_name": event.node_name,
+            "metric": event.metric,
+            "value": event.value,
+            "threshold": event.threshold,
+            "expected_at": event.expected_at,
+            "severity": event.severity,
+            "state": state,
+        });
+
+        Self {
+            kind: EventKind::Alert,
+            node_id: event.node_id,
+            node_name: event.node_name.clone(),
+            port: None,
+            json: body.to_string(),
+        }
+    }
+}
+
+/// Subscriber-side selection from query parameters. Each parameter takes a
+/// comma-separated list; an absent parameter matches everything.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct FeedFilter {
+    /// Aliases or `!hex` / decimal node ids
+    nodes: Vec<String>,
+    /// Port names, case-insensitive, `_APP` suffix optional
+    ports: Vec<String>,
+    kinds: Vec<EventKind>,
+}
+
+impl FeedFilter {
+    pub fn from_query(query: &HashMap<String, String>) -> Result<Self, String> {
+        let list = |key: &str| -> Vec<String> {
+            query
+                .get(key)
+                .map(|v| {
+                    v.split(',')
+                        .map(str::trim)
+                        .filter(|s| !s.is_empty())
+                        .map(String::from)
+                        .collect()
+                })
+                .unwrap_or_default()
+        };
+
+        let kinds = list("type")
+            .iter()
+            .map(|kind| match kind.as_str() {
+                "message" => Ok(EventKind::Message),
+                "alert" => Ok(EventKind::Alert),
+                "link" => Ok(EventKind::Link),
+                other => Err(format!(
+                    "unknown event type `{}`, expected message, alert or link",
+                    other
+                )),
+            })
+            .collect::<Result<_, _>>()?;
+
+        Ok(Self {
+            nodes: list("node"),
+            ports: list("port")
+                .into_iter()
+                .map(|p| p.to_ascii_uppercase())
+                .collect(),
+            kinds,
+        })
+    }
+
+    pub fn matches(&self, event: &FeedEvent) -> bool {
+        if !self.kinds.is_empty() && !self.kinds.contains(&event.kind) {
+            return false;
+        }
+        if !self.nodes.is_empty()
+            && !self.nodes.iter().any(|n| {
+                *n == event.node_name || n.parse::<NodeId>().is_ok_and(|id| id.0 == event.node_id)
+            })
+        {
+            return false;
+        }
+        if !self.ports.is_empty() {
+            // Alerts are not tied to a port, so a port filter excludes them
+            let Some(port) = event.port else {
+                return false;
+            };
+            if !self
+                .ports
+                .iter()
+                .any(|p| p == port || port.strip_suffix("_APP") == Some(p.as_str()))
+            {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+/// Publishes decoded messages and alerts to live feed subscribers, and
+/// better copies of messages while their dedup window is open.
+pub struct FeedSink {
+    sender: FeedSender,
+    /// (from, packet id) → node name and port of messages still in the
+    /// dedup window
+    open: HashMap<(u32, u32), (String, &'static str)>,
+}
+
+impl FeedSink {
+    pub fn new(sender: FeedSender) -> Self {
+        Self {
+            sender,
+            open: HashMap::new(),
+        }
+    }
+
+    fn send(&self, event: impl FnOnce() -> FeedEvent) {
+        // Only fails when nobody is subscribed
+        if self.sender.receiver_count() > 0 {
+            let _ = self.sender.send(Arc::new(event()));
+        }
+    }
+}
+
+impl Sink for FeedSink {
+    fn name(&self) -> &'static str {
+        "feed"
+    }
+
+    fn on_message(&mut self, msg: &DecodedMessage) {
+        if msg.packet_id != 0 {
+            let key = (msg.message.node_id, msg.packet_id);
+            let port = msg.message.portnum.as_str_name();
+            self.open.insert(key, (msg.node_name.clone(), port));
+        }
+        self.send(|| FeedEvent::message(msg));
+    }
+
+    fn on_better_copy(&mut self, copies: &PacketCopies) {
+        if let Some((name, port)) = self.open.get(&(copies.from, copies.id)) {
+            self.send(|| FeedEvent::link(copies, name, port));
+        }
+    }
+
+    fn on_link(&mut self, copies: &PacketCopies) {
+        self.open.remove(&(copies.from, copies.id));
+    }
+
+    fn on_alert(&mut self, event: &AlertEvent) {
+        self.send(|| FeedEvent::alert(event));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event(kind: EventKind, port: Option<&'static str>) -> FeedEvent {
+        FeedEvent {
+            kind,
+            node_id: 5,
+            node_name: "bridge".to_string(),
+            port,
+            json: String::new(),
+        }
+    }
+
+    fn filter(query: &[(&str, &str)]) -> FeedFilter {
+        let query = query
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        FeedFilter::from_query(&query).unwrap()
+    }
+
+    #[test]
+    fn filters_by_node_port_and_type() {
+        let telemetry = event(EventKind::Message, Some("TELEMETRY_APP"));
+        let alert = event(EventKind::Alert, None);
+
+        assert!(filter(&[]).matches(&telemetry));
+        assert!(filter(&[("node", "other,bridge")]).matches(&telemetry));
+        assert!(filter(&[("node", "!00000005")]).matches(&alert));
+        assert!(!filter(&[("node", "other")]).matches(&telemetry));
+
+        assert!(filter(&[("port", "telemetry")]).matches(&telemetry));
+        assert!(filter(&[("port", "TELEMETRY_APP")]).matches(&telemetry));
+        assert!(!filter(&[("port", "position")]).matches(&telemetry));
+        assert!(!filter(&[("port", "telemetry")]).matches(&alert));
+
+        assert!(filter(&[("type", "alert")]).matches(&alert));
+        assert!(!filter(&[("type", "alert")]).matches(&telemetry));
+    }
+
+    #[test]
+    fn publishes_better_copies_of_open_messages() {
+        use crate::dedup::CopyInfo;
+        use crate::radio_message::Telemetry;
+
+        let sender = channel();
+        let mut events = sender.subscribe();
+        let mut sink = FeedSink::new(sender);
+        let mut msg = DecodedMessage::test(
+            5,
+            "bridge",
+            100,
+            AppMessage::Telemetry(Telemetry::Device {
+                battery_level: Some(90),
+                voltage: None,
+                uptime_seconds: None,
+            }),
+            vec![("battery_level", 90.0)],
+        );
+        msg.packet_id = 7;
+        let mut copies = PacketCopies {
+            from: 5,
+            id: 7,
+            first_seen: 100,
+            copies: 2,
+            relays: Default::default(),
+            best: CopyInfo {
+                connection: "south".to_string(),
+                rx_snr: 6.5,
+                rx_rssi: -90,
+                hops: None,
+                relay_node: 0,
+            },
+        };
+
+        sink.on_message(&msg);
+        sink.on_better_copy(&copies);
+        sink.on_link(&copies);
+        // The window has closed
+        copies.best.rx_snr = 9.0;
+        sink.on_better_copy(&copies);
+
+        assert_eq!(events.try_recv().unwrap().kind, EventKind::Message);
+        let link = events.try_recv().unwrap();
+        assert!(filter(&[("type", "link"), ("port", "telemetry")]).matches(&link));
+        let json: Value = serde_json::from_str(&link.json).unwrap();
+        assert_eq!(json["connection"], "south");
+        assert_eq!(json["packet_id"], 7);
+        assert_eq!(json["rx_rssi"], -90);
+        assert!(events.try_recv().is_err());
+    }
+
+    #[test]
+    fn rejects_unknown_event_types() {
+        let query = HashMap::from([("type".to_string(), "reading".to_string())]);
+        assert!(FeedFilter::from_query(&query).is_err());
+    }
+}
@@ -0,0 +1,675 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fmt::Write as _;
+
+use serde::Serialize;
+
+use crate::alerts::{AlertEvent, AlertState, StageRule, stage_rules};
+use crate::config::{Config, ForecastConfig, ForecastModel, Severity};
+use crate::nodes::NodeDirectory;
+
+/// A `water_level` rule forecast to be breached raises an alert named after
+/// it with this suffix, e.g. `moderate-flood-forecast`.
+pub const RULE_SUFFIX: &str = "-forecast";
+
+/// How far past `alert_within_hours` a crossing must move before its alert
+/// clears, so a wobbling trend does not flap.
+const CLEAR_MARGIN: f64 = 1.5;
+
+/// Resolution of crossing times.
+const CROSSING_STEP_SECS: u64 = 60;
+
+/// Forecast horizons the back-test scores, within `horizon_hours`.
+const BACKTEST_HORIZONS_MINS: [u64; 6] = [30, 60, 120, 180, 360, 720];
+
+/// Back-test readings further than this from the forecast time are not
+/// interpolated between.
+const MAX_GAP_SECS: u64 = 3600;
+
+/// One forecast level with its confidence interval.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct ForecastPoint {
+    pub at: u64,
+    pub level: f64,
+    pub low: f64,
+    pub high: f64,
+}
+
+/// When a gauge is expected to breach a `water_level` rule.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Crossing {
+    pub rule: String,
+    pub threshold: f64,
+    pub severity: Severity,
+    pub at: u64,
+    /// By the upper end of the interval
+    pub earliest: u64,
+    /// By the lower end; `None` when it may not cross within the horizon
+    pub latest: Option<u64>,
+}
+
+/// A gauge's stage projected over the next `horizon_hours`.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct StageForecast {
+    pub model: ForecastModel,
+    /// Time of the latest reading, which the forecast starts from
+    pub made_at: u64,
+    /// Fitted level at `made_at` and its rate of change
+    pub level: f64,
+    pub rate_per_hour: f64,
+    pub confidence: f64,
+    pub points: Vec<ForecastPoint>,
+    /// Rules above the current level that the forecast breaches, soonest
+    /// first
+    pub crossings: Vec<Crossing>,
+}
+
+/* ---------------- Models ---------------- */
+
+/// A model fitted to a window of readings, in hours from the latest.
+enum Fit {
+    /// Least-squares polynomial, coefficients lowest power first, with
+    /// `(X'X)^-1` and the residual standard error for prediction intervals
+    Polynomial {
+        coefficients: Vec<f64>,
+        inverse: Vec<Vec<f64>>,
+        sigma: f64,
+    },
+    /// Holt's smoothed level and trend per hour, with the standard error of
+    /// one-step forecasts and the mean step in hours
+    Smoothing {
+        level: f64,
+        trend: f64,
+        sigma: f64,
+        step_hours: f64,
+        alpha: f64,
+        beta: f64,
+    },
+}
+
+impl Fit {
+    fn new(config: &ForecastConfig, samples: &[(u64, f64)]) -> Option<Self> {
+        let &(last, _) = samples.last()?;
+        if samples.len() < config.min_samples {
+            return None;
+        }
+        let hours = |t: u64| -((last - t) as f64) / 3600.0;
+        match config.model {
+            ForecastModel::Linear => fit_polynomial(samples, hours, 1),
+            ForecastModel::Quadratic => fit_polynomial(samples, hours, 2),
+            ForecastModel::Smoothing => {
+                let (alpha, beta) = (config.alpha, config.beta);
+                let (mut level, mut trend) = (samples[0].1, 0.0);
+                let mut errors = Vec::new();
+                for pair in samples.windows(2) {
+                    let dt = (pair[1].0 - pair[0].0) as f64 / 3600.0;
+                    let predicted = level + trend * dt;
+                    if pair[0].0 > samples[0].0 {
+                        errors.push(pair[1].1 - predicted);
+                    }
+                    let previous = level;
+                    level = alpha * pair[1].1 + (1.0 - alpha) * predicted;
+                    if dt > 0.0 {
+                        trend = beta * (level - previous) / dt + (1.0 - beta) * trend;
+                    }
+                }
+                let sigma =
+                    (errors.iter().map(|e| e * e).sum::<f64>() / errors.len() as f64).sqrt();
+                Some(Fit::Smoothing {
+                    level,
+                    trend,
+                    sigma,
+                    step_hours: (last - samples[0].0) as f64 / 3600.0 / (samples.len() - 1) as f64,
+                    alpha,
+                    beta,
+                })
+            }
+        }
+    }
+
+    /// Level and standard error `hours` after the latest reading.
+    fn predict(&self, hours: f64) -> (f64, f64) {
+        match self {
+            Fit::Polynomial {
+                coefficients,
+                inverse,
+                sigma,
+            } => {
+                let x: Vec<f64> = (0..coefficients.len())
+                    .map(|p| hours.powi(p as i32))
+                    .collect();
+                let level = coefficients.iter().zip(&x).map(|(c, x)| c * x).sum();
+                let leverage: f64 = (0..x.len())
+                    .map(|i| {
+                        (0..x.len())
+                            .map(|j| x[i] * inverse[i][j] * x[j])
+                            .sum::<f64>()
+                    })
+                    .sum();
+                (level, sigma * (1.0 + leverage).sqrt())
+            }
+            Fit::Smoothing {
+                level,
+                trend,
+                sigma,
+                step_hours,
+                alpha,
+                beta,
+            } => {
+                // Variance of a Holt forecast `steps` ahead
+                let steps = (hours / step_hours).round().max(1.0) as usize;
+                let spread: f64 = (1..steps)
+                    .map(|j| (alpha * (1.0 + j as f64 * beta)).powi(2))
+                    .sum();
+                (level + trend * hours, sigma * (1.0 + spread).sqrt())
+            }
+        }
+    }
+
+    /// Rate of change per hour at the latest reading.
+    fn rate(&self) -> f64 {
+        match self {
+            Fit::Polynomial { coefficients, .. } => coefficients[1],
+            Fit::Smoothing { trend, .. } => *trend,
+        }
+    }
+}
+
+fn fit_polynomial(
+    samples: &[(u64, f64)],
+    hours: impl Fn(u64) -> f64,
+    degree: usize,
+) -> Option<Fit> {
+    let terms = degree + 1;
+    let mut xtx = vec![vec![0.0; terms]; terms];
+    let mut xty = vec![0.0; terms];
+    for &(t, y) in samples {
+        let x = hours(t);
+        for (i, row) in xtx.iter_mut().enumerate() {
+            xty[i] += x.powi(i as i32) * y;
+            for (j, cell) in row.iter_mut().enumerate() {
+                *cell += x.powi((i + j) as i32);
+            }
+        }
+    }
+    let inverse = invert(xtx)?;
+    let coefficients: Vec<f64> = inverse
+        .iter()
+        .map(|row| row.iter().zip(&xty).map(|(a, b)| a * b).sum())
+        .collect();
+    let residuals: f64 = samples
+        .iter()
+        .map(|&(t, y)| {
+            let x = hours(t);
+            let fitted: f64 = coefficients
+                .iter()
+                .enumerate()
+                .map(|(p, c)| c * x.powi(p as i32))
+                .sum();
+            (y - fitted).powi(2)
+        })
+        .sum();
+    let sigma = (residuals / (samples.len() - terms) as f64).sqrt();
+    Some(Fit::Polynomial {
+        coefficients,
+        inverse,
+        sigma,
+    })
+}
+
+/// Gauss-Jordan inverse of a small matrix; `None` when it is singular,
+/// e.g. all readings at one time.
+fn invert(mut m: Vec<Vec<f64>>) -> Option<Vec<Vec<f64>>> {
+    let n = m.len();
+    let mut inverse: Vec<Vec<f64>> = (0..n)
+        .map(|i| (0..n).map(|j| if i == j { 1.0 } else { 0.0 }).collect())
+        .collect();
+    for col in 0..n {
+        let pivot = (col..n).max_by(|&a, &b| m[a][col].abs().total_cmp(&m[b][col].abs()))?;
+        if m[pivot][col].abs() < 1e-12 {
+            return None;
+        }
+        m.swap(col, pivot);
+        inverse.swap(col, pivot);
+        let scale = m[col][col];
+        for j in 0..n {
+            m[col][j] /= scale;
+            inverse[col][j] /= scale;
+        }
+        for row in 0..n {
+            if row != col {
+                let factor = m[row][col];
+                for j in 0..n {
+                    m[row][j] -= factor * m[col][j];
+                    inverse[row][j] -= factor * inverse[col][j];
+                }
+            }
+        }
+    }
+    Some(inverse)
+}
+
+/// Two-sided standard normal quantile for `confidence`, within 5e-4
+/// (Abramowitz and Stegun 26.2.23).
+fn z_score(confidence: f64) -> f64 {
+    let p = (1.0 - confidence) / 2.0;
+    let t = (-2.0 * p.ln()).sqrt();
+    t - (2.515517 + 0.802853 * t + 0.010328 * t * t)
+        / (1.0 + 1.432788 * t + 0.189269 * t * t + 0.001308 * t * t * t)
+}
+
+/// Forecasts the stage from `samples`, (timestamp, level) oldest first,
+/// with crossing times for `rules`.
+pub fn forecast(
+    config: &ForecastConfig,
+    samples: &[(u64, f64)],
+    rules: &[&StageRule],
+) -> Option<StageForecast> {
+    let fit = Fit::new(config, samples)?;
+    let made_at = samples.last()?.0;
+    let z = z_score(config.confidence);
+    let horizon = (config.horizon_hours * 3600.0) as u64;
+    let at = |secs: u64| {
+        let (level, se) = fit.predict(secs as f64 / 3600.0);
+        ForecastPoint {
+            at: made_at + secs,
+            level,
+            low: level - z * se,
+            high: level + z * se,
+        }
+    };
+    let level = fit.predict(0.0).0;
+    let points = (1..)
+        .map(|k| k * config.step_mins * 60)
+        .take_while(|&secs| secs <= horizon)
+        .map(at)
+        .collect();
+
+    let mut crossings: Vec<Crossing> = rules
+        .iter()
+        .filter(|rule| level <= rule.above)
+        .filter_map(|rule| {
+            let fine: Vec<ForecastPoint> = (0..=horizon / CROSSING_STEP_SECS)
+                .map(|k| at(k * CROSSING_STEP_SECS))
+                .collect();
+            let first = |value: fn(&ForecastPoint) -> f64| {
+                fine.iter().find(|p| value(p) > rule.above).map(|p| p.at)
+            };
+            Some(Crossing {
+                rule: rule.name.clone(),
+                threshold: rule.above,
+                severity: rule.severity,
+                at: first(|p| p.level)?,
+                earliest: first(|p| p.high)?,
+                latest: first(|p| p.low),
+            })
+        })
+        .collect();
+    crossings.sort_by_key(|c| c.at);
+
+    Some(StageForecast {
+        model: config.model,
+        made_at,
+        level,
+        rate_per_hour: fit.rate(),
+        confidence: config.confidence,
+        points,
+        crossings,
+    })
+}
+
+/* ---------------- Monitor ---------------- */
+
+/// Forecasts each gauge's stage from its recent good `water_level`
+/// readings. With `[forecast]` enabled, a `water_level` rule expected to be
+/// breached within `alert_within_hours` raises `<rule>-forecast` with the
+/// expected time; it clears once the rule is breached or no longer
+/// expected to be soon.
+pub struct ForecastMonitor {
+    config: ForecastConfig,
+    rules: Vec<StageRule>,
+    /// Readings within `window_hours`, oldest first
+    samples: HashMap<u32, VecDeque<(u64, f64)>>,
+    /// (node, rule) forecast alerts raised
+    raised: HashSet<(u32, String)>,
+}
+
+impl ForecastMonitor {
+    pub fn from_config(config: &Config) -> Self {
+        let mut monitor = Self {
+            config: config.forecast.clone(),
+            rules: Vec::new(),
+            samples: HashMap::new(),
+            raised: HashSet::new(),
+        };
+        monitor.reconfigure(config);
+        monitor
+    }
+
+    /// Takes settings and rules from a reloaded config, keeping readings
+    /// and raised alerts.
+    pub fn reconfigure(&mut self, config: &Config) {
+        self.config = config.forecast.clone();
+        self.rules = stage_rules(config);
+    }
+
+    /// Marks forecast alerts raised before a restart as active, so they
+    /// clear as usual.
+    pub fn restore(&mut self, active: &HashSet<(String, u32)>) {
+        for (rule, node_id) in active {
+            if let Some(name) = rule.strip_suffix(RULE_SUFFIX)
+                && self.rules.iter().any(|r| r.name == name)
+            {
+                self.raised.insert((*node_id, name.to_string()));
+            }
+        }
+    }
+
+    /// Adds a good `water_level` reading, returning the gauge's forecast
+    /// and any forecast alerts raised or cleared.
+    pub fn observe(
+        &mut self,
+        timestamp: u64,
+        node_id: u32,
+        level: f64,
+        directory: &NodeDirectory,
+    ) -> (Option<StageForecast>, Vec<AlertEvent>) {
+        let samples = self.samples.entry(node_id).or_default();
+        if samples.back().is_some_and(|&(t, _)| timestamp <= t) {
+            return (None, Vec::new());
+        }
+        let window = (self.config.window_hours * 3600.0) as u64;
+        samples.push_back((timestamp, level));
+        while samples
+            .front()
+            .is_some_and(|&(t, _)| t + window < timestamp)
+        {
+            samples.pop_front();
+        }
+
+        let rules: Vec<&StageRule> = self
+            .rules
+            .iter()
+            .filter(|r| r.applies_to(node_id))
+            .collect();
+        let forecast = forecast(&self.config, samples.make_contiguous(), &rules);
+
+        let mut events = Vec::new();
+        let within = self.config.alert_within_hours * 3600.0;
+        for rule in rules {
+            let key = (node_id, rule.name.clone());
+            let raised = self.raised.contains(&key);
+            let limit = if raised {
+                within * CLEAR_MARGIN
+            } else {
+                within
+            };
+            let crossing = forecast
+                .iter()
+                .flat_map(|f| &f.crossings)
+                .find(|c| c.rule == rule.name)
+                .filter(|c| (c.at - timestamp) as f64 <= limit);
+            let due = self.config.enabled && crossing.is_some();
+            if due == raised {
+                continue;
+            }
+            let name = directory.display_name(node_id);
+            if let Some(crossing) = crossing {
+                log::warn!(
+                    "{} expected to breach {} in {} min",
+                    name,
+                    rule.name,
+                    (crossing.at - timestamp) / 60
+                );
+                self.raised.insert(key);
+            } else {
+                self.raised.remove(&key);
+            }
+            events.push(AlertEvent {
+                rule: format!("{}{}", rule.name, RULE_SUFFIX),
+                timestamp,
+                node_id,
+                node_name: name,
+                metric: "water_level".to_string(),
+                value: level,
+                threshold: rule.above,
+                expected_at: crossing.map(|c| c.at),
+                severity: rule.severity,
+                state: if due {
+                    AlertState::Raised
+                } else {
+                    AlertState::Cleared
+                },
+            });
+        }
+        (forecast, events)
+    }
+}
+
+/* ---------------- Back-test ---------------- */
+
+/// The level at `t` interpolated from `series`, oldest first.
+fn level_at(series: &[(u64, f64)], t: u64) -> Option<f64> {
+    let i = series.partition_point(|&(s, _)| s < t);
+    let &(t1, v1) = series.get(i)?;
+    if t1 == t {
+        return Some(v1);
+    }
+    let &(t0, v0) = series.get(i.checked_sub(1)?)?;
+    (t1 - t0 <= MAX_GAP_SECS).then(|| v0 + (v1 - v0) * (t - t0) as f64 / (t1 - t0) as f64)
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct Score {
+    pub forecasts: usize,
+    pub abs_error: f64,
+    pub sq_error: f64,
+    /// Readings inside the forecast's confidence interval
+    pub covered: usize,
+}
+
+/// Scores forecasts made at every reading of `series` against the readings
+/// that followed, per horizon in minutes.
+pub fn backtest(config: &ForecastConfig, series: &[(u64, f64)]) -> Vec<(u64, Score)> {
+    let horizons: Vec<u64> = BACKTEST_HORIZONS_MINS
+        .into_iter()
+        .filter(|&mins| mins as f64 <= config.horizon_hours * 60.0)
+        .collect();
+    let mut scores = vec![Score::default(); horizons.len()];
+    let window = (config.window_hours * 3600.0) as u64;
+    let z = z_score(config.confidence);
+    let mut start = 0;
+    for end in 0..series.len() {
+        let now = series[end].0;
+        while series[start].0 + window < now {
+            start += 1;
+        }
+        let Some(fit) = Fit::new(config, &series[start..=end]) else {
+            continue;
+        };
+        for (&mins, score) in horizons.iter().zip(&mut scores) {
+            let Some(actual) = level_at(series, now + mins * 60) else {
+                continue;
+            };
+            let (level, se) = fit.predict(mins as f64 / 60.0);
+            let error = actual - level;
+            score.forecasts += 1;
+            score.abs_error += error.abs();
+            score.sq_error += error * error;
+            if error.abs() <= z * se {
+                score.covered += 1;
+            }
+        }
+    }
+    horizons.into_iter().zip(scores).collect()
+}
+
+/// A table of back-test scores for every gauge in `series`.
+pub fn backtest_report(config: &Config, series: &HashMap<u32, Vec<(u64, f64)>>) -> String {
+    let forecast = &config.forecast;
+    let directory = NodeDirectory::from_config(config);
+    let mut out = format!(
+        "Back-test of the {} model over {} h windows, {:.0} % intervals\n\n\
+         {:<20} {:>7} {:>9} {:>9} {:>9} {:>11}\n",
+        forecast.model,
+        forecast.window_hours,
+        forecast.confidence * 100.0,
+        "gauge",
+        "horizon",
+        "forecasts",
+        "mae",
+        "rmse",
+        "in interval"
+    );
+    if series.is_empty() {
+        out.push_str("(no good water_level readings in the recordings)\n");
+    }
+    let mut nodes: Vec<&u32> = series.keys().collect();
+    nodes.sort();
+    for node_id in nodes {
+        let name = directory.display_name(*node_id);
+        for (mins, score) in backtest(forecast, &series[node_id]) {
+            if score.forecasts == 0 {
+                continue;
+            }
+            let n = score.forecasts as f64;
+            let _ = writeln!(
+                out,
+                "{:<20} {:>7} {:>9} {:>9.3} {:>9.3} {:>10.0} %",
+                name,
+                crate::alerts::lead_time(mins * 60).trim_start_matches('~'),
+                score.forecasts,
+                score.abs_error / n,
+                (score.sq_error / n).sqrt(),
+                score.covered as f64 / n * 100.0
+            );
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MIN: u64 = 60;
+
+    fn config(toml: &str) -> Config {
+        Config::parse(toml).unwrap()
+    }
+
+    /// Readings every 10 minutes along `level(hours)`.
+    fn series(count: u64, level: impl Fn(f64) -> f64) -> Vec<(u64, f64)> {
+        (0..count)
+            .map(|i| (i * 10 * MIN, level(i as f64 / 6.0)))
+            .collect()
+    }
+
+    #[test]
+    fn fits_each_model_to_a_steady_rise() {
+        let rule = StageRule {
+            name: "moderate".to_string(),
+            node: None,
+            above: 3.0,
+            severity: Severity::Warning,
+        };
+        // 0.25 m/h with a little noise
+        let samples = series(18, |h| 1.0 + 0.25 * h + 0.01 * (h * 20.0).sin());
+        for model in ["linear", "quadratic", "smoothing"] {
+            let config = config(&format!("[forecast]\nmodel = \"{}\"", model)).forecast;
+            let forecast = forecast(&config, &samples, &[&rule]).unwrap();
+            assert!(
+                (forecast.rate_per_hour - 0.25).abs() < 0.05,
+                "{} {:?}",
+                model,
+                forecast
+            );
+            assert_eq!(forecast.points.len(), 24);
+            let point = forecast.points[3];
+            assert!(point.low < point.level && point.level < point.high);
+
+            // 3.0 m is 8 h of rise from 1.0 m, 5.17 h after the last reading
+            let crossing = &forecast.crossings[0];
+            let hours = (crossing.at - forecast.made_at) as f64 / 3600.0;
+            assert!((hours - 5.17).abs() < 0.4, "{} {}", model, hours);
+            assert!(crossing.earliest <= crossing.at);
+            assert!(crossing.latest.is_none_or(|latest| latest >= crossing.at));
+        }
+    }
+
+    #[test]
+    fn raises_a_forecast_alert_before_the_rule_and_clears_it_when_breached() {
+        let config = config(
+            r#"
+            [forecast]
+            enabled = true
+            alert_within_hours = 2
+
+            [[nodes]]
+            id = 5
+            alias = "bridge"
+
+            [[alerts]]
+            name = "moderate-flood"
+            node = "bridge"
+            metric = "water_level"
+            above = 3.0
+            severity = "critical"
+            "#,
+        );
+        let nodes = NodeDirectory::from_config(&config);
+        let mut monitor = ForecastMonitor::from_config(&config);
+        let mut transitions = Vec::new();
+        for (t, level) in series(40, |h| 1.0 + 0.5 * h) {
+            let (forecast, events) = monitor.observe(t, 5, level, &nodes);
+            if t >= 50 * MIN {
+                assert!(forecast.is_some());
+            }
+            for event in events {
+                transitions.push((
+                    t / MIN,
+                    event.rule,
+                    event.state,
+                    event.expected_at.map(|at| at / MIN),
+                ));
+            }
+        }
+        // 3.0 m is reached after 4 h; the alert leads it by 2 h and clears
+        // with the first reading above it
+        assert_eq!(
+            transitions,
+            [
+                (
+                    120,
+                    "moderate-flood-forecast".to_string(),
+                    AlertState::Raised,
+                    Some(240)
+                ),
+                (
+                    250,
+                    "moderate-flood-forecast".to_string(),
+                    AlertState::Cleared,
+                    None
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn backtests_against_later_readings() {
+        let config = config("[forecast]\nhorizon_hours = 2").forecast;
+        let scores = backtest(
+            &config,
+            &series(60, |h| 2.0 + 0.1 * h + 0.01 * (h * 20.0).sin()),
+        );
+        assert_eq!(
+            scores.iter().map(|(mins, _)| *mins).collect::<Vec<_>>(),
+            [30, 60, 120]
+        );
+        // From the sixth reading until two hours before the last
+        let (_, score) = scores[2];
+        assert_eq!(score.forecasts, 60 - 5 - 12);
+        let mae = score.abs_error / score.forecasts as f64;
+        assert!(mae < 0.02, "{}", mae);
+        assert!(score.covered * 10 >= score.forecasts * 8, "{:?}", score);
+    }
+}
@@ -0,0 +1,82 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use meshtastic::Message;
+use meshtastic::protobufs::{FromRadio, ToRadio};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    /// `FromRadio`, device to monitor
+    Inbound,
+    /// `ToRadio`, monitor to device
+    Outbound,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum RadioFrame {
+    FromRadio(FromRadio),
+    ToRadio(ToRadio),
+}
+
+/// One frame flowing through the pipeline, whether it was just read from a
+/// radio or replayed from a `.bin` capture.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Frame {
+    /// `id` of the connection the frame went through; recordings don't
+    /// store it, so replayed frames carry `REPLAY_CONNECTION`
+    pub connection: String,
+    /// Seconds since the Unix epoch when the frame was received or sent
+    pub timestamp: u64,
+    /// Part of the configure handshake and initial device state dump
+    pub preamble: bool,
+    pub frame: RadioFrame,
+}
+
+pub const REPLAY_CONNECTION: &str = "replay";
+
+impl Frame {
+    pub fn inbound(connection: &str, from_radio: FromRadio, preamble: bool) -> Self {
+        Self {
+            connection: connection.to_string(),
+            timestamp: now_secs(),
+            preamble,
+            frame: RadioFrame::FromRadio(from_radio),
+        }
+    }
+
+    pub fn outbound(connection: &str, to_radio: ToRadio, preamble: bool) -> Self {
+        Self {
+            connection: connection.to_string(),
+            timestamp: now_secs(),
+            preamble,
+            frame: RadioFrame::ToRadio(to_radio),
+        }
+    }
+
+    pub fn direction(&self) -> Direction {
+        match self.frame {
+            RadioFrame::FromRadio(_) => Direction::Inbound,
+            RadioFrame::ToRadio(_) => Direction::Outbound,
+        }
+    }
+
+    pub fn as_from_radio(&self) -> Option<&FromRadio> {
+        match &self.frame {
+            RadioFrame::FromRadio(msg) => Some(msg),
+            RadioFrame::ToRadio(_) => None,
+        }
+    }
+
+    pub fn encode_payload(&self) -> Vec<u8> {
+        match &self.frame {
+            RadioFrame::FromRadio(msg) => msg.encode_to_vec(),
+            RadioFrame::ToRadio(msg) => msg.encode_to_vec(),
+        }
+    }
+}
+
+pub fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("time went backwards")
+        .as_secs()
+}
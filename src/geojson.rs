@@ -0,0 +1,199 @@
+use serde_json::{Value, json};
+
+use crate::quality::Quality;
+use crate::store::{NodeStatus, Store};
+use crate::table::Timezone;
+
+/// Every node with a known position as a GeoJSON `FeatureCollection`, one
+/// `Point` per node at the latest position it sent, or else the one in the
+/// radio's node database. Times in the properties are
+/// RFC 3339 in UTC, which Leaflet popups and QGIS both take as they are.
+pub fn feature_collection(store: &Store) -> Value {
+    let features: Vec<Value> = store
+        .nodes()
+        .filter_map(|node| feature(store, node))
+        .collect();
+    json!({
+        "type": "FeatureCollection",
+        "features": features,
+    })
+}
+
+fn feature(store: &Store, node: &NodeStatus) -> Option<Value> {
+    let position = node.position.as_ref()?;
+    let mut coordinates = vec![json!(position.longitude), json!(position.latitude)];
+    // Meshtastic reports 0 when the altitude is unknown
+    if position.altitude != 0 {
+        coordinates.push(json!(position.altitude));
+    }
+
+    let time = |t: u64| Timezone::Utc.format(t);
+    let reading = |metric: &str| node.readings.get(metric);
+
+    // Alerts on the node's water level, most severe first; a sensor fault
+    // notice says nothing about the river
+    let stage_alerts: Vec<_> = store
+        .active_alerts()
+        .into_iter()
+        .filter(|a| a.node == node.id && a.metric == "water_level")
+        .filter(|a| !Quality::FAULTS.iter().any(|q| q.rule() == a.rule))
+        .collect();
+    let stage = stage_alerts
+        .first()
+        .map_or_else(|| "normal".to_string(), |a| a.severity.to_string());
+
+    Some(json!({
+        "type": "Feature",
+        "id": node.id,
+        "geometry": {
+            "type": "Point",
+            "coordinates": coordinates,
+        },
+        "properties": {
+            "node": node.id,
+            "name": node.name,
+            "gauge": node.gauge,
+            "water_level": reading("water_level").map(|s| s.value),
+            "water_level_time": reading("water_level").map(|s| time(s.timestamp)),
+            "water_level_quality": reading("water_level").map(|s| s.quality),
+            "stage": stage,
+            "stage_alerts": stage_alerts.iter().map(|a| &a.rule).collect::<Vec<_>>(),
+            "battery_level": reading("battery_level").map(|s| s.value),
+            "voltage": reading("voltage").map(|s| s.value),
+            "last_heard": node.last_heard.map(time),
+            "position_time": time(position.timestamp),
+        },
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::alerts::{AlertEvent, AlertState};
+    use crate::config::NodeId;
+    use crate::config::{Config, Severity};
+    use crate::handler::{DecodedMessage, NodeInfo};
+    use crate::radio_message::{AppMessage, Position, Telemetry};
+
+    fn message(node_id: u32, timestamp: u64, app: AppMessage) -> DecodedMessage {
+        let readings = match &app {
+            AppMessage::Telemetry(_) => vec![("water_level", 4.1), ("battery_level", 76.0)],
+            _ => Vec::new(),
+        };
+        let name = if node_id == 5 { "bridge" } else { "!00000006" };
+        DecodedMessage::test(node_id, name, timestamp, app, readings)
+    }
+
+    fn position(latitude: f64, longitude: f64) -> Position {
+        Position {
+            latitude,
+            longitude,
+            altitude: 12,
+            accuracy: 0,
+            speed: 0.0,
+            heading: 0.0,
+        }
+    }
+
+    #[test]
+    fn one_point_per_located_node_with_stage() {
+        let config = Config::parse(
+            r#"
+            [[nodes]]
+            id = 5
+            alias = "bridge"
+            calibration = { offset = 6.0, scale = -0.001 }
+            "#,
+        )
+        .unwrap();
+        let mut store = Store::new(&config);
+        store.record_message(&message(
+            5,
+            100,
+            AppMessage::Position(position(51.5, -0.12)),
+        ));
+        // No GPS fix, so the earlier position stands
+        store.record_message(&message(5, 150, AppMessage::Position(position(0.0, 0.0))));
+        store.record_message(&message(
+            5,
+            200,
+            AppMessage::Telemetry(Telemetry::Device {
+                battery_level: None,
+                voltage: None,
+                uptime_seconds: None,
+            }),
+        ));
+        // Heard, but never sent a position
+        store.record_message(&message(
+            6,
+            200,
+            AppMessage::Telemetry(Telemetry::Device {
+                battery_level: None,
+                voltage: None,
+                uptime_seconds: None,
+            }),
+        ));
+        store.record_alert(&AlertEvent {
+            rule: "flood-stage".to_string(),
+            timestamp: 200,
+            node_id: 5,
+            node_name: "bridge".to_string(),
+            metric: "water_level".to_string(),
+            value: 4.1,
+            threshold: 4.0,
+            expected_at: None,
+            severity: Severity::Critical,
+            state: AlertState::Raised,
+        });
+
+        let collection = feature_collection(&store);
+        let features = collection["features"].as_array().unwrap();
+        assert_eq!(features.len(), 1);
+
+        let bridge = &features[0];
+        assert_eq!(bridge["geometry"]["coordinates"], json!([-0.12, 51.5, 12]));
+        let properties = &bridge["properties"];
+        assert_eq!(properties["name"], "bridge");
+        assert_eq!(properties["water_level"], 4.1);
+        assert_eq!(properties["stage"], "critical");
+        assert_eq!(properties["stage_alerts"], json!(["flood-stage"]));
+        assert_eq!(properties["battery_level"], 76.0);
+        assert_eq!(properties["last_heard"], "1970-01-01T00:03:20Z");
+    }
+
+    #[test]
+    fn falls_back_on_the_node_database_position() {
+        let mut store = Store::new(&Config::default());
+        store.record_message(&message(
+            5,
+            100,
+            AppMessage::Position(position(51.5, -0.12)),
+        ));
+        for node_id in [5, 6] {
+            store.record_node_info(&NodeInfo {
+                node_id,
+                node_name: NodeId(node_id).to_string(),
+                long_name: None,
+                position: Some((300, position(52.0, -1.0))),
+            });
+        }
+
+        let collection = feature_collection(&store);
+        let features = collection["features"].as_array().unwrap();
+        assert_eq!(features.len(), 2);
+        // A position the node sent beats the radio's record of it
+        assert_eq!(
+            features[0]["geometry"]["coordinates"],
+            json!([-0.12, 51.5, 12])
+        );
+        assert_eq!(
+            features[1]["geometry"]["coordinates"],
+            json!([-1.0, 52.0, 12])
+        );
+        assert_eq!(
+            features[1]["properties"]["position_time"],
+            "1970-01-01T00:05:00Z"
+        );
+        assert_eq!(features[1]["properties"]["last_heard"], Value::Null);
+    }
+}
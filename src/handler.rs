@@ -1,154 +1,354 @@
-use meshtastic::protobufs::{DeviceMetrics, FromRadio, PortNum, mesh_packet::PayloadVariant};
-use std::sync::atomic::{AtomicUsize, Ordering};
+use std::collections::HashSet;
 
-use crate::radio_message::{AppMessage, RadioMessage, Telemetry};
+use meshtastic::protobufs::{
+    self, FromRadio, MeshPacket, PortNum, from_radio::PayloadVariant, mesh_packet,
+};
 
-static TELEMETRY_COUNT: AtomicUsize = AtomicUsize::new(0);
-use meshtastic::Message;
+use crate::alerts::{AlertEngine, AlertEvent};
+use crate::battery::BatteryMonitor;
+use crate::config::Config;
+use crate::forecast::{ForecastMonitor, StageForecast};
+use crate::heartbeat::HeartbeatMonitor;
+use crate::metrics;
+use crate::nodes::NodeDirectory;
+use crate::quality::{Quality, QualityMonitor};
+use crate::radio_message::{AppMessage, DecodeError, Position, RadioMessage};
+use crate::rain::RainMonitor;
+use crate::reach::ReachMonitor;
 
-pub fn handle_from_radio(msg: FromRadio) {
-    let payload = &msg.payload_variant.clone();
-    match payload.as_ref().unwrap() {
-        meshtastic::protobufs::from_radio::PayloadVariant::Channel(channel) => {
-            log::info!("Received channel packet: {:?}", channel);
-            // You can handle channel-specific logic here
+/// A decoded mesh packet together with the config-derived context sinks
+/// need to present it. The link fields are those of the first copy heard;
+/// copies heard later with a better SNR reach sinks through
+/// [`Sink::on_better_copy`](crate::sinks::Sink::on_better_copy).
+#[derive(Debug, Clone)]
+pub struct DecodedMessage {
+    /// Connection the packet was first heard on
+    pub connection: String,
+    /// When the frame was received, in seconds since the Unix epoch
+    pub timestamp: u64,
+    /// When the radio received the packet, by the radio's clock; falls back
+    /// to `timestamp` when the radio has no time
+    pub packet_time: u64,
+    /// Channel index the packet was received on
+    pub channel: u32,
+    /// Mesh packet id, what a reply refers to
+    pub packet_id: u32,
+    /// Reception quality of the first copy heard; RSSI is 0 when unknown
+    pub rx_snr: f32,
+    pub rx_rssi: i32,
+    pub node_name: String,
+    pub message: RadioMessage,
+    /// Telemetry flattened to metrics, including calibrated `water_level`
+    pub readings: Vec<(&'static str, f64)>,
+    /// Quality codes of the readings that failed a data-quality check; the
+    /// others are good
+    pub quality: Vec<(&'static str, Quality)>,
+    /// Stage forecast when the message carried a good `water_level`
+    pub forecast: Option<StageForecast>,
+}
+
+impl DecodedMessage {
+    pub fn quality(&self, metric: &str) -> Quality {
+        self.quality
+            .iter()
+            .find(|(m, _)| *m == metric)
+            .map_or(Quality::Good, |&(_, q)| q)
+    }
+
+    /// A message heard from `node_id` at `timestamp` on channel 0, with
+    /// good readings, no link quality and no forecast.
+    #[cfg(test)]
+    pub fn test(
+        node_id: u32,
+        node_name: &str,
+        timestamp: u64,
+        app: AppMessage,
+        readings: Vec<(&'static str, f64)>,
+    ) -> Self {
+        Self {
+            connection: "radio".to_string(),
+            timestamp,
+            packet_time: timestamp,
+            channel: 0,
+            packet_id: 0,
+            rx_snr: 0.0,
+            rx_rssi: 0,
+            node_name: node_name.to_string(),
+            message: RadioMessage {
+                node_id,
+                portnum: app.portnum(),
+                app,
+            },
+            readings,
+            quality: Vec::new(),
+            forecast: None,
         }
-        meshtastic::protobufs::from_radio::PayloadVariant::NodeInfo(node_info) => {
-            //log::info!("Received node info packet: {:?}", node_info);
-            node_info.user.as_ref().map(|user| {
-                log::info!("Node User Info: {:?}", user);
-            });
-            node_info.device_metrics.as_ref().map(|dm| {
-                log::info!("Node Device Metrics: {:?}", dm);
-            });
-            node_info.position.as_ref().map(|pos| {
-                log::info!("Node Position: {:?}", pos);
-            });
-            // Handle node info logic here
+    }
+}
+
+/// A node from the radio's node database, as sent in the config dump
+/// when a session starts.
+#[derive(Debug, Clone)]
+pub struct NodeInfo {
+    pub node_id: u32,
+    pub node_name: String,
+    /// Name the node's owner set, if any
+    pub long_name: Option<String>,
+    /// Last position the radio knows, and when the node reported it
+    pub position: Option<(u64, Position)>,
+}
+
+/// What a single `FromRadio` frame produced.
+#[derive(Debug, Default)]
+pub struct Handled {
+    pub message: Option<DecodedMessage>,
+    pub node_info: Option<NodeInfo>,
+    pub alerts: Vec<AlertEvent>,
+}
+
+/// Decodes `FromRadio` frames and evaluates alert rules, using the node
+/// aliases, calibrations and alert rules from the config.
+pub struct Handler {
+    nodes: NodeDirectory,
+    alerts: AlertEngine,
+    heartbeat: HeartbeatMonitor,
+    battery: BatteryMonitor,
+    quality: QualityMonitor,
+    reach: ReachMonitor,
+    forecast: ForecastMonitor,
+    rain: RainMonitor,
+}
+
+impl Handler {
+    pub fn new(config: &Config) -> Self {
+        Self {
+            nodes: NodeDirectory::from_config(config),
+            alerts: AlertEngine::from_config(config),
+            heartbeat: HeartbeatMonitor::from_config(config),
+            battery: BatteryMonitor::from_config(config),
+            quality: QualityMonitor::from_config(config),
+            reach: ReachMonitor::from_config(config),
+            forecast: ForecastMonitor::from_config(config),
+            rain: RainMonitor::from_config(config),
         }
-        meshtastic::protobufs::from_radio::PayloadVariant::Packet(mesh_packet) => {
-            log::info!("Received mesh packet: {:?}", mesh_packet);
-
-            if let Some(pv) = &mesh_packet.payload_variant {
-                match pv {
-                    meshtastic::protobufs::mesh_packet::PayloadVariant::Decoded(data) => {
-                        let port = meshtastic::protobufs::PortNum::from_i32(data.portnum);
-                        use meshtastic::protobufs::{
-                            DeviceMetrics, EnvironmentMetrics, PowerMetrics,
-                        };
-
-                        if let Some(meshtastic::protobufs::PortNum::TelemetryApp) = port {
-                            // Try decoding as DeviceMetrics
-                            let decoded = DeviceMetrics::decode(&data.payload[..])
-                                .map(|dm| {
-                                    log::info!("Decoded Device Metrics → {:?}", dm);
-                                    //dm
-                                })
-                                .or_else(|_| {
-                                    EnvironmentMetrics::decode(&data.payload[..]).map(|em| {
-                                        log::info!("Decoded Environment Metrics → {:?}", em);
-                                        //em
-                                    })
-                                })
-                                .or_else(|_| {
-                                    PowerMetrics::decode(&data.payload[..]).map(|pm| {
-                                        log::info!("Decoded Power Metrics → {:?}", pm);
-                                        //pm
-                                    })
-                                });
-
-                            if decoded.is_err() {
-                                log::trace!(
-                                    "Telemetry payload could not be decoded, storing raw: {}",
-                                    data.payload
-                                        .iter()
-                                        .map(|b| format!("{:02X}", b))
-                                        .collect::<Vec<_>>()
-                                        .join(" ")
-                                );
-                            }
-                        }
-                    }
-                    _ => log::trace!("Unhandled mesh packet payload variant"),
-                }
+    }
+
+    /// Applies node aliases, calibrations and alert rules from a reloaded
+    /// config without dropping the state of alerts that are still defined.
+    pub fn reload(&mut self, config: &Config) {
+        self.nodes.reconfigure(config);
+        self.alerts.replace_rules(config);
+        self.heartbeat.reconfigure(config);
+        self.battery.reconfigure(config);
+        self.quality.reconfigure(config);
+        self.reach.reconfigure(config);
+        self.forecast.reconfigure(config);
+        self.rain.reconfigure(config);
+    }
+
+    /// See [`AlertEngine::restore`] and the `restore` of each monitor.
+    pub fn restore_alerts(&mut self, active: impl IntoIterator<Item = (String, u32)>) {
+        let active: HashSet<_> = active.into_iter().collect();
+        self.heartbeat.restore(&active);
+        self.battery.restore(&active);
+        self.quality.restore(&active);
+        self.reach.restore(&active);
+        self.forecast.restore(&active);
+        self.alerts.restore(active);
+    }
+
+    /// Alerts due by `now` without a reading: heartbeat alerts for nodes
+    /// silent too long, crest forecasts that have run out, and rain rules
+    /// on totals that rain has since dropped out of.
+    pub fn check(&mut self, now: u64) -> Vec<AlertEvent> {
+        let mut events = self.heartbeat.check(now, &self.nodes);
+        events.extend(self.reach.check(now, &self.nodes));
+        let nodes = &self.nodes;
+        for (node_id, totals) in self.rain.check(now, |id| nodes.display_name(id)) {
+            let name = nodes.display_name(node_id);
+            for (metric, value) in totals {
+                events.extend(self.alerts.evaluate(now, node_id, &name, metric, value));
             }
         }
-        _ => {
-            log::trace!("Unhandled FromRadio payload variant");
-        }
+        events
     }
 
-    /*
-    match RadioMessage::try_from(&msg) {
-        Ok(rm) => match &rm.app {
-            AppMessage::Telemetry(tel) => {
-                // Increment global telemetry counter
-                let count = TELEMETRY_COUNT.fetch_add(1, Ordering::SeqCst) + 1;
-
-                // Pattern match on the specific telemetry variant
-                match tel {
-                    Telemetry::Device {
-                        battery_level,
-                        voltage,
-                        uptime_seconds,
-                    } => {
-                        log::info!(
-                            "Node {} Device Telemetry #{} → Voltage: {:?} V, Battery: {:?} %, Uptime: {:?} s",
-                            rm.node_id,
-                            count,
-                            voltage,
-                            battery_level,
-                            uptime_seconds
-                        );
-                    }
-                    Telemetry::Environment {
-                        temperature,
-                        humidity,
-                        pressure,
-                    } => {
-                        log::info!(
-                            "Node {} Environment Telemetry #{} → Temp: {:?} °C, Humidity: {:?} %, Pressure: {:?} hPa",
-                            rm.node_id,
-                            count,
-                            temperature,
-                            humidity,
-                            pressure
-                        );
+    pub fn handle_from_radio(
+        &mut self,
+        connection: &str,
+        timestamp: u64,
+        msg: &FromRadio,
+    ) -> Handled {
+        match &msg.payload_variant {
+            Some(PayloadVariant::Channel(channel)) => {
+                log::info!("Received channel packet: {:?}", channel);
+            }
+            Some(PayloadVariant::NodeInfo(node_info)) => {
+                log::debug!("Received node info: {:?}", node_info);
+                return Handled {
+                    node_info: Some(self.handle_node_info(timestamp, node_info)),
+                    ..Handled::default()
+                };
+            }
+            Some(PayloadVariant::Packet(mesh_packet)) => {
+                log::debug!("Received mesh packet: {:?}", mesh_packet);
+                let port = port_name(mesh_packet);
+                metrics::PACKETS.inc(port);
+                // Any packet shows the node is alive, even one we can't decode
+                let mut alerts = self.heartbeat.heard(
+                    timestamp,
+                    mesh_packet.from,
+                    &self.nodes.display_name(mesh_packet.from),
+                    port == "TELEMETRY_APP",
+                );
+                let mut handled = match RadioMessage::try_from(msg) {
+                    Ok(rm) => self.handle_radio_message(connection, timestamp, mesh_packet, rm),
+                    // Already counted by port; not decoding it is no error
+                    Err(DecodeError::UnsupportedPort(port)) => {
+                        log::trace!("Ignoring packet on unsupported port {:?}", port);
+                        Handled::default()
                     }
-                    Telemetry::Power { voltage, current } => {
-                        log::info!(
-                            "Node {} Power Telemetry #{} → Voltage: {:?} V, Current: {:?} A",
-                            rm.node_id,
-                            count,
-                            voltage,
-                            current
+                    Err(e) => {
+                        metrics::decode_error(&e);
+                        log::trace!(
+                            "Failed to parse mesh packet from node {}: {:?}",
+                            self.nodes.display_name(mesh_packet.from),
+                            e
                         );
+                        Handled::default()
                     }
-                }
+                };
+                alerts.append(&mut handled.alerts);
+                handled.alerts = alerts;
+                return handled;
             }
-            AppMessage::Position(pos) => {
-                log::info!(
-                    "Node {} Position → Lat: {:.7}, Lon: {:.7}, Alt: {} m, Accuracy: {} m",
-                    rm.node_id,
-                    pos.latitude,
-                    pos.longitude,
-                    pos.altitude,
-                    pos.accuracy
-                );
+            _ => {
+                log::trace!("Unhandled FromRadio payload variant");
+            }
+        }
+
+        Handled::default()
+    }
+
+    fn handle_node_info(&mut self, timestamp: u64, node_info: &protobufs::NodeInfo) -> NodeInfo {
+        let long_name = node_info
+            .user
+            .as_ref()
+            .map(|user| user.long_name.clone())
+            .filter(|name| !name.is_empty());
+        if let Some(name) = &long_name {
+            self.nodes.learn_name(node_info.num, name);
+        }
+        // Without a time of its own, the position is as old as the last
+        // time the radio heard the node, or else the frame
+        let position = node_info.position.map(|pos| {
+            let reported = [pos.time, node_info.last_heard]
+                .into_iter()
+                .find(|&t| t != 0)
+                .map_or(timestamp, u64::from);
+            (reported, Position::from(pos))
+        });
+        NodeInfo {
+            node_id: node_info.num,
+            node_name: self.nodes.display_name(node_info.num),
+            long_name,
+            position,
+        }
+    }
+
+    fn handle_radio_message(
+        &mut self,
+        connection: &str,
+        timestamp: u64,
+        packet: &MeshPacket,
+        rm: RadioMessage,
+    ) -> Handled {
+        let node_name = self.nodes.display_name(rm.node_id);
+        log::trace!(
+            "Decoded {:?} message from node {} via {}",
+            rm.portnum,
+            node_name,
+            connection
+        );
+
+        let mut readings = match &rm.app {
+            AppMessage::Telemetry(tel) => self.nodes.readings(rm.node_id, tel),
+            AppMessage::Position(_)
+            | AppMessage::Text(_)
+            | AppMessage::Detection(_)
+            | AppMessage::Serial(_) => Vec::new(),
+        };
+
+        let (quality, mut alerts) = self
+            .quality
+            .assess(timestamp, rm.node_id, &node_name, &readings);
+        alerts.extend(
+            self.battery
+                .observe(timestamp, rm.node_id, &node_name, &mut readings),
+        );
+        self.rain.observe(
+            timestamp,
+            rm.node_id,
+            &node_name,
+            &rm.app,
+            &quality,
+            &mut readings,
+        );
+        // Flagged readings must not raise flood alerts
+        let mut forecast = None;
+        for (metric, value) in &readings {
+            if quality.iter().any(|(m, _)| m == metric) {
+                continue;
             }
-            AppMessage::Text(text) => {
-                log::info!(
-                    "Node {} Text → From: {:?}, To: {:?}, Msg: {}",
-                    rm.node_id,
-                    text.from,
-                    text.to,
-                    text.msg
+            alerts.extend(
+                self.alerts
+                    .evaluate(timestamp, rm.node_id, &node_name, metric, *value),
+            );
+            if *metric == "water_level" {
+                alerts.extend(
+                    self.reach
+                        .observe(timestamp, rm.node_id, *value, &self.nodes),
                 );
+                let (stage, events) =
+                    self.forecast
+                        .observe(timestamp, rm.node_id, *value, &self.nodes);
+                forecast = stage;
+                alerts.extend(events);
             }
-        },
-        Err(e) => {
-            log::trace!("Failed to parse FromRadio message from node {}: {:?}", msg.id, e);
         }
+
+        Handled {
+            message: Some(DecodedMessage {
+                connection: connection.to_string(),
+                timestamp,
+                packet_time: match packet.rx_time {
+                    0 => timestamp,
+                    rx_time => rx_time.into(),
+                },
+                channel: packet.channel,
+                packet_id: packet.id,
+                rx_snr: packet.rx_snr,
+                rx_rssi: packet.rx_rssi,
+                node_name,
+                message: rm,
+                readings,
+                quality,
+                forecast,
+            }),
+            node_info: None,
+            alerts,
+        }
+    }
+}
+
+/// Port label for the packet counter; payloads the radio could not decrypt
+/// have no port.
+fn port_name(packet: &MeshPacket) -> &'static str {
+    match &packet.payload_variant {
+        Some(mesh_packet::PayloadVariant::Decoded(data)) => PortNum::try_from(data.portnum)
+            .map(|p| p.as_str_name())
+            .unwrap_or("UNKNOWN"),
+        Some(mesh_packet::PayloadVariant::Encrypted(_)) => "ENCRYPTED",
+        None => "EMPTY",
     }
-    */
 }
@@ -0,0 +1,317 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+
+use crate::alerts::{AlertEvent, AlertState};
+use crate::config::{Config, HeartbeatConfig};
+use crate::nodes::NodeDirectory;
+
+/// Alert raised when a node misses `stale_after_intervals` reports.
+pub const STALE_RULE: &str = "node-stale";
+/// Alert raised when a node misses `lost_after_intervals` reports.
+pub const LOST_RULE: &str = "node-lost";
+/// What the heartbeat alerts measure: seconds since the node was last heard.
+pub const SILENCE_METRIC: &str = "silence_secs";
+
+/// Telemetry gaps remembered per node to learn its reporting interval.
+const LEARNED_GAPS: usize = 16;
+
+/// Gaps shorter than this are one burst, e.g. device and environment
+/// telemetry sent back to back, not separate reports.
+const MIN_GAP_SECS: u64 = 60;
+
+#[derive(Debug, Default)]
+struct Heartbeat {
+    last_heard: Option<u64>,
+    last_telemetry: Option<u64>,
+    /// Latest gaps between telemetry reports, oldest first
+    gaps: VecDeque<u64>,
+    stale: bool,
+    lost: bool,
+}
+
+impl Heartbeat {
+    /// The median gap, once enough have been seen.
+    fn learned_interval(&self, min_samples: usize) -> Option<u64> {
+        if self.gaps.len() < min_samples {
+            return None;
+        }
+        let mut gaps: Vec<u64> = self.gaps.iter().copied().collect();
+        gaps.sort_unstable();
+        Some(gaps[gaps.len() / 2])
+    }
+}
+
+/// Notices nodes that stop reporting. Any packet counts as hearing from a
+/// node; telemetry reports also teach it how often the node reports, unless
+/// `report_interval_secs` is configured. A node silent for
+/// `stale_after_intervals` of those raises `node-stale`, and for
+/// `lost_after_intervals` `node-lost`; both clear once it is heard again.
+pub struct HeartbeatMonitor {
+    config: HeartbeatConfig,
+    /// Configured nodes, with their `report_interval_secs` if set
+    watched: HashMap<u32, Option<u64>>,
+    nodes: HashMap<u32, Heartbeat>,
+}
+
+impl HeartbeatMonitor {
+    pub fn from_config(config: &Config) -> Self {
+        let mut monitor = Self {
+            config: config.heartbeat.clone(),
+            watched: HashMap::new(),
+            nodes: HashMap::new(),
+        };
+        monitor.reconfigure(config);
+        monitor
+    }
+
+    /// Takes settings from a reloaded config, keeping what has been
+    /// learned and which alerts are raised.
+    pub fn reconfigure(&mut self, config: &Config) {
+        self.config = config.heartbeat.clone();
+        self.watched = config
+            .nodes
+            .iter()
+            .map(|n| (n.id.0, n.report_interval_secs))
+            .collect();
+    }
+
+    /// Marks heartbeat alerts raised before a restart as active, so they
+    /// clear when the node is heard again.
+    pub fn restore(&mut self, active: &HashSet<(String, u32)>) {
+        for (rule, node_id) in active {
+            let node = self.nodes.entry(*node_id).or_default();
+            match rule.as_str() {
+                STALE_RULE => node.stale = true,
+                LOST_RULE => node.lost = true,
+                _ => {}
+            }
+        }
+    }
+
+    /// Records a packet from `node_id`, clearing its heartbeat alerts.
+    pub fn heard(
+        &mut self,
+        timestamp: u64,
+        node_id: u32,
+        node_name: &str,
+        telemetry: bool,
+    ) -> Vec<AlertEvent> {
+        let node = self.nodes.entry(node_id).or_default();
+        let silence = node
+            .last_heard
+            .map_or(0, |last| timestamp.saturating_sub(last));
+        node.last_heard = Some(node.last_heard.map_or(timestamp, |t| t.max(timestamp)));
+
+        if telemetry {
+            match node.last_telemetry {
+                Some(last) if timestamp < last + MIN_GAP_SECS => {}
+                Some(last) => {
+                    if node.gaps.len() == LEARNED_GAPS {
+                        node.gaps.pop_front();
+                    }
+                    node.gaps.push_back(timestamp - last);
+                    node.last_telemetry = Some(timestamp);
+                }
+                None => node.last_telemetry = Some(timestamp),
+            }
+        }
+
+        let cleared = [
+            (STALE_RULE, std::mem::take(&mut node.stale)),
+            (LOST_RULE, std::mem::take(&mut node.lost)),
+        ];
+        if cleared.iter().any(|(_, was)| *was) {
+            log::info!("Heard from {} again after {} s", node_name, silence);
+        }
+        let thresholds = self.thresholds(node_id);
+        cleared
+            .into_iter()
+            .filter(|(_, was)| *was)
+            .map(|(rule, _)| {
+                let threshold = thresholds.map_or(0, |(stale, lost)| match rule {
+                    STALE_RULE => stale,
+                    _ => lost,
+                });
+                AlertEvent {
+                    value: silence as f64,
+                    threshold: threshold as f64,
+                    ..self.event(rule, AlertState::Cleared, timestamp, node_id, node_name)
+                }
+            })
+            .collect()
+    }
+
+    /// Raises alerts for nodes that have been silent too long by `now`.
+    pub fn check(&mut self, now: u64, directory: &NodeDirectory) -> Vec<AlertEvent> {
+        if !self.config.enabled {
+            return Vec::new();
+        }
+        let mut due = Vec::new();
+        for (&node_id, node) in &self.nodes {
+            let (Some(last_heard), Some((stale_after, lost_after))) =
+                (node.last_heard, self.thresholds(node_id))
+            else {
+                continue;
+            };
+            let silence = now.saturating_sub(last_heard);
+            if !node.stale && silence >= stale_after {
+                due.push((node_id, STALE_RULE, silence, stale_after));
+            }
+            if !node.lost && silence >= lost_after {
+                due.push((node_id, LOST_RULE, silence, lost_after));
+            }
+        }
+        // Stale before lost when a node skips straight past both
+        due.sort_unstable_by_key(|&(node_id, rule, ..)| (node_id, rule == LOST_RULE));
+
+        let mut events = Vec::new();
+        for (node_id, rule, silence, threshold) in due {
+            let node = self.nodes.get_mut(&node_id).expect("collected above");
+            match rule {
+                STALE_RULE => node.stale = true,
+                _ => node.lost = true,
+            }
+            let name = directory.display_name(node_id);
+            events.push(AlertEvent {
+                value: silence as f64,
+                threshold: threshold as f64,
+                ..self.event(rule, AlertState::Raised, now, node_id, &name)
+            });
+        }
+        events
+    }
+
+    /// Seconds of silence after which a watched node is stale and lost, from
+    /// its configured or learned reporting interval.
+    fn thresholds(&self, node_id: u32) -> Option<(u64, u64)> {
+        let configured = match self.watched.get(&node_id) {
+            Some(configured) => *configured,
+            None if self.config.all_nodes => None,
+            None => return None,
+        };
+        let interval = configured.or_else(|| {
+            self.nodes
+                .get(&node_id)?
+                .learned_interval(self.config.min_samples)
+        })? as f64;
+        Some((
+            (interval * self.config.stale_after_intervals).round() as u64,
+            (interval * self.config.lost_after_intervals).round() as u64,
+        ))
+    }
+
+    fn event(
+        &self,
+        rule: &str,
+        state: AlertState,
+        timestamp: u64,
+        node_id: u32,
+        node_name: &str,
+    ) -> AlertEvent {
+        AlertEvent {
+            rule: rule.to_string(),
+            timestamp,
+            node_id,
+            node_name: node_name.to_string(),
+            metric: SILENCE_METRIC.to_string(),
+            value: 0.0,
+            threshold: 0.0,
+            expected_at: None,
+            severity: match rule {
+                STALE_RULE => self.config.stale_severity,
+                _ => self.config.lost_severity,
+            },
+            state,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn monitor(toml: &str) -> (HeartbeatMonitor, NodeDirectory) {
+        let config = Config::parse(toml).unwrap();
+        (
+            HeartbeatMonitor::from_config(&config),
+            NodeDirectory::from_config(&config),
+        )
+    }
+
+    fn states(events: &[AlertEvent]) -> Vec<(&str, AlertState)> {
+        events.iter().map(|e| (e.rule.as_str(), e.state)).collect()
+    }
+
+    #[test]
+    fn learns_interval_then_raises_stale_lost_and_clears() {
+        let (mut monitor, nodes) = monitor(
+            r#"
+            [heartbeat]
+            enabled = true
+            stale_after_intervals = 2
+            lost_after_intervals = 4
+            min_samples = 3
+
+            [[nodes]]
+            id = 5
+            alias = "bridge"
+
+            [[nodes]]
+            id = 6
+            alias = "ford"
+            report_interval_secs = 1000
+            "#,
+        );
+        // Every 600 s, with a second packet of each burst ignored
+        for t in [0, 600, 630, 1_200, 1_800] {
+            assert!(monitor.heard(t, 5, "bridge", true).is_empty());
+        }
+        // A handset nobody configured
+        monitor.heard(0, 9, "!00000009", true);
+        monitor.heard(1_800, 6, "ford", false);
+
+        assert!(monitor.check(2_900, &nodes).is_empty());
+        let stale = monitor.check(3_000, &nodes);
+        assert_eq!(states(&stale), [(STALE_RULE, AlertState::Raised)]);
+        assert_eq!((stale[0].value, stale[0].threshold), (1_200.0, 1_200.0));
+        assert!(monitor.check(3_100, &nodes).is_empty());
+
+        // The ford's configured interval, skipping straight past both
+        let events = monitor.check(10_000, &nodes);
+        assert_eq!(
+            events
+                .iter()
+                .map(|e| (e.node_name.as_str(), e.rule.as_str()))
+                .collect::<Vec<_>>(),
+            [
+                ("bridge", LOST_RULE),
+                ("ford", STALE_RULE),
+                ("ford", LOST_RULE)
+            ]
+        );
+
+        // Any packet clears both
+        let cleared = monitor.heard(10_050, 5, "bridge", false);
+        assert_eq!(
+            states(&cleared),
+            [
+                (STALE_RULE, AlertState::Cleared),
+                (LOST_RULE, AlertState::Cleared)
+            ]
+        );
+        assert_eq!(cleared[0].value, 8_250.0);
+        assert!(monitor.check(10_100, &nodes).is_empty());
+    }
+
+    #[test]
+    fn clears_alerts_restored_after_a_restart() {
+        let (mut monitor, _) = monitor("[heartbeat]\nenabled = true");
+        monitor.restore(&HashSet::from([
+            (LOST_RULE.to_string(), 5),
+            ("flood".to_string(), 5),
+        ]));
+        assert_eq!(
+            states(&monitor.heard(100, 5, "bridge", true)),
+            [(LOST_RULE, AlertState::Cleared)]
+        );
+    }
+}
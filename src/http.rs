@@ -0,0 +1,604 @@
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::io::{self, Write};
+use std::sync::{Arc, Mutex};
+
+use axum::extract::{FromRef, Path, Query, State};
+use axum::http::{StatusCode, header};
+use axum::response::sse::{Event, KeepAlive, Sse};
+use axum::response::{IntoResponse, Response};
+use axum::routing::{get, post};
+use axum::{Json, Router};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tokio::net::TcpListener;
+use tokio::task::JoinHandle;
+use tokio_stream::wrappers::BroadcastStream;
+use tokio_stream::wrappers::errors::BroadcastStreamRecvError;
+use tokio_stream::{Stream, StreamExt};
+
+use crate::config::{Config, NodeId};
+use crate::export::{self, ExportFormat, TableFormat};
+use crate::feed::{self, FeedFilter, FeedSender};
+use crate::forecast::StageForecast;
+use crate::store::{Sample, SharedStore, Store};
+use crate::table::{self, TableBuilder, TableOptions};
+use crate::{dashboard, frame, geojson, metrics};
+
+/// Everything the handlers read, shared with the sinks that fill it.
+#[derive(Clone)]
+pub struct ApiState {
+    pub store: SharedStore,
+    pub feed: FeedSender,
+}
+
+impl ApiState {
+    pub fn new(config: &Config) -> Self {
+        Self {
+            store: Store::shared(config),
+            feed: feed::channel(),
+        }
+    }
+}
+
+impl FromRef<ApiState> for SharedStore {
+    fn from_ref(state: &ApiState) -> Self {
+        state.store.clone()
+    }
+}
+
+impl FromRef<ApiState> for FeedSender {
+    fn from_ref(state: &ApiState) -> Self {
+        state.feed.clone()
+    }
+}
+
+/// Binds `listen` straight away, so a bad address fails at startup, then
+/// serves the API in the background.
+pub async fn spawn(listen: &str, state: ApiState) -> io::Result<JoinHandle<()>> {
+    let listener = TcpListener::bind(listen).await?;
+    log::info!("HTTP API listening on http://{}", listener.local_addr()?);
+
+    Ok(tokio::spawn(async move {
+        if let Err(e) = axum::serve(listener, router(state)).await {
+            log::error!("HTTP server stopped: {}", e);
+        }
+    }))
+}
+
+pub fn router(state: ApiState) -> Router {
+    Router::new()
+        .route("/", get(dashboard::index))
+        .route("/dashboard.js", get(dashboard::script))
+        .route("/dashboard.css", get(dashboard::style))
+        .route("/api/nodes", get(list_nodes))
+        .route("/api/nodes/{node}", get(get_node))
+        .route("/api/nodes/{node}/history", get(node_history))
+        .route("/api/nodes.geojson", get(nodes_geojson))
+        .route("/api/gauges", get(list_gauges))
+        .route("/api/alerts", get(list_alerts))
+        .route("/api/alerts/audit", get(alert_audit))
+        .route("/api/alerts/{id}/ack", post(acknowledge_alert))
+        .route("/api/events", get(events))
+        .route("/api/export", get(export_table))
+        .route("/metrics", get(prometheus_metrics))
+        .with_state(state)
+}
+
+/// A JSON `{"error": ...}` body with a status code.
+struct ApiError(StatusCode, String);
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        (self.0, Json(json!({ "error": self.1 }))).into_response()
+    }
+}
+
+fn unknown_node(node: &str) -> ApiError {
+    ApiError(StatusCode::NOT_FOUND, format!("unknown node `{}`", node))
+}
+
+/* ---------------- Handlers ---------------- */
+
+async fn list_nodes(State(store): State<SharedStore>) -> Response {
+    let store = store.read().unwrap();
+    Json(store.nodes().collect::<Vec<_>>()).into_response()
+}
+
+async fn get_node(
+    State(store): State<SharedStore>,
+    Path(node): Path<String>,
+) -> Result<Response, ApiError> {
+    let store = store.read().unwrap();
+    let status = store.find_node(&node).ok_or_else(|| unknown_node(&node))?;
+    Ok(Json(status).into_response())
+}
+
+/// Latest position and status of every located node, for Leaflet or QGIS.
+async fn nodes_geojson(State(store): State<SharedStore>) -> Response {
+    let collection = geojson::feature_collection(&store.read().unwrap());
+    (
+        [(header::CONTENT_TYPE, "application/geo+json")],
+        collection.to_string(),
+    )
+        .into_response()
+}
+
+#[derive(Serialize)]
+struct GaugeReading {
+    node: NodeId,
+    name: String,
+    water_level: Option<Sample>,
+    battery_level: Option<Sample>,
+    last_heard: Option<u64>,
+    /// Projected stage and when it is expected to breach each flood rule
+    forecast: Option<StageForecast>,
+}
+
+/// Latest water level and stage forecast of every calibrated node.
+async fn list_gauges(State(store): State<SharedStore>) -> Response {
+    let store = store.read().unwrap();
+    let gauges: Vec<GaugeReading> = store
+        .nodes()
+        .filter(|n| n.gauge)
+        .map(|n| GaugeReading {
+            node: n.id,
+            name: n.name.clone(),
+            water_level: n.readings.get("water_level").copied(),
+            battery_level: n.readings.get("battery_level").copied(),
+            last_heard: n.last_heard,
+            forecast: n.forecast.clone(),
+        })
+        .collect();
+    Json(gauges).into_response()
+}
+
+async fn list_alerts(State(store): State<SharedStore>) -> Response {
+    let store = store.read().unwrap();
+    Json(store.active_alerts()).into_response()
+}
+
+#[derive(Deserialize)]
+struct AckRequest {
+    /// Who is acknowledging, as recorded in the audit log
+    by: String,
+}
+
+/// Acknowledges an active alert, which stops it escalating.
+async fn acknowledge_alert(
+    State(store): State<SharedStore>,
+    Path(id): Path<u32>,
+    Json(request): Json<AckRequest>,
+) -> Result<Response, ApiError> {
+    let by = request.by.trim();
+    if by.is_empty() {
+        return Err(ApiError(
+            StatusCode::BAD_REQUEST,
+            "`by` must name who acknowledges".to_string(),
+        ));
+    }
+    let mut store = store.write().unwrap();
+    let alert = store
+        .alert(id)
+        .ok_or_else(|| ApiError(StatusCode::NOT_FOUND, format!("no active alert #{}", id)))?;
+    if let Some(ack) = &alert.acknowledged {
+        return Err(ApiError(
+            StatusCode::CONFLICT,
+            format!("alert #{} was already acknowledged by {}", id, ack.by),
+        ));
+    }
+    store.acknowledge(id, by, frame::now_secs());
+    Ok(Json(store.alert(id)).into_response())
+}
+
+/// Recent raises, acknowledgements, escalations, resolutions and mutes,
+/// oldest first.
+async fn alert_audit(State(store): State<SharedStore>) -> Response {
+    let store = store.read().unwrap();
+    Json(store.audit().collect::<Vec<_>>()).into_response()
+}
+
+#[derive(Deserialize)]
+struct HistoryQuery {
+    #[serde(default = "default_metric")]
+    metric: String,
+    /// Unix seconds, inclusive
+    from: Option<u64>,
+    /// Unix seconds, inclusive
+    to: Option<u64>,
+}
+
+fn default_metric() -> String {
+    "water_level".to_string()
+}
+
+#[derive(Serialize)]
+struct History {
+    node: NodeId,
+    name: String,
+    metric: String,
+    samples: Vec<Sample>,
+}
+
+async fn node_history(
+    State(store): State<SharedStore>,
+    Path(node): Path<String>,
+    Query(query): Query<HistoryQuery>,
+) -> Result<Response, ApiError> {
+    if let (Some(from), Some(to)) = (query.from, query.to)
+        && from > to
+    {
+        return Err(ApiError(
+            StatusCode::BAD_REQUEST,
+            "`from` is after `to`".to_string(),
+        ));
+    }
+
+    let store = store.read().unwrap();
+    let status = store.find_node(&node).ok_or_else(|| unknown_node(&node))?;
+    let samples = store.history(status.id.0, &query.metric, query.from, query.to);
+
+    Ok(Json(History {
+        node: status.id,
+        name: status.name.clone(),
+        metric: query.metric,
+        samples,
+    })
+    .into_response())
+}
+
+#[derive(Deserialize)]
+struct ExportQuery {
+    #[serde(default = "default_export_format")]
+    format: String,
+    columns: Option<String>,
+    interval: Option<String>,
+    timezone: Option<String>,
+    from: Option<u64>,
+    to: Option<u64>,
+}
+
+fn default_export_format() -> String {
+    "csv".to_string()
+}
+
+/// Collects a table in memory; the writers need an owned `Write`.
+#[derive(Clone, Default)]
+struct Buffer(Arc<Mutex<Vec<u8>>>);
+
+impl Write for Buffer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// The retained history of every node as a CSV or Parquet table, with
+/// the same options as the `export` command.
+async fn export_table(
+    State(store): State<SharedStore>,
+    Query(query): Query<ExportQuery>,
+) -> Result<Response, ApiError> {
+    let bad_request = |e: String| ApiError(StatusCode::BAD_REQUEST, e);
+    let ExportFormat::Table(format) = query.format.parse().map_err(bad_request)? else {
+        return Err(bad_request("expected csv or parquet".to_string()));
+    };
+    let content_type = match format {
+        TableFormat::Csv => "text/csv; charset=utf-8",
+        TableFormat::Parquet => "application/vnd.apache.parquet",
+    };
+    let mut options = TableOptions::default();
+    if let Some(columns) = &query.columns {
+        options.columns = table::parse_columns(columns).map_err(bad_request)?;
+    }
+    if let Some(interval) = &query.interval {
+        options.interval = Some(table::parse_interval(interval).map_err(bad_request)?);
+    }
+    if let Some(timezone) = &query.timezone {
+        options.timezone = timezone.parse().map_err(bad_request)?;
+    }
+
+    let buffer = Buffer::default();
+    let internal = |e: io::Error| ApiError(StatusCode::INTERNAL_SERVER_ERROR, e.to_string());
+    let writer = export::table_writer(format, buffer.clone(), &options).map_err(internal)?;
+    let mut table = TableBuilder::new(options, writer);
+    table::push_store(&mut table, &store.read().unwrap(), query.from, query.to)
+        .map_err(internal)?;
+    table.finish().map_err(internal)?;
+
+    let body = std::mem::take(&mut *buffer.0.lock().unwrap());
+    Ok(([(header::CONTENT_TYPE, content_type)], body).into_response())
+}
+
+async fn prometheus_metrics(State(store): State<SharedStore>) -> Response {
+    let text = metrics::render(&store.read().unwrap(), frame::now_secs());
+    ([(header::CONTENT_TYPE, "text/plain; version=0.0.4")], text).into_response()
+}
+
+/// Server-sent events: every decoded message and alert as it happens, and
+/// better copies of messages, narrowed by `?node=`, `?port=` and
+/// `?type=message|alert|link`.
+async fn events(
+    State(feed): State<FeedSender>,
+    Query(query): Query<HashMap<String, String>>,
+) -> Result<Sse<impl Stream<Item = Result<Event, Infallible>>>, ApiError> {
+    let filter =
+        FeedFilter::from_query(&query).map_err(|e| ApiError(StatusCode::BAD_REQUEST, e))?;
+
+    let stream = BroadcastStream::new(feed.subscribe()).filter_map(move |event| match event {
+        Ok(event) if filter.matches(&event) => Some(Ok(Event::default()
+            .event(match event.kind {
+                feed::EventKind::Message => "message",
+                feed::EventKind::Alert => "alert",
+                feed::EventKind::Link => "link",
+            })
+            .data(event.json.as_str()))),
+        Ok(_) => None,
+        // The client fell behind the buffer; skip ahead rather than stall the feed
+        Err(BroadcastStreamRecvError::Lagged(skipped)) => {
+            log::debug!("Live feed client lagged, skipped {} events", skipped);
+            Some(Ok(Event::default()
+                .event("lagged")
+                .data(json!({ "skipped": skipped }).to_string())))
+        }
+    });
+
+    Ok(Sse::new(stream).keep_alive(KeepAlive::default()))
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::body::{self, Body};
+    use axum::http::Request;
+    use std::sync::Arc;
+
+    use serde_json::Value;
+    use tower::ServiceExt;
+
+    use super::*;
+    use crate::alerts::{AlertEvent, AlertState};
+    use crate::config::ForecastModel;
+    use crate::config::{Config, Severity};
+    use crate::feed::FeedEvent;
+    use crate::forecast::Crossing;
+    use crate::handler::DecodedMessage;
+    use crate::radio_message::{AppMessage, Telemetry};
+    use crate::store::AuditAction;
+
+    fn state() -> ApiState {
+        let config = Config::parse(
+            r#"
+            [[nodes]]
+            id = 5
+            alias = "bridge"
+            calibration = { offset = 6.0, scale = -0.001 }
+            "#,
+        )
+        .unwrap();
+        let state = ApiState::new(&config);
+        let store = &state.store;
+
+        for (timestamp, level) in [(100, 3.9), (200, 4.1)] {
+            let app = AppMessage::Telemetry(Telemetry::Environment {
+                temperature: None,
+                humidity: None,
+                pressure: None,
+                distance: None,
+                rainfall_1h: None,
+                rainfall_24h: None,
+            });
+            let readings = vec![("water_level", level)];
+            store.write().unwrap().record_message(&DecodedMessage {
+                forecast: (timestamp == 200).then(|| StageForecast {
+                    model: ForecastModel::Linear,
+                    made_at: 200,
+                    level: 4.1,
+                    rate_per_hour: 0.2,
+                    confidence: 0.9,
+                    points: Vec::new(),
+                    crossings: vec![Crossing {
+                        rule: "major-flood".to_string(),
+                        threshold: 4.5,
+                        severity: Severity::Critical,
+                        at: 7400,
+                        earliest: 5600,
+                        latest: None,
+                    }],
+                }),
+                ..DecodedMessage::test(5, "bridge", timestamp, app, readings)
+            });
+        }
+        store.write().unwrap().record_alert(&AlertEvent {
+            rule: "flood".to_string(),
+            timestamp: 200,
+            node_id: 5,
+            node_name: "bridge".to_string(),
+            metric: "water_level".to_string(),
+            value: 4.1,
+            threshold: 4.0,
+            expected_at: None,
+            severity: Severity::Critical,
+            state: AlertState::Raised,
+        });
+        state
+    }
+
+    async fn get(uri: &str) -> (StatusCode, Value) {
+        let response = router(state())
+            .oneshot(Request::get(uri).body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        let status = response.status();
+        let bytes = body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        (status, serde_json::from_slice(&bytes).unwrap())
+    }
+
+    #[tokio::test]
+    async fn serves_gauges_alerts_and_history() {
+        let (status, gauges) = get("/api/gauges").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(gauges[0]["node"], "!00000005");
+        assert_eq!(gauges[0]["water_level"]["value"], 4.1);
+        let crossing = &gauges[0]["forecast"]["crossings"][0];
+        assert_eq!(crossing["rule"], "major-flood");
+        assert_eq!(crossing["at"], 7400);
+        assert_eq!(crossing["latest"], Value::Null);
+
+        let (_, alerts) = get("/api/alerts").await;
+        assert_eq!(alerts[0]["rule"], "flood");
+        assert_eq!(alerts[0]["severity"], "critical");
+
+        let (_, history) = get("/api/nodes/bridge/history?from=150").await;
+        assert_eq!(history["metric"], "water_level");
+        assert_eq!(history["samples"].as_array().unwrap().len(), 1);
+        assert_eq!(history["samples"][0]["timestamp"], 200);
+
+        // The bridge has not reported a position
+        let (status, geojson) = get("/api/nodes.geojson").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(geojson["type"], "FeatureCollection");
+        assert_eq!(geojson["features"], json!([]));
+    }
+
+    #[tokio::test]
+    async fn acknowledges_alerts_once() {
+        let state = state();
+        let ack = |id: u32, body: &'static str| {
+            router(state.clone()).oneshot(
+                Request::post(format!("/api/alerts/{}/ack", id))
+                    .header(header::CONTENT_TYPE, "application/json")
+                    .body(Body::from(body))
+                    .unwrap(),
+            )
+        };
+
+        let response = ack(1, r#"{"by": "duty officer"}"#).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let bytes = body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let alert: Value = serde_json::from_slice(&bytes).unwrap();
+        assert_eq!(alert["status"], "acknowledged");
+        assert_eq!(alert["acknowledged"]["by"], "duty officer");
+
+        let response = ack(1, r#"{"by": "someone else"}"#).await.unwrap();
+        assert_eq!(response.status(), StatusCode::CONFLICT);
+        let response = ack(7, r#"{"by": "duty officer"}"#).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        let response = ack(1, r#"{"by": " "}"#).await.unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+        let actions: Vec<_> = state
+            .store
+            .read()
+            .unwrap()
+            .audit()
+            .map(|e| (e.action, e.by.clone()))
+            .collect();
+        assert_eq!(
+            actions,
+            [
+                (AuditAction::Raised, None),
+                (AuditAction::Acknowledged, Some("duty officer".to_string()))
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn reports_unknown_nodes_and_bad_ranges() {
+        let (status, body) = get("/api/nodes/nowhere").await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert!(body["error"].as_str().unwrap().contains("nowhere"));
+
+        let (status, _) = get("/api/nodes/!00000005/history?from=10&to=5").await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn streams_filtered_events() {
+        let state = state();
+        let feed = state.feed.clone();
+        let response = router(state)
+            .oneshot(
+                Request::get("/api/events?type=alert")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let message = FeedEvent {
+            kind: feed::EventKind::Message,
+            node_id: 5,
+            node_name: "bridge".to_string(),
+            port: Some("TELEMETRY_APP"),
+            json: "{}".to_string(),
+        };
+        let alert = FeedEvent {
+            kind: feed::EventKind::Alert,
+            port: None,
+            json: r#"{"rule":"flood"}"#.to_string(),
+            ..message.clone()
+        };
+        feed.send(Arc::new(message)).unwrap();
+        feed.send(Arc::new(alert)).unwrap();
+
+        let mut body = response.into_body().into_data_stream();
+        let chunk = body.next().await.unwrap().unwrap();
+        assert_eq!(
+            String::from_utf8_lossy(&chunk),
+            "event: alert\ndata: {\"rule\":\"flood\"}\n\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn exports_history_as_csv() {
+        let response = router(state())
+            .oneshot(
+                Request::get("/api/export?columns=water_level&from=150")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let bytes = body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        assert_eq!(
+            String::from_utf8_lossy(&bytes),
+            "timestamp,node,name,water_level,quality\n1970-01-01T00:03:20Z,!00000005,bridge,4.1,\n"
+        );
+
+        let (status, _) = get("/api/export?format=jsonl").await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn serves_the_dashboard_and_its_assets() {
+        for (uri, content_type) in [
+            ("/", "text/html; charset=utf-8"),
+            ("/dashboard.js", "text/javascript; charset=utf-8"),
+            ("/dashboard.css", "text/css; charset=utf-8"),
+        ] {
+            let response = router(state())
+                .oneshot(Request::get(uri).body(Body::empty()).unwrap())
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::OK, "{}", uri);
+            assert_eq!(response.headers()[header::CONTENT_TYPE], content_type);
+        }
+    }
+
+    #[tokio::test]
+    async fn rejects_bad_event_filters() {
+        let (status, _) = get("/api/events?type=nope").await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+}
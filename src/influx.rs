@@ -0,0 +1,337 @@
+use std::fs::OpenOptions;
+use std::io::{self, BufWriter, Write};
+use std::sync::atomic::Ordering;
+use std::sync::mpsc::{self, RecvTimeoutError, SyncSender, TrySendError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::config::{InfluxSinkConfig, NodeId};
+use crate::handler::DecodedMessage;
+use crate::metrics;
+use crate::sinks::Sink;
+
+/// Lines held for the writer thread. A live pipeline drops lines beyond
+/// this rather than wait; a replay waits for the writer instead.
+const QUEUE_CAPACITY: usize = 100_000;
+
+/// First wait before retrying a failed batch, doubled per attempt.
+const RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// How long `flush` waits for the writer to drain at shutdown.
+const FLUSH_TIMEOUT: Duration = Duration::from_secs(30);
+
+enum Command {
+    Line(String),
+    /// Write whatever is batched, then acknowledge.
+    Flush(mpsc::Sender<()>),
+}
+
+/// Writes telemetry as InfluxDB line protocol. Formatting happens inline;
+/// batching, writing and retries happen on a writer thread so a slow or
+/// unreachable endpoint never holds up a live pipeline. A replay, which is
+/// how history gets backfilled, waits for the writer instead of dropping.
+pub struct InfluxSink {
+    measurement: String,
+    queue: SyncSender<Command>,
+    live: bool,
+    overflowing: bool,
+}
+
+impl InfluxSink {
+    pub fn spawn(config: &InfluxSinkConfig, live: bool) -> io::Result<Self> {
+        let target = match (&config.url, &config.file) {
+            (Some(url), _) => {
+                log::info!("Writing line protocol to {}", url);
+                Target::Http {
+                    agent: ureq::Agent::new_with_defaults(),
+                    url: url.clone(),
+                    token: config.token.clone(),
+                }
+            }
+            (None, Some(path)) => {
+                log::info!("Writing line protocol to {}", path.display());
+                let file = OpenOptions::new().create(true).append(true).open(path)?;
+                Target::Writer(Box::new(BufWriter::new(file)))
+            }
+            (None, None) => Target::Writer(Box::new(io::stdout())),
+        };
+
+        let (queue, commands) = mpsc::sync_channel(QUEUE_CAPACITY);
+        let writer = Writer {
+            target,
+            batch_size: config.batch_size,
+            flush_interval: Duration::from_secs(config.flush_interval_secs),
+            max_retries: config.max_retries,
+        };
+        thread::Builder::new()
+            .name("influx-writer".to_string())
+            .spawn(move || writer.run(commands))?;
+
+        Ok(Self {
+            measurement: config.measurement.clone(),
+            queue,
+            live,
+            overflowing: false,
+        })
+    }
+}
+
+impl Sink for InfluxSink {
+    fn name(&self) -> &'static str {
+        "influx"
+    }
+
+    fn on_message(&mut self, msg: &DecodedMessage) {
+        let Some(line) = line(&self.measurement, msg) else {
+            return;
+        };
+        if !self.live {
+            let _ = self.queue.send(Command::Line(line));
+            return;
+        }
+        match self.queue.try_send(Command::Line(line)) {
+            Ok(()) => self.overflowing = false,
+            Err(TrySendError::Full(_)) => {
+                if !self.overflowing {
+                    log::warn!("Line protocol queue full, dropping telemetry");
+                }
+                self.overflowing = true;
+                metrics::INFLUX_DROPPED_LINES.fetch_add(1, Ordering::Relaxed);
+            }
+            Err(TrySendError::Disconnected(_)) => {}
+        }
+    }
+
+    fn flush(&mut self) {
+        let (ack, done) = mpsc::channel();
+        if self.queue.send(Command::Flush(ack)).is_ok() && done.recv_timeout(FLUSH_TIMEOUT).is_err()
+        {
+            log::warn!("Timed out writing the last line protocol batch");
+        }
+    }
+}
+
+/* ---------------- Line protocol ---------------- */
+
+/// One line with every reading of a telemetry packet as a field, stamped
+/// with the packet's own time so a replay backfills history. A flagged
+/// reading also gets a `<metric>_quality` string field.
+pub fn line(measurement: &str, msg: &DecodedMessage) -> Option<String> {
+    let mut fields: Vec<String> = msg
+        .readings
+        .iter()
+        // Line protocol has no representation for NaN or infinity
+        .filter(|(_, value)| value.is_finite())
+        .map(|(metric, value)| format!("{}={}", escape(metric, ",= "), value))
+        .collect();
+    if fields.is_empty() {
+        return None;
+    }
+    fields.extend(
+        msg.quality
+            .iter()
+            .map(|(metric, quality)| format!("{}_quality=\"{}\"", escape(metric, ",= "), quality)),
+    );
+
+    Some(format!(
+        "{},node={},name={},channel={},port={} {} {}",
+        escape(measurement, ", "),
+        NodeId(msg.message.node_id),
+        escape(&msg.node_name, ",= "),
+        msg.channel,
+        msg.message.portnum.as_str_name(),
+        fields.join(","),
+        msg.packet_time * 1_000_000_000
+    ))
+}
+
+fn escape(value: &str, special: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        if special.contains(c) || c == '\\' {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/* ---------------- Writer thread ---------------- */
+
+enum Target {
+    Writer(Box<dyn Write + Send>),
+    Http {
+        agent: ureq::Agent,
+        url: String,
+        token: Option<String>,
+    },
+}
+
+impl Target {
+    fn write(&mut self, body: &str) -> Result<(), String> {
+        match self {
+            Target::Writer(out) => out
+                .write_all(body.as_bytes())
+                .and_then(|()| out.flush())
+                .map_err(|e| e.to_string()),
+            Target::Http { agent, url, token } => {
+                let mut request = agent
+                    .post(url.as_str())
+                    .header("Content-Type", "text/plain; charset=utf-8");
+                if let Some(token) = token {
+                    request = request.header("Authorization", &format!("Token {}", token));
+                }
+                request.send(body).map(|_| ()).map_err(|e| e.to_string())
+            }
+        }
+    }
+}
+
+struct Writer {
+    target: Target,
+    batch_size: usize,
+    flush_interval: Duration,
+    max_retries: u32,
+}
+
+impl Writer {
+    fn run(mut self, commands: mpsc::Receiver<Command>) {
+        let mut batch: Vec<String> = Vec::new();
+        // When the oldest line in `batch` arrived
+        let mut started = Instant::now();
+
+        loop {
+            let timeout = if batch.is_empty() {
+                Duration::MAX
+            } else {
+                self.flush_interval.saturating_sub(started.elapsed())
+            };
+
+            match commands.recv_timeout(timeout) {
+                Ok(Command::Line(line)) => {
+                    if batch.is_empty() {
+                        started = Instant::now();
+                    }
+                    batch.push(line);
+                    if batch.len() >= self.batch_size {
+                        self.write_batch(&mut batch);
+                    }
+                }
+                Ok(Command::Flush(ack)) => {
+                    self.write_batch(&mut batch);
+                    let _ = ack.send(());
+                }
+                Err(RecvTimeoutError::Timeout) => self.write_batch(&mut batch),
+                Err(RecvTimeoutError::Disconnected) => {
+                    self.write_batch(&mut batch);
+                    return;
+                }
+            }
+        }
+    }
+
+    /// Writes and clears `batch`, retrying with backoff before giving up.
+    fn write_batch(&mut self, batch: &mut Vec<String>) {
+        if batch.is_empty() {
+            return;
+        }
+        let mut body = batch.join("\n");
+        body.push('\n');
+
+        let mut delay = RETRY_DELAY;
+        for attempt in 0..=self.max_retries {
+            match self.target.write(&body) {
+                Ok(()) => break,
+                Err(e) if attempt == self.max_retries => {
+                    metrics::INFLUX_DROPPED_LINES.fetch_add(batch.len() as u64, Ordering::Relaxed);
+                    log::error!(
+                        "Dropping {} line protocol points after {} attempts: {}",
+                        batch.len(),
+                        attempt + 1,
+                        e
+                    );
+                }
+                Err(e) => {
+                    log::warn!("Line protocol write failed, retrying in {:?}: {}", delay, e);
+                    thread::sleep(delay);
+                    delay *= 2;
+                }
+            }
+        }
+        batch.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::quality::Quality;
+    use crate::radio_message::{AppMessage, Telemetry};
+
+    fn message(readings: Vec<(&'static str, f64)>) -> DecodedMessage {
+        let app = AppMessage::Telemetry(Telemetry::Power {
+            voltage: None,
+            current: None,
+        });
+        DecodedMessage {
+            packet_time: 1_700_000_000,
+            channel: 1,
+            ..DecodedMessage::test(5, "main st, bridge", 2_000, app, readings)
+        }
+    }
+
+    #[test]
+    fn formats_tags_fields_and_packet_time() {
+        let line = line(
+            "flood",
+            &message(vec![("distance", 2500.0), ("water_level", 3.6)]),
+        )
+        .unwrap();
+        assert_eq!(
+            line,
+            "flood,node=!00000005,name=main\\ st\\,\\ bridge,channel=1,port=TELEMETRY_APP \
+             distance=2500,water_level=3.6 1700000000000000000"
+        );
+
+        let mut flagged = message(vec![("distance", 0.0), ("water_level", 5.0)]);
+        flagged.quality = vec![
+            ("distance", Quality::Invalid),
+            ("water_level", Quality::Invalid),
+        ];
+        assert!(
+            super::line("flood", &flagged)
+                .unwrap()
+                .contains(" distance=0,water_level=5,distance_quality=\"invalid\",water_level_quality=\"invalid\" ")
+        );
+    }
+
+    #[test]
+    fn skips_packets_without_finite_readings() {
+        assert!(line("flood", &message(Vec::new())).is_none());
+        assert!(line("flood", &message(vec![("temperature", f64::NAN)])).is_none());
+    }
+
+    #[test]
+    fn flush_writes_partial_batch_to_file() {
+        let path = std::env::temp_dir().join(format!("influx-sink-{}.lp", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let mut sink = InfluxSink::spawn(
+            &InfluxSinkConfig {
+                file: Some(path.clone()),
+                flush_interval_secs: 3600,
+                ..InfluxSinkConfig::default()
+            },
+            true,
+        )
+        .unwrap();
+        sink.on_message(&message(vec![("battery_level", 90.0)]));
+        sink.on_message(&message(vec![("battery_level", 89.0)]));
+        sink.flush();
+
+        let written = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(written.lines().count(), 2);
+        assert!(written.ends_with("battery_level=89 1700000000000000000\n"));
+    }
+}
@@ -1,30 +1,50 @@
+mod alerts;
+mod battery;
+mod bot;
+mod cli;
+mod config;
+mod dashboard;
+mod dedup;
+mod export;
+mod feed;
+mod forecast;
+mod frame;
+mod geojson;
 mod handler;
+mod heartbeat;
+mod http;
+mod influx;
+mod mesh;
+mod metrics;
+mod mqtt;
+mod nodes;
+mod notify;
+mod pipeline;
 mod playback;
+mod quality;
+mod rain;
 mod radio_message;
+mod reach;
 mod recording_stream;
+mod reload;
+mod sinks;
+mod sources;
+mod store;
+mod table;
 
 use std::env;
+use std::path::PathBuf;
+use std::process::ExitCode;
 
-use handler::handle_from_radio;
-use playback::PlaybackStream;
-use recording_stream::RecordingStream;
+use cli::{Cli, Command};
+use config::Config;
+use http::ApiState;
+use pipeline::Pipeline;
 
-use meshtastic::api::StreamApi;
-use meshtastic::utils;
+use tokio::sync::mpsc;
 
 #[tokio::main]
-async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    env_logger::Builder::new()
-        .filter_level(log::LevelFilter::Debug)
-        .filter_module(
-            "meshtastic::connections::stream_buffer",
-            log::LevelFilter::Error,
-        )
-        .init();
-
-    let args: Vec<String> = env::args().collect();
-
-    
+async fn main() -> Result<ExitCode, sources::SourceError> {
     /*
         Usage:
 
@@ -32,89 +52,194 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
             cargo run
             cargo run -- live
 
-        Record mode:
+        Record mode (live, plus every frame written to rotating .bin files):
+            cargo run -- --record recordings
             cargo run -- record recordings
 
         Playback mode:
             cargo run -- replay recordings/meshtastic-recording-00000.bin
-    */
 
-    match args.get(1).map(String::as_str) {
-        Some("replay") => {
-            let path = args.get(2).expect("missing replay file path");
-            run_playback(path)?;
-        }
-        Some("record") => {
-            let path = args.get(2).expect("missing record file path");
-            run_record(path).await?;
-        }
-        _ => {
-            run_live().await?;
-        }
-    }
+        Explore a capture through the JSON API (serves until Ctrl-C):
+            cargo run -- --http 127.0.0.1:8080 replay recordings/meshtastic-recording-00000.bin
 
-    Ok(())
-}
+        Export a recording as JSON Lines, and turn that back into a recording:
+            cargo run -- export recordings/meshtastic-recording-00000.bin --output out.jsonl
+            cargo run -- import out.jsonl imported
 
-/* ---------------- Live Path ---------------- */
+        Export gauge readings as a table, averaged per 15 minutes in local time:
+            cargo run -- export --format csv --interval 15m --timezone local \
+                --columns water_level,battery_level --output levels.csv \
+                recordings/meshtastic-recording-00000.bin recordings/meshtastic-recording-00001.bin
 
-async fn run_live() -> Result<(), Box<dyn std::error::Error>> {
-    println!("Starting live Meshtastic stream…");
+        Where every node last reported from, for Leaflet or QGIS:
+            cargo run -- export --format geojson --output nodes.geojson \
+                recordings/meshtastic-recording-00000.bin
 
-    let stream_api = StreamApi::new();
-    let serial_stream =
-        utils::stream::build_serial_stream("/dev/ttyACM0".to_string(), None, None, None)?;
+        Learn how long a crest takes between the gauges of each reach:
+            cargo run -- learn-reaches recordings/meshtastic-recording-0000*.bin
 
-    let (mut decoded_listener, stream_api) = stream_api.connect(serial_stream).await;
-    let config_id = utils::generate_rand_id();
-    let _stream_api = stream_api.configure(config_id).await?;
+        Check how well the stage forecast would have done on past captures:
+            cargo run -- backtest recordings/meshtastic-recording-0000*.bin
 
-    while let Some(from_radio) = decoded_listener.recv().await {
-        handle_from_radio(from_radio);
-    }
+        Validate a config file before deploying:
+            cargo run -- check-config flood_monitor.toml
+    */
 
-    Ok(())
-}
+    let cli = match Cli::parse(env::args().skip(1)) {
+        Ok(cli) => cli,
+        Err(e) => {
+            eprintln!("error: {}\n\n{}", e, cli::USAGE);
+            return Ok(ExitCode::FAILURE);
+        }
+    };
 
-/* ---------------- Record Path ---------------- */
+    let mut config = match Config::load_or_default(cli.config_path.as_deref()) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("invalid config: {}", e);
+            return Ok(ExitCode::FAILURE);
+        }
+    };
+    cli.apply_overrides(&mut config);
 
-async fn run_record(path: &str) -> Result<(), Box<dyn std::error::Error>> {
-    println!("Recording to: {}", path);
+    let issues = config.validate();
+    if !issues.is_empty() {
+        for issue in issues {
+            eprintln!("invalid config: at `{}`: {}", issue.key, issue.message);
+        }
+        return Ok(ExitCode::FAILURE);
+    }
 
-    let mut recorder = RecordingStream::new(path)?;
+    if cli.command == Command::CheckConfig {
+        println!("config OK");
+        return Ok(ExitCode::SUCCESS);
+    }
 
-    let stream_api = StreamApi::new();
-    let serial_stream =
-        utils::stream::build_serial_stream("/dev/ttyACM0".to_string(), None, None, None)?;
+    config.init_logging();
 
-    let (mut decoded_listener, stream_api) = stream_api.connect(serial_stream).await;
-    let config_id = utils::generate_rand_id();
-    let _stream_api = stream_api.configure(config_id).await?;
+    match &cli.command {
+        Command::Export { inputs, output } => {
+            let count = export::export(inputs, output.as_deref(), cli.format, &config, &cli.table)?;
+            eprintln!("Exported {} records", count);
+            return Ok(ExitCode::SUCCESS);
+        }
+        Command::Import { input, dir } => {
+            let dir = dir.as_ref().unwrap_or(&config.recording.dir);
+            let count = export::import(input, dir, config.recording.max_file_size)?;
+            eprintln!("Imported {} records into {}", count, dir.display());
+            return Ok(ExitCode::SUCCESS);
+        }
+        Command::LearnReaches(inputs) => {
+            if config.reaches.is_empty() {
+                eprintln!("error: no [[reaches]] configured to learn");
+                return Ok(ExitCode::FAILURE);
+            }
+            print!("{}", export::learn_reaches(inputs, &config)?);
+            return Ok(ExitCode::SUCCESS);
+        }
+        Command::Backtest(inputs) => {
+            print!("{}", export::backtest(inputs, &config)?);
+            return Ok(ExitCode::SUCCESS);
+        }
+        _ => {}
+    }
 
-    while let Some(from_radio) = decoded_listener.recv().await {
-        let raw = meshtastic::Message::encode_to_vec(&from_radio);
-        recorder.record(&raw)?;
+    // Old alerts from a recording must not page anyone
+    if matches!(cli.command, Command::Replay(_)) && !config.notifiers.is_empty() {
+        log::info!("Notifiers are disabled while replaying");
+        config.notifiers.clear();
+    }
+    // Nor be broadcast onto a live mesh, and the bot must not answer commands sent long ago
+    if matches!(cli.command, Command::Replay(_))
+        && (config.mesh.alerts.enabled || config.mesh.bot.enabled)
+    {
+        log::info!("Mesh alert broadcasts and commands are disabled while replaying");
+        config.mesh.alerts.enabled = false;
+        config.mesh.bot.enabled = false;
+    }
+    let (outbox, mut mesh_packets) = if config.mesh.alerts.enabled || config.mesh.bot.enabled {
+        let (outbox, packets) = mesh::spawn(&config.mesh);
+        (Some(outbox), Some(packets))
+    } else {
+        (None, None)
+    };
+
+    let api = ApiState::new(&config);
+    let live = !matches!(cli.command, Command::Replay(_));
+    // A replay starts from a clean slate and must not touch live state
+    if live {
+        let mut store = api.store.write().unwrap();
+        store.persist(&config.alert_lifecycle)?;
+        // Alerts of rules deleted while the monitor was down
+        store.resolve_removed_rules(&config, frame::now_secs());
+    }
+    let pipeline = Pipeline::new(&config, &api, outbox.as_ref(), live)?;
+    let server = if config.http.enabled {
+        Some(http::spawn(&config.http.listen, api.clone()).await?)
+    } else {
+        None
+    };
+
+    let (frames_tx, frames_rx) = mpsc::channel(pipeline::FRAME_CHANNEL_CAPACITY);
+
+    let (sources, reloads) = match &cli.command {
+        Command::Replay(path) => {
+            println!("Replaying {}", path.display());
+            let source = sources::spawn_replay(path.clone(), config.recording.enabled, frames_tx);
+            (vec![source], mpsc::unbounded_channel().1)
+        }
+        Command::Live | Command::Record(_) => {
+            println!("Starting live Meshtastic stream…");
+            // One task per radio, all feeding the same pipeline; one of them
+            // also transmits
+            let transmitter = config.mesh_connection().map(|c| c.id.clone());
+            let sources = config
+                .connections
+                .iter()
+                .map(|conn| {
+                    let packets = mesh_packets
+                        .take_if(|_| transmitter.as_ref() == Some(&conn.id));
+                    sources::spawn_radio(conn.clone(), frames_tx.clone(), packets)
+                })
+                .collect();
+            (sources, watch_config(&cli, &config))
+        }
+        Command::CheckConfig
+        | Command::Export { .. }
+        | Command::Import { .. }
+        | Command::LearnReaches(_)
+        | Command::Backtest(_) => unreachable!(),
+    };
+
+    pipeline::run(pipeline, frames_rx, reloads, live).await;
+    for source in sources {
+        source.await??;
+    }
 
-        handle_from_radio(from_radio);
+    // A replay is over in seconds; keep the API up so it can be explored
+    if let Some(server) = server
+        && matches!(cli.command, Command::Replay(_))
+    {
+        log::info!("Sources finished, still serving HTTP API until Ctrl-C");
+        tokio::select! {
+            _ = server => {}
+            _ = tokio::signal::ctrl_c() => {}
+        }
     }
 
-    recorder.flush()?;
-    Ok(())
+    Ok(ExitCode::SUCCESS)
 }
 
-/* ---------------- Playback Path ---------------- */
-
-fn run_playback(path: &str) -> Result<(), Box<dyn std::error::Error>> {
-    log::info!("Replaying capture from: {}", path);
-
-    let playback = PlaybackStream::open(path)?;
-    log::info!("Playback started");
-
-    for msg in playback {
-        let from_radio = msg?;
-        //log::info!("Replayed FromRadio: {:?}", from_radio);
-        handle_from_radio(from_radio);
+/// Reloaded configs for long-running modes; never yields when running on
+/// built-in defaults without a config file.
+fn watch_config(cli: &Cli, config: &Config) -> mpsc::UnboundedReceiver<Config> {
+    let path = cli
+        .config_path
+        .clone()
+        .or_else(|| Some(PathBuf::from(config::DEFAULT_CONFIG_PATH)).filter(|p| p.exists()));
+
+    match path {
+        Some(path) => reload::spawn_config_watcher(path, cli.clone(), config.clone()),
+        None => mpsc::unbounded_channel().1,
     }
-
-    Ok(())
 }
@@ -0,0 +1,447 @@
+use std::collections::VecDeque;
+use std::time::Duration;
+
+use meshtastic::protobufs::{Data, MeshPacket, PortNum, mesh_packet};
+use meshtastic::utils;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tokio::time::Instant;
+
+use crate::alerts::{AlertEvent, AlertState};
+use crate::config::{MeshAlertsConfig, MeshConfig, MeshTemplates, ModemPreset, Severity};
+use crate::metrics;
+use crate::notify::{self, RateLimiter};
+use crate::sinks::Sink;
+use crate::store::SharedStore;
+
+/// Longest text the Meshtastic apps let a user type; the packet header and
+/// protobuf framing take the rest of the 255 byte LoRa frame.
+pub const MAX_TEXT_BYTES: usize = 228;
+
+/// Texts waiting for airtime; the oldest is dropped beyond this.
+const QUEUE_CAPACITY: usize = 32;
+
+const BROADCAST: u32 = u32::MAX;
+
+/// Meshtastic header plus `Data` framing around a text payload, in bytes.
+const PACKET_OVERHEAD: usize = 16 + 6;
+const PREAMBLE_SYMBOLS: f64 = 16.0;
+const HOUR: Duration = Duration::from_secs(3600);
+
+/// A text message for the monitor's own radio to send.
+#[derive(Debug, Clone, PartialEq)]
+pub struct OutgoingText {
+    /// Node number for a direct message, or `None` to broadcast.
+    pub to: Option<u32>,
+    pub channel: u32,
+    pub text: String,
+    /// Id of the packet this answers, shown as a reply by the apps.
+    pub reply_id: Option<u32>,
+}
+
+/// Where sinks queue texts for the mesh.
+pub type Outbox = mpsc::UnboundedSender<OutgoingText>;
+
+/* ---------------- Airtime ---------------- */
+
+/// (spreading factor, bandwidth in kHz, coding rate denominator minus 4)
+fn lora_params(preset: ModemPreset) -> (i32, f64, u32) {
+    match preset {
+        ModemPreset::ShortTurbo => (7, 500.0, 1),
+        ModemPreset::ShortFast => (7, 250.0, 1),
+        ModemPreset::ShortSlow => (8, 250.0, 1),
+        ModemPreset::MediumFast => (9, 250.0, 1),
+        ModemPreset::MediumSlow => (10, 250.0, 1),
+        ModemPreset::LongFast => (11, 250.0, 1),
+        ModemPreset::LongModerate => (11, 125.0, 4),
+        ModemPreset::LongSlow => (12, 125.0, 4),
+    }
+}
+
+/// Time on air of one text, by the formula in Semtech's SX127x datasheet.
+/// Rebroadcasts by other nodes are not counted; they spend their own duty
+/// cycle.
+pub fn airtime(preset: ModemPreset, text_bytes: usize) -> Duration {
+    let (sf, bandwidth_khz, coding_rate) = lora_params(preset);
+    let symbol_secs = 2f64.powi(sf) / (bandwidth_khz * 1000.0);
+    let low_data_rate = if symbol_secs > 0.016 { 1.0 } else { 0.0 };
+    let payload = (text_bytes + PACKET_OVERHEAD) as f64;
+    let sf = f64::from(sf);
+    // Explicit header, CRC on
+    let blocks = ((8.0 * payload - 4.0 * sf + 28.0 + 16.0) / (4.0 * (sf - 2.0 * low_data_rate)))
+        .ceil()
+        .max(0.0);
+    let symbols = PREAMBLE_SYMBOLS + 4.25 + 8.0 + blocks * f64::from(coding_rate + 4);
+    Duration::from_secs_f64(symbols * symbol_secs)
+}
+
+/// Spaces transmissions `min_interval` apart and keeps the estimated
+/// airtime of any hour within budget.
+#[derive(Debug)]
+struct Pacer {
+    budget: Duration,
+    min_interval: Duration,
+    /// (sent at, airtime) over the last hour
+    sent: VecDeque<(Instant, Duration)>,
+}
+
+impl Pacer {
+    fn new(config: &MeshConfig) -> Self {
+        Self {
+            budget: Duration::from_secs_f64(config.airtime_per_hour_secs),
+            min_interval: Duration::from_secs(config.min_interval_secs),
+            sent: VecDeque::new(),
+        }
+    }
+
+    /// How long to hold a transmission of `airtime` before it may go out.
+    fn wait(&mut self, now: Instant, airtime: Duration) -> Duration {
+        while self.sent.front().is_some_and(|&(t, _)| now - t >= HOUR) {
+            self.sent.pop_front();
+        }
+        let spacing = self.sent.back().map_or(Duration::ZERO, |&(t, _)| {
+            (t + self.min_interval).saturating_duration_since(now)
+        });
+
+        // Until enough of the hour's transmissions have aged out
+        let mut used: Duration = self.sent.iter().map(|&(_, a)| a).sum();
+        let mut budget_wait = Duration::ZERO;
+        for &(t, a) in &self.sent {
+            if used + airtime <= self.budget {
+                break;
+            }
+            used -= a;
+            budget_wait = (t + HOUR).saturating_duration_since(now);
+        }
+        spacing.max(budget_wait)
+    }
+
+    fn record(&mut self, now: Instant, airtime: Duration) {
+        self.sent.push_back((now, airtime));
+    }
+}
+
+/* ---------------- Outbox ---------------- */
+
+/// Cuts `text` to at most `max_bytes` without splitting a character.
+fn truncate(mut text: String, max_bytes: usize) -> String {
+    if text.len() > max_bytes {
+        let mut end = max_bytes;
+        while !text.is_char_boundary(end) {
+            end -= 1;
+        }
+        text.truncate(end);
+    }
+    text
+}
+
+fn packet(text: &OutgoingText) -> MeshPacket {
+    MeshPacket {
+        to: text.to.unwrap_or(BROADCAST),
+        channel: text.channel,
+        id: utils::generate_rand_id(),
+        // Broadcasts are never acknowledged
+        want_ack: text.to.is_some(),
+        payload_variant: Some(mesh_packet::PayloadVariant::Decoded(Data {
+            portnum: PortNum::TextMessageApp as i32,
+            payload: text.text.as_bytes().to_vec(),
+            reply_id: text.reply_id.unwrap_or(0),
+            ..Default::default()
+        })),
+        ..Default::default()
+    }
+}
+
+/// Starts the task that paces queued texts onto the radio. Returns the
+/// outbox for sinks and the packets for the transmitting connection.
+pub fn spawn(config: &MeshConfig) -> (Outbox, mpsc::UnboundedReceiver<MeshPacket>) {
+    let (outbox, texts) = mpsc::unbounded_channel();
+    let (packets_tx, packets_rx) = mpsc::unbounded_channel();
+    run_outbox(config, texts, packets_tx);
+    (outbox, packets_rx)
+}
+
+fn run_outbox(
+    config: &MeshConfig,
+    mut texts: mpsc::UnboundedReceiver<OutgoingText>,
+    packets: mpsc::UnboundedSender<MeshPacket>,
+) -> JoinHandle<()> {
+    let preset = config.modem_preset;
+    let max_text_bytes = config.max_text_bytes;
+    let mut pacer = Pacer::new(config);
+
+    tokio::spawn(async move {
+        let mut queue: VecDeque<(OutgoingText, Duration)> = VecDeque::new();
+        loop {
+            let wait = match queue.front() {
+                Some(&(_, airtime)) => pacer.wait(Instant::now(), airtime),
+                None => Duration::MAX,
+            };
+
+            tokio::select! {
+                text = texts.recv() => {
+                    // The pipeline has shut down
+                    let Some(mut text) = text else { return };
+                    text.text = truncate(text.text, max_text_bytes);
+                    let airtime = airtime(preset, text.text.len());
+                    if airtime > pacer.budget {
+                        log::warn!(
+                            "Mesh text needs {:?} on air, more than the hourly budget: {}",
+                            airtime,
+                            text.text
+                        );
+                        metrics::MESH_TEXTS.inc("over_budget");
+                        continue;
+                    }
+                    if queue.len() == QUEUE_CAPACITY
+                        && let Some((dropped, _)) = queue.pop_front()
+                    {
+                        log::warn!("Mesh outbox full, dropping: {}", dropped.text);
+                        metrics::MESH_TEXTS.inc("queue_full");
+                    }
+                    queue.push_back((text, airtime));
+                }
+                _ = tokio::time::sleep(wait), if !queue.is_empty() => {
+                    let (text, airtime) = queue.pop_front().expect("queue is not empty");
+                    pacer.record(Instant::now(), airtime);
+                    log::info!("Sending to mesh on channel {}: {}", text.channel, text.text);
+                    if packets.send(packet(&text)).is_err() {
+                        log::warn!("No radio to send mesh texts through");
+                        return;
+                    }
+                    metrics::MESH_TEXTS.inc("sent");
+                }
+            }
+        }
+    })
+}
+
+/* ---------------- Alert broadcasts ---------------- */
+
+/// The text for an alert, from its severity's template or the cleared one.
+/// Values are rounded, every byte counts on the mesh.
+pub fn alert_text(templates: &MeshTemplates, event: &AlertEvent) -> Result<String, String> {
+    let template = match event.state {
+        AlertState::Raised => templates.for_severity(event.severity),
+        AlertState::Cleared => &templates.cleared,
+    };
+    let mut fields = notify::fields(event, None);
+    for (name, value) in &mut fields {
+        match *name {
+            "value" => *value = format!("{:.2}", event.value),
+            "threshold" => *value = format!("{:.2}", event.threshold),
+            _ => {}
+        }
+    }
+    notify::render(template, &fields, false)
+}
+
+/// Problems with the templates, by template name.
+pub fn check_templates(templates: &MeshTemplates) -> Vec<(&'static str, String)> {
+    let mut sample = AlertEvent {
+        rule: "flood-stage".to_string(),
+        timestamp: 0,
+        node_id: 1,
+        node_name: "gauge".to_string(),
+        metric: "water_level".to_string(),
+        value: 4.2,
+        threshold: 4.0,
+        expected_at: None,
+        severity: Severity::Info,
+        state: AlertState::Raised,
+    };
+    let mut problems = Vec::new();
+    for (name, severity, state) in [
+        ("info", Severity::Info, AlertState::Raised),
+        ("warning", Severity::Warning, AlertState::Raised),
+        ("critical", Severity::Critical, AlertState::Raised),
+        ("cleared", Severity::Critical, AlertState::Cleared),
+    ] {
+        sample.severity = severity;
+        sample.state = state;
+        if let Err(e) = alert_text(templates, &sample) {
+            problems.push((name, e));
+        }
+    }
+    problems
+}
+
+/// Broadcasts alerts as text messages on a channel of the mesh, unless
+/// alerts have been muted in `store`.
+pub struct MeshAlertSink {
+    config: MeshAlertsConfig,
+    outbox: Outbox,
+    store: SharedStore,
+    limiter: RateLimiter,
+}
+
+impl MeshAlertSink {
+    pub fn new(config: &MeshAlertsConfig, outbox: Outbox, store: SharedStore) -> Self {
+        Self {
+            config: config.clone(),
+            outbox,
+            store,
+            limiter: RateLimiter::new(config.max_per_hour as usize),
+        }
+    }
+}
+
+impl Sink for MeshAlertSink {
+    fn name(&self) -> &'static str {
+        "mesh-alerts"
+    }
+
+    fn on_alert(&mut self, event: &AlertEvent) {
+        if event.state == AlertState::Cleared && !self.config.notify_cleared {
+            return;
+        }
+        if !notify::routes(self.config.min_severity, &self.config.nodes, event) {
+            return;
+        }
+        if self.store.read().unwrap().muted(event.timestamp) {
+            log::info!("Alerts are muted, not broadcasting {}", event);
+            return;
+        }
+        if !self.limiter.allow(None, event.timestamp) {
+            log::warn!(
+                "Mesh alert broadcasts over the hourly limit, dropping {}",
+                event
+            );
+            metrics::MESH_TEXTS.inc("rate_limited");
+            return;
+        }
+        // Templates were checked with the config
+        let Ok(text) = alert_text(&self.config.templates, event) else {
+            return;
+        };
+        let _ = self.outbox.send(OutgoingText {
+            to: None,
+            channel: self.config.channel,
+            text,
+            reply_id: None,
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::store::Store;
+
+    #[test]
+    fn estimates_airtime_per_preset() {
+        let long_fast = airtime(ModemPreset::LongFast, 200);
+        assert!(
+            long_fast > Duration::from_millis(1700) && long_fast < Duration::from_millis(2100),
+            "{:?}",
+            long_fast
+        );
+        assert!(airtime(ModemPreset::LongSlow, 200) > long_fast * 4);
+        assert!(airtime(ModemPreset::ShortTurbo, 200) < long_fast / 10);
+        assert!(airtime(ModemPreset::LongFast, 20) < long_fast / 3);
+    }
+
+    #[test]
+    fn paces_transmissions_within_airtime_budget() {
+        let mut pacer = Pacer::new(&MeshConfig {
+            airtime_per_hour_secs: 5.0,
+            min_interval_secs: 30,
+            ..MeshConfig::default()
+        });
+        let start = Instant::now();
+        let airtime = Duration::from_secs(2);
+
+        assert_eq!(pacer.wait(start, airtime), Duration::ZERO);
+        pacer.record(start, airtime);
+        // Spacing first
+        assert_eq!(
+            pacer.wait(start + Duration::from_secs(10), airtime),
+            Duration::from_secs(20)
+        );
+        pacer.record(start + Duration::from_secs(30), airtime);
+        // 4 s used; another 2 s waits for the first to age out
+        let later = start + Duration::from_secs(600);
+        assert_eq!(pacer.wait(later, airtime), Duration::from_secs(3600 - 600));
+        assert_eq!(pacer.wait(later, Duration::from_secs(1)), Duration::ZERO);
+        assert_eq!(pacer.wait(start + HOUR, airtime), Duration::ZERO);
+    }
+
+    #[test]
+    fn broadcasts_routed_alerts_from_templates() {
+        let config = Config::parse(
+            r#"
+            [mesh.alerts]
+            enabled = true
+            channel = 2
+            min_severity = "warning"
+            max_per_hour = 2
+
+            [mesh.alerts.templates]
+            critical = "EVACUATE {{node_name}} {{value}}m"
+            "#,
+        )
+        .unwrap();
+        let (outbox, mut texts) = mpsc::unbounded_channel();
+        let store = Store::shared(&config);
+        let mut sink = MeshAlertSink::new(&config.mesh.alerts, outbox, store.clone());
+
+        let mut event = AlertEvent {
+            rule: "flood-stage".to_string(),
+            timestamp: 1_000,
+            node_id: 5,
+            node_name: "bridge".to_string(),
+            metric: "water_level".to_string(),
+            value: 4.1000000000000005,
+            threshold: 4.0,
+            expected_at: None,
+            severity: Severity::Info,
+            state: AlertState::Raised,
+        };
+        sink.on_alert(&event);
+        assert!(texts.try_recv().is_err());
+
+        event.severity = Severity::Critical;
+        sink.on_alert(&event);
+        let text = texts.try_recv().unwrap();
+        assert_eq!(text.text, "EVACUATE bridge 4.10m");
+        assert_eq!((text.to, text.channel), (None, 2));
+
+        event.state = AlertState::Cleared;
+        sink.on_alert(&event);
+        assert_eq!(
+            texts.try_recv().unwrap().text,
+            "Cleared bridge: flood-stage, water_level 4.10"
+        );
+
+        // Muted from a handset
+        store.write().unwrap().mute(Some(2_000), "!00000001", 1_000);
+        sink.on_alert(&event);
+        assert!(texts.try_recv().is_err());
+
+        // Over max_per_hour
+        store.write().unwrap().mute(None, "!00000001", 1_500);
+        sink.on_alert(&event);
+        assert!(texts.try_recv().is_err());
+
+        let err = Config::parse("[mesh.alerts.templates]\nwarning = \"{{level}}\"").unwrap_err();
+        assert!(
+            err.to_string().contains("mesh.alerts.templates.warning"),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn truncates_on_character_boundaries() {
+        assert_eq!(truncate("Hochwasser ö".to_string(), 12), "Hochwasser ");
+        assert_eq!(truncate("short".to_string(), 200), "short");
+        let text = packet(&OutgoingText {
+            to: Some(7),
+            channel: 1,
+            text: "ok".to_string(),
+            reply_id: Some(42),
+        });
+        assert_eq!((text.to, text.channel, text.want_ack), (7, 1, true));
+    }
+}
@@ -0,0 +1,296 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::radio_message::DecodeError;
+use crate::store::{NodeStatus, Store};
+
+/* ---------------- Process counters ---------------- */
+
+/// A counter split by one label, e.g. packets by port.
+pub struct LabeledCounter(Mutex<BTreeMap<String, u64>>);
+
+impl LabeledCounter {
+    const fn new() -> Self {
+        Self(Mutex::new(BTreeMap::new()))
+    }
+
+    pub fn inc(&self, label: &str) {
+        *self.0.lock().unwrap().entry(label.to_string()).or_default() += 1;
+    }
+
+    fn snapshot(&self) -> BTreeMap<String, u64> {
+        self.0.lock().unwrap().clone()
+    }
+}
+
+/// Unique mesh packets by port name, after de-duplication.
+pub static PACKETS: LabeledCounter = LabeledCounter::new();
+/// Packets that could not be decoded, by `DecodeError` variant. Packets on
+/// ports the monitor doesn't decode only show up in `PACKETS`.
+pub static DECODE_ERRORS: LabeledCounter = LabeledCounter::new();
+/// Radio reconnect attempts, by connection id; see `sources::spawn_radio`.
+pub static RECONNECTS: LabeledCounter = LabeledCounter::new();
+/// Texts for the mesh, by `sent` or why they were dropped.
+pub static MESH_TEXTS: LabeledCounter = LabeledCounter::new();
+/// Telemetry lines that never reached InfluxDB.
+pub static INFLUX_DROPPED_LINES: AtomicU64 = AtomicU64::new(0);
+pub static RECORDER_BYTES: AtomicU64 = AtomicU64::new(0);
+pub static RECORDER_ROTATIONS: AtomicU64 = AtomicU64::new(0);
+
+pub fn decode_error(error: &DecodeError) {
+    DECODE_ERRORS.inc(error.variant_name());
+}
+
+/* ---------------- Exposition ---------------- */
+
+/// Per-node gauges: (metric name, reading, help). A node whose latest
+/// reading failed a data-quality check has no sample until a good one.
+const NODE_GAUGES: &[(&str, &str, &str)] = &[
+    (
+        "flood_monitor_water_level",
+        "water_level",
+        "Calibrated water level",
+    ),
+    (
+        "flood_monitor_battery_level",
+        "battery_level",
+        "Battery level in percent",
+    ),
+    (
+        "flood_monitor_voltage",
+        "voltage",
+        "Battery voltage in volts",
+    ),
+    (
+        "flood_monitor_temperature",
+        "temperature",
+        "Temperature in degrees Celsius",
+    ),
+    (
+        "flood_monitor_rain_1h",
+        "rain_1h",
+        "Rain over the last hour in mm",
+    ),
+    (
+        "flood_monitor_rain_24h",
+        "rain_24h",
+        "Rain over the last 24 hours in mm",
+    ),
+    (
+        "flood_monitor_rain_intensity",
+        "rain_intensity",
+        "Recent rain intensity in mm per hour",
+    ),
+];
+
+/// Renders everything in the Prometheus text exposition format. `now` is
+/// the current Unix time, for seconds since last heard.
+pub fn render(store: &Store, now: u64) -> String {
+    let mut out = String::new();
+
+    for &(name, reading, help) in NODE_GAUGES {
+        node_gauge(&mut out, store, name, help, |node| {
+            node.readings
+                .get(reading)
+                .filter(|s| s.quality.is_good())
+                .map(|s| s.value)
+        });
+    }
+    node_gauge(
+        &mut out,
+        store,
+        "flood_monitor_rx_snr",
+        "SNR of the best copy of the last packet heard from the node, in dB",
+        |node| node.rx_snr.map(f64::from),
+    );
+    node_gauge(
+        &mut out,
+        store,
+        "flood_monitor_rx_rssi",
+        "RSSI of the best copy of the last packet heard from the node, in dBm",
+        |node| node.rx_rssi.map(f64::from),
+    );
+    node_gauge(
+        &mut out,
+        store,
+        "flood_monitor_seconds_since_last_heard",
+        "Seconds since any packet was heard from the node",
+        |node| node.last_heard.map(|t| now.saturating_sub(t) as f64),
+    );
+
+    labeled_counter(
+        &mut out,
+        "flood_monitor_packets_total",
+        "Unique mesh packets received, by port",
+        "port",
+        &PACKETS,
+    );
+    labeled_counter(
+        &mut out,
+        "flood_monitor_decode_errors_total",
+        "Mesh packets that failed to decode, by error",
+        "error",
+        &DECODE_ERRORS,
+    );
+    labeled_counter(
+        &mut out,
+        "flood_monitor_reconnects_total",
+        "Radio reconnect attempts, by connection",
+        "connection",
+        &RECONNECTS,
+    );
+    labeled_counter(
+        &mut out,
+        "flood_monitor_mesh_texts_total",
+        "Text messages the monitor sent onto the mesh or dropped, by outcome",
+        "outcome",
+        &MESH_TEXTS,
+    );
+
+    header(
+        &mut out,
+        "flood_monitor_influx_dropped_lines_total",
+        "counter",
+        "Line protocol lines dropped on a full queue or after failed writes",
+    );
+    sample_line(
+        &mut out,
+        "flood_monitor_influx_dropped_lines_total",
+        "",
+        INFLUX_DROPPED_LINES.load(Ordering::Relaxed) as f64,
+    );
+    header(
+        &mut out,
+        "flood_monitor_recorder_bytes_written_total",
+        "counter",
+        "Bytes written to .bin recordings",
+    );
+    sample_line(
+        &mut out,
+        "flood_monitor_recorder_bytes_written_total",
+        "",
+        RECORDER_BYTES.load(Ordering::Relaxed) as f64,
+    );
+    header(
+        &mut out,
+        "flood_monitor_recorder_rotations_total",
+        "counter",
+        "Recording file rotations",
+    );
+    sample_line(
+        &mut out,
+        "flood_monitor_recorder_rotations_total",
+        "",
+        RECORDER_ROTATIONS.load(Ordering::Relaxed) as f64,
+    );
+
+    out
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+fn sample_line(out: &mut String, name: &str, labels: &str, value: f64) {
+    let _ = writeln!(out, "{}{} {}", name, labels, value);
+}
+
+/// One gauge with a sample per node that has a value for it.
+fn node_gauge(
+    out: &mut String,
+    store: &Store,
+    name: &str,
+    help: &str,
+    value: impl Fn(&NodeStatus) -> Option<f64>,
+) {
+    header(out, name, "gauge", help);
+    for node in store.nodes() {
+        if let Some(value) = value(node) {
+            sample_line(out, name, &node_labels(node), value);
+        }
+    }
+}
+
+fn node_labels(node: &NodeStatus) -> String {
+    format!(
+        "{{node=\"{}\",name=\"{}\"}}",
+        node.id,
+        escape_label(&node.name)
+    )
+}
+
+fn labeled_counter(
+    out: &mut String,
+    name: &str,
+    help: &str,
+    label: &str,
+    counter: &LabeledCounter,
+) {
+    header(out, name, "counter", help);
+    for (value, count) in counter.snapshot() {
+        let labels = format!("{{{}=\"{}\"}}", label, escape_label(&value));
+        sample_line(out, name, &labels, count as f64);
+    }
+}
+
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::handler::DecodedMessage;
+    use crate::quality::Quality;
+    use crate::radio_message::{AppMessage, Telemetry};
+
+    #[test]
+    fn renders_node_gauges_with_labels() {
+        let config = Config::parse(
+            r#"
+            [[nodes]]
+            id = 5
+            alias = "the \"bridge\""
+            "#,
+        )
+        .unwrap();
+        let mut store = Store::new(&config);
+        let app = AppMessage::Telemetry(Telemetry::Device {
+            battery_level: Some(87),
+            voltage: Some(39.0),
+            uptime_seconds: None,
+        });
+        store.record_message(&DecodedMessage {
+            rx_snr: 6.25,
+            rx_rssi: -97,
+            quality: vec![("voltage", Quality::OutOfRange)],
+            ..DecodedMessage::test(
+                5,
+                "the \"bridge\"",
+                1_000,
+                app,
+                vec![("battery_level", 87.0), ("voltage", 39.0)],
+            )
+        });
+
+        let text = render(&store, 1_030);
+        let labels = r#"{node="!00000005",name="the \"bridge\""}"#;
+        assert!(text.contains(&format!("flood_monitor_battery_level{} 87\n", labels)));
+        // Flagged readings are left out
+        assert!(!text.contains("flood_monitor_voltage{"));
+        assert!(text.contains(&format!("flood_monitor_rx_snr{} 6.25\n", labels)));
+        assert!(text.contains(&format!("flood_monitor_rx_rssi{} -97\n", labels)));
+        assert!(text.contains(&format!(
+            "flood_monitor_seconds_since_last_heard{} 30\n",
+            labels
+        )));
+        assert!(text.contains("# TYPE flood_monitor_packets_total counter\n"));
+    }
+}
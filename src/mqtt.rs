@@ -0,0 +1,352 @@
+use std::time::Duration;
+
+use rumqttc::{AsyncClient, ClientError, Event, MqttOptions, Packet, QoS};
+use serde_json::json;
+
+use crate::alerts::AlertEvent;
+use crate::config::{MqttSinkConfig, NodeId};
+use crate::feed::FeedEvent;
+use crate::handler::DecodedMessage;
+use crate::radio_message::AppMessage;
+use crate::sinks::Sink;
+
+/// Wait between attempts while the broker is unreachable.
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+/// One MQTT message to publish.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Publication {
+    pub topic: String,
+    pub payload: String,
+    pub retain: bool,
+}
+
+/// Publishes readings and alerts to an MQTT broker. Publishing never
+/// blocks the pipeline: messages queue in the client's request channel,
+/// `buffer` deep, while the broker is unreachable and the event loop task
+/// keeps reconnecting. Messages that don't fit are dropped.
+pub struct MqttSink {
+    client: AsyncClient,
+    config: MqttSinkConfig,
+    qos: QoS,
+}
+
+impl MqttSink {
+    /// Creates the client and spawns its event loop; must be called from
+    /// within the Tokio runtime.
+    pub fn spawn(config: &MqttSinkConfig) -> Self {
+        let mut options = MqttOptions::new(&config.client_id, &config.host, config.port);
+        options.set_keep_alive(Duration::from_secs(30));
+        // Resume the session so QoS 1/2 messages in flight survive a reconnect
+        options.set_clean_session(false);
+        if let Some(username) = &config.username {
+            options.set_credentials(username, config.password.as_deref().unwrap_or_default());
+        }
+
+        let (client, mut event_loop) = AsyncClient::new(options, config.buffer);
+        let broker = format!("{}:{}", config.host, config.port);
+        log::info!("Publishing to MQTT broker {}", broker);
+
+        tokio::spawn(async move {
+            let mut connected = false;
+            loop {
+                match event_loop.poll().await {
+                    Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                        log::info!("Connected to MQTT broker {}", broker);
+                        connected = true;
+                    }
+                    Ok(_) => {}
+                    Err(e) => {
+                        if connected {
+                            log::warn!("Lost MQTT broker {}: {}", broker, e);
+                        } else {
+                            log::debug!("MQTT broker {} unreachable: {}", broker, e);
+                        }
+                        connected = false;
+                        tokio::time::sleep(RECONNECT_DELAY).await;
+                    }
+                }
+            }
+        });
+
+        Self {
+            client,
+            config: config.clone(),
+            qos: rumqttc::qos(config.qos).unwrap_or(QoS::AtLeastOnce),
+        }
+    }
+
+    fn publish(&self, publication: Publication) {
+        let topic = publication.topic.clone();
+        match self.client.try_publish(
+            publication.topic,
+            self.qos,
+            publication.retain,
+            publication.payload,
+        ) {
+            Ok(()) => {}
+            Err(ClientError::TryRequest(_)) => {
+                log::warn!("MQTT buffer full, dropping message for {}", topic);
+            }
+            Err(e) => log::warn!("MQTT publish to {} failed: {}", topic, e),
+        }
+    }
+}
+
+impl Sink for MqttSink {
+    fn name(&self) -> &'static str {
+        "mqtt"
+    }
+
+    fn on_message(&mut self, msg: &DecodedMessage) {
+        for publication in message_publications(&self.config, msg) {
+            self.publish(publication);
+        }
+    }
+
+    fn on_alert(&mut self, event: &AlertEvent) {
+        self.publish(alert_publication(&self.config, event));
+    }
+}
+
+/* ---------------- Topics ---------------- */
+
+/// A node name usable as a single topic level.
+fn topic_level(name: &str) -> String {
+    name.chars()
+        .map(|c| match c {
+            '/' | '+' | '#' => '_',
+            c if c.is_whitespace() => '_',
+            c => c,
+        })
+        .collect()
+}
+
+/// `<prefix>/<node>/<metric>` per telemetry reading with its quality code,
+/// `<prefix>/<node>/position` and `<prefix>/<node>/text`. Text messages
+/// are never retained.
+pub fn message_publications(config: &MqttSinkConfig, msg: &DecodedMessage) -> Vec<Publication> {
+    let node_id = NodeId(msg.message.node_id);
+    let base = format!("{}/{}", config.topic_prefix, topic_level(&msg.node_name));
+    let publication = |suffix: &str, payload: serde_json::Value, retain: bool| Publication {
+        topic: format!("{}/{}", base, suffix),
+        payload: payload.to_string(),
+        retain,
+    };
+
+    match &msg.message.app {
+        // Detection and serial packets carry the readings of rain gauges
+        AppMessage::Telemetry(_) | AppMessage::Detection(_) | AppMessage::Serial(_) => msg
+            .readings
+            .iter()
+            .map(|(metric, value)| {
+                publication(
+                    metric,
+                    json!({
+                        "node": node_id,
+                        "name": msg.node_name,
+                        "metric": metric,
+                        "value": value,
+                        "quality": msg.quality(metric),
+                        "timestamp": msg.timestamp,
+                    }),
+                    config.retain,
+                )
+            })
+            .collect(),
+        AppMessage::Position(pos) => vec![publication(
+            "position",
+            json!({
+                "node": node_id,
+                "name": msg.node_name,
+                "latitude": pos.latitude,
+                "longitude": pos.longitude,
+                "altitude": pos.altitude,
+                "timestamp": msg.timestamp,
+            }),
+            config.retain,
+        )],
+        AppMessage::Text(text) => vec![publication(
+            "text",
+            json!({
+                "node": node_id,
+                "name": msg.node_name,
+                "text": text.msg,
+                "timestamp": msg.timestamp,
+            }),
+            false,
+        )],
+    }
+}
+
+/// `<alert_topic>/<node>/<rule>`, retained so a subscriber sees whether
+/// the alert is currently raised.
+pub fn alert_publication(config: &MqttSinkConfig, event: &AlertEvent) -> Publication {
+    Publication {
+        topic: format!(
+            "{}/{}/{}",
+            config.alert_topic,
+            topic_level(&event.node_name),
+            topic_level(&event.rule)
+        ),
+        payload: FeedEvent::alert(event).json,
+        retain: config.retain,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+    use std::net::{TcpListener, TcpStream};
+
+    use super::*;
+    use crate::alerts::AlertState;
+    use crate::config::Severity;
+    use crate::radio_message::{Telemetry, TextMessage};
+
+    fn message(app: AppMessage, readings: Vec<(&'static str, f64)>) -> DecodedMessage {
+        DecodedMessage::test(5, "main st/bridge", 1_000, app, readings)
+    }
+
+    #[test]
+    fn publishes_one_retained_topic_per_reading() {
+        let config = MqttSinkConfig::default();
+        let msg = message(
+            AppMessage::Telemetry(Telemetry::Environment {
+                temperature: None,
+                humidity: None,
+                pressure: None,
+                distance: Some(2500.0),
+                rainfall_1h: None,
+                rainfall_24h: None,
+            }),
+            vec![("distance", 2500.0), ("water_level", 3.5)],
+        );
+
+        let publications = message_publications(&config, &msg);
+        let topics: Vec<&str> = publications.iter().map(|p| p.topic.as_str()).collect();
+        assert_eq!(
+            topics,
+            vec![
+                "flood/main_st_bridge/distance",
+                "flood/main_st_bridge/water_level"
+            ]
+        );
+        assert!(publications.iter().all(|p| p.retain));
+
+        let payload: serde_json::Value = serde_json::from_str(&publications[1].payload).unwrap();
+        assert_eq!(payload["node"], "!00000005");
+        assert_eq!(payload["value"], 3.5);
+        assert_eq!(payload["timestamp"], 1_000);
+    }
+
+    #[test]
+    fn text_is_not_retained_and_alerts_use_their_own_topic() {
+        let config = MqttSinkConfig::default();
+        let text = message(
+            AppMessage::Text(TextMessage {
+                to: None,
+                from: None,
+                msg: "hello".to_string(),
+            }),
+            Vec::new(),
+        );
+        let publications = message_publications(&config, &text);
+        assert_eq!(publications[0].topic, "flood/main_st_bridge/text");
+        assert!(!publications[0].retain);
+
+        let alert = alert_publication(
+            &config,
+            &AlertEvent {
+                rule: "flood-stage".to_string(),
+                timestamp: 1_000,
+                node_id: 5,
+                node_name: "bridge".to_string(),
+                metric: "water_level".to_string(),
+                value: 4.5,
+                threshold: 4.2,
+                expected_at: None,
+                severity: Severity::Critical,
+                state: AlertState::Raised,
+            },
+        );
+        assert_eq!(alert.topic, "flood/alerts/bridge/flood-stage");
+        assert!(alert.payload.contains(r#""state":"raised""#));
+    }
+
+    /// A broker that accepts one client, acknowledges its CONNECT and
+    /// returns the first message it publishes.
+    fn receive_one(listener: TcpListener) -> Publication {
+        let (mut stream, _) = listener.accept().unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(10)))
+            .unwrap();
+        let (header, _) = read_packet(&mut stream);
+        assert_eq!(header >> 4, 1, "expected CONNECT");
+        stream.write_all(&[0x20, 2, 0, 0]).unwrap();
+
+        loop {
+            let (header, body) = read_packet(&mut stream);
+            if header >> 4 != 3 {
+                continue;
+            }
+            let topic_len = u16::from_be_bytes([body[0], body[1]]) as usize;
+            let topic = String::from_utf8(body[2..2 + topic_len].to_vec()).unwrap();
+            // QoS 1 and 2 carry a packet id after the topic
+            let packet_id_len = if (header >> 1) & 3 > 0 { 2 } else { 0 };
+            let payload = &body[2 + topic_len + packet_id_len..];
+            return Publication {
+                topic,
+                payload: String::from_utf8(payload.to_vec()).unwrap(),
+                retain: header & 1 == 1,
+            };
+        }
+    }
+
+    /// Fixed header byte and body of one MQTT packet.
+    fn read_packet(stream: &mut TcpStream) -> (u8, Vec<u8>) {
+        let mut byte = [0; 1];
+        stream.read_exact(&mut byte).unwrap();
+        let header = byte[0];
+        let (mut len, mut shift) = (0, 0);
+        loop {
+            stream.read_exact(&mut byte).unwrap();
+            len |= ((byte[0] & 0x7f) as usize) << shift;
+            shift += 7;
+            if byte[0] & 0x80 == 0 {
+                break;
+            }
+        }
+        let mut body = vec![0; len];
+        stream.read_exact(&mut body).unwrap();
+        (header, body)
+    }
+
+    #[tokio::test]
+    async fn publishes_to_the_broker_once_connected() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let config = MqttSinkConfig {
+            host: "127.0.0.1".to_string(),
+            port: listener.local_addr().unwrap().port(),
+            ..MqttSinkConfig::default()
+        };
+        let broker = std::thread::spawn(move || receive_one(listener));
+
+        // Published before the client has connected, so it waits in the queue
+        let msg = message(
+            AppMessage::Telemetry(Telemetry::Device {
+                battery_level: Some(90),
+                voltage: None,
+                uptime_seconds: None,
+            }),
+            vec![("battery_level", 90.0)],
+        );
+        let mut sink = MqttSink::spawn(&config);
+        sink.on_message(&msg);
+
+        let received = tokio::task::spawn_blocking(move || broker.join().unwrap())
+            .await
+            .unwrap();
+        assert_eq!(received, message_publications(&config, &msg).remove(0));
+    }
+}
@@ -0,0 +1,71 @@
+use std::collections::HashMap;
+
+use crate::config::{Calibration, Config, NodeId};
+use crate::radio_message::Telemetry;
+
+/// Operator-facing names and sensor calibrations for known nodes.
+#[derive(Debug, Clone, Default)]
+pub struct NodeDirectory {
+    aliases: HashMap<u32, String>,
+    calibrations: HashMap<u32, Calibration>,
+    /// Names the nodes' owners set, from the radio's node database
+    long_names: HashMap<u32, String>,
+}
+
+impl NodeDirectory {
+    pub fn from_config(config: &Config) -> Self {
+        let mut directory = Self::default();
+        for node in &config.nodes {
+            if let Some(alias) = &node.alias {
+                directory.aliases.insert(node.id.0, alias.clone());
+            }
+            if let Some(cal) = node.calibration {
+                directory.calibrations.insert(node.id.0, cal);
+            }
+        }
+        directory
+    }
+
+    /// Takes aliases and calibrations from a reloaded config, keeping the
+    /// names learned from the radio.
+    pub fn reconfigure(&mut self, config: &Config) {
+        let long_names = std::mem::take(&mut self.long_names);
+        *self = Self {
+            long_names,
+            ..Self::from_config(config)
+        };
+    }
+
+    /// Remembers the long name a node's owner set.
+    pub fn learn_name(&mut self, node_id: u32, long_name: &str) {
+        self.long_names.insert(node_id, long_name.to_string());
+    }
+
+    /// The configured alias, else the node's long name, else the
+    /// `!a1b2c3d4` form for unknown nodes.
+    pub fn display_name(&self, node_id: u32) -> String {
+        self.aliases
+            .get(&node_id)
+            .or_else(|| self.long_names.get(&node_id))
+            .cloned()
+            .unwrap_or_else(|| NodeId(node_id).to_string())
+    }
+
+    /// Metric readings for a telemetry packet, with `water_level` added for
+    /// nodes that have a calibration and reported a `distance`.
+    pub fn readings(&self, node_id: u32, telemetry: &Telemetry) -> Vec<(&'static str, f64)> {
+        let mut readings = telemetry.metrics();
+
+        if let Some(cal) = self.calibrations.get(&node_id) {
+            let distance = readings
+                .iter()
+                .find(|(name, _)| *name == "distance")
+                .map(|(_, v)| *v);
+            if let Some(distance) = distance {
+                readings.push(("water_level", cal.apply(distance)));
+            }
+        }
+
+        readings
+    }
+}
@@ -0,0 +1,956 @@
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::ops::RangeInclusive;
+use std::sync::mpsc::{self, SyncSender, TrySendError};
+use std::thread;
+use std::time::Duration;
+
+use lettre::Transport as _;
+use lettre::message::header::ContentType;
+use lettre::message::{Mailbox, Message};
+use lettre::transport::smtp::SmtpTransport;
+use lettre::transport::smtp::authentication::Credentials;
+use serde_json::Value;
+
+use crate::alerts::{AlertEvent, AlertState, lead_time};
+use crate::config::{
+    AlertLifecycleConfig, NodeId, NotifierConfig, NotifierKind, Severity, SmtpTls,
+};
+use crate::feed::FeedEvent;
+use crate::sinks::Sink;
+use crate::store::SharedStore;
+use crate::table::Timezone;
+
+/// Notifications held per notifier while earlier ones are being sent.
+const QUEUE_CAPACITY: usize = 1000;
+
+/// First wait before retrying a failed notification, doubled per attempt.
+const RETRY_DELAY: Duration = if cfg!(test) {
+    Duration::from_millis(10)
+} else {
+    Duration::from_secs(2)
+};
+
+/// Per request or SMTP session.
+const SEND_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How long `flush` waits for queued notifications at shutdown.
+const FLUSH_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Names usable as `{{placeholder}}` in templates.
+pub const PLACEHOLDERS: &[&str] = &[
+    "rule",
+    "node",
+    "node_name",
+    "metric",
+    "value",
+    "threshold",
+    "expected",
+    "expected_in",
+    "severity",
+    "state",
+    "time",
+    "timestamp",
+    "text",
+    "to",
+];
+
+const DEFAULT_SMS_TEMPLATE: &str = r#"{"to": "{{to}}", "message": "{{text}}"}"#;
+const DEFAULT_SUBJECT: &str = "[{{severity}}] {{node_name}}: {{rule}} {{state}}";
+const DEFAULT_EMAIL_BODY: &str = "{{text}}
+
+Rule:      {{rule}}
+Node:      {{node_name}} ({{node}})
+Metric:    {{metric}} = {{value}}, threshold {{threshold}}
+Severity:  {{severity}}
+Time:      {{time}}
+";
+
+/* ---------------- Templates ---------------- */
+
+/// One line for SMS and chat, e.g.
+/// `CRITICAL bridge: water_level 4.21, threshold 4.00 (flood-stage raised)`,
+/// ending in `, expected in ~2h40m` for a raised forecast.
+pub fn summary(event: &AlertEvent) -> String {
+    let state = match event.state {
+        AlertState::Raised => "raised",
+        AlertState::Cleared => "cleared",
+    };
+    let mut summary = format!(
+        "{} {}: {} {:.2}, threshold {:.2} ({} {})",
+        event.severity.to_string().to_uppercase(),
+        event.node_name,
+        event.metric,
+        event.value,
+        event.threshold,
+        event.rule,
+        state
+    );
+    if let (Some(at), AlertState::Raised) = (event.expected_at, event.state) {
+        summary.push_str(&format!(
+            ", expected in {}",
+            lead_time(at.saturating_sub(event.timestamp))
+        ));
+    }
+    summary
+}
+
+pub fn fields(event: &AlertEvent, to: Option<&str>) -> Vec<(&'static str, String)> {
+    let state = match event.state {
+        AlertState::Raised => "raised",
+        AlertState::Cleared => "cleared",
+    };
+    vec![
+        ("rule", event.rule.clone()),
+        ("node", NodeId(event.node_id).to_string()),
+        ("node_name", event.node_name.clone()),
+        ("metric", event.metric.clone()),
+        ("value", event.value.to_string()),
+        ("threshold", event.threshold.to_string()),
+        (
+            "expected",
+            event
+                .expected_at
+                .map(|at| Timezone::Utc.format(at))
+                .unwrap_or_default(),
+        ),
+        (
+            "expected_in",
+            event
+                .expected_at
+                .map(|at| lead_time(at.saturating_sub(event.timestamp)))
+                .unwrap_or_default(),
+        ),
+        ("severity", event.severity.to_string()),
+        ("state", state.to_string()),
+        ("time", Timezone::Utc.format(event.timestamp)),
+        ("timestamp", event.timestamp.to_string()),
+        ("text", summary(event)),
+        ("to", to.unwrap_or_default().to_string()),
+    ]
+}
+
+/// Substitutes `{{placeholder}}`s. With `json`, values are escaped for use
+/// inside a JSON string, which leaves numbers as they are.
+pub fn render(template: &str, fields: &[(&str, String)], json: bool) -> Result<String, String> {
+    let mut out = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find("{{") {
+        out.push_str(&rest[..start]);
+        let after = &rest[start + 2..];
+        let end = after
+            .find("}}")
+            .ok_or_else(|| "unterminated `{{`".to_string())?;
+        let name = after[..end].trim();
+        let value = fields
+            .iter()
+            .find(|(field, _)| *field == name)
+            .map(|(_, value)| value)
+            .ok_or_else(|| {
+                format!(
+                    "unknown placeholder `{{{{{}}}}}`, expected one of {}",
+                    name,
+                    PLACEHOLDERS.join(", ")
+                )
+            })?;
+        if json {
+            let quoted = Value::String(value.clone()).to_string();
+            out.push_str(&quoted[1..quoted.len() - 1]);
+        } else {
+            out.push_str(value);
+        }
+        rest = &after[end + 2..];
+    }
+    out.push_str(rest);
+    Ok(out)
+}
+
+/// Request body for a webhook or SMS gateway. Without a template, a
+/// webhook gets the alert as on the live feed plus `text`, which Slack and
+/// Teams display as it is.
+fn http_body(
+    config: &NotifierConfig,
+    event: &AlertEvent,
+    to: Option<&str>,
+) -> Result<String, String> {
+    let template = match (&config.template, config.kind) {
+        (Some(template), _) => template.as_str(),
+        (None, NotifierKind::Sms) => DEFAULT_SMS_TEMPLATE,
+        (None, _) => {
+            let mut body: Value =
+                serde_json::from_str(&FeedEvent::alert(event).json).map_err(|e| e.to_string())?;
+            body["text"] = Value::String(summary(event));
+            if let Some(to) = to {
+                body["to"] = Value::String(to.to_string());
+            }
+            return Ok(body.to_string());
+        }
+    };
+    let body = render(template, &fields(event, to), true)?;
+    serde_json::from_str::<Value>(&body)
+        .map_err(|e| format!("template does not render to JSON: {}", e))?;
+    Ok(body)
+}
+
+/// Problems with a notifier's templates, found by rendering them for a
+/// sample alert.
+pub fn check_templates(config: &NotifierConfig) -> Vec<String> {
+    let sample = AlertEvent {
+        rule: "flood-stage".to_string(),
+        timestamp: 0,
+        node_id: 1,
+        node_name: "gauge \"one\"".to_string(),
+        metric: "water_level".to_string(),
+        value: 4.2,
+        threshold: 4.0,
+        expected_at: None,
+        severity: Severity::Critical,
+        state: AlertState::Raised,
+    };
+    let fields = fields(&sample, Some("recipient"));
+    let mut problems = Vec::new();
+    match config.kind {
+        NotifierKind::Webhook | NotifierKind::Sms => {
+            if let Err(e) = http_body(config, &sample, Some("recipient")) {
+                problems.push(e);
+            }
+        }
+        NotifierKind::Smtp => {
+            for template in [&config.subject, &config.template].into_iter().flatten() {
+                if let Err(e) = render(template, &fields, false) {
+                    problems.push(e);
+                }
+            }
+        }
+    }
+    problems
+}
+
+/* ---------------- Routing ---------------- */
+
+/// Whether an alert passes a `min_severity` and `nodes` filter.
+pub fn routes(min_severity: Option<Severity>, nodes: &[String], event: &AlertEvent) -> bool {
+    min_severity.is_none_or(|min| event.severity >= min)
+        && (nodes.is_empty()
+            || nodes.iter().any(|n| {
+                *n == event.node_name || n.parse::<NodeId>().is_ok_and(|id| id.0 == event.node_id)
+            }))
+}
+
+/// Indices of the recipients in contact `tiers` an alert goes to; a
+/// notifier without recipients has the single implicit recipient `None`.
+fn route(
+    config: &NotifierConfig,
+    event: &AlertEvent,
+    tiers: &RangeInclusive<u32>,
+) -> Vec<Option<usize>> {
+    if event.state == AlertState::Cleared && !config.notify_cleared {
+        return Vec::new();
+    }
+    if !routes(config.min_severity, &config.nodes, event) {
+        return Vec::new();
+    }
+    if config.recipients.is_empty() {
+        return if tiers.contains(&config.tier) {
+            vec![None]
+        } else {
+            Vec::new()
+        };
+    }
+    config
+        .recipients
+        .iter()
+        .enumerate()
+        .filter(|(_, r)| tiers.contains(&r.tier.unwrap_or(config.tier)))
+        .filter(|(_, r)| routes(r.min_severity, &r.nodes, event))
+        .map(|(i, _)| Some(i))
+        .collect()
+}
+
+/// At most `max_per_hour` notifications per recipient in any hour.
+#[derive(Debug, Default)]
+pub struct RateLimiter {
+    max_per_hour: usize,
+    sent: HashMap<Option<usize>, VecDeque<u64>>,
+}
+
+impl RateLimiter {
+    pub fn new(max_per_hour: usize) -> Self {
+        Self {
+            max_per_hour,
+            ..Self::default()
+        }
+    }
+
+    pub fn allow(&mut self, recipient: Option<usize>, now: u64) -> bool {
+        let sent = self.sent.entry(recipient).or_default();
+        while sent.front().is_some_and(|&t| now.saturating_sub(t) >= 3600) {
+            sent.pop_front();
+        }
+        if sent.len() >= self.max_per_hour {
+            return false;
+        }
+        sent.push_back(now);
+        true
+    }
+}
+
+/* ---------------- Notifiers ---------------- */
+
+/// One notification; `to` is empty for a webhook without recipients.
+struct Delivery {
+    event: AlertEvent,
+    to: Vec<String>,
+}
+
+enum Command {
+    Deliver(Delivery),
+    /// Send whatever is queued, then acknowledge.
+    Flush(mpsc::Sender<()>),
+}
+
+/// Routes and rate-limits alerts for one `[[notifiers]]` entry inline;
+/// sending and retries happen on a thread of its own so a slow mail server
+/// never holds up the pipeline.
+pub struct Notifier {
+    config: NotifierConfig,
+    limiter: RateLimiter,
+    queue: SyncSender<Command>,
+    suppressed: u64,
+}
+
+impl Notifier {
+    pub fn spawn(config: &NotifierConfig) -> io::Result<Self> {
+        let transport = Transport::new(config)?;
+        let (queue, commands) = mpsc::sync_channel(QUEUE_CAPACITY);
+        let worker_config = config.clone();
+        thread::Builder::new()
+            .name(format!("notify-{}", config.name))
+            .spawn(move || {
+                for command in commands {
+                    match command {
+                        Command::Deliver(delivery) => {
+                            transport.deliver(&worker_config, &delivery);
+                        }
+                        Command::Flush(ack) => {
+                            let _ = ack.send(());
+                        }
+                    }
+                }
+            })?;
+
+        Ok(Self {
+            config: config.clone(),
+            limiter: RateLimiter::new(config.max_per_hour as usize),
+            queue,
+            suppressed: 0,
+        })
+    }
+
+    /// Highest contact tier of any recipient.
+    fn max_tier(&self) -> u32 {
+        self.config
+            .recipients
+            .iter()
+            .map(|r| r.tier.unwrap_or(self.config.tier))
+            .chain([self.config.tier])
+            .max()
+            .unwrap_or_default()
+    }
+
+    fn notify(&mut self, event: &AlertEvent, tiers: &RangeInclusive<u32>) {
+        let mut allowed = Vec::new();
+        for recipient in route(&self.config, event, tiers) {
+            if self.limiter.allow(recipient, event.timestamp) {
+                allowed.push(recipient);
+            } else {
+                self.suppressed += 1;
+                log::warn!(
+                    "Notifier {} rate limited, not sending {} to {} ({} suppressed so far)",
+                    self.config.name,
+                    event.rule,
+                    recipient.map_or("the webhook", |i| &self.config.recipients[i].to),
+                    self.suppressed
+                );
+            }
+        }
+
+        let to: Vec<String> = allowed
+            .iter()
+            .flatten()
+            .map(|&i| self.config.recipients[i].to.clone())
+            .collect();
+        let deliveries = match self.config.kind {
+            // One email to everyone
+            NotifierKind::Smtp if !to.is_empty() => vec![to],
+            NotifierKind::Smtp => Vec::new(),
+            // One request per recipient
+            NotifierKind::Webhook | NotifierKind::Sms => {
+                let mut each: Vec<Vec<String>> = to.into_iter().map(|t| vec![t]).collect();
+                if allowed.contains(&None) {
+                    each.push(Vec::new());
+                }
+                each
+            }
+        };
+
+        for to in deliveries {
+            let delivery = Delivery {
+                event: event.clone(),
+                to,
+            };
+            if let Err(TrySendError::Full(_)) = self.queue.try_send(Command::Deliver(delivery)) {
+                log::warn!(
+                    "Notifier {} queue full, dropping {}",
+                    self.config.name,
+                    event.rule
+                );
+            }
+        }
+    }
+
+    fn flush(&self) {
+        let (ack, done) = mpsc::channel();
+        if self.queue.send(Command::Flush(ack)).is_ok() && done.recv_timeout(FLUSH_TIMEOUT).is_err()
+        {
+            log::warn!("Timed out sending notifications for {}", self.config.name);
+        }
+    }
+}
+
+enum Transport {
+    Http {
+        agent: ureq::Agent,
+        url: String,
+    },
+    Smtp {
+        transport: Box<SmtpTransport>,
+        from: Mailbox,
+    },
+}
+
+impl Transport {
+    fn new(config: &NotifierConfig) -> io::Result<Self> {
+        let invalid = |e: String| io::Error::new(io::ErrorKind::InvalidInput, e);
+        match config.kind {
+            NotifierKind::Webhook | NotifierKind::Sms => {
+                let agent = ureq::Agent::config_builder()
+                    .timeout_global(Some(SEND_TIMEOUT))
+                    .build()
+                    .into();
+                Ok(Transport::Http {
+                    agent,
+                    url: config.url.clone().unwrap_or_default(),
+                })
+            }
+            NotifierKind::Smtp => {
+                let host = config.host.as_deref().unwrap_or_default();
+                let builder = match config.tls {
+                    SmtpTls::None => SmtpTransport::builder_dangerous(host),
+                    SmtpTls::Starttls => {
+                        SmtpTransport::starttls_relay(host).map_err(|e| invalid(e.to_string()))?
+                    }
+                    SmtpTls::Tls => {
+                        SmtpTransport::relay(host).map_err(|e| invalid(e.to_string()))?
+                    }
+                };
+                let mut builder = builder.port(config.port).timeout(Some(SEND_TIMEOUT));
+                if let Some(username) = &config.username {
+                    builder = builder.credentials(Credentials::new(
+                        username.clone(),
+                        config.password.clone().unwrap_or_default(),
+                    ));
+                }
+                let from = config
+                    .from
+                    .as_deref()
+                    .unwrap_or_default()
+                    .parse()
+                    .map_err(|e: lettre::address::AddressError| invalid(e.to_string()))?;
+                Ok(Transport::Smtp {
+                    transport: Box::new(builder.build()),
+                    from,
+                })
+            }
+        }
+    }
+
+    fn send(&self, config: &NotifierConfig, delivery: &Delivery) -> Result<(), String> {
+        let event = &delivery.event;
+        match self {
+            Transport::Http { agent, url } => {
+                let body = http_body(config, event, delivery.to.first().map(String::as_str))?;
+                let mut request = agent
+                    .post(url.as_str())
+                    .header("Content-Type", "application/json");
+                for (name, value) in &config.headers {
+                    request = request.header(name.as_str(), value.as_str());
+                }
+                request.send(body).map(|_| ()).map_err(|e| e.to_string())
+            }
+            Transport::Smtp { transport, from } => {
+                let fields = fields(event, Some(&delivery.to.join(", ")));
+                let subject = render(
+                    config.subject.as_deref().unwrap_or(DEFAULT_SUBJECT),
+                    &fields,
+                    false,
+                )?;
+                let body = render(
+                    config.template.as_deref().unwrap_or(DEFAULT_EMAIL_BODY),
+                    &fields,
+                    false,
+                )?;
+
+                let mut message = Message::builder()
+                    .from(from.clone())
+                    .subject(subject)
+                    .header(ContentType::TEXT_PLAIN);
+                for to in &delivery.to {
+                    message = message.to(to
+                        .parse()
+                        .map_err(|e: lettre::address::AddressError| e.to_string())?);
+                }
+                let message = message.body(body).map_err(|e| e.to_string())?;
+                transport
+                    .send(&message)
+                    .map(|_| ())
+                    .map_err(|e| e.to_string())
+            }
+        }
+    }
+
+    /// Sends, retrying with backoff before giving up.
+    fn deliver(&self, config: &NotifierConfig, delivery: &Delivery) {
+        let mut delay = RETRY_DELAY;
+        for attempt in 0..=config.max_retries {
+            match self.send(config, delivery) {
+                Ok(()) => {
+                    log::info!(
+                        "Notifier {} sent {} for {}",
+                        config.name,
+                        delivery.event.rule,
+                        delivery.event.node_name
+                    );
+                    return;
+                }
+                Err(e) if attempt == config.max_retries => {
+                    log::error!(
+                        "Notifier {} dropping {} after {} attempts: {}",
+                        config.name,
+                        delivery.event.rule,
+                        attempt + 1,
+                        e
+                    );
+                }
+                Err(e) => {
+                    log::warn!(
+                        "Notifier {} failed, retrying in {:?}: {}",
+                        config.name,
+                        delay,
+                        e
+                    );
+                    thread::sleep(delay);
+                    delay *= 2;
+                }
+            }
+        }
+    }
+}
+
+/// Sends alerts through every configured notifier, unless alerts have
+/// been muted in `store`. New alerts go to tier 0 recipients; one left
+/// unacknowledged for `escalate_after_mins` goes to the next tier, and so
+/// on, counting from the end of a mute. Clearing reaches every tier the
+/// alert got to.
+pub struct NotifySink {
+    notifiers: Vec<Notifier>,
+    store: SharedStore,
+    escalate_after_secs: u64,
+    max_tier: u32,
+}
+
+impl NotifySink {
+    pub fn spawn(
+        configs: &[NotifierConfig],
+        lifecycle: &AlertLifecycleConfig,
+        store: SharedStore,
+    ) -> io::Result<Self> {
+        let notifiers: Vec<Notifier> = configs
+            .iter()
+            .map(Notifier::spawn)
+            .collect::<io::Result<_>>()?;
+        let max_tier = notifiers.iter().map(Notifier::max_tier).max().unwrap_or(0);
+        Ok(Self {
+            notifiers,
+            store,
+            escalate_after_secs: lifecycle.escalate_after_mins * 60,
+            max_tier,
+        })
+    }
+}
+
+impl Sink for NotifySink {
+    fn name(&self) -> &'static str {
+        "notify"
+    }
+
+    fn on_alert(&mut self, event: &AlertEvent) {
+        let store = self.store.read().unwrap();
+        if store.muted(event.timestamp) {
+            log::info!("Alerts are muted, not notifying {}", event);
+            return;
+        }
+        // Runs before the store sink, so a clearing alert is still there
+        let reached = store
+            .alert_for(&event.rule, event.node_id)
+            .map_or(0, |a| a.tier);
+        drop(store);
+        for notifier in &mut self.notifiers {
+            notifier.notify(event, &(0..=reached));
+        }
+    }
+
+    fn on_tick(&mut self, now: u64) {
+        if self.max_tier == 0 || self.store.read().unwrap().muted(now) {
+            return;
+        }
+        let due = self.store.read().unwrap().due_escalations(
+            now,
+            self.escalate_after_secs,
+            self.max_tier,
+        );
+        for id in due {
+            let Some(alert) = self.store.write().unwrap().escalate(id, now) else {
+                continue;
+            };
+            log::warn!(
+                "Alert #{} ({} {}) not acknowledged, escalating to tier {}",
+                id,
+                alert.node_name,
+                alert.rule,
+                alert.tier
+            );
+            let event = alert.event(now);
+            for notifier in &mut self.notifiers {
+                notifier.notify(&event, &(alert.tier..=alert.tier));
+            }
+        }
+    }
+
+    fn flush(&mut self) {
+        for notifier in &self.notifiers {
+            notifier.flush();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+    use std::sync::{Arc, Mutex};
+
+    fn event(severity: Severity, node_name: &str) -> AlertEvent {
+        AlertEvent {
+            rule: "flood-stage".to_string(),
+            timestamp: 1_000,
+            node_id: 5,
+            node_name: node_name.to_string(),
+            metric: "water_level".to_string(),
+            value: 4.25,
+            threshold: 4.0,
+            expected_at: None,
+            severity,
+            state: AlertState::Raised,
+        }
+    }
+
+    fn notifier(toml: &str) -> NotifierConfig {
+        Config::parse(toml).unwrap().notifiers.remove(0)
+    }
+
+    #[test]
+    fn renders_json_safe_templates() {
+        let config = notifier(
+            r#"
+            [[notifiers]]
+            name = "discord"
+            kind = "webhook"
+            url = "http://localhost/hook"
+            template = '{"content": "{{text}}", "level": {{value}}}'
+            "#,
+        );
+        let body = http_body(&config, &event(Severity::Critical, "main \"st\""), None).unwrap();
+        let body: Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(
+            body["content"],
+            "CRITICAL main \"st\": water_level 4.25, threshold 4.00 (flood-stage raised)"
+        );
+        assert_eq!(body["level"], 4.25);
+
+        let err = Config::parse(
+            r#"
+            [[notifiers]]
+            name = "bad"
+            kind = "webhook"
+            url = "http://localhost/hook"
+            template = '{"text": "{{level}}"}'
+            "#,
+        )
+        .unwrap_err();
+        assert!(err.to_string().contains("unknown placeholder"), "{}", err);
+    }
+
+    #[test]
+    fn routes_by_severity_and_node_then_rate_limits() {
+        let config = notifier(
+            r#"
+            [[nodes]]
+            id = 5
+            alias = "bridge"
+
+            [[notifiers]]
+            name = "sms"
+            kind = "sms"
+            url = "http://localhost/sms"
+            min_severity = "warning"
+
+            [[notifiers.recipients]]
+            to = "+15550001"
+
+            [[notifiers.recipients]]
+            to = "+15550002"
+            min_severity = "critical"
+            nodes = ["bridge"]
+            "#,
+        );
+        assert!(route(&config, &event(Severity::Info, "bridge"), &(0..=0)).is_empty());
+        assert_eq!(
+            route(&config, &event(Severity::Warning, "bridge"), &(0..=0)),
+            vec![Some(0)]
+        );
+        assert_eq!(
+            route(&config, &event(Severity::Critical, "bridge"), &(0..=0)),
+            vec![Some(0), Some(1)]
+        );
+        assert_eq!(
+            route(&config, &event(Severity::Critical, "ford"), &(0..=0)),
+            vec![Some(0)]
+        );
+
+        let mut limiter = RateLimiter::new(2);
+        assert!(limiter.allow(Some(0), 0));
+        assert!(limiter.allow(Some(0), 100));
+        assert!(!limiter.allow(Some(0), 200));
+        assert!(limiter.allow(Some(1), 200));
+        assert!(limiter.allow(Some(0), 3_600));
+    }
+
+    #[test]
+    fn escalates_unacknowledged_alerts_by_tier() {
+        let config = Config::parse(
+            r#"
+            [alert_lifecycle]
+            escalate_after_mins = 10
+
+            [[notifiers]]
+            name = "sms"
+            kind = "sms"
+            url = "http://localhost/sms"
+            notify_cleared = true
+
+            [[notifiers.recipients]]
+            to = "+15550001"
+
+            [[notifiers.recipients]]
+            to = "+15550002"
+            tier = 1
+            "#,
+        )
+        .unwrap();
+        let notifier = &config.notifiers[0];
+        let raised = event(Severity::Critical, "bridge");
+        assert_eq!(route(notifier, &raised, &(0..=0)), [Some(0)]);
+        assert_eq!(route(notifier, &raised, &(1..=1)), [Some(1)]);
+
+        let store = SharedStore::default();
+        let mut sink =
+            NotifySink::spawn(&config.notifiers, &config.alert_lifecycle, store.clone()).unwrap();
+        assert_eq!(sink.max_tier, 1);
+        store.write().unwrap().record_alert(&raised);
+        sink.on_tick(1_500);
+        assert_eq!(store.read().unwrap().alert(1).unwrap().tier, 0);
+        sink.on_tick(1_600);
+        assert_eq!(store.read().unwrap().alert(1).unwrap().tier, 1);
+        // No tier 2 to go to
+        sink.on_tick(9_000);
+        assert_eq!(store.read().unwrap().alert(1).unwrap().tier, 1);
+
+        // Acknowledged alerts stay put
+        let mut second = raised.clone();
+        second.rule = "second".to_string();
+        store.write().unwrap().record_alert(&second);
+        store.write().unwrap().acknowledge(2, "+15550001", 1_100);
+        sink.on_tick(9_000);
+        assert_eq!(store.read().unwrap().alert(2).unwrap().tier, 0);
+    }
+
+    /// Answers each request with the next status, recording the bodies.
+    fn http_catcher(statuses: Vec<u16>) -> (String, Arc<Mutex<Vec<String>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        let bodies = Arc::new(Mutex::new(Vec::new()));
+        let received = bodies.clone();
+        thread::spawn(move || {
+            for (status, stream) in statuses.into_iter().zip(listener.incoming()) {
+                let mut stream = stream.unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut length = 0;
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    if let Some(value) = line.to_ascii_lowercase().strip_prefix("content-length:") {
+                        length = value.trim().parse().unwrap();
+                    }
+                    if line == "\r\n" {
+                        break;
+                    }
+                }
+                let mut body = vec![0; length];
+                reader.read_exact(&mut body).unwrap();
+                received
+                    .lock()
+                    .unwrap()
+                    .push(String::from_utf8(body).unwrap());
+                write!(
+                    stream,
+                    "HTTP/1.1 {} X\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                    status
+                )
+                .unwrap();
+            }
+        });
+        (url, bodies)
+    }
+
+    #[test]
+    fn retries_webhook_until_accepted() {
+        let (url, bodies) = http_catcher(vec![500, 200]);
+        let mut sink = NotifySink::spawn(
+            &[notifier(&format!(
+                r#"
+            [[notifiers]]
+            name = "hook"
+            kind = "webhook"
+            url = "{}"
+            "#,
+                url
+            ))],
+            &AlertLifecycleConfig::default(),
+            Default::default(),
+        )
+        .unwrap();
+        sink.on_alert(&event(Severity::Warning, "bridge"));
+        sink.flush();
+
+        let bodies = bodies.lock().unwrap();
+        assert_eq!(bodies.len(), 2);
+        let body: Value = serde_json::from_str(&bodies[1]).unwrap();
+        assert_eq!(body["rule"], "flood-stage");
+        assert_eq!(body["state"], "raised");
+        assert!(body["text"].as_str().unwrap().starts_with("WARNING bridge"));
+    }
+
+    /// Just enough of an SMTP server to accept one message.
+    fn smtp_sink() -> (u16, Arc<Mutex<String>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let transcript = Arc::new(Mutex::new(String::new()));
+        let received = transcript.clone();
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            stream.write_all(b"220 localhost ESMTP\r\n").unwrap();
+            let mut in_data = false;
+            loop {
+                let mut line = String::new();
+                if reader.read_line(&mut line).unwrap() == 0 {
+                    return;
+                }
+                received.lock().unwrap().push_str(&line);
+                let reply: &[u8] = if in_data {
+                    if line != ".\r\n" {
+                        continue;
+                    }
+                    in_data = false;
+                    b"250 queued\r\n"
+                } else {
+                    match &line.to_ascii_uppercase()[..4] {
+                        "EHLO" => b"250 localhost\r\n",
+                        "DATA" => {
+                            in_data = true;
+                            b"354 go ahead\r\n"
+                        }
+                        "QUIT" => {
+                            stream.write_all(b"221 bye\r\n").unwrap();
+                            return;
+                        }
+                        _ => b"250 ok\r\n",
+                    }
+                };
+                stream.write_all(reply).unwrap();
+            }
+        });
+        (port, transcript)
+    }
+
+    #[test]
+    fn emails_every_routed_recipient_at_once() {
+        let (port, transcript) = smtp_sink();
+        let mut sink = NotifySink::spawn(
+            &[notifier(&format!(
+                r#"
+            [[notifiers]]
+            name = "email"
+            kind = "smtp"
+            host = "127.0.0.1"
+            port = {}
+            tls = "none"
+            from = "Flood monitor <flood@example.org>"
+
+            [[notifiers.recipients]]
+            to = "duty@example.org"
+
+            [[notifiers.recipients]]
+            to = "chief@example.org"
+            min_severity = "critical"
+            "#,
+                port
+            ))],
+            &AlertLifecycleConfig::default(),
+            Default::default(),
+        )
+        .unwrap();
+        sink.on_alert(&event(Severity::Critical, "bridge"));
+        sink.flush();
+
+        let transcript = transcript.lock().unwrap();
+        assert!(
+            transcript.contains("RCPT TO:<duty@example.org>"),
+            "{}",
+            transcript
+        );
+        assert!(
+            transcript.contains("RCPT TO:<chief@example.org>"),
+            "{}",
+            transcript
+        );
+        assert!(
+            transcript.contains("Subject: [critical] bridge: flood-stage raised"),
+            "{}",
+            transcript
+        );
+    }
+}
@@ -0,0 +1,372 @@
+use std::io;
+use std::time::Duration;
+
+use tokio::sync::mpsc;
+
+use crate::alerts::AlertEvent;
+use crate::config::Config;
+use crate::dedup::{Deduplicator, Observation};
+use crate::frame::{self, Frame};
+use crate::handler::Handler;
+use crate::http::ApiState;
+use crate::mesh::Outbox;
+use crate::sinks::{self, Sink};
+
+/// Capacity of the channel sources feed frames into. Replay blocks on a
+/// full channel; live radios are drained by the library into an unbounded
+/// queue first, so they never stall.
+pub const FRAME_CHANNEL_CAPACITY: usize = 1024;
+
+/// How often sinks get `on_tick`.
+const TICK_INTERVAL: Duration = Duration::from_secs(30);
+
+/// The single path every frame takes, whether it came from a radio or a
+/// recording: raw frame sinks, then de-duplication across radios, then
+/// decoding and alerting, then message and alert sinks.
+pub struct Pipeline {
+    dedup: Deduplicator,
+    handler: Handler,
+    sinks: Vec<Box<dyn Sink>>,
+}
+
+impl Pipeline {
+    /// Alerts already in `api.store`, restored from an earlier run, stay
+    /// raised. See [`sinks::build_sinks`] for `live`.
+    pub fn new(
+        config: &Config,
+        api: &ApiState,
+        outbox: Option<&Outbox>,
+        live: bool,
+    ) -> io::Result<Self> {
+        let sinks = sinks::build_sinks(config, api, outbox, live)?;
+        let mut pipeline = Self::with_sinks(config, sinks);
+        let active: Vec<_> = api
+            .store
+            .read()
+            .unwrap()
+            .active_alerts()
+            .into_iter()
+            .map(|a| (a.rule.clone(), a.node.0))
+            .collect();
+        pipeline.handler.restore_alerts(active);
+        Ok(pipeline)
+    }
+
+    pub fn with_sinks(config: &Config, sinks: Vec<Box<dyn Sink>>) -> Self {
+        log::debug!(
+            "Pipeline sinks: {:?}",
+            sinks.iter().map(|s| s.name()).collect::<Vec<_>>()
+        );
+        Self {
+            dedup: Deduplicator::new(config.dedup.window_secs),
+            handler: Handler::new(config),
+            sinks,
+        }
+    }
+
+    pub fn process(&mut self, frame: &Frame) {
+        for sink in &mut self.sinks {
+            sink.on_frame(frame);
+        }
+
+        let Some(from_radio) = frame.as_from_radio() else {
+            return;
+        };
+
+        self.expire_packets(frame.timestamp);
+        match self
+            .dedup
+            .observe(frame.timestamp, &frame.connection, from_radio)
+        {
+            Observation::Duplicate => {
+                log::trace!("Dropping duplicate packet via {}", frame.connection);
+                return;
+            }
+            Observation::Better { from, id } => {
+                if let Some(copies) = self.dedup.copies(from, id) {
+                    for sink in &mut self.sinks {
+                        sink.on_better_copy(copies);
+                    }
+                }
+                return;
+            }
+            Observation::Untracked | Observation::First => {}
+        }
+
+        let handled =
+            self.handler
+                .handle_from_radio(&frame.connection, frame.timestamp, from_radio);
+
+        if let Some(msg) = &handled.message {
+            for sink in &mut self.sinks {
+                sink.on_message(msg);
+            }
+        }
+        if let Some(info) = &handled.node_info {
+            for sink in &mut self.sinks {
+                sink.on_node_info(info);
+            }
+        }
+        self.dispatch_alerts(&handled.alerts);
+        let due = self.handler.check(frame.timestamp);
+        self.dispatch_alerts(&due);
+    }
+
+    fn dispatch_alerts(&mut self, alerts: &[AlertEvent]) {
+        for event in alerts {
+            log::warn!("Alert {}", event);
+            for sink in &mut self.sinks {
+                sink.on_alert(event);
+            }
+        }
+    }
+
+    pub fn reload(&mut self, config: &Config) {
+        self.handler.reload(config);
+        for sink in &mut self.sinks {
+            sink.reload(config);
+        }
+    }
+
+    /// Hands the tallies of packets whose dedup window has closed to sinks.
+    fn expire_packets(&mut self, now: u64) {
+        for copies in self.dedup.expire(now) {
+            for sink in &mut self.sinks {
+                sink.on_link(&copies);
+            }
+        }
+    }
+
+    /// Wall-clock housekeeping, which also notices nodes going silent
+    /// and forecasts running out while no frames arrive at all.
+    pub fn tick(&mut self, now: u64) {
+        let due = self.handler.check(now);
+        self.dispatch_alerts(&due);
+        for sink in &mut self.sinks {
+            sink.on_tick(now);
+        }
+    }
+
+    pub fn finish(&mut self) {
+        self.expire_packets(u64::MAX);
+        for sink in &mut self.sinks {
+            sink.flush();
+        }
+    }
+}
+
+/// Drives the pipeline until every source has hung up. Only `live` sources
+/// get wall-clock ticks; a replay runs on the recording's own time.
+pub async fn run(
+    mut pipeline: Pipeline,
+    mut frames: mpsc::Receiver<Frame>,
+    mut reloads: mpsc::UnboundedReceiver<Config>,
+    live: bool,
+) {
+    let mut ticks = tokio::time::interval(TICK_INTERVAL);
+    loop {
+        tokio::select! {
+            frame = frames.recv() => match frame {
+                Some(frame) => pipeline.process(&frame),
+                None => break,
+            },
+            Some(new_config) = reloads.recv() => pipeline.reload(&new_config),
+            _ = ticks.tick(), if live => pipeline.tick(frame::now_secs()),
+        }
+    }
+
+    pipeline.finish();
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use meshtastic::Message;
+    use meshtastic::protobufs::{
+        Data, EnvironmentMetrics, FromRadio, MeshPacket, NodeInfo, PortNum, ToRadio, User,
+        from_radio, mesh_packet, telemetry,
+    };
+
+    use super::*;
+    use crate::dedup::PacketCopies;
+    use crate::frame::RadioFrame;
+    use crate::handler::DecodedMessage;
+    use crate::store::{SharedStore, Store, StoreSink};
+
+    #[derive(Default)]
+    struct Seen {
+        frames: usize,
+        messages: Vec<String>,
+        alerts: Vec<String>,
+        /// (packet id, copies)
+        links: Vec<(u32, u32)>,
+    }
+
+    struct CaptureSink(Arc<Mutex<Seen>>);
+
+    impl Sink for CaptureSink {
+        fn name(&self) -> &'static str {
+            "capture"
+        }
+
+        fn on_frame(&mut self, _frame: &Frame) {
+            self.0.lock().unwrap().frames += 1;
+        }
+
+        fn on_message(&mut self, msg: &DecodedMessage) {
+            self.0.lock().unwrap().messages.push(msg.node_name.clone());
+        }
+
+        fn on_alert(&mut self, event: &AlertEvent) {
+            self.0.lock().unwrap().alerts.push(event.rule.clone());
+        }
+
+        fn on_link(&mut self, copies: &PacketCopies) {
+            self.0
+                .lock()
+                .unwrap()
+                .links
+                .push((copies.id, copies.copies));
+        }
+    }
+
+    fn distance_frame(from: u32, id: u32, distance: f32) -> Frame {
+        let envelope = meshtastic::protobufs::Telemetry {
+            time: 0,
+            variant: Some(telemetry::Variant::EnvironmentMetrics(EnvironmentMetrics {
+                distance: Some(distance),
+                ..Default::default()
+            })),
+        };
+        let packet = MeshPacket {
+            from,
+            id,
+            payload_variant: Some(mesh_packet::PayloadVariant::Decoded(Data {
+                portnum: PortNum::TelemetryApp as i32,
+                payload: envelope.encode_to_vec(),
+                ..Default::default()
+            })),
+            ..Default::default()
+        };
+        Frame::inbound(
+            "radio",
+            FromRadio {
+                id: 1,
+                payload_variant: Some(from_radio::PayloadVariant::Packet(packet)),
+            },
+            false,
+        )
+    }
+
+    #[test]
+    fn frames_flow_through_decoding_to_sinks() {
+        let config = Config::parse(
+            r#"
+            [[nodes]]
+            id = 5
+            alias = "bridge"
+            calibration = { offset = 6.0, scale = -0.001 }
+
+            [[alerts]]
+            name = "flood"
+            node = "bridge"
+            metric = "water_level"
+            above = 4.0
+            "#,
+        )
+        .unwrap();
+
+        let seen = Arc::new(Mutex::new(Seen::default()));
+        let mut pipeline = Pipeline::with_sinks(&config, vec![Box::new(CaptureSink(seen.clone()))]);
+
+        pipeline.process(&Frame::outbound("radio", ToRadio::default(), true));
+        pipeline.process(&distance_frame(5, 1, 2500.0)); // 3.5 m
+        pipeline.process(&distance_frame(5, 2, 1500.0)); // 4.5 m
+        // The same packet heard again by a second radio
+        let mut copy = distance_frame(5, 2, 1500.0);
+        copy.connection = "backup".to_string();
+        pipeline.process(&copy);
+        pipeline.finish();
+
+        let seen = seen.lock().unwrap();
+        assert_eq!(seen.frames, 4);
+        assert_eq!(seen.messages, vec!["bridge", "bridge"]);
+        assert_eq!(seen.alerts, vec!["flood"]);
+        assert_eq!(seen.links, vec![(1, 1), (2, 2)]);
+    }
+
+    #[test]
+    fn config_dump_names_nodes() {
+        let config = Config::default();
+        let store = Store::shared(&config);
+        let seen = Arc::new(Mutex::new(Seen::default()));
+        let mut pipeline = Pipeline::with_sinks(
+            &config,
+            vec![
+                Box::new(StoreSink(store.clone())),
+                Box::new(CaptureSink(seen.clone())),
+            ],
+        );
+
+        let node_info = NodeInfo {
+            num: 6,
+            user: Some(User {
+                long_name: "Mill Creek gauge".to_string(),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        let dump = FromRadio {
+            id: 1,
+            payload_variant: Some(from_radio::PayloadVariant::NodeInfo(node_info)),
+        };
+        pipeline.process(&Frame::inbound("radio", dump, true));
+        {
+            let store = store.read().unwrap();
+            let node = store.nodes().next().unwrap();
+            assert_eq!(node.long_name.as_deref(), Some("Mill Creek gauge"));
+            assert_eq!(node.last_heard, None);
+        }
+
+        pipeline.process(&distance_frame(6, 1, 2500.0));
+        assert_eq!(seen.lock().unwrap().messages, vec!["Mill Creek gauge"]);
+    }
+
+    #[test]
+    fn better_copies_update_link_quality() {
+        let config = Config::default();
+        let store = Store::shared(&config);
+        let mut pipeline = Pipeline::with_sinks(&config, vec![Box::new(StoreSink(store.clone()))]);
+        let heard = |connection: &str, id: u32, rx_snr: f32, rx_rssi: i32| {
+            let mut frame = distance_frame(5, id, 2500.0);
+            frame.connection = connection.to_string();
+            if let RadioFrame::FromRadio(FromRadio {
+                payload_variant: Some(from_radio::PayloadVariant::Packet(packet)),
+                ..
+            }) = &mut frame.frame
+            {
+                packet.rx_snr = rx_snr;
+                packet.rx_rssi = rx_rssi;
+            }
+            frame
+        };
+        let link = |store: &SharedStore| {
+            let store = store.read().unwrap();
+            let node = store.nodes().next().unwrap();
+            (node.rx_snr, node.rx_rssi)
+        };
+
+        pipeline.process(&heard("radio", 1, -7.5, -120));
+        pipeline.process(&heard("backup", 1, 4.0, -95));
+        assert_eq!(link(&store), (Some(4.0), Some(-95)));
+        // A worse copy leaves the best one in place
+        pipeline.process(&heard("radio", 1, 1.0, -101));
+        assert_eq!(link(&store), (Some(4.0), Some(-95)));
+
+        // A late copy of an older packet does not override a newer one
+        pipeline.process(&heard("radio", 2, -3.0, -110));
+        pipeline.process(&heard("backup", 1, 9.0, -80));
+        assert_eq!(link(&store), (Some(-3.0), Some(-110)));
+    }
+}
@@ -1,61 +1,148 @@
 use std::fs::File;
 use std::io::{self, BufReader, Read};
+use std::path::Path;
 
-use meshtastic::protobufs::FromRadio;
 use meshtastic::Message;
+use meshtastic::protobufs::{FromRadio, ToRadio};
+
+use crate::frame::{Direction, Frame, REPLAY_CONNECTION, RadioFrame};
+use crate::recording_stream::{FLAG_OUTBOUND, FLAG_PREAMBLE, LENGTH_MASK};
 
 pub struct PlaybackStream {
     reader: BufReader<File>,
+    skip_outbound: bool,
 }
 
 impl PlaybackStream {
-    pub fn open(path: &str) -> io::Result<Self> {
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
         Ok(Self {
             reader: BufReader::new(File::open(path)?),
+            skip_outbound: false,
         })
     }
-}
 
-impl Iterator for PlaybackStream {
-    type Item = io::Result<FromRadio>;
+    /// Only yield frames received from the radio.
+    pub fn skip_outbound(mut self) -> Self {
+        self.skip_outbound = true;
+        self
+    }
 
-    fn next(&mut self) -> Option<Self::Item> {
-    // Read 8-byte timestamp (discard for now)
-    let mut ts_buf = [0u8; 8];
-    if let Err(e) = self.reader.read_exact(&mut ts_buf) {
-        if e.kind() == io::ErrorKind::UnexpectedEof {
-            return None;
-        } else {
-            return Some(Err(e.into()));
+    fn read_frame(&mut self) -> Option<io::Result<Frame>> {
+        let mut ts_buf = [0u8; 8];
+        if let Err(e) = self.reader.read_exact(&mut ts_buf) {
+            if e.kind() == io::ErrorKind::UnexpectedEof {
+                return None;
+            } else {
+                return Some(Err(e));
+            }
         }
-    }
 
-    // Now read 4-byte payload length
-    let mut len_buf = [0u8; 4];
-    if let Err(e) = self.reader.read_exact(&mut len_buf) {
-        if e.kind() == io::ErrorKind::UnexpectedEof {
-            return None;
-        } else {
-            return Some(Err(e.into()));
+        // 4-byte header: payload length plus direction / preamble flags
+        let mut header_buf = [0u8; 4];
+        if let Err(e) = self.reader.read_exact(&mut header_buf) {
+            if e.kind() == io::ErrorKind::UnexpectedEof {
+                return None;
+            } else {
+                return Some(Err(e));
+            }
         }
-    }
 
-    let len = u32::from_le_bytes(len_buf) as usize;
+        let header = u32::from_le_bytes(header_buf);
+        let len = (header & LENGTH_MASK) as usize;
 
-    let mut buf = vec![0u8; len];
-    if let Err(e) = self.reader.read_exact(&mut buf) {
-        if e.kind() == io::ErrorKind::UnexpectedEof {
-            eprintln!("Warning: truncated frame at end of file");
-            return None;
+        let mut buf = vec![0u8; len];
+        if let Err(e) = self.reader.read_exact(&mut buf) {
+            if e.kind() == io::ErrorKind::UnexpectedEof {
+                eprintln!("Warning: truncated frame at end of file");
+                return None;
+            } else {
+                return Some(Err(e));
+            }
+        }
+
+        let frame = if header & FLAG_OUTBOUND != 0 {
+            ToRadio::decode(&buf[..]).map(RadioFrame::ToRadio)
         } else {
-            return Some(Err(e.into()));
+            FromRadio::decode(&buf[..]).map(RadioFrame::FromRadio)
+        };
+
+        match frame {
+            Ok(frame) => Some(Ok(Frame {
+                connection: REPLAY_CONNECTION.to_string(),
+                timestamp: u64::from_le_bytes(ts_buf),
+                preamble: header & FLAG_PREAMBLE != 0,
+                frame,
+            })),
+            Err(e) => Some(Err(io::Error::new(io::ErrorKind::InvalidData, e))),
         }
     }
+}
+
+impl Iterator for PlaybackStream {
+    type Item = io::Result<Frame>;
 
-    match FromRadio::decode(&buf[..]) {
-        Ok(msg) => Some(Ok(msg)),
-        Err(e) => Some(Err(io::Error::new(io::ErrorKind::InvalidData, e))),
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let frame = self.read_frame()?;
+            match frame {
+                Ok(f) if self.skip_outbound && f.direction() == Direction::Outbound => continue,
+                other => return Some(other),
+            }
+        }
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::recording_stream::RecordingStream;
+    use meshtastic::protobufs::{from_radio, to_radio};
+
+    #[test]
+    fn round_trips_direction_and_preamble() {
+        let dir =
+            std::env::temp_dir().join(format!("flood_monitor-playback-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let mut recorder = RecordingStream::new(&dir, 1024 * 1024).unwrap();
+
+        let want_config = ToRadio {
+            payload_variant: Some(to_radio::PayloadVariant::WantConfigId(7)),
+        };
+        let complete = FromRadio {
+            id: 1,
+            payload_variant: Some(from_radio::PayloadVariant::ConfigCompleteId(7)),
+        };
+        let later = FromRadio {
+            id: 2,
+            ..Default::default()
+        };
+        for frame in [
+            Frame::outbound("a", want_config.clone(), true),
+            Frame::inbound("a", complete.clone(), true),
+            Frame::inbound("a", later.clone(), false),
+        ] {
+            recorder.record_frame(&frame).unwrap();
+        }
+
+        let path = dir.join("meshtastic-recording-00000.bin");
+        let frames: Vec<_> = PlaybackStream::open(&path)
+            .unwrap()
+            .map(Result::unwrap)
+            .collect();
+        assert_eq!(frames.len(), 3);
+        assert_eq!(frames[0].frame, RadioFrame::ToRadio(want_config));
+        assert!(frames[0].preamble);
+        assert_eq!(frames[1].direction(), Direction::Inbound);
+        assert!(frames[1].preamble);
+        assert!(!frames[2].preamble);
+
+        let inbound: Vec<_> = PlaybackStream::open(&path)
+            .unwrap()
+            .skip_outbound()
+            .map(|f| f.unwrap().as_from_radio().unwrap().clone())
+            .collect();
+        assert_eq!(inbound, vec![complete, later]);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
 }
@@ -0,0 +1,429 @@
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+use crate::alerts::{AlertEvent, AlertState};
+use crate::config::{Config, MetricLimits, NodeId, QualityConfig};
+
+/// Data-quality code of a reading.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Quality {
+    #[default]
+    Good,
+    /// The same value for `stuck_hours`
+    Stuck,
+    /// Further than `max_step` from the last good reading
+    Spike,
+    /// Outside `min` and `max`
+    OutOfRange,
+    /// NaN, or zero where the sensor reports zero for no reading
+    Invalid,
+}
+
+impl Quality {
+    pub const FAULTS: [Quality; 4] = [
+        Quality::Stuck,
+        Quality::Spike,
+        Quality::OutOfRange,
+        Quality::Invalid,
+    ];
+
+    pub fn is_good(&self) -> bool {
+        *self == Quality::Good
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Quality::Good => "good",
+            Quality::Stuck => "stuck",
+            Quality::Spike => "spike",
+            Quality::OutOfRange => "out_of_range",
+            Quality::Invalid => "invalid",
+        }
+    }
+
+    /// Name of the maintenance notice raised for this fault.
+    pub fn rule(&self) -> String {
+        format!("sensor-{}", self.as_str().replace('_', "-"))
+    }
+}
+
+impl fmt::Display for Quality {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// What is remembered about one metric of one node.
+#[derive(Debug, Default)]
+struct Series {
+    last_good: Option<f64>,
+    /// Readings since the last good one that jumped away from it
+    jumps: Vec<f64>,
+    /// (since, value) of the current run of identical readings
+    run: Option<(u64, f64)>,
+    fault: Option<Quality>,
+    good_run: usize,
+}
+
+/// Checks readings between decoding and alerting. Flagged readings keep
+/// their value but carry a quality code, are left out of alert rules, and
+/// raise a `sensor-<fault>` maintenance notice per node until the metric
+/// has read good `clear_after` times in a row.
+pub struct QualityMonitor {
+    config: QualityConfig,
+    /// `metrics` limits with their node resolved; `None` applies to all
+    limits: Vec<(Option<u32>, MetricLimits)>,
+    series: HashMap<(u32, &'static str), Series>,
+    /// (node, fault) notices currently raised, with the metric that
+    /// raised them
+    notices: HashMap<(u32, Quality), &'static str>,
+}
+
+impl QualityMonitor {
+    pub fn from_config(config: &Config) -> Self {
+        let mut monitor = Self {
+            config: config.quality.clone(),
+            limits: Vec::new(),
+            series: HashMap::new(),
+            notices: HashMap::new(),
+        };
+        monitor.reconfigure(config);
+        monitor
+    }
+
+    /// Takes limits from a reloaded config, keeping each series' history.
+    pub fn reconfigure(&mut self, config: &Config) {
+        self.config = config.quality.clone();
+        self.limits = config
+            .quality
+            .metrics
+            .iter()
+            .map(|limits| {
+                let node = limits.node.as_deref().and_then(|node| {
+                    config
+                        .nodes
+                        .iter()
+                        .find(|n| n.alias.as_deref() == Some(node))
+                        .map(|n| n.id.0)
+                        .or_else(|| node.parse::<NodeId>().ok().map(|id| id.0))
+                });
+                (node, limits.clone())
+            })
+            .collect();
+    }
+
+    /// Marks notices raised before a restart as active, so they clear once
+    /// the node reads good again.
+    pub fn restore(&mut self, active: &HashSet<(String, u32)>) {
+        for (rule, node_id) in active {
+            if let Some(fault) = Quality::FAULTS.into_iter().find(|q| q.rule() == *rule) {
+                self.notices.insert((*node_id, fault), "");
+            }
+        }
+    }
+
+    /// Quality codes of the readings that are not good, and notices raised
+    /// or cleared by this packet.
+    pub fn assess(
+        &mut self,
+        timestamp: u64,
+        node_id: u32,
+        node_name: &str,
+        readings: &[(&'static str, f64)],
+    ) -> (Vec<(&'static str, Quality)>, Vec<AlertEvent>) {
+        if !self.config.enabled {
+            return (Vec::new(), Vec::new());
+        }
+        let mut flags = Vec::new();
+        let mut events = Vec::new();
+        for &(metric, value) in readings {
+            let limits: Vec<&MetricLimits> = self
+                .limits
+                .iter()
+                .filter(|(node, l)| l.metric == metric && node.is_none_or(|n| n == node_id))
+                .map(|(_, l)| l)
+                .collect();
+            let series = self.series.entry((node_id, metric)).or_default();
+            let (quality, threshold) =
+                check(series, &limits, timestamp, value, self.config.confirm_after);
+
+            if quality.is_good() {
+                series.good_run += 1;
+                if series.good_run >= self.config.clear_after {
+                    series.fault = None;
+                }
+                continue;
+            }
+            flags.push((metric, quality));
+            series.good_run = 0;
+            if series.fault != Some(quality) {
+                log::warn!(
+                    "{} {} reading {} flagged {}",
+                    node_name,
+                    metric,
+                    value,
+                    quality
+                );
+            }
+            series.fault = Some(quality);
+            if let Entry::Vacant(notice) = self.notices.entry((node_id, quality)) {
+                notice.insert(metric);
+                events.push(AlertEvent {
+                    metric: metric.to_string(),
+                    value,
+                    threshold,
+                    ..self.notice(quality, AlertState::Raised, timestamp, node_id, node_name)
+                });
+            }
+        }
+
+        // A water level computed from a flagged distance is no better
+        if let Some(&(_, quality)) = flags.iter().find(|(m, _)| *m == "distance")
+            && readings.iter().any(|(m, _)| *m == "water_level")
+            && !flags.iter().any(|(m, _)| *m == "water_level")
+        {
+            flags.push(("water_level", quality));
+        }
+
+        for fault in Quality::FAULTS {
+            let faulty = self
+                .series
+                .iter()
+                .any(|(&(node, _), s)| node == node_id && s.fault == Some(fault));
+            if faulty {
+                continue;
+            }
+            let Some(metric) = self.notices.remove(&(node_id, fault)) else {
+                continue;
+            };
+            let value = readings.iter().find(|(m, _)| *m == metric);
+            events.push(AlertEvent {
+                metric: metric.to_string(),
+                value: value.map_or(0.0, |&(_, v)| v),
+                ..self.notice(fault, AlertState::Cleared, timestamp, node_id, node_name)
+            });
+        }
+        (flags, events)
+    }
+
+    fn notice(
+        &self,
+        fault: Quality,
+        state: AlertState,
+        timestamp: u64,
+        node_id: u32,
+        node_name: &str,
+    ) -> AlertEvent {
+        AlertEvent {
+            rule: fault.rule(),
+            timestamp,
+            node_id,
+            node_name: node_name.to_string(),
+            metric: String::new(),
+            value: 0.0,
+            threshold: 0.0,
+            expected_at: None,
+            severity: self.config.notice_severity,
+            state,
+        }
+    }
+}
+
+/// The quality of one reading and the limit it broke, updating `series`.
+fn check(
+    series: &mut Series,
+    limits: &[&MetricLimits],
+    timestamp: u64,
+    value: f64,
+    confirm_after: usize,
+) -> (Quality, f64) {
+    if !value.is_finite() || (value == 0.0 && limits.iter().any(|l| l.zero_invalid)) {
+        return (Quality::Invalid, 0.0);
+    }
+    for l in limits {
+        if let Some(min) = l.min.filter(|&min| value < min) {
+            return (Quality::OutOfRange, min);
+        }
+        if let Some(max) = l.max.filter(|&max| value > max) {
+            return (Quality::OutOfRange, max);
+        }
+    }
+
+    let max_step = limits.iter().filter_map(|l| l.max_step).reduce(f64::min);
+    if let (Some(step), Some(last)) = (max_step, series.last_good)
+        && (value - last).abs() > step
+    {
+        series.jumps.push(value);
+        // Readings that agree with each other are a real change of level,
+        // e.g. a sensor remounted, not a false echo
+        let (lo, hi) = series
+            .jumps
+            .iter()
+            .fold((f64::MAX, f64::MIN), |(lo, hi), &v| (lo.min(v), hi.max(v)));
+        if series.jumps.len() < confirm_after || hi - lo > step {
+            if hi - lo > step {
+                series.jumps = vec![value];
+            }
+            return (Quality::Spike, step);
+        }
+    }
+    series.jumps.clear();
+    series.last_good = Some(value);
+
+    let stuck_secs = limits
+        .iter()
+        .filter_map(|l| l.stuck_hours)
+        .reduce(f64::min)
+        .map(|hours| (hours * 3600.0) as u64);
+    match series.run {
+        Some((since, run)) if run == value => {
+            if let Some(secs) = stuck_secs.filter(|&secs| timestamp.saturating_sub(since) >= secs) {
+                return (Quality::Stuck, secs as f64 / 3600.0);
+            }
+        }
+        _ => series.run = Some((timestamp, value)),
+    }
+    (Quality::Good, 0.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HOUR: u64 = 3600;
+
+    fn monitor(toml: &str) -> QualityMonitor {
+        QualityMonitor::from_config(&Config::parse(toml).unwrap())
+    }
+
+    fn flag(
+        monitor: &mut QualityMonitor,
+        timestamp: u64,
+        metric: &'static str,
+        value: f64,
+    ) -> Quality {
+        let (flags, _) = monitor.assess(timestamp, 5, "bridge", &[(metric, value)]);
+        flags.first().map_or(Quality::Good, |&(_, q)| q)
+    }
+
+    #[test]
+    fn flags_invalid_out_of_range_and_stuck_readings() {
+        let mut monitor = monitor("");
+        assert_eq!(
+            flag(&mut monitor, 0, "distance", f64::NAN),
+            Quality::Invalid
+        );
+        assert_eq!(flag(&mut monitor, 0, "distance", 0.0), Quality::Invalid);
+        assert_eq!(
+            flag(&mut monitor, 0, "humidity", 120.0),
+            Quality::OutOfRange
+        );
+        assert_eq!(flag(&mut monitor, 0, "humidity", 0.0), Quality::Good);
+        // Zero is a reading for metrics without `zero_invalid`
+        assert_eq!(flag(&mut monitor, 0, "temperature", 0.0), Quality::Good);
+
+        for hour in 0..24 {
+            assert_eq!(
+                flag(&mut monitor, hour * HOUR, "distance", 2500.0),
+                Quality::Good
+            );
+        }
+        assert_eq!(
+            flag(&mut monitor, 24 * HOUR, "distance", 2500.0),
+            Quality::Stuck
+        );
+        assert_eq!(
+            flag(&mut monitor, 25 * HOUR, "distance", 2501.0),
+            Quality::Good
+        );
+    }
+
+    #[test]
+    fn flags_spikes_until_the_new_level_is_confirmed() {
+        let mut monitor = monitor(
+            r#"
+            [[nodes]]
+            id = 5
+            alias = "bridge"
+
+            [[quality.metrics]]
+            metric = "distance"
+            node = "bridge"
+            max_step = 100
+            "#,
+        );
+        let levels = [
+            2500.0, 2520.0, 400.0, 2510.0, 1500.0, 1510.0, 1505.0, 1490.0,
+        ];
+        let flags: Vec<Quality> = levels
+            .into_iter()
+            .enumerate()
+            .map(|(i, v)| flag(&mut monitor, i as u64 * 600, "distance", v))
+            .collect();
+        use Quality::{Good, Spike};
+        // A lone false echo, then a level change once three readings agree
+        assert_eq!(flags, [Good, Good, Spike, Good, Spike, Spike, Good, Good]);
+        // Limits for another node do not apply
+        let (flags, _) = monitor.assess(0, 6, "ford", &[("distance", 2500.0)]);
+        assert!(flags.is_empty());
+        let (flags, _) = monitor.assess(600, 6, "ford", &[("distance", 400.0)]);
+        assert!(flags.is_empty());
+    }
+
+    #[test]
+    fn raises_a_notice_and_clears_it_after_good_readings() {
+        let mut monitor = monitor("[quality]\nclear_after = 2");
+        let packet = |distance| [("distance", distance), ("water_level", 3.0)];
+
+        let (flags, events) = monitor.assess(0, 5, "bridge", &packet(0.0));
+        // The water level derived from the distance inherits its flag
+        assert_eq!(
+            flags,
+            [
+                ("distance", Quality::Invalid),
+                ("water_level", Quality::Invalid)
+            ]
+        );
+        assert_eq!(events.len(), 1);
+        assert_eq!(
+            (events[0].rule.as_str(), events[0].state),
+            ("sensor-invalid", AlertState::Raised)
+        );
+        // Still invalid: no second notice
+        assert!(monitor.assess(600, 5, "bridge", &packet(0.0)).1.is_empty());
+
+        assert!(
+            monitor
+                .assess(1_200, 5, "bridge", &packet(2500.0))
+                .1
+                .is_empty()
+        );
+        let (flags, events) = monitor.assess(1_800, 5, "bridge", &packet(2490.0));
+        assert!(flags.is_empty());
+        assert_eq!(
+            (events[0].rule.as_str(), events[0].state, events[0].value),
+            ("sensor-invalid", AlertState::Cleared, 2490.0)
+        );
+    }
+
+    #[test]
+    fn clears_notices_restored_after_a_restart() {
+        let mut monitor = monitor("[quality]\nclear_after = 1");
+        monitor.restore(&HashSet::from([
+            ("sensor-stuck".to_string(), 5),
+            ("flood".to_string(), 5),
+        ]));
+        let (_, events) = monitor.assess(0, 5, "bridge", &[("distance", 2500.0)]);
+        assert_eq!(
+            events
+                .iter()
+                .map(|e| (e.rule.as_str(), e.state))
+                .collect::<Vec<_>>(),
+            [("sensor-stuck", AlertState::Cleared)]
+        );
+    }
+}
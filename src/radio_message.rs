@@ -1,27 +1,36 @@
 use meshtastic::{Message, protobufs::telemetry};
 
-use std::convert::TryFrom;
 use meshtastic::protobufs::{
-    from_radio::PayloadVariant as FromRadioPayload,
-    mesh_packet::PayloadVariant as MeshPayload,
-    FromRadio, Data, PortNum,
+    DeviceMetrics, EnvironmentMetrics, FromRadio, PortNum, PowerMetrics,
+    from_radio::PayloadVariant as FromRadioPayload, mesh_packet::PayloadVariant as MeshPayload,
 };
+use serde::{Deserialize, Serialize};
+use std::convert::TryFrom;
+
+use crate::config::NodeId;
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct TextMessage {
     pub to: Option<String>,
     pub from: Option<String>,
     pub msg: String,
 }
 
-#[derive(Debug, Clone, PartialEq)]
+/// Serialized externally tagged, e.g. `{"Telemetry": {...}}`, the shape
+/// `export --format jsonl` writes under `decoded`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum AppMessage {
     Telemetry(Telemetry),
     Position(Position),
     Text(TextMessage),
+    /// What a detection-sensor node sends when its input triggers, e.g. a
+    /// tipping-bucket rain gauge
+    Detection(String),
+    /// A line relayed from a sensor on a node's serial port
+    Serial(String),
 }
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Position {
     pub latitude: f64,  // in degrees
     pub longitude: f64, // in degrees
@@ -36,20 +45,39 @@ impl TryFrom<&[u8]> for Position {
 
     fn try_from(payload: &[u8]) -> Result<Self, Self::Error> {
         let proto_pos = meshtastic::protobufs::Position::decode(payload)
-            .map_err(|_| DecodeError::PositionDecodeError)?;
+            .map_err(|_| DecodeError::InvalidPosition)?;
+
+        Ok(proto_pos.into())
+    }
+}
 
-                Ok(Self {
-            latitude: proto_pos.latitude_i.map(|lat| lat as f64 / 1e7).unwrap_or(0.0),
-            longitude: proto_pos.longitude_i.map(|lon| lon as f64 / 1e7).unwrap_or(0.0),
+/// Also what the radio's node database holds for each node.
+impl From<meshtastic::protobufs::Position> for Position {
+    fn from(proto_pos: meshtastic::protobufs::Position) -> Self {
+        Self {
+            latitude: proto_pos
+                .latitude_i
+                .map(|lat| lat as f64 / 1e7)
+                .unwrap_or(0.0),
+            longitude: proto_pos
+                .longitude_i
+                .map(|lon| lon as f64 / 1e7)
+                .unwrap_or(0.0),
             altitude: proto_pos.altitude.unwrap_or(0),
             accuracy: proto_pos.gps_accuracy,
-            speed: proto_pos.ground_speed.map(|speed| speed as f32 / 1e7).unwrap_or(0.0),
+            speed: proto_pos
+                .ground_speed
+                .map(|speed| speed as f32 / 1e7)
+                .unwrap_or(0.0),
             heading: 0.0, // TODO: extract heading if available
-        })
+        }
     }
 }
 
-#[derive(Debug, Clone, PartialEq)]
+/// Serialized as just its fields, e.g. `{"battery_level": 100, "voltage":
+/// 4.2, "uptime_seconds": 3600}`; the field names tell the variants apart.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(untagged)]
 pub enum Telemetry {
     Device {
         battery_level: Option<u32>,
@@ -60,6 +88,12 @@ pub enum Telemetry {
         temperature: Option<f32>,
         humidity: Option<f32>,
         pressure: Option<f32>,
+        distance: Option<f32>, // in mm, from ultrasonic / lidar range sensors
+        /// Rain in mm over the trailing hour and day, from rain gauges
+        #[serde(skip_serializing_if = "Option::is_none")]
+        rainfall_1h: Option<f32>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        rainfall_24h: Option<f32>,
     },
     Power {
         voltage: Option<f32>,
@@ -67,73 +101,212 @@ pub enum Telemetry {
     },
 }
 
+impl Telemetry {
+    /// Flattens the reading into `(metric, value)` pairs, skipping fields the
+    /// node did not report. Metric names are the ones alert rules refer to.
+    pub fn metrics(&self) -> Vec<(&'static str, f64)> {
+        let fields: Vec<(&'static str, Option<f64>)> = match self {
+            Telemetry::Device {
+                battery_level,
+                voltage,
+                uptime_seconds,
+            } => vec![
+                ("battery_level", battery_level.map(f64::from)),
+                ("voltage", voltage.map(f64::from)),
+                ("uptime_seconds", uptime_seconds.map(f64::from)),
+            ],
+            Telemetry::Environment {
+                temperature,
+                humidity,
+                pressure,
+                distance,
+                rainfall_1h,
+                rainfall_24h,
+            } => vec![
+                ("temperature", temperature.map(f64::from)),
+                ("humidity", humidity.map(f64::from)),
+                ("pressure", pressure.map(f64::from)),
+                ("distance", distance.map(f64::from)),
+                ("rainfall_1h", rainfall_1h.map(f64::from)),
+                ("rainfall_24h", rainfall_24h.map(f64::from)),
+            ],
+            Telemetry::Power { voltage, current } => vec![
+                ("power_voltage", voltage.map(f64::from)),
+                ("power_current", current.map(f64::from)),
+            ],
+        };
 
-impl TryFrom<&[u8]> for Telemetry {
-
-    
-                
-
-    type Error = DecodeError;
+        fields
+            .into_iter()
+            .filter_map(|(name, value)| value.map(|v| (name, v)))
+            .collect()
+    }
 
-    fn try_from(payload: &[u8]) -> Result<Self, Self::Error> {
+    fn from_device(dm: DeviceMetrics) -> Self {
+        Telemetry::Device {
+            battery_level: dm.battery_level,
+            voltage: dm.voltage,
+            uptime_seconds: dm.uptime_seconds,
+        }
+    }
 
+    fn from_environment(env: EnvironmentMetrics) -> Self {
+        Telemetry::Environment {
+            temperature: env.temperature,
+            humidity: env.relative_humidity,
+            pressure: env.barometric_pressure,
+            distance: env.distance,
+            rainfall_1h: env.rainfall_1h,
+            rainfall_24h: env.rainfall_24h,
+        }
+    }
 
+    fn from_power(pwr: PowerMetrics) -> Self {
+        Telemetry::Power {
+            voltage: pwr.ch1_voltage,
+            current: pwr.ch1_current,
+        }
+    }
+}
 
-        use meshtastic::protobufs::{
-            DeviceMetrics,
-            EnvironmentMetrics,
-            PowerMetrics,
-        };
+impl<'de> Deserialize<'de> for Telemetry {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        // Unknown fields are rejected so each shape only matches its own variant
+        #[derive(Deserialize)]
+        #[serde(deny_unknown_fields)]
+        struct Device {
+            battery_level: Option<u32>,
+            voltage: Option<f32>,
+            uptime_seconds: Option<u32>,
+        }
 
+        #[derive(Deserialize)]
+        #[serde(deny_unknown_fields)]
+        struct Environment {
+            temperature: Option<f32>,
+            humidity: Option<f32>,
+            pressure: Option<f32>,
+            distance: Option<f32>,
+            rainfall_1h: Option<f32>,
+            rainfall_24h: Option<f32>,
+        }
 
+        #[derive(Deserialize)]
+        #[serde(deny_unknown_fields)]
+        struct Power {
+            voltage: Option<f32>,
+            current: Option<f32>,
+        }
 
-        let decoded = DeviceMetrics::decode(payload);
-    match decoded {
-        Ok(dm) => {
-            log::debug!("Decoded DeviceMetrics telemetry: {:?}", dm);
-        },
-        Err(_) => {
-            log::warn!("Not a DeviceMetrics payload, trying EnvironmentMetrics");
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Shape {
+            Device(Device),
+            Environment(Environment),
+            Power(Power),
         }
+
+        Ok(match Shape::deserialize(deserializer)? {
+            Shape::Device(d) => Telemetry::Device {
+                battery_level: d.battery_level,
+                voltage: d.voltage,
+                uptime_seconds: d.uptime_seconds,
+            },
+            Shape::Environment(e) => Telemetry::Environment {
+                temperature: e.temperature,
+                humidity: e.humidity,
+                pressure: e.pressure,
+                distance: e.distance,
+                rainfall_1h: e.rainfall_1h,
+                rainfall_24h: e.rainfall_24h,
+            },
+            Shape::Power(p) => Telemetry::Power {
+                voltage: p.voltage,
+                current: p.current,
+            },
+        })
     }
+}
 
+/// Every metric name `Telemetry::metrics` can produce, plus the calibrated
+/// `water_level` derived from `distance` and the battery and rain metrics
+/// the monitors add.
+pub const METRIC_NAMES: &[&str] = &[
+    "battery_level",
+    "voltage",
+    "uptime_seconds",
+    "temperature",
+    "humidity",
+    "pressure",
+    "distance",
+    "rainfall_1h",
+    "rainfall_24h",
+    "power_voltage",
+    "power_current",
+    "water_level",
+    "voltage_slope",
+    "hours_to_cutoff",
+    "rain_1h",
+    "rain_6h",
+    "rain_24h",
+    "rain_72h",
+    "rain_event",
+    "rain_intensity",
+];
 
-        // Try DeviceMetrics first
+impl TryFrom<&[u8]> for Telemetry {
+    type Error = DecodeError;
+
+    fn try_from(payload: &[u8]) -> Result<Self, Self::Error> {
+        // Firmware sends the `Telemetry` envelope (time + oneof variant)
+        if let Ok(envelope) = meshtastic::protobufs::Telemetry::decode(payload) {
+            match envelope.variant {
+                Some(telemetry::Variant::DeviceMetrics(dm)) => {
+                    log::debug!("Decoded DeviceMetrics telemetry: {:?}", dm);
+                    return Ok(Telemetry::from_device(dm));
+                }
+                Some(telemetry::Variant::EnvironmentMetrics(env)) => {
+                    log::debug!("Decoded EnvironmentMetrics telemetry: {:?}", env);
+                    return Ok(Telemetry::from_environment(env));
+                }
+                Some(telemetry::Variant::PowerMetrics(pwr)) => {
+                    log::debug!("Decoded PowerMetrics telemetry: {:?}", pwr);
+                    return Ok(Telemetry::from_power(pwr));
+                }
+                Some(_) => return Err(DecodeError::InvalidTelemetry),
+                None => {}
+            }
+        }
+
+        // Bare metrics payloads: try DeviceMetrics first
         if let Ok(dm) = DeviceMetrics::decode(payload) {
-            return Ok(Telemetry::Device {
-                battery_level: dm.battery_level,
-                voltage: dm.voltage,
-                uptime_seconds: dm.uptime_seconds,
-            });
+            return Ok(Telemetry::from_device(dm));
         }
 
         // Then EnvironmentMetrics
         if let Ok(env) = EnvironmentMetrics::decode(payload) {
-            return Ok(Telemetry::Environment {
-                temperature: env.temperature,
-                humidity: env.relative_humidity,
-                pressure: env.barometric_pressure,
-            });
+            return Ok(Telemetry::from_environment(env));
         }
 
         // Then PowerMetrics
         if let Ok(pwr) = PowerMetrics::decode(payload) {
-            return Ok(Telemetry::Power {
-                voltage: pwr.ch1_voltage,
-                current: pwr.ch1_current,
-            });
+            return Ok(Telemetry::from_power(pwr));
         }
 
-log::warn!(
-    "Telemetry decode failed: {}",
-    payload.iter().map(|b| format!("{:02X}", b)).collect::<Vec<_>>().join(" ")
-);
+        log::warn!(
+            "Telemetry decode failed: {}",
+            payload
+                .iter()
+                .map(|b| format!("{:02X}", b))
+                .collect::<Vec<_>>()
+                .join(" ")
+        );
 
-        Err(DecodeError::TelemetryDecodeError)
+        Err(DecodeError::InvalidTelemetry)
     }
 }
 
-/* 
+/*
 
 impl TryFrom<&[u8]> for Telemetry {
     type Error = DecodeError;
@@ -144,12 +317,12 @@ impl TryFrom<&[u8]> for Telemetry {
         use meshtastic::protobufs::DeviceMetrics ;
 
         let proto_pos = DeviceMetrics::decode(payload)
-            .map_err(|e| { 
+            .map_err(|e| {
                 log::warn!("Failed to decode telemetry payload: {:?} {:?}", payload, e.to_string());
-                
-                DecodeError::TelemetryDecodeError
+
+                DecodeError::InvalidTelemetry
             })?;
-        
+
         Ok(Self {
             battery_level: proto_pos.battery_level,
             voltage: proto_pos.voltage,
@@ -159,39 +332,118 @@ impl TryFrom<&[u8]> for Telemetry {
 }
 */
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize)]
 pub struct RadioMessage {
     pub node_id: u32,
+    #[serde(serialize_with = "serialize_port")]
     pub portnum: PortNum,
     pub app: AppMessage,
 }
 
+impl AppMessage {
+    /// The port this message travels on.
+    pub fn portnum(&self) -> PortNum {
+        match self {
+            AppMessage::Telemetry(_) => PortNum::TelemetryApp,
+            AppMessage::Position(_) => PortNum::PositionApp,
+            AppMessage::Text(_) => PortNum::TextMessageApp,
+            AppMessage::Detection(_) => PortNum::DetectionSensorApp,
+            AppMessage::Serial(_) => PortNum::SerialApp,
+        }
+    }
+
+    /// Encodes the message as the `Data.payload` a node would send, the
+    /// inverse of decoding. `time` stamps the telemetry envelope.
+    pub fn encode_payload(&self, time: u32) -> Vec<u8> {
+        match self {
+            AppMessage::Telemetry(tel) => {
+                let variant = match *tel {
+                    Telemetry::Device {
+                        battery_level,
+                        voltage,
+                        uptime_seconds,
+                    } => telemetry::Variant::DeviceMetrics(DeviceMetrics {
+                        battery_level,
+                        voltage,
+                        uptime_seconds,
+                        ..Default::default()
+                    }),
+                    Telemetry::Environment {
+                        temperature,
+                        humidity,
+                        pressure,
+                        distance,
+                        rainfall_1h,
+                        rainfall_24h,
+                    } => telemetry::Variant::EnvironmentMetrics(EnvironmentMetrics {
+                        temperature,
+                        relative_humidity: humidity,
+                        barometric_pressure: pressure,
+                        distance,
+                        rainfall_1h,
+                        rainfall_24h,
+                        ..Default::default()
+                    }),
+                    Telemetry::Power { voltage, current } => {
+                        telemetry::Variant::PowerMetrics(PowerMetrics {
+                            ch1_voltage: voltage,
+                            ch1_current: current,
+                            ..Default::default()
+                        })
+                    }
+                };
+                meshtastic::protobufs::Telemetry {
+                    time,
+                    variant: Some(variant),
+                }
+                .encode_to_vec()
+            }
+            AppMessage::Position(pos) => meshtastic::protobufs::Position {
+                latitude_i: Some((pos.latitude * 1e7).round() as i32),
+                longitude_i: Some((pos.longitude * 1e7).round() as i32),
+                altitude: Some(pos.altitude),
+                gps_accuracy: pos.accuracy,
+                ground_speed: Some((pos.speed * 1e7).round() as u32),
+                ..Default::default()
+            }
+            .encode_to_vec(),
+            AppMessage::Text(text) => text.msg.as_bytes().to_vec(),
+            AppMessage::Detection(text) | AppMessage::Serial(text) => text.as_bytes().to_vec(),
+        }
+    }
+}
+
+fn serialize_port<S: serde::Serializer>(port: &PortNum, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(port.as_str_name())
+}
+
 impl TryFrom<&FromRadio> for RadioMessage {
     type Error = DecodeError;
 
     fn try_from(msg: &FromRadio) -> Result<Self, Self::Error> {
-        let node_id = msg.id;
-
         // Extract MeshPacket from FromRadio
         let mesh_packet = match &msg.payload_variant {
             Some(FromRadioPayload::Packet(p)) => p,
-            _ => return Err(DecodeError::MeshPacketDecodeError),
+            _ => return Err(DecodeError::NotMeshPacket),
         };
 
+        // The sending node, not the radio-local `FromRadio.id` sequence number
+        let node_id = mesh_packet.from;
+
         // Extract the inner Data payload
         let data = match &mesh_packet.payload_variant {
             Some(MeshPayload::Decoded(d)) => d,
-            _ => return Err(DecodeError::ExtractDecodeError),
+            _ => return Err(DecodeError::NotDecoded),
         };
 
-        let portnum = PortNum::from_i32(data.portnum).ok_or(DecodeError::CouldNotGetPortNum)?;
+        let portnum =
+            PortNum::try_from(data.portnum).map_err(|_| DecodeError::CouldNotGetPortNum)?;
 
         // Decode based on the port type
         let payload = &data.payload[..];
 
         let app = match portnum {
             PortNum::TelemetryApp => {
-                
                 let telemetry = Telemetry::try_from(payload)?;
                 AppMessage::Telemetry(telemetry)
             }
@@ -204,12 +456,17 @@ impl TryFrom<&FromRadio> for RadioMessage {
                 let msg_str = String::from_utf8_lossy(payload).to_string();
                 log::trace!("Decoded text message: {}", msg_str);
                 let text_msg = TextMessage {
-                    to: None,   // populate if your protocol supplies
-                    from: None, // populate if your protocol supplies
+                    // Broadcasts have no recipient
+                    to: (mesh_packet.to != u32::MAX).then(|| NodeId(mesh_packet.to).to_string()),
+                    from: Some(NodeId(node_id).to_string()),
                     msg: msg_str,
                 };
                 AppMessage::Text(text_msg)
             }
+            PortNum::DetectionSensorApp => {
+                AppMessage::Detection(String::from_utf8_lossy(payload).to_string())
+            }
+            PortNum::SerialApp => AppMessage::Serial(String::from_utf8_lossy(payload).to_string()),
             _ => return Err(DecodeError::UnsupportedPort(portnum)),
         };
 
@@ -221,7 +478,7 @@ impl TryFrom<&FromRadio> for RadioMessage {
     }
 }
 
-/* 
+/*
 
 fn extract_data(msg: &FromRadio) -> Result<(PortNum, &[u8]), DecodeError> {
     let packet = match &msg.payload_variant {
@@ -241,26 +498,33 @@ fn extract_data(msg: &FromRadio) -> Result<(PortNum, &[u8]), DecodeError> {
 
  */
 
-
 #[derive(Debug, PartialEq)]
 pub enum DecodeError {
     CouldNotGetPortNum,
     UnsupportedPort(PortNum),
-    MeshPacketDecodeError,
-    ExtractDecodeError,
-    TelemetryAppError,
-    TelemetryDecodeError,
-    PositionDecodeError,
-    PositionAppError,
-    ExtractedData,
-    LocalSystemMessage,
+    NotMeshPacket,
+    NotDecoded,
+    InvalidTelemetry,
+    InvalidPosition,
     //ProtobufDecodeError(prost::error::DecodeError),
 }
 
+impl DecodeError {
+    /// The variant name without its payload, for metric labels.
+    pub fn variant_name(&self) -> &'static str {
+        match self {
+            DecodeError::CouldNotGetPortNum => "CouldNotGetPortNum",
+            DecodeError::UnsupportedPort(_) => "UnsupportedPort",
+            DecodeError::NotMeshPacket => "NotMeshPacket",
+            DecodeError::NotDecoded => "NotDecoded",
+            DecodeError::InvalidTelemetry => "InvalidTelemetry",
+            DecodeError::InvalidPosition => "InvalidPosition",
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
-    use std::env;
-
     use super::*;
 
     use meshtastic::protobufs::from_radio::PayloadVariant as FromRadioPayload;
@@ -274,6 +538,7 @@ mod tests {
             .try_init();
     }
 
+    #[allow(deprecated)]
     fn make_from_radio(portnum: PortNum) -> FromRadio {
         let data = Data {
             portnum: portnum as i32,
@@ -372,11 +637,81 @@ mod tests {
 
         let msg = RadioMessage::try_from(&from_radio).unwrap();
 
-        // match msg.app {
-        //     AppMessage::Telemetry(dm) => {
-        //         assert_eq!(dm.battery_level , Some(87));
-        //     }
-        //     _ => panic!("Expected telemetry"),
-        // }
+        match msg.app {
+            AppMessage::Telemetry(Telemetry::Device { battery_level, .. }) => {
+                assert_eq!(battery_level, Some(87));
+            }
+            _ => panic!("Expected telemetry"),
+        }
+    }
+
+    #[test]
+    fn decodes_telemetry_envelope() {
+        init_test_logging();
+        let envelope = meshtastic::protobufs::Telemetry {
+            time: 1766889471,
+            variant: Some(telemetry::Variant::EnvironmentMetrics(EnvironmentMetrics {
+                temperature: Some(11.5),
+                distance: Some(2450.0),
+                ..Default::default()
+            })),
+        };
+
+        let telemetry = Telemetry::try_from(&envelope.encode_to_vec()[..]).unwrap();
+        assert_eq!(
+            telemetry.metrics(),
+            vec![("temperature", 11.5), ("distance", 2450.0)]
+        );
+    }
+
+    #[test]
+    fn serializes_in_test_jsonl_shape() {
+        let app = AppMessage::Telemetry(Telemetry::Device {
+            battery_level: Some(100),
+            voltage: Some(4.2),
+            uptime_seconds: Some(3600),
+        });
+        let json = serde_json::to_value(&app).unwrap();
+        assert_eq!(json["Telemetry"]["battery_level"], 100);
+        assert_eq!(json["Telemetry"]["uptime_seconds"], 3600);
+
+        assert_eq!(serde_json::from_value::<AppMessage>(json).unwrap(), app);
+
+        let power: Telemetry =
+            serde_json::from_str(r#"{"voltage": 12.1, "current": 0.4}"#).unwrap();
+        assert!(matches!(power, Telemetry::Power { .. }));
+    }
+
+    #[test]
+    fn encoded_payload_decodes_to_the_same_message() {
+        let messages = [
+            AppMessage::Telemetry(Telemetry::Environment {
+                temperature: Some(11.5),
+                humidity: None,
+                pressure: None,
+                distance: Some(2500.0),
+                rainfall_1h: None,
+                rainfall_24h: None,
+            }),
+            AppMessage::Position(Position {
+                latitude: 45.5,
+                longitude: -122.25,
+                altitude: 30,
+                accuracy: 5,
+                speed: 0.0,
+                heading: 0.0,
+            }),
+        ];
+
+        for app in messages {
+            let payload = app.encode_payload(0);
+            let decoded = match &app {
+                AppMessage::Telemetry(_) => {
+                    AppMessage::Telemetry(Telemetry::try_from(&payload[..]).unwrap())
+                }
+                _ => AppMessage::Position(Position::try_from(&payload[..]).unwrap()),
+            };
+            assert_eq!(decoded, app);
+        }
     }
 }
@@ -0,0 +1,425 @@
+use std::collections::{HashMap, VecDeque};
+
+use crate::config::{Config, RainConfig, RainGauge, RainSource};
+use crate::quality::Quality;
+use crate::radio_message::AppMessage;
+
+/// Rolling totals added to a rain gauge's readings, in mm.
+const WINDOWS: [(&str, u64); 4] = [
+    ("rain_1h", 3600),
+    ("rain_6h", 6 * 3600),
+    ("rain_24h", 24 * 3600),
+    ("rain_72h", 72 * 3600),
+];
+/// Rain since the current event started, 0 between events.
+pub const EVENT_METRIC: &str = "rain_event";
+/// Rain over `intensity_mins`, in mm per hour.
+pub const INTENSITY_METRIC: &str = "rain_intensity";
+
+/// Rain kept per station, the longest window.
+const RETAIN_SECS: u64 = 72 * 3600;
+
+/// How often `check` recomputes totals between reports.
+const CHECK_SECS: u64 = 60;
+
+/// What one packet says about the rain.
+enum Input {
+    /// `rainfall_1h`, the station's own trailing-hour total
+    Hourly(f64),
+    /// One tip of the bucket
+    Tip,
+    /// The bucket's running tip count
+    Count(f64),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Event {
+    start: u64,
+    last_rain: u64,
+    total: f64,
+}
+
+#[derive(Debug, Default)]
+struct Station {
+    /// (timestamp, mm) within `RETAIN_SECS`, oldest first
+    rain: VecDeque<(u64, f64)>,
+    /// Time of the last report and the rainfall or tip count it carried
+    last: Option<(u64, f64)>,
+    event: Option<Event>,
+    /// Totals as last added to readings, so `check` only passes on changes
+    published: Vec<(&'static str, f64)>,
+    checked_at: u64,
+}
+
+impl Station {
+    fn add(&mut self, timestamp: u64, mm: f64) {
+        if mm <= 0.0 {
+            return;
+        }
+        self.rain.push_back((timestamp, mm));
+        let event = self.event.get_or_insert(Event {
+            start: timestamp,
+            last_rain: timestamp,
+            total: 0.0,
+        });
+        event.total += mm;
+        event.last_rain = timestamp;
+    }
+
+    /// Ends the current event once it has been dry for `gap` seconds.
+    fn end_event(&mut self, now: u64, gap: u64, name: &str) {
+        if let Some(event) = self.event
+            && now - event.last_rain > gap
+        {
+            log::info!(
+                "Rain event at {} ended: {:.1} mm over {:.1} h",
+                name,
+                event.total,
+                (event.last_rain - event.start) as f64 / 3600.0
+            );
+            self.event = None;
+        }
+    }
+
+    fn totals(&self, now: u64, config: &RainConfig) -> Vec<(&'static str, f64)> {
+        let since = |secs: u64| -> f64 {
+            self.rain
+                .iter()
+                .rev()
+                .take_while(|&&(t, _)| t + secs > now)
+                .map(|&(_, mm)| mm)
+                .sum()
+        };
+        let mut totals: Vec<(&'static str, f64)> = WINDOWS
+            .iter()
+            .map(|&(metric, secs)| (metric, since(secs)))
+            .collect();
+        totals.push((EVENT_METRIC, self.event.map_or(0.0, |e| e.total)));
+        totals.push((
+            INTENSITY_METRIC,
+            since(config.intensity_mins * 60) * 60.0 / config.intensity_mins as f64,
+        ));
+        totals
+    }
+}
+
+/// Keeps rolling rain totals and rain events for every node that reports
+/// rain: `rainfall_1h` telemetry, or a tipping bucket configured as a
+/// node's `rain_gauge`. The totals are added to the node's readings, so
+/// alert rules can use them like any other metric.
+///
+/// A tip count that goes down means the gauge restarted, and the count is
+/// taken as the tips since then. A count rise across a gap in reports is
+/// spread over the gap. Rainfall telemetry only covers the hour before
+/// each report, so rain in a longer gap is missing from the totals.
+pub struct RainMonitor {
+    config: RainConfig,
+    gauges: HashMap<u32, RainGauge>,
+    stations: HashMap<u32, Station>,
+}
+
+impl RainMonitor {
+    pub fn from_config(config: &Config) -> Self {
+        let mut monitor = Self {
+            config: config.rain.clone(),
+            gauges: HashMap::new(),
+            stations: HashMap::new(),
+        };
+        monitor.reconfigure(config);
+        monitor
+    }
+
+    /// Takes settings and rain gauges from a reloaded config, keeping the
+    /// totals.
+    pub fn reconfigure(&mut self, config: &Config) {
+        self.config = config.rain.clone();
+        self.gauges = config
+            .nodes
+            .iter()
+            .filter_map(|n| Some((n.id.0, n.rain_gauge?)))
+            .collect();
+    }
+
+    /// Adds the rain a packet reports and the node's totals to its
+    /// readings. Flagged `rainfall_1h` readings are ignored.
+    pub fn observe(
+        &mut self,
+        timestamp: u64,
+        node_id: u32,
+        node_name: &str,
+        app: &AppMessage,
+        quality: &[(&'static str, Quality)],
+        readings: &mut Vec<(&'static str, f64)>,
+    ) {
+        let hourly = readings
+            .iter()
+            .find(|(m, _)| *m == "rainfall_1h")
+            .filter(|(m, _)| !quality.iter().any(|(q, _)| q == m));
+        let input = match (hourly, self.gauges.get(&node_id), app) {
+            (Some(&(_, mm)), _, _) => Input::Hourly(mm),
+            (None, Some(gauge), AppMessage::Detection(_))
+                if gauge.source == RainSource::Detection =>
+            {
+                Input::Tip
+            }
+            (None, Some(gauge), AppMessage::Serial(line)) if gauge.source == RainSource::Serial => {
+                match tip_count(line) {
+                    Some(count) => Input::Count(count),
+                    None => {
+                        log::debug!("No tip count in serial line from {}: {}", node_name, line);
+                        return;
+                    }
+                }
+            }
+            _ => return,
+        };
+
+        let mm_per_tip = self.gauges.get(&node_id).map_or(0.0, |g| g.mm_per_tip);
+        let gap = (self.config.event_gap_hours * 3600.0) as u64;
+        let max_gap = self.config.max_gap_mins * 60;
+        let station = self.stations.entry(node_id).or_default();
+        if station.last.is_some_and(|(t, _)| timestamp <= t) {
+            return;
+        }
+        station.end_event(timestamp, gap, node_name);
+
+        let since = station.last.map(|(t, _)| timestamp - t);
+        match input {
+            Input::Hourly(mm) => {
+                if since.is_some_and(|secs| secs > max_gap.max(3600)) {
+                    log::info!(
+                        "{} reported no rainfall for {} min; the rain in between is unknown",
+                        node_name,
+                        since.unwrap_or_default() / 60
+                    );
+                }
+                // Reports more often than hourly overlap
+                let covered = since.unwrap_or(3600).min(3600);
+                station.add(timestamp, mm * covered as f64 / 3600.0);
+                station.last = Some((timestamp, mm));
+            }
+            Input::Tip => {
+                station.add(timestamp, mm_per_tip);
+                station.last = Some((timestamp, 0.0));
+            }
+            Input::Count(count) => {
+                if let Some((last_time, last_count)) = station.last {
+                    let tips = if count >= last_count {
+                        count - last_count
+                    } else {
+                        log::info!(
+                            "Tip count of {} went from {} to {}; counting from a restart",
+                            node_name,
+                            last_count,
+                            count
+                        );
+                        count
+                    };
+                    let mm = tips * mm_per_tip;
+                    if timestamp - last_time > max_gap {
+                        // Even steps across the gap, ending at this report
+                        let step = self.config.intensity_mins * 60;
+                        let steps = (timestamp - last_time).div_ceil(step);
+                        for k in 1..=steps {
+                            let t = (last_time + k * step).min(timestamp);
+                            station.add(t, mm / steps as f64);
+                        }
+                    } else {
+                        station.add(timestamp, mm);
+                    }
+                }
+                station.last = Some((timestamp, count));
+            }
+        }
+
+        while station
+            .rain
+            .front()
+            .is_some_and(|&(t, _)| t + RETAIN_SECS <= timestamp)
+        {
+            station.rain.pop_front();
+        }
+        let totals = station.totals(timestamp, &self.config);
+        readings.extend(totals.iter().copied());
+        station.published = totals;
+        station.checked_at = timestamp;
+    }
+
+    /// Totals that have changed since they were last added to readings,
+    /// as rain drops out of the windows between reports, for alert rules
+    /// to re-evaluate. Recomputed at most once a minute per station.
+    pub fn check(
+        &mut self,
+        now: u64,
+        names: impl Fn(u32) -> String,
+    ) -> Vec<(u32, Vec<(&'static str, f64)>)> {
+        let gap = (self.config.event_gap_hours * 3600.0) as u64;
+        let mut changed = Vec::new();
+        for (&node_id, station) in &mut self.stations {
+            if now < station.checked_at + CHECK_SECS {
+                continue;
+            }
+            station.checked_at = now;
+            station.end_event(now, gap, &names(node_id));
+            let totals = station.totals(now, &self.config);
+            if totals != station.published {
+                station.published = totals.clone();
+                changed.push((node_id, totals));
+            }
+        }
+        changed.sort_by_key(|&(node_id, _)| node_id);
+        changed
+    }
+}
+
+/// The first number in a serial line, e.g. 1234 in `tips=1234`.
+fn tip_count(line: &str) -> Option<f64> {
+    line.split(|c: char| !c.is_ascii_digit())
+        .find(|s| !s.is_empty())?
+        .parse()
+        .ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::radio_message::Telemetry;
+
+    const MIN: u64 = 60;
+    const HOUR: u64 = 3600;
+
+    fn monitor() -> RainMonitor {
+        RainMonitor::from_config(
+            &Config::parse(
+                r#"
+                [[nodes]]
+                id = 7
+                rain_gauge = { source = "serial", mm_per_tip = 0.5 }
+
+                [[nodes]]
+                id = 8
+                rain_gauge = { source = "detection" }
+                "#,
+            )
+            .unwrap(),
+        )
+    }
+
+    fn observe(
+        monitor: &mut RainMonitor,
+        timestamp: u64,
+        node_id: u32,
+        app: AppMessage,
+        readings: Vec<(&'static str, f64)>,
+    ) -> Vec<(&'static str, f64)> {
+        let mut readings = readings;
+        monitor.observe(timestamp, node_id, "gauge", &app, &[], &mut readings);
+        readings
+    }
+
+    fn metric(readings: &[(&'static str, f64)], name: &str) -> f64 {
+        let value = readings.iter().find(|(m, _)| *m == name).unwrap().1;
+        // Sums of 0.2 mm tips are not exact
+        (value * 1000.0).round() / 1000.0
+    }
+
+    #[test]
+    fn counts_tips_across_restarts_and_gaps() {
+        let mut monitor = monitor();
+        let serial = |count: u64| AppMessage::Serial(format!("tips={}\r\n", count));
+        // The first count is only a baseline
+        let readings = observe(&mut monitor, 0, 7, serial(100), Vec::new());
+        assert_eq!(metric(&readings, "rain_72h"), 0.0);
+        let readings = observe(&mut monitor, 10 * MIN, 7, serial(104), Vec::new());
+        assert_eq!(metric(&readings, "rain_1h"), 2.0);
+        assert_eq!(metric(&readings, INTENSITY_METRIC), 8.0);
+
+        // Restarted: 3 tips since
+        let readings = observe(&mut monitor, 20 * MIN, 7, serial(3), Vec::new());
+        assert_eq!(metric(&readings, "rain_1h"), 3.5);
+
+        // 8 tips over a 2 h gap are spread across it
+        let readings = observe(&mut monitor, 140 * MIN, 7, serial(11), Vec::new());
+        assert_eq!(metric(&readings, "rain_1h"), 2.0);
+        assert_eq!(metric(&readings, "rain_6h"), 7.5);
+        assert_eq!(metric(&readings, EVENT_METRIC), 7.5);
+        assert_eq!(metric(&readings, INTENSITY_METRIC), 2.0);
+    }
+
+    #[test]
+    fn ends_events_after_a_dry_spell_and_decays_between_reports() {
+        let mut monitor = monitor();
+        for minute in [0, 5, 10, 15] {
+            observe(
+                &mut monitor,
+                minute * MIN,
+                8,
+                AppMessage::Detection("tip".into()),
+                Vec::new(),
+            );
+        }
+        let name = |_| "gauge".to_string();
+        // Checked at most once a minute
+        assert!(monitor.check(15 * MIN + 30, name).is_empty());
+        let changed = monitor.check(30 * MIN, name);
+        assert_eq!(metric(&changed[0].1, "rain_1h"), 0.8);
+        assert_eq!(metric(&changed[0].1, INTENSITY_METRIC), 0.0);
+        let changed = monitor.check(70 * MIN, name);
+        assert_eq!(changed[0].0, 8);
+        assert_eq!(metric(&changed[0].1, "rain_1h"), 0.2);
+        assert_eq!(metric(&changed[0].1, "rain_24h"), 0.8);
+        assert_eq!(metric(&changed[0].1, EVENT_METRIC), 0.8);
+
+        // 6 h dry ends the event; the next tip starts another
+        let changed = monitor.check(6 * HOUR + 20 * MIN, name);
+        assert_eq!(metric(&changed[0].1, EVENT_METRIC), 0.0);
+        let readings = observe(
+            &mut monitor,
+            7 * HOUR,
+            8,
+            AppMessage::Detection("tip".into()),
+            Vec::new(),
+        );
+        assert_eq!(metric(&readings, EVENT_METRIC), 0.2);
+        assert_eq!(metric(&readings, "rain_24h"), 1.0);
+    }
+
+    #[test]
+    fn turns_hourly_rainfall_telemetry_into_totals() {
+        let mut monitor = monitor();
+        let telemetry = AppMessage::Telemetry(Telemetry::Environment {
+            temperature: None,
+            humidity: None,
+            pressure: None,
+            distance: None,
+            rainfall_1h: Some(4.0),
+            rainfall_24h: None,
+        });
+        let mut last = Vec::new();
+        // Half-hourly reports of 4 mm in the trailing hour
+        for k in 0..6 {
+            last = observe(
+                &mut monitor,
+                k * 30 * MIN,
+                9,
+                telemetry.clone(),
+                vec![("rainfall_1h", 4.0)],
+            );
+        }
+        assert_eq!(metric(&last, "rain_1h"), 4.0);
+        // The first report covers the hour before it
+        assert_eq!(metric(&last, "rain_6h"), 14.0);
+
+        // A flagged reading adds nothing
+        let mut readings = vec![("rainfall_1h", 250.0)];
+        monitor.observe(
+            3 * HOUR,
+            9,
+            "gauge",
+            &telemetry,
+            &[("rainfall_1h", Quality::OutOfRange)],
+            &mut readings,
+        );
+        assert_eq!(readings.len(), 1);
+    }
+}
@@ -0,0 +1,606 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fmt::Write as _;
+
+use crate::alerts::{AlertEvent, AlertState, StageRule, stage_rules};
+use crate::config::{Config, ReachConfig, Severity};
+use crate::nodes::NodeDirectory;
+
+/// Alert raised on a gauge when a crest upstream is forecast to reach it.
+pub const FORECAST_RULE: &str = "crest-forecast";
+
+/// Resolution of the series cross-correlated to learn travel times.
+const LEARN_STEP_SECS: u64 = 300;
+
+/// Gaps in a series longer than this are not interpolated across.
+const MAX_GAP_SECS: u64 = 3600;
+
+/// Fewest overlapping points a learned travel time is based on.
+const MIN_OVERLAP: usize = 24;
+
+/// Learned travel times with a weaker correlation are only suggested.
+const MIN_CORRELATION: f64 = 0.6;
+
+/// A confirmed crest at one gauge.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Crest {
+    timestamp: u64,
+    level: f64,
+    /// Lowest level before the wave
+    base: f64,
+}
+
+#[derive(Debug, Default)]
+struct Gauge {
+    /// (timestamp, level) within `window_hours`, oldest first
+    samples: VecDeque<(u64, f64)>,
+    /// Base and highest (timestamp, level) of the wave under way
+    wave: Option<(f64, (u64, f64))>,
+}
+
+impl Gauge {
+    /// Adds a reading, returning the crest it confirms.
+    fn observe(&mut self, timestamp: u64, level: f64, reach: &ReachConfig) -> Option<Crest> {
+        if self.samples.back().is_some_and(|&(t, _)| timestamp <= t) {
+            return None;
+        }
+        let window = (reach.window_hours * 3600.0) as u64;
+        self.samples.push_back((timestamp, level));
+        while self
+            .samples
+            .front()
+            .is_some_and(|&(t, _)| t + window < timestamp)
+        {
+            self.samples.pop_front();
+        }
+
+        match &mut self.wave {
+            None => {
+                let base = self.base()?;
+                if level >= base + reach.rise {
+                    self.wave = Some((base, (timestamp, level)));
+                }
+                None
+            }
+            Some((_, peak)) if level > peak.1 => {
+                *peak = (timestamp, level);
+                None
+            }
+            Some((base, peak)) if level <= peak.1 - reach.fall => {
+                let crest = Crest {
+                    timestamp: peak.0,
+                    level: peak.1,
+                    base: *base,
+                };
+                // The falling limb is what the next wave rises from
+                self.wave = None;
+                self.samples.clear();
+                self.samples.push_back((timestamp, level));
+                Some(crest)
+            }
+            Some(_) => None,
+        }
+    }
+
+    /// The level before the wave under way, or the lowest of the window.
+    fn base(&self) -> Option<f64> {
+        match self.wave {
+            Some((base, _)) => Some(base),
+            None => self.samples.iter().map(|&(_, v)| v).reduce(f64::min),
+        }
+    }
+}
+
+/// A raised forecast for one gauge.
+#[derive(Debug, Clone, Copy)]
+struct Forecast {
+    expected_at: u64,
+    level: f64,
+    threshold: f64,
+    severity: Severity,
+    /// When it clears if the gauge shows no crest of its own
+    clear_at: u64,
+}
+
+struct Reach {
+    config: ReachConfig,
+    /// `config.gauges` resolved to node ids
+    nodes: Vec<u32>,
+}
+
+/// Watches the gauges of each `[[reaches]]` for flood waves. When one
+/// crests, every gauge below it gets a `crest-forecast` alert with the
+/// expected arrival time and stage: its level before the wave plus the
+/// crest's rise, scaled by each `stage_ratio` on the way. The forecast
+/// takes the severity and threshold of the highest `water_level` rule that
+/// stage would breach; breaching none, the reach's `severity` and the level
+/// before the wave. It clears when the gauge crests itself, or
+/// `clear_after_mins` after the expected arrival.
+pub struct ReachMonitor {
+    reaches: Vec<Reach>,
+    flood_rules: Vec<StageRule>,
+    gauges: HashMap<(usize, u32), Gauge>,
+    /// Latest good `water_level` of every gauge
+    levels: HashMap<u32, f64>,
+    forecasts: HashMap<u32, Forecast>,
+}
+
+impl ReachMonitor {
+    pub fn from_config(config: &Config) -> Self {
+        let mut monitor = Self {
+            reaches: Vec::new(),
+            flood_rules: Vec::new(),
+            gauges: HashMap::new(),
+            levels: HashMap::new(),
+            forecasts: HashMap::new(),
+        };
+        monitor.reconfigure(config);
+        monitor
+    }
+
+    /// Takes reaches from a reloaded config, keeping waves under way and
+    /// raised forecasts.
+    pub fn reconfigure(&mut self, config: &Config) {
+        self.reaches = config
+            .reaches
+            .iter()
+            .filter_map(|reach| {
+                let nodes = reach
+                    .gauges
+                    .iter()
+                    .map(|g| config.resolve_node(&g.node).map(|id| id.0))
+                    .collect::<Option<_>>()?;
+                Some(Reach {
+                    config: reach.clone(),
+                    nodes,
+                })
+            })
+            .collect();
+        self.flood_rules = stage_rules(config);
+        let reaches = self.reaches.len();
+        self.gauges.retain(|&(i, _), _| i < reaches);
+    }
+
+    /// Marks forecasts raised before a restart as active. Their arrival
+    /// time is not kept, so they clear at the next check.
+    pub fn restore(&mut self, active: &HashSet<(String, u32)>) {
+        for (rule, node_id) in active {
+            if rule == FORECAST_RULE {
+                self.forecasts.insert(
+                    *node_id,
+                    Forecast {
+                        expected_at: 0,
+                        level: 0.0,
+                        threshold: 0.0,
+                        severity: Severity::Info,
+                        clear_at: 0,
+                    },
+                );
+            }
+        }
+    }
+
+    /// Adds a good `water_level` reading, returning the forecasts raised
+    /// for the gauges below and the gauge's own forecast cleared if it
+    /// crested.
+    pub fn observe(
+        &mut self,
+        timestamp: u64,
+        node_id: u32,
+        level: f64,
+        directory: &NodeDirectory,
+    ) -> Vec<AlertEvent> {
+        self.levels.insert(node_id, level);
+        let mut events = Vec::new();
+        for (i, reach) in self.reaches.iter().enumerate() {
+            let Some(position) = reach.nodes.iter().position(|&n| n == node_id) else {
+                continue;
+            };
+            let Some(crest) = self.gauges.entry((i, node_id)).or_default().observe(
+                timestamp,
+                level,
+                &reach.config,
+            ) else {
+                continue;
+            };
+            let name = directory.display_name(node_id);
+            log::info!(
+                "Crest of {:.2} at {} on the {} reach",
+                crest.level,
+                name,
+                reach.config.name
+            );
+
+            if let Some(forecast) = self.forecasts.remove(&node_id) {
+                if forecast.expected_at > 0 {
+                    log::info!(
+                        "Crest reached {} {} min from the forecast, {:.2} against {:.2} forecast",
+                        name,
+                        (crest.timestamp as i64 - forecast.expected_at as i64) / 60,
+                        crest.level,
+                        forecast.level
+                    );
+                }
+                events.push(AlertEvent {
+                    rule: FORECAST_RULE.to_string(),
+                    timestamp,
+                    node_id,
+                    node_name: name.clone(),
+                    metric: "water_level".to_string(),
+                    value: crest.level,
+                    threshold: forecast.threshold,
+                    expected_at: None,
+                    severity: forecast.severity,
+                    state: AlertState::Cleared,
+                });
+            }
+
+            let (mut travel_secs, mut rise) = (0.0, crest.level - crest.base);
+            for (gauge, &below) in reach
+                .config
+                .gauges
+                .iter()
+                .zip(&reach.nodes)
+                .skip(position + 1)
+            {
+                travel_secs += gauge.travel_mins.unwrap_or_default() * 60.0;
+                rise *= gauge.stage_ratio;
+                let expected_at = crest.timestamp + travel_secs as u64;
+                // Too late to warn of
+                if expected_at <= timestamp {
+                    continue;
+                }
+                let Some(base) = self
+                    .gauges
+                    .get(&(i, below))
+                    .and_then(Gauge::base)
+                    .or_else(|| self.levels.get(&below).copied())
+                else {
+                    continue;
+                };
+                let level = base + rise;
+                let (threshold, severity) = self
+                    .flood_rules
+                    .iter()
+                    .filter(|rule| rule.applies_to(below) && level > rule.above)
+                    .max_by(|a, b| a.above.total_cmp(&b.above))
+                    .map_or((base, reach.config.severity), |rule| {
+                        (rule.above, rule.severity)
+                    });
+                let below_name = directory.display_name(below);
+                log::warn!(
+                    "Crest at {} forecast to reach {} in {} min at {:.2}",
+                    name,
+                    below_name,
+                    (expected_at - timestamp) / 60,
+                    level
+                );
+                self.forecasts.insert(
+                    below,
+                    Forecast {
+                        expected_at,
+                        level,
+                        threshold,
+                        severity,
+                        clear_at: expected_at + (reach.config.clear_after_mins * 60.0) as u64,
+                    },
+                );
+                events.push(AlertEvent {
+                    rule: FORECAST_RULE.to_string(),
+                    timestamp,
+                    node_id: below,
+                    node_name: below_name,
+                    metric: "water_level".to_string(),
+                    value: level,
+                    threshold,
+                    expected_at: Some(expected_at),
+                    severity,
+                    state: AlertState::Raised,
+                });
+            }
+        }
+        events
+    }
+
+    /// Clears forecasts whose crest should have passed by `now`.
+    pub fn check(&mut self, now: u64, directory: &NodeDirectory) -> Vec<AlertEvent> {
+        let mut due: Vec<u32> = self
+            .forecasts
+            .iter()
+            .filter(|(_, f)| f.clear_at <= now)
+            .map(|(&node_id, _)| node_id)
+            .collect();
+        due.sort_unstable();
+        due.into_iter()
+            .map(|node_id| {
+                let forecast = self.forecasts.remove(&node_id).expect("collected above");
+                AlertEvent {
+                    rule: FORECAST_RULE.to_string(),
+                    timestamp: now,
+                    node_id,
+                    node_name: directory.display_name(node_id),
+                    metric: "water_level".to_string(),
+                    value: self.levels.get(&node_id).copied().unwrap_or_default(),
+                    threshold: forecast.threshold,
+                    expected_at: None,
+                    severity: forecast.severity,
+                    state: AlertState::Cleared,
+                }
+            })
+            .collect()
+    }
+}
+
+/* ---------------- Learning travel times ---------------- */
+
+/// A travel time found by cross-correlating two gauges' series.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Learned {
+    pub travel_secs: u64,
+    /// Correlation of the two series at that lag
+    pub correlation: f64,
+    /// Rise at the lower gauge per unit of rise at the upper one
+    pub stage_ratio: f64,
+    /// Overlapping points the correlation is over
+    pub points: usize,
+}
+
+/// The series resampled to `LEARN_STEP_SECS` steps from `origin`, linearly
+/// interpolated except across gaps longer than `MAX_GAP_SECS`.
+fn resample(series: &[(u64, f64)], origin: u64, len: usize) -> Vec<Option<f64>> {
+    let mut out = vec![None; len];
+    let mut k = 0;
+    for (i, slot) in out.iter_mut().enumerate() {
+        let t = origin + i as u64 * LEARN_STEP_SECS;
+        while k + 1 < series.len() && series[k + 1].0 <= t {
+            k += 1;
+        }
+        let Some(&(t0, v0)) = series.get(k).filter(|&&(t0, _)| t0 <= t) else {
+            continue;
+        };
+        if t == t0 {
+            *slot = Some(v0);
+        } else if let Some(&(t1, v1)) = series.get(k + 1)
+            && t1 - t0 <= MAX_GAP_SECS
+        {
+            *slot = Some(v0 + (v1 - v0) * (t - t0) as f64 / (t1 - t0) as f64);
+        }
+    }
+    out
+}
+
+/// The lag of up to `max_secs` at which the lower gauge's series best
+/// follows the upper one's. Both are (timestamp, level), oldest first.
+pub fn learn_travel_time(
+    upper: &[(u64, f64)],
+    lower: &[(u64, f64)],
+    max_secs: u64,
+) -> Option<Learned> {
+    let origin = upper.first()?.0.min(lower.first()?.0) / LEARN_STEP_SECS * LEARN_STEP_SECS;
+    let end = upper.last()?.0.max(lower.last()?.0);
+    let len = ((end - origin) / LEARN_STEP_SECS + 1) as usize;
+    let (upper, lower) = (resample(upper, origin, len), resample(lower, origin, len));
+
+    let mut best: Option<Learned> = None;
+    for lag in 1..=(max_secs / LEARN_STEP_SECS) as usize {
+        let pairs: Vec<(f64, f64)> = (0..len.saturating_sub(lag))
+            .filter_map(|i| Some((upper[i]?, lower[i + lag]?)))
+            .collect();
+        if pairs.len() < MIN_OVERLAP {
+            continue;
+        }
+        let n = pairs.len() as f64;
+        let mean_x = pairs.iter().map(|p| p.0).sum::<f64>() / n;
+        let mean_y = pairs.iter().map(|p| p.1).sum::<f64>() / n;
+        let (mut sxy, mut sxx, mut syy) = (0.0, 0.0, 0.0);
+        for &(x, y) in &pairs {
+            sxy += (x - mean_x) * (y - mean_y);
+            sxx += (x - mean_x).powi(2);
+            syy += (y - mean_y).powi(2);
+        }
+        if sxx == 0.0 || syy == 0.0 {
+            continue;
+        }
+        let correlation = sxy / (sxx * syy).sqrt();
+        if best.is_none_or(|b| correlation > b.correlation) {
+            best = Some(Learned {
+                travel_secs: lag as u64 * LEARN_STEP_SECS,
+                correlation,
+                stage_ratio: sxy / sxx,
+                points: pairs.len(),
+            });
+        }
+    }
+    best
+}
+
+/// `[[reaches.gauges]]` for every configured reach with travel times and
+/// stage ratios learned from `series`, the `water_level` history of each
+/// node. Lags are searched up to three times the configured travel time,
+/// and at least a day.
+pub fn learn(config: &Config, series: &HashMap<u32, Vec<(u64, f64)>>) -> String {
+    let mut out = String::from(
+        "# Learned by cross-correlating water_level between neighbouring gauges.\n\
+         # Weak correlations are left commented out.\n",
+    );
+    for reach in &config.reaches {
+        let _ = writeln!(out, "\n# Reach `{}`", reach.name);
+        let mut upper: Option<&[(u64, f64)]> = None;
+        for gauge in &reach.gauges {
+            let own = config
+                .resolve_node(&gauge.node)
+                .and_then(|id| series.get(&id.0))
+                .map(Vec::as_slice);
+            let _ = writeln!(out, "[[reaches.gauges]]\nnode = \"{}\"", gauge.node);
+            if let Some(configured) = gauge.travel_mins {
+                let max_secs = ((configured * 180.0) as u64).max(86_400);
+                match upper
+                    .zip(own)
+                    .and_then(|(upper, own)| learn_travel_time(upper, own, max_secs))
+                {
+                    Some(learned) => {
+                        let comment = if learned.correlation >= MIN_CORRELATION {
+                            ""
+                        } else {
+                            "# "
+                        };
+                        let _ = writeln!(
+                            out,
+                            "{}travel_mins = {}    # configured {}; correlation {:.2} over {} points\n\
+                             {}stage_ratio = {:.2}",
+                            comment,
+                            learned.travel_secs / 60,
+                            configured,
+                            learned.correlation,
+                            learned.points,
+                            comment,
+                            learned.stage_ratio
+                        );
+                    }
+                    None => {
+                        let _ = writeln!(
+                            out,
+                            "travel_mins = {}    # configured; too few overlapping readings",
+                            configured
+                        );
+                    }
+                }
+            }
+            out.push('\n');
+            upper = own;
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MIN: u64 = 60;
+
+    fn monitor(ratio: f64) -> (ReachMonitor, NodeDirectory) {
+        let config = Config::parse(&format!(
+            r#"
+            [[nodes]]
+            id = 5
+            alias = "weir"
+
+            [[nodes]]
+            id = 6
+            alias = "town"
+
+            [[alerts]]
+            name = "town-flood"
+            node = "town"
+            metric = "water_level"
+            above = 2.0
+            severity = "critical"
+
+            [[reaches]]
+            name = "main"
+
+            [[reaches.gauges]]
+            node = "weir"
+
+            [[reaches.gauges]]
+            node = "town"
+            travel_mins = 120
+            stage_ratio = {}
+            "#,
+            ratio
+        ))
+        .unwrap();
+        (
+            ReachMonitor::from_config(&config),
+            NodeDirectory::from_config(&config),
+        )
+    }
+
+    /// A wave from 1.0 m to 2.0 m and back, every 10 minutes.
+    const WAVE: [f64; 9] = [1.0, 1.0, 1.2, 1.5, 1.8, 2.0, 1.9, 1.7, 1.4];
+
+    #[test]
+    fn forecasts_a_crest_downstream_and_clears_when_it_arrives() {
+        let (mut monitor, nodes) = monitor(1.0);
+        monitor.observe(0, 6, 1.2, &nodes);
+        let mut raised = Vec::new();
+        for (i, level) in WAVE.into_iter().enumerate() {
+            raised.extend(monitor.observe(i as u64 * 10 * MIN, 5, level, &nodes));
+        }
+        // Confirmed by the fall to 1.9 m, ten minutes after the crest
+        assert_eq!(raised.len(), 1);
+        let forecast = &raised[0];
+        assert_eq!(
+            (
+                forecast.rule.as_str(),
+                forecast.node_name.as_str(),
+                forecast.state
+            ),
+            (FORECAST_RULE, "town", AlertState::Raised)
+        );
+        assert_eq!(forecast.timestamp, 60 * MIN);
+        assert_eq!(forecast.expected_at, Some(50 * MIN + 120 * MIN));
+        assert!((forecast.value - 2.2).abs() < 1e-9);
+        // Breaches the town's flood rule
+        assert_eq!(
+            (forecast.threshold, forecast.severity),
+            (2.0, Severity::Critical)
+        );
+
+        // The town's own crest clears it
+        let mut cleared = Vec::new();
+        for (i, level) in WAVE.into_iter().enumerate() {
+            cleared.extend(monitor.observe((160 + i as u64 * 10) * MIN, 6, level + 0.2, &nodes));
+        }
+        assert_eq!(cleared.len(), 1);
+        assert_eq!(
+            (cleared[0].state, cleared[0].value),
+            (AlertState::Cleared, 2.2)
+        );
+        assert!(monitor.check(u64::MAX, &nodes).is_empty());
+    }
+
+    #[test]
+    fn clears_a_forecast_that_never_arrives() {
+        let (mut monitor, nodes) = monitor(0.2);
+        monitor.observe(0, 6, 1.2, &nodes);
+        let raised: Vec<_> = WAVE
+            .into_iter()
+            .enumerate()
+            .flat_map(|(i, level)| monitor.observe(i as u64 * 10 * MIN, 5, level, &nodes))
+            .collect();
+        // Breaches nothing: the reach's severity, against the level before
+        assert!((raised[0].value - 1.4).abs() < 1e-9);
+        assert_eq!(
+            (raised[0].threshold, raised[0].severity),
+            (1.2, Severity::Info)
+        );
+
+        assert!(monitor.check(290 * MIN - 1, &nodes).is_empty());
+        let cleared = monitor.check(290 * MIN, &nodes);
+        assert_eq!(
+            (cleared[0].node_id, cleared[0].state),
+            (6, AlertState::Cleared)
+        );
+    }
+
+    #[test]
+    fn learns_travel_time_and_stage_ratio_from_history() {
+        // Two days of a slow swell with a flood wave, every 15 minutes at
+        // the weir and every 10 at the town, arriving 3 hours later at
+        // 80 % of the height
+        let wave =
+            |t: f64| 1.0 + 0.2 * (t / 40_000.0).sin() + (-((t - 90_000.0) / 8_000.0).powi(2)).exp();
+        let weir: Vec<(u64, f64)> = (0..192)
+            .map(|i| (i * 900, wave(i as f64 * 900.0)))
+            .collect();
+        let town: Vec<(u64, f64)> = (0..288)
+            .map(|i| (i * 600, 0.5 + 0.8 * wave(i as f64 * 600.0 - 10_800.0)))
+            .collect();
+
+        let learned = learn_travel_time(&weir, &town, 86_400).unwrap();
+        assert_eq!(learned.travel_secs, 10_800);
+        assert!(learned.correlation > 0.99, "{:?}", learned);
+        assert!((learned.stage_ratio - 0.8).abs() < 0.02, "{:?}", learned);
+    }
+}
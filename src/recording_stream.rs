@@ -2,77 +2,138 @@ use std::{
     fs::{File, OpenOptions},
     io::{self, Write},
     path::{Path, PathBuf},
-    time::{SystemTime, UNIX_EPOCH},
+    sync::atomic::Ordering,
 };
 
-const MAX_FILE_SIZE: u64 = 10 * 1024 * 1024; // 10 MB
+use crate::frame::{Direction, Frame};
+use crate::metrics;
+
+/*
+    Record layout (little endian):
+
+        u64 timestamp   seconds since the Unix epoch
+        u32 header      payload length in the low 30 bits, plus flags:
+                            bit 31  outbound `ToRadio` frame (else inbound `FromRadio`)
+                            bit 30  session preamble (configure handshake / config dump)
+        [u8] payload    protobuf-encoded `FromRadio` or `ToRadio`
+
+    Recordings made before the flags existed have both bits clear and read
+    back as ordinary inbound frames.
+*/
+
+pub const FLAG_OUTBOUND: u32 = 1 << 31;
+pub const FLAG_PREAMBLE: u32 = 1 << 30;
+pub const LENGTH_MASK: u32 = FLAG_PREAMBLE - 1;
+
+const FILE_PREFIX: &str = "meshtastic-recording-";
 
 pub struct RecordingStream {
     dir: PathBuf,
+    max_file_size: u64,
     current_file: File,
     current_size: u64,
     file_index: u64,
 }
 
-
-
 impl RecordingStream {
-    pub fn new<P: AsRef<Path>>(dir: P) -> io::Result<Self> {
+    /// Appends to the newest recording already in `dir`, so a restart
+    /// neither rewrites the first file nor lets the last one outgrow
+    /// `max_file_size`.
+    pub fn new<P: AsRef<Path>>(dir: P, max_file_size: u64) -> io::Result<Self> {
         std::fs::create_dir_all(&dir)?;
 
+        let file_index = Self::last_index(&dir)?.unwrap_or(0);
+        let current_file = Self::open_file(&dir, file_index)?;
         let stream = Self {
             dir: dir.as_ref().to_path_buf(),
-            current_file: Self::open_file(&dir, 0)?,
-            current_size: 0,
-            file_index: 0,
+            max_file_size,
+            current_size: current_file.metadata()?.len(),
+            current_file,
+            file_index,
         };
 
         Ok(stream)
     }
 
+    fn file_name(index: u64) -> String {
+        format!("{}{:05}.bin", FILE_PREFIX, index)
+    }
+
     fn open_file<P: AsRef<Path>>(dir: P, index: u64) -> io::Result<File> {
-        let filename = format!("meshtastic-recording-{:05}.bin", index);
-        let path = dir.as_ref().join(filename);
+        let path = dir.as_ref().join(Self::file_name(index));
+
+        OpenOptions::new().create(true).append(true).open(path)
+    }
 
-        OpenOptions::new()
-            .create(true)
-            .append(true)
-            .open(path)
+    /// Highest index of the recording files in `dir`.
+    fn last_index<P: AsRef<Path>>(dir: P) -> io::Result<Option<u64>> {
+        let mut last = None;
+        for entry in std::fs::read_dir(dir)? {
+            let name = entry?.file_name();
+            let index = name
+                .to_str()
+                .and_then(|n| n.strip_prefix(FILE_PREFIX))
+                .and_then(|n| n.strip_suffix(".bin"))
+                .and_then(|n| n.parse::<u64>().ok());
+            last = last.max(index);
+        }
+        Ok(last)
     }
 
     fn rotate_if_needed(&mut self, next_record_size: u64) -> io::Result<()> {
-        if self.current_size + next_record_size <= MAX_FILE_SIZE {
+        if self.current_size + next_record_size <= self.max_file_size {
             return Ok(());
         }
 
         self.file_index += 1;
         self.current_file = Self::open_file(&self.dir, self.file_index)?;
         self.current_size = 0;
+        metrics::RECORDER_ROTATIONS.fetch_add(1, Ordering::Relaxed);
 
         Ok(())
     }
 
-    pub fn record(&mut self, raw_payload: &[u8]) -> io::Result<()> {
-        let timestamp = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .expect("time went backwards")
-            .as_secs();
+    pub fn record_frame(&mut self, frame: &Frame) -> io::Result<()> {
+        let raw = frame.encode_payload();
+        self.record(frame.timestamp, &raw, frame.direction(), frame.preamble)
+    }
 
+    pub fn record(
+        &mut self,
+        timestamp: u64,
+        raw_payload: &[u8],
+        direction: Direction,
+        preamble: bool,
+    ) -> io::Result<()> {
         let payload_len = raw_payload.len() as u32;
+        if payload_len > LENGTH_MASK {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "frame too large to record",
+            ));
+        }
+
+        let mut header = payload_len;
+        if direction == Direction::Outbound {
+            header |= FLAG_OUTBOUND;
+        }
+        if preamble {
+            header |= FLAG_PREAMBLE;
+        }
 
-        let record_size =
-            8 + // timestamp
-            4 + // payload_len
+        let record_size = 8 + // timestamp
+            4 + // header
             payload_len as u64;
 
         self.rotate_if_needed(record_size)?;
 
         self.current_file.write_all(&timestamp.to_le_bytes())?;
-        self.current_file.write_all(&payload_len.to_le_bytes())?;
+        self.current_file.write_all(&header.to_le_bytes())?;
         self.current_file.write_all(raw_payload)?;
-	self.current_file.flush()?;
+        self.current_file.flush()?;
 
         self.current_size += record_size;
+        metrics::RECORDER_BYTES.fetch_add(record_size, Ordering::Relaxed);
 
         Ok(())
     }
@@ -82,3 +143,41 @@ impl RecordingStream {
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn resumes_the_last_file_at_its_size() {
+        let dir =
+            std::env::temp_dir().join(format!("flood_monitor-recording-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let size = |index: u64| {
+            std::fs::metadata(dir.join(RecordingStream::file_name(index)))
+                .unwrap()
+                .len()
+        };
+
+        // 12-byte header plus 8 bytes of payload per record
+        let mut recorder = RecordingStream::new(&dir, 50).unwrap();
+        for _ in 0..3 {
+            recorder
+                .record(1, &[0; 8], Direction::Inbound, false)
+                .unwrap();
+        }
+        drop(recorder);
+        assert_eq!((size(0), size(1)), (40, 20));
+
+        let mut recorder = RecordingStream::new(&dir, 50).unwrap();
+        recorder
+            .record(2, &[0; 8], Direction::Inbound, false)
+            .unwrap();
+        recorder
+            .record(2, &[0; 8], Direction::Inbound, false)
+            .unwrap();
+        drop(recorder);
+        assert_eq!((size(0), size(1), size(2)), (40, 40, 20));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
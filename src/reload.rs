@@ -0,0 +1,434 @@
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use tokio::signal::unix::{SignalKind, signal};
+use tokio::sync::mpsc;
+
+use crate::cli::Cli;
+use crate::config::{Config, ConfigError};
+
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Watches the config file and re-reads it when it changes on disk or the
+/// process receives SIGHUP. Only configs that pass validation are sent on;
+/// a bad edit is logged and the running config stays in place.
+pub fn spawn_config_watcher(
+    path: PathBuf,
+    cli: Cli,
+    current: Config,
+) -> mpsc::UnboundedReceiver<Config> {
+    let (tx, rx) = mpsc::unbounded_channel();
+
+    tokio::spawn(async move {
+        let mut hangup = match signal(SignalKind::hangup()) {
+            Ok(s) => Some(s),
+            Err(e) => {
+                log::warn!("Cannot listen for SIGHUP, relying on file polling: {}", e);
+                None
+            }
+        };
+        let mut interval = tokio::time::interval(POLL_INTERVAL);
+        let mut last_modified = modified(&path);
+        let mut current = current;
+
+        loop {
+            tokio::select! {
+                _ = interval.tick() => {
+                    let m = modified(&path);
+                    if m == last_modified {
+                        continue;
+                    }
+                    last_modified = m;
+                    log::info!("Config file {} changed, reloading", path.display());
+                }
+                Some(()) = async {
+                    match hangup.as_mut() {
+                        Some(s) => s.recv().await,
+                        None => std::future::pending().await,
+                    }
+                } => {
+                    log::info!("SIGHUP received, reloading {}", path.display());
+                }
+            }
+
+            let mut next = match Config::load(&path) {
+                Ok(config) => config,
+                Err(e) => {
+                    log::error!("Rejected config reload, keeping current config: {}", e);
+                    continue;
+                }
+            };
+            cli.apply_overrides(&mut next);
+            // As at startup, the overrides may break what the file alone passed
+            let issues = next.validate();
+            if !issues.is_empty() {
+                log::error!(
+                    "Rejected config reload, keeping current config: {}",
+                    ConfigError::Invalid(issues)
+                );
+                continue;
+            }
+
+            if !restart_only_equal(&current, &next) {
+                log::warn!(
+                    "Changes to logging, connections, recording, dedup, sinks, http, history, notifiers, alert_lifecycle or mesh take effect after a restart"
+                );
+                keep_restart_only(&current, &mut next);
+            }
+
+            let changes = diff(&current, &next);
+            current = next.clone();
+            if changes.is_empty() {
+                log::info!("Config reloaded, no changes to hot-reloadable settings");
+                continue;
+            }
+            for change in &changes {
+                log::info!("Config reload: {}", change);
+            }
+
+            if tx.send(next).is_err() {
+                break;
+            }
+        }
+    });
+
+    rx
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+/// Sections that are only read at startup; the rest are compared by
+/// [`diff`] and applied on reload.
+fn restart_only_equal(old: &Config, new: &Config) -> bool {
+    old.logging == new.logging
+        && old.connections == new.connections
+        && old.recording == new.recording
+        && old.dedup == new.dedup
+        && old.sinks == new.sinks
+        && old.http == new.http
+        && old.history == new.history
+        && old.notifiers == new.notifiers
+        && old.alert_lifecycle == new.alert_lifecycle
+        && old.mesh == new.mesh
+}
+
+/// Puts the running values of the restart-only sections back into `next`,
+/// so the config passed on, and compared against on the next reload, is
+/// the one in effect and a pending restart keeps being reported.
+fn keep_restart_only(current: &Config, next: &mut Config) {
+    next.logging = current.logging.clone();
+    next.connections = current.connections.clone();
+    next.recording = current.recording.clone();
+    next.dedup = current.dedup.clone();
+    next.sinks = current.sinks.clone();
+    next.http = current.http.clone();
+    next.history = current.history.clone();
+    next.notifiers = current.notifiers.clone();
+    next.alert_lifecycle = current.alert_lifecycle.clone();
+    next.mesh = current.mesh.clone();
+}
+
+/// Human-readable changes to the hot-reloadable parts of the config: the
+/// per-node settings, alert rules and the monitors' sections. A reload with
+/// none of these is not passed on.
+pub fn diff(old: &Config, new: &Config) -> Vec<String> {
+    let mut changes = Vec::new();
+
+    for node in &new.nodes {
+        match old.nodes.iter().find(|n| n.id == node.id) {
+            None => changes.push(format!("node {} added ({:?})", node.id, node.alias)),
+            Some(prev) => {
+                if prev.alias != node.alias {
+                    changes.push(format!(
+                        "node {} alias {:?} → {:?}",
+                        node.id, prev.alias, node.alias
+                    ));
+                }
+                if prev.calibration != node.calibration {
+                    changes.push(format!(
+                        "node {} calibration {:?} → {:?}",
+                        node.id, prev.calibration, node.calibration
+                    ));
+                }
+                if prev.report_interval_secs != node.report_interval_secs {
+                    changes.push(format!(
+                        "node {} report_interval_secs {:?} → {:?}",
+                        node.id, prev.report_interval_secs, node.report_interval_secs
+                    ));
+                }
+                if prev.cutoff_voltage != node.cutoff_voltage {
+                    changes.push(format!(
+                        "node {} cutoff_voltage {:?} → {:?}",
+                        node.id, prev.cutoff_voltage, node.cutoff_voltage
+                    ));
+                }
+                if prev.rain_gauge != node.rain_gauge {
+                    changes.push(format!(
+                        "node {} rain_gauge {:?} → {:?}",
+                        node.id, prev.rain_gauge, node.rain_gauge
+                    ));
+                }
+            }
+        }
+    }
+    for node in &old.nodes {
+        if !new.nodes.iter().any(|n| n.id == node.id) {
+            changes.push(format!("node {} removed", node.id));
+        }
+    }
+
+    for rule in &new.alerts {
+        match old.alerts.iter().find(|r| r.name == rule.name) {
+            None => changes.push(format!("alert rule `{}` added", rule.name)),
+            Some(prev) if prev != rule => changes.push(format!(
+                "alert rule `{}` changed: {:?} → {:?}",
+                rule.name, prev, rule
+            )),
+            Some(_) => {}
+        }
+    }
+    for rule in &old.alerts {
+        if !new.alerts.iter().any(|r| r.name == rule.name) {
+            changes.push(format!("alert rule `{}` removed", rule.name));
+        }
+    }
+
+    for reach in &new.reaches {
+        match old.reaches.iter().find(|r| r.name == reach.name) {
+            None => changes.push(format!("reach `{}` added", reach.name)),
+            Some(prev) if prev != reach => changes.push(format!(
+                "reach `{}` changed: {:?} → {:?}",
+                reach.name, prev, reach
+            )),
+            Some(_) => {}
+        }
+    }
+    for reach in &old.reaches {
+        if !new.reaches.iter().any(|r| r.name == reach.name) {
+            changes.push(format!("reach `{}` removed", reach.name));
+        }
+    }
+
+    section(&mut changes, "heartbeat", &old.heartbeat, &new.heartbeat);
+    section(&mut changes, "battery", &old.battery, &new.battery);
+    section(&mut changes, "quality", &old.quality, &new.quality);
+    section(&mut changes, "forecast", &old.forecast, &new.forecast);
+    section(&mut changes, "rain", &old.rain, &new.rain);
+
+    changes
+}
+
+fn section<T: PartialEq + fmt::Debug>(changes: &mut Vec<String>, name: &str, old: &T, new: &T) {
+    if old != new {
+        changes.push(format!("[{}] changed: {:?} → {:?}", name, old, new));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn restart_only_changes_stay_pending() {
+        let current = Config::default();
+        let mut next = Config::parse(
+            r#"
+            [http]
+            enabled = true
+
+            [[alerts]]
+            name = "flood"
+            metric = "water_level"
+            above = 4.0
+            "#,
+        )
+        .unwrap();
+        assert!(!restart_only_equal(&current, &next));
+
+        keep_restart_only(&current, &mut next);
+        assert!(!next.http.enabled);
+        assert_eq!(next.alerts.len(), 1);
+        // What is passed on and kept as current is what actually runs
+        assert!(restart_only_equal(&current, &next));
+    }
+
+    #[test]
+    fn diff_reports_node_and_rule_changes() {
+        let old = Config::parse(
+            r#"
+            [[nodes]]
+            id = 1
+            alias = "bridge"
+
+            [[nodes]]
+            id = 2
+            alias = "mill"
+
+            [[alerts]]
+            name = "flood"
+            metric = "water_level"
+            above = 4.0
+            "#,
+        )
+        .unwrap();
+        let new = Config::parse(
+            r#"
+            [[nodes]]
+            id = 1
+            alias = "main-st-bridge"
+
+            [[alerts]]
+            name = "flood"
+            metric = "water_level"
+            above = 3.8
+            "#,
+        )
+        .unwrap();
+
+        let changes = diff(&old, &new);
+        assert_eq!(changes.len(), 3, "{:?}", changes);
+        assert!(changes[0].contains("alias"));
+        assert!(changes[1].contains("removed"));
+        assert!(changes[2].starts_with("alert rule `flood` changed"));
+
+        assert!(diff(&new, &new).is_empty());
+    }
+
+    #[test]
+    fn diff_reports_heartbeat_changes() {
+        let old = Config::parse("[[nodes]]\nid = 1").unwrap();
+        let new = Config::parse(
+            r#"
+            [heartbeat]
+            enabled = true
+
+            [[nodes]]
+            id = 1
+            report_interval_secs = 900
+            "#,
+        )
+        .unwrap();
+
+        let changes = diff(&old, &new);
+        assert_eq!(changes.len(), 2, "{:?}", changes);
+        assert!(changes[0].contains("report_interval_secs None → Some(900)"));
+        assert!(changes[1].starts_with("[heartbeat] changed"));
+    }
+
+    #[test]
+    fn diff_reports_battery_changes() {
+        let old = Config::parse("[[nodes]]\nid = 1").unwrap();
+        let new = Config::parse(
+            r#"
+            [battery]
+            enabled = true
+            alert_within_hours = 12
+
+            [[nodes]]
+            id = 1
+            cutoff_voltage = 2.9
+            "#,
+        )
+        .unwrap();
+
+        let changes = diff(&old, &new);
+        assert_eq!(changes.len(), 2, "{:?}", changes);
+        assert!(changes[0].contains("cutoff_voltage None → Some(2.9)"));
+        assert!(changes[1].starts_with("[battery] changed"));
+    }
+
+    #[test]
+    fn diff_reports_quality_changes() {
+        let old = Config::default();
+        let new = Config::parse(
+            r#"
+            [quality]
+            confirm_after = 3
+
+            [[quality.metrics]]
+            metric = "water_level"
+            min = 0.0
+            max = 8.0
+            "#,
+        )
+        .unwrap();
+
+        let changes = diff(&old, &new);
+        assert_eq!(changes.len(), 1, "{:?}", changes);
+        assert!(changes[0].starts_with("[quality] changed"));
+    }
+
+    #[test]
+    fn diff_reports_reach_changes() {
+        let reach = |name: &str, travel: u32| {
+            format!(
+                "[[reaches]]\nname = \"{}\"\n[[reaches.gauges]]\nnode = \"1\"\n\
+                 [[reaches.gauges]]\nnode = \"2\"\ntravel_mins = {}\n",
+                name, travel
+            )
+        };
+        let nodes = "[[nodes]]\nid = 1\n[[nodes]]\nid = 2\n";
+        let old = Config::parse(&format!(
+            "{}{}{}",
+            nodes,
+            reach("main", 60),
+            reach("mill", 30)
+        ))
+        .unwrap();
+        let new = Config::parse(&format!(
+            "{}{}{}",
+            nodes,
+            reach("main", 90),
+            reach("creek", 20)
+        ))
+        .unwrap();
+
+        let changes = diff(&old, &new);
+        assert_eq!(changes.len(), 3, "{:?}", changes);
+        assert!(changes[0].starts_with("reach `main` changed"));
+        assert_eq!(changes[1], "reach `creek` added");
+        assert_eq!(changes[2], "reach `mill` removed");
+    }
+
+    #[test]
+    fn diff_reports_forecast_changes() {
+        let old = Config::default();
+        let new = Config::parse(
+            r#"
+            [forecast]
+            enabled = true
+            model = "smoothing"
+            alert_within_hours = 2
+            "#,
+        )
+        .unwrap();
+
+        let changes = diff(&old, &new);
+        assert_eq!(changes.len(), 1, "{:?}", changes);
+        assert!(changes[0].starts_with("[forecast] changed"));
+    }
+
+    #[test]
+    fn diff_reports_rain_changes() {
+        let old = Config::parse("[[nodes]]\nid = 1").unwrap();
+        let new = Config::parse(
+            r#"
+            [rain]
+            event_gap_hours = 12
+
+            [[nodes]]
+            id = 1
+            rain_gauge = { source = "detection" }
+            "#,
+        )
+        .unwrap();
+
+        let changes = diff(&old, &new);
+        assert_eq!(changes.len(), 2, "{:?}", changes);
+        assert!(changes[0].contains("rain_gauge None → Some("));
+        assert!(changes[1].starts_with("[rain] changed"));
+    }
+}
@@ -0,0 +1,269 @@
+use std::io;
+
+use crate::alerts::AlertEvent;
+use crate::bot::MeshBot;
+use crate::config::{Config, NodeId};
+use crate::dedup::PacketCopies;
+use crate::feed::FeedSink;
+use crate::frame::Frame;
+use crate::handler::{DecodedMessage, NodeInfo};
+use crate::http::ApiState;
+use crate::influx::InfluxSink;
+use crate::mesh::{MeshAlertSink, Outbox};
+use crate::mqtt::MqttSink;
+use crate::notify::NotifySink;
+use crate::radio_message::{AppMessage, Telemetry};
+use crate::recording_stream::RecordingStream;
+use crate::store::StoreSink;
+
+/// An output of the pipeline. Every method has a no-op default so a sink
+/// only implements the stages it cares about.
+pub trait Sink: Send {
+    fn name(&self) -> &'static str;
+
+    /// Every raw frame, inbound and outbound, before decoding.
+    fn on_frame(&mut self, _frame: &Frame) {}
+
+    fn on_message(&mut self, _msg: &DecodedMessage) {}
+
+    fn on_alert(&mut self, _event: &AlertEvent) {}
+
+    /// A node from the radio's node database, live or replayed from a
+    /// recording's config dump.
+    fn on_node_info(&mut self, _info: &NodeInfo) {}
+
+    /// A duplicate of an already handled packet was heard with a better
+    /// SNR; `copies.best` is that copy.
+    fn on_better_copy(&mut self, _copies: &PacketCopies) {}
+
+    /// Reception tally of a mesh packet, once its dedup window has closed.
+    fn on_link(&mut self, _copies: &PacketCopies) {}
+
+    /// Called every few seconds of wall-clock time, for work that is due
+    /// without any frame arriving.
+    fn on_tick(&mut self, _now: u64) {}
+
+    /// A hot-reloaded config; sinks that only read config at startup ignore it.
+    fn reload(&mut self, _config: &Config) {}
+
+    /// Called once when the source is exhausted or the monitor shuts down.
+    fn flush(&mut self) {}
+}
+
+/// Builds the sinks enabled in config. `api` is what the HTTP API serves;
+/// `outbox` is where texts for the mesh go, if anything may transmit. A
+/// replay is not `live`, so sinks may hold it up rather than drop data.
+pub fn build_sinks(
+    config: &Config,
+    api: &ApiState,
+    outbox: Option<&Outbox>,
+    live: bool,
+) -> io::Result<Vec<Box<dyn Sink>>> {
+    let mut sinks: Vec<Box<dyn Sink>> = Vec::new();
+
+    if config.recording.enabled {
+        println!("Recording to: {}", config.recording.dir.display());
+        sinks.push(Box::new(RecordingSink {
+            recorder: RecordingStream::new(&config.recording.dir, config.recording.max_file_size)?,
+        }));
+    }
+    if config.sinks.log.enabled {
+        sinks.push(Box::new(LogSink::default()));
+    }
+    if config.sinks.mqtt.enabled {
+        sinks.push(Box::new(MqttSink::spawn(&config.sinks.mqtt)));
+    }
+    if config.sinks.influx.enabled {
+        sinks.push(Box::new(InfluxSink::spawn(&config.sinks.influx, live)?));
+    }
+    if !config.notifiers.is_empty() {
+        sinks.push(Box::new(NotifySink::spawn(
+            &config.notifiers,
+            &config.alert_lifecycle,
+            api.store.clone(),
+        )?));
+    }
+    if config.mesh.alerts.enabled
+        && let Some(outbox) = outbox
+    {
+        sinks.push(Box::new(MeshAlertSink::new(
+            &config.mesh.alerts,
+            outbox.clone(),
+            api.store.clone(),
+        )));
+    }
+    // Alert lifecycle state lives in the store, and the bot answers from it,
+    // so they need one even without the API. After the notifiers, which
+    // look up a clearing alert before it is removed.
+    if config.http.enabled
+        || !config.notifiers.is_empty()
+        || (config.mesh.bot.enabled && outbox.is_some())
+    {
+        sinks.push(Box::new(StoreSink(api.store.clone())));
+    }
+    if config.mesh.bot.enabled
+        && let Some(outbox) = outbox
+    {
+        sinks.push(Box::new(MeshBot::new(
+            &config.mesh.bot,
+            config.mesh_connection().map(|c| c.id.clone()),
+            api.store.clone(),
+            outbox.clone(),
+        )));
+    }
+    if config.http.enabled {
+        sinks.push(Box::new(FeedSink::new(api.feed.clone())));
+    }
+
+    Ok(sinks)
+}
+
+/* ---------------- Recording ---------------- */
+
+/// Writes every frame to rotating `.bin` files.
+pub struct RecordingSink {
+    recorder: RecordingStream,
+}
+
+impl Sink for RecordingSink {
+    fn name(&self) -> &'static str {
+        "recording"
+    }
+
+    fn on_frame(&mut self, frame: &Frame) {
+        if let Err(e) = self.recorder.record_frame(frame) {
+            log::error!("Failed to record frame: {}", e);
+        }
+    }
+
+    fn flush(&mut self) {
+        if let Err(e) = self.recorder.flush() {
+            log::error!("Failed to flush recording: {}", e);
+        }
+    }
+}
+
+/* ---------------- Log ---------------- */
+
+/// Decoded messages written through the `log` facade.
+#[derive(Default)]
+pub struct LogSink {
+    telemetry_count: usize,
+}
+
+impl Sink for LogSink {
+    fn name(&self) -> &'static str {
+        "log"
+    }
+
+    fn on_message(&mut self, msg: &DecodedMessage) {
+        let name = &format!("{} via {}", msg.node_name, msg.connection);
+
+        match &msg.message.app {
+            AppMessage::Telemetry(tel) => {
+                self.telemetry_count += 1;
+                log_telemetry(name, self.telemetry_count, tel);
+                if let Some((_, level)) = msg.readings.iter().find(|(m, _)| *m == "water_level") {
+                    log::info!("Node {} Water Level → {:.3}", name, level);
+                }
+            }
+            AppMessage::Position(pos) => {
+                log::info!(
+                    "Node {} Position → Lat: {:.7}, Lon: {:.7}, Alt: {} m, Accuracy: {} m",
+                    name,
+                    pos.latitude,
+                    pos.longitude,
+                    pos.altitude,
+                    pos.accuracy
+                );
+            }
+            AppMessage::Text(text) => {
+                log::info!(
+                    "Node {} Text → From: {:?}, To: {:?}, Msg: {}",
+                    name,
+                    text.from,
+                    text.to,
+                    text.msg
+                );
+            }
+            AppMessage::Detection(text) => {
+                log::info!("Node {} Detection → {}", name, text);
+            }
+            AppMessage::Serial(text) => {
+                log::info!("Node {} Serial → {}", name, text.trim_end());
+            }
+        }
+        let reading = |metric| msg.readings.iter().find(|(m, _)| *m == metric).map(|r| r.1);
+        if let Some(day) = reading("rain_24h") {
+            log::info!(
+                "Node {} Rain → 1h: {:.1} mm, 24h: {:.1} mm, event: {:.1} mm",
+                name,
+                reading("rain_1h").unwrap_or_default(),
+                day,
+                reading("rain_event").unwrap_or_default()
+            );
+        }
+    }
+
+    fn on_link(&mut self, copies: &PacketCopies) {
+        let best = &copies.best;
+        log::debug!(
+            "Packet {:08x} from {}: {} copies via {} relays, best SNR {} dB / RSSI {} dBm on {}{}",
+            copies.id,
+            NodeId(copies.from),
+            copies.copies,
+            copies.relays.len(),
+            best.rx_snr,
+            best.rx_rssi,
+            best.connection,
+            best.hops
+                .map(|h| format!(" after {} hops", h))
+                .unwrap_or_default()
+        );
+    }
+}
+
+fn log_telemetry(name: &str, count: usize, tel: &Telemetry) {
+    match tel {
+        Telemetry::Device {
+            battery_level,
+            voltage,
+            uptime_seconds,
+        } => {
+            log::info!(
+                "Node {} Device Telemetry #{} → Voltage: {:?} V, Battery: {:?} %, Uptime: {:?} s",
+                name,
+                count,
+                voltage,
+                battery_level,
+                uptime_seconds
+            );
+        }
+        Telemetry::Environment {
+            temperature,
+            humidity,
+            pressure,
+            distance,
+            ..
+        } => {
+            log::info!(
+                "Node {} Environment Telemetry #{} → Temp: {:?} °C, Humidity: {:?} %, Pressure: {:?} hPa, Distance: {:?} mm",
+                name,
+                count,
+                temperature,
+                humidity,
+                pressure,
+                distance
+            );
+        }
+        Telemetry::Power { voltage, current } => {
+            log::info!(
+                "Node {} Power Telemetry #{} → Voltage: {:?} V, Current: {:?} A",
+                name,
+                count,
+                voltage,
+                current
+            );
+        }
+    }
+}
@@ -0,0 +1,202 @@
+use std::path::PathBuf;
+use std::time::Duration;
+
+use meshtastic::api::{ConnectedStreamApi, StreamApi};
+use meshtastic::packet::PacketReceiver;
+use meshtastic::protobufs::{MeshPacket, ToRadio, from_radio, to_radio};
+use meshtastic::utils;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+
+use crate::config::ConnectionConfig;
+use crate::frame::Frame;
+use crate::metrics;
+use crate::playback::PlaybackStream;
+
+pub type SourceError = Box<dyn std::error::Error + Send + Sync>;
+
+/* ---------------- Radio ---------------- */
+
+/// Opens the connection and runs the configure handshake. Also returns the
+/// `want_config_id` sent, which the device echoes in `config_complete_id`.
+async fn connect(
+    conn: &ConnectionConfig,
+) -> Result<(PacketReceiver, ConnectedStreamApi, u32), SourceError> {
+    let stream_api = StreamApi::new();
+
+    let (decoded_listener, stream_api) = match (&conn.serial, &conn.tcp) {
+        (Some(path), _) => {
+            log::info!("Connecting to {} over serial {}", conn.id, path);
+            let serial_stream = utils::stream::build_serial_stream(path.clone(), None, None, None)?;
+            stream_api.connect(serial_stream).await
+        }
+        (None, Some(address)) => {
+            log::info!("Connecting to {} over TCP {}", conn.id, address);
+            let tcp_stream = utils::stream::build_tcp_stream(address.clone()).await?;
+            stream_api.connect(tcp_stream).await
+        }
+        (None, None) => return Err(format!("connection {} has no serial or tcp", conn.id).into()),
+    };
+
+    let config_id = utils::generate_rand_id();
+    let stream_api = stream_api.configure(config_id).await?;
+
+    Ok((decoded_listener, stream_api, config_id))
+}
+
+/// Wait before the first reconnect attempt, doubled after each failure.
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(60);
+
+/// Reads frames from a radio until the pipeline goes away, reconnecting
+/// with backoff whenever the radio fails or disconnects. The radio that
+/// transmits for the monitor also gets the mesh outbox's `packets`; they
+/// wait in the channel while it is disconnected.
+pub fn spawn_radio(
+    conn: ConnectionConfig,
+    frames: mpsc::Sender<Frame>,
+    mut packets: Option<mpsc::UnboundedReceiver<MeshPacket>>,
+) -> JoinHandle<Result<(), SourceError>> {
+    tokio::spawn(async move {
+        let mut delay = RECONNECT_DELAY;
+        loop {
+            match stream_radio(&conn, &frames, &mut packets).await {
+                Ok(Streamed::PipelineClosed) => return Ok(()),
+                Ok(Streamed::Disconnected) => {
+                    log::warn!("Connection {} closed", conn.id);
+                    delay = RECONNECT_DELAY;
+                }
+                // With several radios the others keep running
+                Err(e) => log::error!("Connection {} failed: {}", conn.id, e),
+            }
+
+            log::info!("Reconnecting {} in {}s", conn.id, delay.as_secs());
+            tokio::select! {
+                _ = tokio::time::sleep(delay) => {}
+                _ = frames.closed() => return Ok(()),
+            }
+            delay = (delay * 2).min(MAX_RECONNECT_DELAY);
+            metrics::RECONNECTS.inc(&conn.id);
+        }
+    })
+}
+
+enum Streamed {
+    Disconnected,
+    PipelineClosed,
+}
+
+/// One connection's lifetime: connect, then forward frames until either
+/// side goes away, sending outgoing packets in between.
+async fn stream_radio(
+    conn: &ConnectionConfig,
+    frames: &mpsc::Sender<Frame>,
+    packets: &mut Option<mpsc::UnboundedReceiver<MeshPacket>>,
+) -> Result<Streamed, SourceError> {
+    let (mut decoded_listener, mut stream_api, config_id) = connect(conn).await?;
+
+    // The handshake request `configure` sent, so a recording sees both sides
+    let want_config = ToRadio {
+        payload_variant: Some(to_radio::PayloadVariant::WantConfigId(config_id)),
+    };
+    if frames
+        .send(Frame::outbound(&conn.id, want_config, true))
+        .await
+        .is_err()
+    {
+        return Ok(Streamed::PipelineClosed);
+    }
+
+    // Everything up to and including `config_complete_id` is the config dump
+    let mut in_preamble = true;
+
+    loop {
+        tokio::select! {
+            from_radio = decoded_listener.recv() => {
+                let Some(from_radio) = from_radio else { break };
+                let preamble = in_preamble;
+                if let Some(from_radio::PayloadVariant::ConfigCompleteId(id)) =
+                    from_radio.payload_variant
+                    && id == config_id
+                {
+                    in_preamble = false;
+                }
+
+                if frames
+                    .send(Frame::inbound(&conn.id, from_radio, preamble))
+                    .await
+                    .is_err()
+                {
+                    return Ok(Streamed::PipelineClosed);
+                }
+            }
+            Some(packet) = next_packet(packets) => {
+                let to_radio = ToRadio {
+                    payload_variant: Some(to_radio::PayloadVariant::Packet(packet)),
+                };
+                stream_api
+                    .send_to_radio_packet(to_radio.payload_variant.clone())
+                    .await?;
+                // Recorded like the handshake, so a capture has both sides
+                if frames
+                    .send(Frame::outbound(&conn.id, to_radio, false))
+                    .await
+                    .is_err()
+                {
+                    return Ok(Streamed::PipelineClosed);
+                }
+            }
+        }
+    }
+
+    Ok(Streamed::Disconnected)
+}
+
+/// The next packet to transmit; never resolves for a radio that doesn't
+/// transmit.
+async fn next_packet(
+    packets: &mut Option<mpsc::UnboundedReceiver<MeshPacket>>,
+) -> Option<MeshPacket> {
+    match packets {
+        Some(packets) => packets.recv().await,
+        None => std::future::pending().await,
+    }
+}
+
+/* ---------------- Replay ---------------- */
+
+/// Feeds a recording through the pipeline as fast as it will take it.
+/// Outbound frames are only passed on when something downstream records
+/// them; decoding only looks at inbound frames.
+pub fn spawn_replay(
+    path: PathBuf,
+    include_outbound: bool,
+    frames: mpsc::Sender<Frame>,
+) -> JoinHandle<Result<(), SourceError>> {
+    tokio::task::spawn_blocking(move || {
+        log::info!("Replaying capture from: {}", path.display());
+
+        let mut playback = PlaybackStream::open(&path)?;
+        if !include_outbound {
+            playback = playback.skip_outbound();
+        }
+        log::info!("Playback started");
+
+        let mut preamble_frames = 0;
+        for frame in playback {
+            let frame = frame?;
+            if frame.preamble {
+                preamble_frames += 1;
+            }
+            if frames.blocking_send(frame).is_err() {
+                break;
+            }
+        }
+
+        log::info!(
+            "Playback finished ({} session preamble frames)",
+            preamble_frames
+        );
+        Ok(())
+    })
+}
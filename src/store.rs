@@ -0,0 +1,909 @@
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+
+use serde::{Deserialize, Serialize};
+
+use crate::alerts::{self, AlertEvent, AlertState};
+use crate::config::{AlertLifecycleConfig, Config, NodeId, Severity};
+use crate::dedup::PacketCopies;
+use crate::forecast::StageForecast;
+use crate::frame;
+use crate::handler::{DecodedMessage, NodeInfo};
+use crate::quality::Quality;
+use crate::radio_message::{AppMessage, Position};
+use crate::sinks::Sink;
+
+/// The store as shared between the pipeline and the HTTP server.
+pub type SharedStore = Arc<RwLock<Store>>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct Sample {
+    pub timestamp: u64,
+    pub value: f64,
+    pub quality: Quality,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct NodePosition {
+    pub timestamp: u64,
+    pub latitude: f64,
+    pub longitude: f64,
+    pub altitude: i32,
+}
+
+impl NodePosition {
+    /// `None` for nodes without a GPS fix, which report 0, 0.
+    fn new(timestamp: u64, pos: &Position) -> Option<Self> {
+        (pos.latitude != 0.0 || pos.longitude != 0.0).then_some(Self {
+            timestamp,
+            latitude: pos.latitude,
+            longitude: pos.longitude,
+            altitude: pos.altitude,
+        })
+    }
+}
+
+/// What is known about one node, configured or heard.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct NodeStatus {
+    pub id: NodeId,
+    pub name: String,
+    /// Name the node's owner set, from the radio's node database
+    pub long_name: Option<String>,
+    /// Listed under `[[nodes]]` in the config
+    pub configured: bool,
+    /// Has a calibration, so reports `water_level`
+    pub gauge: bool,
+    pub last_heard: Option<u64>,
+    /// Connection the last packet was first heard on
+    pub connection: Option<String>,
+    pub position: Option<NodePosition>,
+    /// Reception quality of the best copy of the last packet heard from
+    /// the node
+    pub rx_snr: Option<f32>,
+    pub rx_rssi: Option<i32>,
+    /// Id of the last packet heard, which later copies may improve on
+    #[serde(skip)]
+    packet_id: u32,
+    /// Latest value of every metric the node has reported
+    pub readings: BTreeMap<&'static str, Sample>,
+    /// Stage forecast from the latest good `water_level`
+    pub forecast: Option<StageForecast>,
+}
+
+impl NodeStatus {
+    fn new(id: u32, name: String) -> Self {
+        Self {
+            id: NodeId(id),
+            name,
+            long_name: None,
+            configured: false,
+            gauge: false,
+            last_heard: None,
+            connection: None,
+            position: None,
+            rx_snr: None,
+            rx_rssi: None,
+            packet_id: 0,
+            readings: BTreeMap::new(),
+            forecast: None,
+        }
+    }
+}
+
+/// Where a raised alert is in its lifecycle; once its rule clears it is
+/// resolved and no longer active.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AlertStatus {
+    Open,
+    Acknowledged,
+    /// Unacknowledged and passed on to a higher contact tier
+    Escalated,
+}
+
+/// An alert rule currently in the raised state for a node.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ActiveAlert {
+    /// Short number people refer to the alert by, e.g. `ACK 12`
+    pub id: u32,
+    pub rule: String,
+    pub node: NodeId,
+    pub node_name: String,
+    pub metric: String,
+    pub value: f64,
+    pub threshold: f64,
+    /// When a forecast alert expects its value
+    #[serde(default)]
+    pub expected_at: Option<u64>,
+    pub severity: Severity,
+    pub raised_at: u64,
+    pub status: AlertStatus,
+    /// Highest contact tier notified so far
+    pub tier: u32,
+    pub escalated_at: Option<u64>,
+    pub acknowledged: Option<Acknowledgement>,
+}
+
+impl ActiveAlert {
+    /// The alert as a raise event at `timestamp`, for notifying a tier.
+    pub fn event(&self, timestamp: u64) -> AlertEvent {
+        AlertEvent {
+            rule: self.rule.clone(),
+            timestamp,
+            node_id: self.node.0,
+            node_name: self.node_name.clone(),
+            metric: self.metric.clone(),
+            value: self.value,
+            threshold: self.threshold,
+            expected_at: self.expected_at,
+            severity: self.severity,
+            state: AlertState::Raised,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Acknowledgement {
+    /// Who acknowledged, e.g. the node a mesh command came from
+    pub by: String,
+    pub at: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AuditAction {
+    Raised,
+    Acknowledged,
+    Escalated,
+    Resolved,
+    Muted,
+    Unmuted,
+}
+
+/// One line of the audit log.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AuditEntry {
+    pub time: u64,
+    pub action: AuditAction,
+    /// Number of the alert acted on; mutes apply to every alert
+    pub alert: Option<u32>,
+    /// Who acted; the monitor itself when unset
+    pub by: Option<String>,
+    pub detail: String,
+}
+
+/// Audit entries kept in memory for the API.
+const AUDIT_CAPACITY: usize = 1000;
+
+/// What `alert_lifecycle.state_file` holds.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct SavedAlerts {
+    next_alert_id: u32,
+    muted_until: Option<u64>,
+    mute_ended: Option<u64>,
+    alerts: Vec<ActiveAlert>,
+}
+
+/// Latest state and recent history of every node, kept in memory for the
+/// HTTP API. Fed by `StoreSink`.
+#[derive(Debug, Default)]
+pub struct Store {
+    retention_secs: u64,
+    nodes: BTreeMap<u32, NodeStatus>,
+    /// Keyed by (rule name, node id), like the alert engine
+    alerts: BTreeMap<(String, u32), ActiveAlert>,
+    /// node id → metric → samples, oldest first
+    history: HashMap<u32, HashMap<&'static str, VecDeque<Sample>>>,
+    next_alert_id: u32,
+    /// Notifications and mesh broadcasts are held back until then
+    muted_until: Option<u64>,
+    /// When the latest mute ended, or will; escalation waits from then
+    mute_ended: Option<u64>,
+    /// Latest entries, oldest first
+    audit: VecDeque<AuditEntry>,
+    state_file: Option<PathBuf>,
+    audit_log: Option<PathBuf>,
+}
+
+impl Store {
+    pub fn new(config: &Config) -> Self {
+        let mut store = Self::default();
+        store.reload(config);
+        store
+    }
+
+    pub fn shared(config: &Config) -> SharedStore {
+        Arc::new(RwLock::new(Self::new(config)))
+    }
+
+    /// Applies node aliases and calibrations from the config, and resolves
+    /// alerts of rules it no longer has. Nodes that were dropped from the
+    /// config but have been heard stay listed, under their long name if the
+    /// radio knows one.
+    pub fn reload(&mut self, config: &Config) {
+        self.retention_secs = config.history.retention_hours * 3600;
+
+        for status in self.nodes.values_mut() {
+            status.configured = false;
+            status.gauge = false;
+            status.name = status
+                .long_name
+                .clone()
+                .unwrap_or_else(|| status.id.to_string());
+        }
+        for node in &config.nodes {
+            let status = self
+                .nodes
+                .entry(node.id.0)
+                .or_insert_with(|| NodeStatus::new(node.id.0, node.id.to_string()));
+            status.configured = true;
+            status.gauge = node.calibration.is_some();
+            if let Some(alias) = &node.alias {
+                status.name = alias.clone();
+            }
+        }
+        self.nodes.retain(|_, status| {
+            status.configured || status.last_heard.is_some() || status.long_name.is_some()
+        });
+        self.resolve_removed_rules(config, frame::now_secs());
+    }
+
+    /// Resolves alerts whose rule has been removed from the config. Nothing
+    /// would clear them, so they would stay active and keep escalating.
+    pub fn resolve_removed_rules(&mut self, config: &Config, at: u64) {
+        let removed: Vec<(String, u32)> = self
+            .alerts
+            .keys()
+            .filter(|(rule, _)| !alerts::rule_exists(config, rule))
+            .cloned()
+            .collect();
+        if removed.is_empty() {
+            return;
+        }
+        for key in removed {
+            if let Some(alert) = self.alerts.remove(&key) {
+                let detail = format!("{} {}: rule removed", alert.node_name, alert.rule);
+                self.log(at, AuditAction::Resolved, Some(alert.id), None, detail);
+            }
+        }
+        self.save();
+    }
+
+    /// Takes the link quality of a better copy of the node's last packet.
+    pub fn record_better_copy(&mut self, copies: &PacketCopies) {
+        let best = &copies.best;
+        if let Some(status) = self.nodes.get_mut(&copies.from)
+            && status.packet_id == copies.id
+            && best.rx_rssi != 0
+        {
+            status.rx_snr = Some(best.rx_snr);
+            status.rx_rssi = Some(best.rx_rssi);
+        }
+    }
+
+    /// Lists a node from the radio's node database before it is heard, and
+    /// names it by its long name unless it has an alias. Its position there
+    /// only stands in until the node sends a position of its own.
+    pub fn record_node_info(&mut self, info: &NodeInfo) {
+        let status = self
+            .nodes
+            .entry(info.node_id)
+            .or_insert_with(|| NodeStatus::new(info.node_id, info.node_name.clone()));
+        status.name.clone_from(&info.node_name);
+        status.long_name.clone_from(&info.long_name);
+        if status.position.is_none()
+            && let Some((timestamp, pos)) = &info.position
+        {
+            status.position = NodePosition::new(*timestamp, pos);
+        }
+    }
+
+    pub fn record_message(&mut self, msg: &DecodedMessage) {
+        let node_id = msg.message.node_id;
+        let status = self
+            .nodes
+            .entry(node_id)
+            .or_insert_with(|| NodeStatus::new(node_id, msg.node_name.clone()));
+        status.name = msg.node_name.clone();
+        status.last_heard = Some(msg.timestamp);
+        status.connection = Some(msg.connection.clone());
+        status.packet_id = msg.packet_id;
+        // Packets from the locally attached node carry no signal report
+        if msg.rx_rssi != 0 {
+            status.rx_snr = Some(msg.rx_snr);
+            status.rx_rssi = Some(msg.rx_rssi);
+        }
+
+        if let AppMessage::Position(pos) = &msg.message.app
+            && let Some(position) = NodePosition::new(msg.timestamp, pos)
+        {
+            status.position = Some(position);
+        }
+
+        // A flagged reading leaves the last forecast in place
+        if msg.quality("water_level").is_good()
+            && msg.readings.iter().any(|(m, _)| *m == "water_level")
+        {
+            status.forecast = msg.forecast.clone();
+        }
+
+        for &(metric, value) in &msg.readings {
+            let sample = Sample {
+                timestamp: msg.timestamp,
+                value,
+                quality: msg.quality(metric),
+            };
+            status.readings.insert(metric, sample);
+
+            let series = self
+                .history
+                .entry(node_id)
+                .or_default()
+                .entry(metric)
+                .or_default();
+            series.push_back(sample);
+            while let Some(oldest) = series.front() {
+                if msg.timestamp.saturating_sub(oldest.timestamp) <= self.retention_secs {
+                    break;
+                }
+                series.pop_front();
+            }
+        }
+    }
+
+    pub fn record_alert(&mut self, event: &AlertEvent) {
+        let key = (event.rule.clone(), event.node_id);
+        let description = format!(
+            "{} {} {} {:.2}, threshold {:.2}",
+            event.severity, event.node_name, event.rule, event.value, event.threshold
+        );
+        match event.state {
+            AlertState::Raised => {
+                // A rule raised again before clearing keeps its number and
+                // place in the lifecycle
+                if let Some(alert) = self.alerts.get_mut(&key) {
+                    alert.value = event.value;
+                    alert.threshold = event.threshold;
+                    alert.expected_at = event.expected_at;
+                    alert.severity = event.severity;
+                } else {
+                    self.next_alert_id += 1;
+                    let id = self.next_alert_id;
+                    self.alerts.insert(
+                        key,
+                        ActiveAlert {
+                            id,
+                            rule: event.rule.clone(),
+                            node: NodeId(event.node_id),
+                            node_name: event.node_name.clone(),
+                            metric: event.metric.clone(),
+                            value: event.value,
+                            threshold: event.threshold,
+                            expected_at: event.expected_at,
+                            severity: event.severity,
+                            raised_at: event.timestamp,
+                            status: AlertStatus::Open,
+                            tier: 0,
+                            escalated_at: None,
+                            acknowledged: None,
+                        },
+                    );
+                    self.log(
+                        event.timestamp,
+                        AuditAction::Raised,
+                        Some(id),
+                        None,
+                        description,
+                    );
+                }
+            }
+            AlertState::Cleared => {
+                if let Some(alert) = self.alerts.remove(&key) {
+                    self.log(
+                        event.timestamp,
+                        AuditAction::Resolved,
+                        Some(alert.id),
+                        None,
+                        description,
+                    );
+                }
+            }
+        }
+        self.save();
+    }
+
+    pub fn alert(&self, id: u32) -> Option<&ActiveAlert> {
+        self.alerts.values().find(|a| a.id == id)
+    }
+
+    /// The active alert of a rule on a node.
+    pub fn alert_for(&self, rule: &str, node_id: u32) -> Option<&ActiveAlert> {
+        self.alerts.get(&(rule.to_string(), node_id))
+    }
+
+    /// Marks an active alert as seen, unless someone already has. False
+    /// for an unknown id.
+    pub fn acknowledge(&mut self, id: u32, by: &str, at: u64) -> bool {
+        let Some(alert) = self.alerts.values_mut().find(|a| a.id == id) else {
+            return false;
+        };
+        if alert.acknowledged.is_some() {
+            return true;
+        }
+        alert.acknowledged = Some(Acknowledgement {
+            by: by.to_string(),
+            at,
+        });
+        alert.status = AlertStatus::Acknowledged;
+        let detail = format!("{} {}", alert.node_name, alert.rule);
+        self.log(at, AuditAction::Acknowledged, Some(id), Some(by), detail);
+        self.save();
+        true
+    }
+
+    /// Unacknowledged alerts below `max_tier` that have waited `after_secs`
+    /// at their current tier. Time spent muted does not count; an alert
+    /// waits its full time again once a mute ends.
+    pub fn due_escalations(&self, now: u64, after_secs: u64, max_tier: u32) -> Vec<u32> {
+        let unmuted = self.mute_ended.unwrap_or(0);
+        self.alerts
+            .values()
+            .filter(|a| a.acknowledged.is_none() && a.tier < max_tier)
+            .filter(|a| {
+                let since = a.escalated_at.unwrap_or(a.raised_at).max(unmuted);
+                now.saturating_sub(since) >= after_secs
+            })
+            .map(|a| a.id)
+            .collect()
+    }
+
+    /// Moves an unacknowledged alert up one contact tier, returning it.
+    pub fn escalate(&mut self, id: u32, at: u64) -> Option<ActiveAlert> {
+        let alert = self
+            .alerts
+            .values_mut()
+            .find(|a| a.id == id && a.acknowledged.is_none())?;
+        alert.tier += 1;
+        alert.escalated_at = Some(at);
+        alert.status = AlertStatus::Escalated;
+        let alert = alert.clone();
+        let detail = format!(
+            "{} {} unacknowledged, now tier {}",
+            alert.node_name, alert.rule, alert.tier
+        );
+        self.log(at, AuditAction::Escalated, Some(id), None, detail);
+        self.save();
+        Some(alert)
+    }
+
+    /// Holds back notifications until `until`, or lifts the mute with `None`.
+    pub fn mute(&mut self, until: Option<u64>, by: &str, at: u64) {
+        self.muted_until = until;
+        self.mute_ended = match until {
+            Some(until) => Some(until),
+            // Lifted early
+            None => self.mute_ended.map(|end| end.min(at)),
+        };
+        match until {
+            Some(until) => {
+                let detail = format!("until {}", crate::table::Timezone::Utc.format(until));
+                self.log(at, AuditAction::Muted, None, Some(by), detail);
+            }
+            None => self.log(at, AuditAction::Unmuted, None, Some(by), String::new()),
+        }
+        self.save();
+    }
+
+    /// Latest audit entries, oldest first.
+    pub fn audit(&self) -> impl Iterator<Item = &AuditEntry> {
+        self.audit.iter()
+    }
+
+    /// Restores alerts saved by an earlier run and keeps saving them, and
+    /// starts appending to the audit log, as configured.
+    pub fn persist(&mut self, config: &AlertLifecycleConfig) -> io::Result<()> {
+        if let Some(path) = &config.state_file
+            && path.exists()
+        {
+            let saved: SavedAlerts = serde_json::from_slice(&fs::read(path)?).map_err(|e| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("{}: {}", path.display(), e),
+                )
+            })?;
+            log::info!(
+                "Restored {} open alerts from {}",
+                saved.alerts.len(),
+                path.display()
+            );
+            self.next_alert_id = saved.next_alert_id;
+            self.muted_until = saved.muted_until;
+            self.mute_ended = saved.mute_ended;
+            self.alerts = saved
+                .alerts
+                .into_iter()
+                .map(|a| ((a.rule.clone(), a.node.0), a))
+                .collect();
+        }
+        self.state_file = config.state_file.clone();
+        self.audit_log = config.audit_log.clone();
+        Ok(())
+    }
+
+    /// Writes the alert state if it is kept across restarts. Failing to is
+    /// logged, as the monitor must keep alerting regardless.
+    fn save(&self) {
+        let Some(path) = &self.state_file else {
+            return;
+        };
+        let saved = SavedAlerts {
+            next_alert_id: self.next_alert_id,
+            muted_until: self.muted_until,
+            mute_ended: self.mute_ended,
+            alerts: self.alerts.values().cloned().collect(),
+        };
+        // Written aside first so a crash never leaves half a file
+        let tmp = path.with_extension("tmp");
+        let result = serde_json::to_vec_pretty(&saved)
+            .map_err(io::Error::other)
+            .and_then(|json| fs::write(&tmp, json))
+            .and_then(|()| fs::rename(&tmp, path));
+        if let Err(e) = result {
+            log::error!("Cannot save alert state to {}: {}", path.display(), e);
+        }
+    }
+
+    fn log(
+        &mut self,
+        time: u64,
+        action: AuditAction,
+        alert: Option<u32>,
+        by: Option<&str>,
+        detail: String,
+    ) {
+        let entry = AuditEntry {
+            time,
+            action,
+            alert,
+            by: by.map(str::to_string),
+            detail,
+        };
+        if let Some(path) = &self.audit_log {
+            let result = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .and_then(|mut file| {
+                    writeln!(
+                        file,
+                        "{}",
+                        serde_json::to_string(&entry).map_err(io::Error::other)?
+                    )
+                });
+            if let Err(e) = result {
+                log::error!("Cannot append to audit log {}: {}", path.display(), e);
+            }
+        }
+        if self.audit.len() == AUDIT_CAPACITY {
+            self.audit.pop_front();
+        }
+        self.audit.push_back(entry);
+    }
+
+    pub fn muted_until(&self) -> Option<u64> {
+        self.muted_until
+    }
+
+    pub fn muted(&self, now: u64) -> bool {
+        self.muted_until.is_some_and(|until| now < until)
+    }
+
+    /// Looks a node up by alias or id, as written in config.
+    pub fn find_node(&self, name: &str) -> Option<&NodeStatus> {
+        self.nodes
+            .values()
+            .find(|n| n.name == name)
+            .or_else(|| self.nodes.get(&name.parse::<NodeId>().ok()?.0))
+    }
+
+    pub fn nodes(&self) -> impl Iterator<Item = &NodeStatus> {
+        self.nodes.values()
+    }
+
+    /// Active alerts, most severe first.
+    pub fn active_alerts(&self) -> Vec<&ActiveAlert> {
+        let mut alerts: Vec<_> = self.alerts.values().collect();
+        alerts.sort_by_key(|a| std::cmp::Reverse(a.severity));
+        alerts
+    }
+
+    /// Samples of one node's metric with `from <= timestamp <= to`.
+    pub fn history(
+        &self,
+        node_id: u32,
+        metric: &str,
+        from: Option<u64>,
+        to: Option<u64>,
+    ) -> Vec<Sample> {
+        let Some(series) = self.history.get(&node_id).and_then(|m| m.get(metric)) else {
+            return Vec::new();
+        };
+
+        series
+            .iter()
+            .filter(|s| from.is_none_or(|from| s.timestamp >= from))
+            .filter(|s| to.is_none_or(|to| s.timestamp <= to))
+            .copied()
+            .collect()
+    }
+}
+
+/// Keeps the shared store up to date from the pipeline.
+pub struct StoreSink(pub SharedStore);
+
+impl Sink for StoreSink {
+    fn name(&self) -> &'static str {
+        "store"
+    }
+
+    fn on_message(&mut self, msg: &DecodedMessage) {
+        self.0.write().unwrap().record_message(msg);
+    }
+
+    fn on_alert(&mut self, event: &AlertEvent) {
+        self.0.write().unwrap().record_alert(event);
+    }
+
+    fn on_node_info(&mut self, info: &NodeInfo) {
+        self.0.write().unwrap().record_node_info(info);
+    }
+
+    fn on_better_copy(&mut self, copies: &PacketCopies) {
+        self.0.write().unwrap().record_better_copy(copies);
+    }
+
+    fn reload(&mut self, config: &Config) {
+        self.0.write().unwrap().reload(config);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::radio_message::Telemetry;
+
+    fn config() -> Config {
+        Config::parse(
+            r#"
+            [history]
+            retention_hours = 1
+
+            [[nodes]]
+            id = 5
+            alias = "bridge"
+            calibration = { offset = 6.0, scale = -0.001 }
+            "#,
+        )
+        .unwrap()
+    }
+
+    fn level(timestamp: u64, value: f64) -> DecodedMessage {
+        let app = AppMessage::Telemetry(Telemetry::Environment {
+            temperature: None,
+            humidity: None,
+            pressure: None,
+            distance: None,
+            rainfall_1h: None,
+            rainfall_24h: None,
+        });
+        DecodedMessage::test(5, "bridge", timestamp, app, vec![("water_level", value)])
+    }
+
+    #[test]
+    fn keeps_latest_reading_and_bounded_history() {
+        let mut store = Store::new(&config());
+        assert!(store.find_node("bridge").unwrap().last_heard.is_none());
+
+        store.record_message(&level(1_000, 3.0));
+        store.record_message(&level(2_000, 3.5));
+        store.record_message(&level(5_000, 4.0));
+
+        let bridge = store.find_node("!00000005").unwrap();
+        assert_eq!(bridge.last_heard, Some(5_000));
+        assert_eq!(bridge.readings["water_level"].value, 4.0);
+
+        // 1_000 fell out of the one hour retention
+        let all = store.history(5, "water_level", None, None);
+        assert_eq!(
+            all.iter().map(|s| s.timestamp).collect::<Vec<_>>(),
+            vec![2_000, 5_000]
+        );
+        assert_eq!(store.history(5, "water_level", Some(3_000), None).len(), 1);
+        assert!(store.history(5, "battery_level", None, None).is_empty());
+    }
+
+    #[test]
+    fn names_unaliased_nodes_by_long_name() {
+        let mut store = Store::new(&config());
+        for (node_id, node_name, long_name) in [
+            (5, "bridge", "Bridge St sensor"),
+            (6, "Mill Creek", "Mill Creek"),
+        ] {
+            store.record_node_info(&NodeInfo {
+                node_id,
+                node_name: node_name.to_string(),
+                long_name: Some(long_name.to_string()),
+                position: None,
+            });
+        }
+        assert_eq!(store.find_node("!00000005").unwrap().name, "bridge");
+        assert_eq!(store.find_node("!00000006").unwrap().name, "Mill Creek");
+
+        // Dropping the alias falls back on the long name, not the id
+        store.reload(&Config::default());
+        assert_eq!(
+            store.find_node("!00000005").unwrap().name,
+            "Bridge St sensor"
+        );
+        assert_eq!(store.find_node("Mill Creek").unwrap().id, NodeId(6));
+    }
+
+    #[test]
+    fn escalation_waits_out_a_mute() {
+        let mut store = Store::new(&config());
+        store.record_alert(&AlertEvent {
+            rule: "flood".to_string(),
+            timestamp: 1_000,
+            node_id: 5,
+            node_name: "bridge".to_string(),
+            metric: "water_level".to_string(),
+            value: 4.1,
+            threshold: 4.0,
+            expected_at: None,
+            severity: Severity::Critical,
+            state: AlertState::Raised,
+        });
+        store.mute(Some(5_000), "!0000abcd", 1_100);
+        store.mute(None, "!0000abcd", 3_000);
+        // Overdue since 1_900, but only unmuted at 3_000
+        assert!(store.due_escalations(3_100, 900, 1).is_empty());
+        assert_eq!(store.due_escalations(3_900, 900, 1), [1]);
+
+        // A mute left to run out counts the same
+        assert_eq!(store.escalate(1, 3_900).unwrap().tier, 1);
+        store.mute(Some(6_000), "!0000abcd", 4_000);
+        assert!(store.due_escalations(6_500, 900, 2).is_empty());
+        assert_eq!(store.due_escalations(6_900, 900, 2), [1]);
+    }
+
+    #[test]
+    fn alert_lifecycle_survives_a_restart() {
+        let dir = std::env::temp_dir().join(format!("alert-lifecycle-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let lifecycle = AlertLifecycleConfig {
+            state_file: Some(dir.join("alerts.json")),
+            audit_log: Some(dir.join("audit.jsonl")),
+            ..AlertLifecycleConfig::default()
+        };
+        let mut event = AlertEvent {
+            rule: "flood".to_string(),
+            timestamp: 1_000,
+            node_id: 5,
+            node_name: "bridge".to_string(),
+            metric: "water_level".to_string(),
+            value: 4.1,
+            threshold: 4.0,
+            expected_at: None,
+            severity: Severity::Critical,
+            state: AlertState::Raised,
+        };
+
+        let mut store = Store::new(&config());
+        store.persist(&lifecycle).unwrap();
+        store.record_alert(&event);
+        assert!(store.due_escalations(1_500, 900, 1).is_empty());
+        assert_eq!(store.due_escalations(1_900, 900, 1), [1]);
+        assert_eq!(store.escalate(1, 1_900).unwrap().tier, 1);
+        // Tier 1 is the last one
+        assert!(store.due_escalations(5_000, 900, 1).is_empty());
+
+        let mut restarted = Store::new(&config());
+        restarted.persist(&lifecycle).unwrap();
+        let alert = restarted.alert(1).unwrap();
+        assert_eq!(
+            (alert.status, alert.tier, alert.escalated_at),
+            (AlertStatus::Escalated, 1, Some(1_900))
+        );
+        assert!(restarted.acknowledge(1, "!0000abcd", 2_000));
+        assert_eq!(
+            restarted.alert(1).unwrap().status,
+            AlertStatus::Acknowledged
+        );
+        event.timestamp = 3_000;
+        event.state = AlertState::Cleared;
+        restarted.record_alert(&event);
+        assert!(restarted.alert(1).is_none());
+
+        // Numbering carries on where it left off
+        event.state = AlertState::Raised;
+        restarted.record_alert(&event);
+        assert!(restarted.alert(2).is_some());
+
+        let audit = fs::read_to_string(dir.join("audit.jsonl")).unwrap();
+        let actions: Vec<AuditAction> = audit
+            .lines()
+            .map(|line| serde_json::from_str::<AuditEntry>(line).unwrap().action)
+            .collect();
+        assert_eq!(
+            actions,
+            [
+                AuditAction::Raised,
+                AuditAction::Escalated,
+                AuditAction::Acknowledged,
+                AuditAction::Resolved,
+                AuditAction::Raised
+            ]
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn resolves_alerts_of_removed_rules() {
+        let with_rule = Config::parse(
+            r#"
+            [[nodes]]
+            id = 5
+            alias = "bridge"
+
+            [[alerts]]
+            name = "flood"
+            metric = "water_level"
+            above = 4.0
+            "#,
+        )
+        .unwrap();
+        let mut store = Store::new(&with_rule);
+        for rule in ["flood", "flood-forecast", "node-stale"] {
+            store.record_alert(&AlertEvent {
+                rule: rule.to_string(),
+                timestamp: 1_000,
+                node_id: 5,
+                node_name: "bridge".to_string(),
+                metric: "water_level".to_string(),
+                value: 4.1,
+                threshold: 4.0,
+                expected_at: None,
+                severity: Severity::Critical,
+                state: AlertState::Raised,
+            });
+        }
+        store.reload(&with_rule);
+        assert_eq!(store.active_alerts().len(), 3);
+
+        // The rule and its forecast go; the built-in heartbeat alert stays
+        store.reload(&config());
+        let active: Vec<&str> = store
+            .active_alerts()
+            .iter()
+            .map(|a| a.rule.as_str())
+            .collect();
+        assert_eq!(active, ["node-stale"]);
+        assert_eq!(store.due_escalations(u64::MAX, 900, 1), [3]);
+        let resolved: Vec<&str> = store
+            .audit()
+            .filter(|e| e.action == AuditAction::Resolved)
+            .map(|e| e.detail.as_str())
+            .collect();
+        assert_eq!(
+            resolved,
+            [
+                "bridge flood: rule removed",
+                "bridge flood-forecast: rule removed"
+            ]
+        );
+    }
+}
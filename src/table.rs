@@ -0,0 +1,670 @@
+use std::collections::{BTreeMap, HashMap};
+use std::io::{self, Write};
+use std::str::FromStr;
+use std::sync::Arc;
+
+use chrono::{DateTime, FixedOffset, Local, Offset, SecondsFormat, TimeZone, Utc};
+use parquet::basic::Compression;
+use parquet::data_type::{ByteArray, ByteArrayType, DoubleType, Int64Type};
+use parquet::file::properties::WriterProperties;
+use parquet::file::writer::SerializedFileWriter;
+use parquet::schema::parser::parse_message_type;
+
+use crate::config::NodeId;
+use crate::quality::Quality;
+use crate::radio_message::METRIC_NAMES;
+use crate::store::{Sample, Store};
+
+/// Rows buffered per Parquet row group; bounds memory on large exports.
+const ROW_GROUP_SIZE: usize = 65_536;
+
+/// How timestamps are written to CSV and where resampling buckets start.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Timezone {
+    #[default]
+    Utc,
+    /// The machine's zone, daylight saving included
+    Local,
+    Fixed(FixedOffset),
+}
+
+impl Timezone {
+    fn offset_at(&self, timestamp: u64) -> FixedOffset {
+        match self {
+            Timezone::Utc => Utc.fix(),
+            Timezone::Local => Local
+                .timestamp_opt(timestamp as i64, 0)
+                .single()
+                .map_or_else(|| Utc.fix(), |t| t.offset().fix()),
+            Timezone::Fixed(offset) => *offset,
+        }
+    }
+
+    /// RFC 3339 in this zone, e.g. `2025-12-28T04:37:51+02:00`.
+    pub fn format(&self, timestamp: u64) -> String {
+        DateTime::from_timestamp(timestamp as i64, 0)
+            .unwrap_or_default()
+            .with_timezone(&self.offset_at(timestamp))
+            .to_rfc3339_opts(SecondsFormat::Secs, true)
+    }
+}
+
+impl FromStr for Timezone {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "utc" | "UTC" | "Z" => Ok(Timezone::Utc),
+            "local" => Ok(Timezone::Local),
+            offset => offset.parse().map(Timezone::Fixed).map_err(|_| {
+                format!(
+                    "unknown timezone `{}`, expected utc, local or an offset like +02:00",
+                    offset
+                )
+            }),
+        }
+    }
+}
+
+/// What goes into a telemetry table.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TableOptions {
+    /// Metric columns, after `timestamp`, `node` and `name`; every metric
+    /// by default
+    pub columns: Vec<&'static str>,
+    /// Average readings into buckets of this many seconds; `None` writes
+    /// one row per telemetry packet
+    pub interval: Option<u64>,
+    pub timezone: Timezone,
+}
+
+impl Default for TableOptions {
+    fn default() -> Self {
+        Self {
+            columns: METRIC_NAMES.to_vec(),
+            interval: None,
+            timezone: Timezone::Utc,
+        }
+    }
+}
+
+/// Parses a comma-separated list of metric names, any of
+/// [`METRIC_NAMES`].
+pub fn parse_columns(list: &str) -> Result<Vec<&'static str>, String> {
+    list.split(',')
+        .map(str::trim)
+        .filter(|s| !s.is_empty())
+        .map(|name| {
+            METRIC_NAMES
+                .iter()
+                .find(|m| **m == name)
+                .copied()
+                .ok_or_else(|| format!("unknown column `{}`", name))
+        })
+        .collect()
+}
+
+/// Parses `90`, `30s`, `15m`, `6h` or `1d` into seconds.
+pub fn parse_interval(s: &str) -> Result<u64, String> {
+    let (digits, unit) = match s.find(|c: char| !c.is_ascii_digit()) {
+        Some(i) => s.split_at(i),
+        None => (s, "s"),
+    };
+    let unit = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 3600,
+        "d" => 86_400,
+        _ => return Err(format!("invalid interval `{}`", s)),
+    };
+    match digits.parse::<u64>() {
+        Ok(n) if n > 0 => Ok(n * unit),
+        _ => Err(format!("invalid interval `{}`", s)),
+    }
+}
+
+/* ---------------- Rows ---------------- */
+
+/// One row of the wide table; `values` follow `TableOptions::columns`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Row {
+    pub timestamp: u64,
+    pub node: u32,
+    pub name: String,
+    pub values: Vec<Option<f64>>,
+    /// Flagged columns as `metric:code` separated by spaces, e.g.
+    /// `distance:spike water_level:spike`; empty when all are good
+    pub quality: String,
+}
+
+/// Readings averaged over one resampling interval of one node. Flagged
+/// readings are left out of the averages but listed in the row's quality.
+struct Bucket {
+    start: u64,
+    name: String,
+    sums: Vec<f64>,
+    counts: Vec<u32>,
+    flags: Vec<(usize, Quality)>,
+}
+
+impl Bucket {
+    fn into_row(self, node: u32, columns: &[&str]) -> Row {
+        Row {
+            timestamp: self.start,
+            node,
+            name: self.name,
+            values: self
+                .sums
+                .iter()
+                .zip(&self.counts)
+                .map(|(sum, &count)| (count > 0).then(|| sum / f64::from(count)))
+                .collect(),
+            quality: quality_field(self.flags.iter().map(|&(i, q)| (columns[i], q))),
+        }
+    }
+}
+
+fn quality_field<'a>(flags: impl Iterator<Item = (&'a str, Quality)>) -> String {
+    flags
+        .map(|(metric, quality)| format!("{}:{}", metric, quality))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Turns readings into rows as they arrive and hands them to a writer.
+/// Only one open bucket per node is held, so input of any length streams
+/// through in constant memory.
+pub struct TableBuilder {
+    options: TableOptions,
+    writer: Box<dyn TableWriter>,
+    buckets: HashMap<u32, Bucket>,
+    rows: usize,
+}
+
+impl TableBuilder {
+    pub fn new(options: TableOptions, writer: Box<dyn TableWriter>) -> Self {
+        Self {
+            options,
+            writer,
+            buckets: HashMap::new(),
+            rows: 0,
+        }
+    }
+
+    /// Adds the readings of one packet, with the quality codes of those
+    /// that are not good. Non-finite values are left out.
+    pub fn push(
+        &mut self,
+        timestamp: u64,
+        node: u32,
+        name: &str,
+        readings: &[(&str, f64)],
+        quality: &[(&str, Quality)],
+    ) -> io::Result<()> {
+        let values: Vec<Option<f64>> = self
+            .options
+            .columns
+            .iter()
+            .map(|column| {
+                readings
+                    .iter()
+                    .find(|(metric, value)| metric == column && value.is_finite())
+                    .map(|(_, value)| *value)
+            })
+            .collect();
+        if values.iter().all(Option::is_none) {
+            return Ok(());
+        }
+        let flags: Vec<(usize, Quality)> = self
+            .options
+            .columns
+            .iter()
+            .enumerate()
+            .filter_map(|(i, column)| {
+                let &(_, q) = quality.iter().find(|(m, _)| m == column)?;
+                Some((i, q))
+            })
+            .collect();
+
+        let Some(interval) = self.options.interval else {
+            let columns = &self.options.columns;
+            let quality = quality_field(flags.iter().map(|&(i, q)| (columns[i], q)));
+            return self.write(Row {
+                timestamp,
+                node,
+                name: name.to_string(),
+                values,
+                quality,
+            });
+        };
+
+        // Buckets line up with midnight in the chosen timezone
+        let offset = self.options.timezone.offset_at(timestamp).local_minus_utc() as i64;
+        let local = timestamp as i64 + offset;
+        let start = (local - local.rem_euclid(interval as i64) - offset).max(0) as u64;
+
+        if self.buckets.get(&node).is_some_and(|b| b.start != start)
+            && let Some(done) = self.buckets.remove(&node)
+        {
+            let row = done.into_row(node, &self.options.columns);
+            self.write(row)?;
+        }
+        let columns = self.options.columns.len();
+        let bucket = self.buckets.entry(node).or_insert_with(|| Bucket {
+            start,
+            name: String::new(),
+            sums: vec![0.0; columns],
+            counts: vec![0; columns],
+            flags: Vec::new(),
+        });
+        bucket.name = name.to_string();
+        for (i, value) in values.iter().enumerate() {
+            if let Some(&(_, q)) = flags.iter().find(|(f, _)| *f == i) {
+                if !bucket.flags.contains(&(i, q)) {
+                    bucket.flags.push((i, q));
+                }
+            } else if let Some(value) = value {
+                bucket.sums[i] += value;
+                bucket.counts[i] += 1;
+            }
+        }
+        Ok(())
+    }
+
+    fn write(&mut self, row: Row) -> io::Result<()> {
+        self.rows += 1;
+        self.writer.write_row(&row)
+    }
+
+    /// Writes the open buckets and closes the output. Returns the rows written.
+    pub fn finish(mut self) -> io::Result<usize> {
+        let mut open: Vec<(u32, Bucket)> = self.buckets.drain().collect();
+        open.sort_by_key(|(node, bucket)| (bucket.start, *node));
+        for (node, bucket) in open {
+            let row = bucket.into_row(node, &self.options.columns);
+            self.write(row)?;
+        }
+        self.writer.finish()?;
+        Ok(self.rows)
+    }
+}
+
+/// Feeds the in-memory history of every node through `table`, oldest
+/// first per node, limited to `from <= timestamp <= to`.
+pub fn push_store(
+    table: &mut TableBuilder,
+    store: &Store,
+    from: Option<u64>,
+    to: Option<u64>,
+) -> io::Result<()> {
+    let columns = table.options.columns.clone();
+    for node in store.nodes() {
+        let mut packets: BTreeMap<u64, Vec<(&str, Sample)>> = BTreeMap::new();
+        for metric in &columns {
+            for sample in store.history(node.id.0, metric, from, to) {
+                packets
+                    .entry(sample.timestamp)
+                    .or_default()
+                    .push((metric, sample));
+            }
+        }
+        for (timestamp, samples) in packets {
+            let readings: Vec<(&str, f64)> = samples.iter().map(|(m, s)| (*m, s.value)).collect();
+            let quality: Vec<(&str, Quality)> = samples
+                .iter()
+                .filter(|(_, s)| !s.quality.is_good())
+                .map(|(m, s)| (*m, s.quality))
+                .collect();
+            table.push(timestamp, node.id.0, &node.name, &readings, &quality)?;
+        }
+    }
+    Ok(())
+}
+
+/* ---------------- Writers ---------------- */
+
+pub trait TableWriter {
+    fn write_row(&mut self, row: &Row) -> io::Result<()>;
+
+    /// Writes anything buffered and the file footer, if the format has one.
+    fn finish(&mut self) -> io::Result<()>;
+}
+
+/// Comma-separated values with a header line. Timestamps are RFC 3339 in
+/// the chosen timezone; missing readings are empty fields.
+pub struct CsvWriter<W: Write> {
+    out: W,
+    timezone: Timezone,
+}
+
+impl<W: Write> CsvWriter<W> {
+    pub fn new(mut out: W, options: &TableOptions) -> io::Result<Self> {
+        write!(out, "timestamp,node,name")?;
+        for column in &options.columns {
+            write!(out, ",{}", column)?;
+        }
+        writeln!(out, ",quality")?;
+        Ok(Self {
+            out,
+            timezone: options.timezone,
+        })
+    }
+}
+
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+impl<W: Write> TableWriter for CsvWriter<W> {
+    fn write_row(&mut self, row: &Row) -> io::Result<()> {
+        write!(
+            self.out,
+            "{},{},{}",
+            self.timezone.format(row.timestamp),
+            NodeId(row.node),
+            csv_field(&row.name)
+        )?;
+        for value in &row.values {
+            match value {
+                Some(value) => write!(self.out, ",{}", value)?,
+                None => write!(self.out, ",")?,
+            }
+        }
+        writeln!(self.out, ",{}", row.quality)
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
+
+/// Parquet with a UTC millisecond `timestamp`, `node` and `name` strings,
+/// one optional double per metric column and a `quality` string. Rows are
+/// written in row groups as they fill up.
+pub struct ParquetWriter<W: Write + Send> {
+    // `None` once finished
+    writer: Option<SerializedFileWriter<W>>,
+    pending: Vec<Row>,
+}
+
+impl<W: Write + Send> ParquetWriter<W> {
+    pub fn new(out: W, options: &TableOptions) -> io::Result<Self> {
+        let mut schema = String::from(
+            "message telemetry {
+                REQUIRED INT64 timestamp (TIMESTAMP(MILLIS,true));
+                REQUIRED BYTE_ARRAY node (UTF8);
+                REQUIRED BYTE_ARRAY name (UTF8);",
+        );
+        for column in &options.columns {
+            schema.push_str(&format!("OPTIONAL DOUBLE {};", column));
+        }
+        schema.push_str("REQUIRED BYTE_ARRAY quality (UTF8);}");
+
+        let schema = Arc::new(parse_message_type(&schema).map_err(io::Error::other)?);
+        let properties = Arc::new(
+            WriterProperties::builder()
+                .set_compression(Compression::SNAPPY)
+                .build(),
+        );
+        let writer =
+            SerializedFileWriter::new(out, schema, properties).map_err(io::Error::other)?;
+        Ok(Self {
+            writer: Some(writer),
+            pending: Vec::new(),
+        })
+    }
+
+    fn write_row_group(&mut self) -> parquet::errors::Result<()> {
+        let (Some(writer), false) = (self.writer.as_mut(), self.pending.is_empty()) else {
+            return Ok(());
+        };
+        let rows = std::mem::take(&mut self.pending);
+        let mut group = writer.next_row_group()?;
+        let mut index = 0;
+        let quality_index = 3 + rows.first().map_or(0, |r| r.values.len());
+        while let Some(mut column) = group.next_column()? {
+            match index {
+                0 => {
+                    let millis: Vec<i64> = rows.iter().map(|r| r.timestamp as i64 * 1000).collect();
+                    column
+                        .typed::<Int64Type>()
+                        .write_batch(&millis, None, None)?;
+                }
+                _ if index == 1 || index == 2 || index == quality_index => {
+                    let strings: Vec<ByteArray> = rows
+                        .iter()
+                        .map(|r| match index {
+                            1 => ByteArray::from(NodeId(r.node).to_string().as_str()),
+                            2 => ByteArray::from(r.name.as_str()),
+                            _ => ByteArray::from(r.quality.as_str()),
+                        })
+                        .collect();
+                    column
+                        .typed::<ByteArrayType>()
+                        .write_batch(&strings, None, None)?;
+                }
+                _ => {
+                    let values: Vec<f64> =
+                        rows.iter().filter_map(|r| r.values[index - 3]).collect();
+                    let defined: Vec<i16> = rows
+                        .iter()
+                        .map(|r| i16::from(r.values[index - 3].is_some()))
+                        .collect();
+                    column
+                        .typed::<DoubleType>()
+                        .write_batch(&values, Some(&defined), None)?;
+                }
+            }
+            column.close()?;
+            index += 1;
+        }
+        group.close()?;
+        Ok(())
+    }
+}
+
+impl<W: Write + Send> TableWriter for ParquetWriter<W> {
+    fn write_row(&mut self, row: &Row) -> io::Result<()> {
+        self.pending.push(row.clone());
+        if self.pending.len() >= ROW_GROUP_SIZE {
+            self.write_row_group().map_err(io::Error::other)?;
+        }
+        Ok(())
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        self.write_row_group().map_err(io::Error::other)?;
+        if let Some(writer) = self.writer.take() {
+            writer.close().map_err(io::Error::other)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    /// Collects rows for inspection.
+    struct Rows(Arc<Mutex<Vec<Row>>>);
+
+    impl TableWriter for Rows {
+        fn write_row(&mut self, row: &Row) -> io::Result<()> {
+            self.0.lock().unwrap().push(row.clone());
+            Ok(())
+        }
+
+        fn finish(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn table(options: TableOptions) -> (TableBuilder, Arc<Mutex<Vec<Row>>>) {
+        let rows = Arc::new(Mutex::new(Vec::new()));
+        let builder = TableBuilder::new(options, Box::new(Rows(rows.clone())));
+        (builder, rows)
+    }
+
+    #[test]
+    fn parses_options() {
+        assert_eq!(parse_interval("15m"), Ok(900));
+        assert_eq!(parse_interval("90"), Ok(90));
+        assert!(parse_interval("0h").is_err());
+        assert!(parse_interval("5w").is_err());
+        assert_eq!(
+            parse_columns("water_level, battery_level"),
+            Ok(vec!["water_level", "battery_level"])
+        );
+        assert!(parse_columns("depth").is_err());
+        // Derived metrics are columns too
+        assert_eq!(
+            parse_columns("rainfall_1h,rain_24h,rain_intensity,hours_to_cutoff").map(|c| c.len()),
+            Ok(4)
+        );
+
+        let tz: Timezone = "+02:00".parse().unwrap();
+        assert_eq!(tz.format(1_766_889_471), "2025-12-28T04:37:51+02:00");
+        assert_eq!(Timezone::Utc.format(1_766_889_471), "2025-12-28T02:37:51Z");
+        assert!("Mars/Olympus".parse::<Timezone>().is_err());
+    }
+
+    #[test]
+    fn writes_one_row_per_packet_with_selected_columns() {
+        let (mut builder, rows) = table(TableOptions {
+            columns: vec!["water_level", "battery_level"],
+            ..TableOptions::default()
+        });
+        builder
+            .push(
+                100,
+                5,
+                "bridge",
+                &[("distance", 2500.0), ("water_level", 3.5)],
+                &[],
+            )
+            .unwrap();
+        // Nothing selected, so no row
+        builder
+            .push(110, 5, "bridge", &[("humidity", 80.0)], &[])
+            .unwrap();
+        builder
+            .push(120, 5, "bridge", &[("battery_level", 90.0)], &[])
+            .unwrap();
+        assert_eq!(builder.finish().unwrap(), 2);
+
+        let rows = rows.lock().unwrap();
+        assert_eq!(rows[0].values, vec![Some(3.5), None]);
+        assert_eq!(rows[1].timestamp, 120);
+        assert_eq!(rows[1].values, vec![None, Some(90.0)]);
+    }
+
+    #[test]
+    fn averages_into_buckets_aligned_to_the_timezone() {
+        let (mut builder, rows) = table(TableOptions {
+            columns: vec!["water_level"],
+            interval: Some(86_400),
+            timezone: "+02:00".parse().unwrap(),
+        });
+        // 21:00 and 23:00 UTC on the first day are on either side of
+        // midnight at +02:00
+        builder
+            .push(75_600, 5, "bridge", &[("water_level", 1.0)], &[])
+            .unwrap();
+        builder
+            .push(82_800, 5, "bridge", &[("water_level", 3.0)], &[])
+            .unwrap();
+        builder
+            .push(86_400, 5, "bridge", &[("water_level", 5.0)], &[])
+            .unwrap();
+        // A spike is left out of the average but listed
+        builder
+            .push(
+                87_000,
+                5,
+                "bridge",
+                &[("water_level", 40.0)],
+                &[("water_level", Quality::Spike)],
+            )
+            .unwrap();
+        builder
+            .push(1_000, 6, "ford", &[("water_level", 2.0)], &[])
+            .unwrap();
+        assert_eq!(builder.finish().unwrap(), 3);
+
+        let rows = rows.lock().unwrap();
+        assert_eq!((rows[0].node, rows[0].values[0]), (5, Some(1.0)));
+        assert_eq!(rows[0].timestamp, 0);
+        assert_eq!(rows[2].timestamp, 79_200);
+        assert_eq!((rows[2].node, rows[2].values[0]), (5, Some(4.0)));
+        assert_eq!(rows[2].quality, "water_level:spike");
+        assert_eq!(rows[0].quality, "");
+    }
+
+    #[test]
+    fn csv_quotes_names_and_leaves_gaps_empty() {
+        let options = TableOptions {
+            columns: vec!["water_level", "battery_level"],
+            ..TableOptions::default()
+        };
+        let mut out = Vec::new();
+        let mut writer = CsvWriter::new(&mut out, &options).unwrap();
+        writer
+            .write_row(&Row {
+                timestamp: 0,
+                node: 5,
+                name: "main st, bridge".to_string(),
+                values: vec![Some(3.25), None],
+                quality: "water_level:stuck".to_string(),
+            })
+            .unwrap();
+        writer.finish().unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "timestamp,node,name,water_level,battery_level,quality\n\
+             1970-01-01T00:00:00Z,!00000005,\"main st, bridge\",3.25,,water_level:stuck\n"
+        );
+    }
+
+    #[test]
+    fn parquet_round_trips_rows() {
+        use parquet::file::reader::{FileReader, SerializedFileReader};
+
+        let options = TableOptions {
+            columns: vec!["water_level", "battery_level"],
+            ..TableOptions::default()
+        };
+        let path = std::env::temp_dir().join(format!("table-{}.parquet", std::process::id()));
+        let mut writer =
+            ParquetWriter::new(std::fs::File::create(&path).unwrap(), &options).unwrap();
+        for (timestamp, level) in [(100, Some(3.5)), (200, None)] {
+            writer
+                .write_row(&Row {
+                    timestamp,
+                    node: 5,
+                    name: "bridge".to_string(),
+                    values: vec![level, Some(90.0)],
+                    quality: String::new(),
+                })
+                .unwrap();
+        }
+        writer.finish().unwrap();
+
+        let reader = SerializedFileReader::new(std::fs::File::open(&path).unwrap()).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let rows: Vec<String> = reader
+            .get_row_iter(None)
+            .unwrap()
+            .map(|row| row.unwrap().to_string())
+            .collect();
+        assert_eq!(rows.len(), 2);
+        assert!(rows[0].contains("name: \"bridge\""), "{}", rows[0]);
+        assert!(rows[0].contains("water_level: 3.5"), "{}", rows[0]);
+        assert!(rows[1].contains("water_level: null"), "{}", rows[1]);
+        assert!(rows[1].contains("quality: \"\""), "{}", rows[1]);
+    }
+}